@@ -0,0 +1,163 @@
+//! Measures how request throughput scales with the number of threads sending
+//! requests, for a store in a single shard and for a sharded one, each with
+//! and without a write-ahead log. Requests go through
+//! `ConstructCacheServer::handle_request`, the same path the server takes
+//! for a request off the socket: decoding it, the keyspace with its locks,
+//! eviction bookkeeping and log, and encoding the response.
+//!
+//! Run with `cargo bench --bench store_scaling`. Each thread runs a mix of
+//! reads and updates over random keys for a fixed time; the table shows the
+//! total requests per second.
+//!
+//! With a log, every update is appended and synced to disk while holding the
+//! log's lock, so updates run one at a time however many shards there are.
+//! Those columns mostly measure how fast the disk syncs, and sharding does
+//! little for them beyond letting reads run alongside the sync.
+
+use construct_cache::key_value_store::sharded_store::DEFAULT_SHARD_COUNT;
+use construct_cache::proto::*;
+use construct_cache::socket_interface::server_impl::{ConstructCacheServer, ServerOptions,
+    Session};
+use prost::Message;
+use std::path::PathBuf;
+use std::sync::{Arc, Barrier};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const KEY_COUNT: u64 = 100_000;
+const RUN_TIME: Duration = Duration::from_millis(1000);
+/// Out of every 10 requests, how many are updates
+const WRITES_PER_10: u64 = 2;
+/// Requests between checks of the clock
+const BATCH: u64 = 256;
+/// Keys created by each transaction filling the store, the most one may hold
+const FILL_BATCH: u64 = 1000;
+const STORE: &str = "bench";
+
+/// A server to run the workload against, along with where its log is kept
+struct Setup {
+    name: String,
+    server: Arc<ConstructCacheServer>,
+    wal_dir: Option<PathBuf>,
+}
+
+impl Drop for Setup {
+    fn drop(&mut self) {
+        if let Some(dir) = &self.wal_dir {
+            let _ = std::fs::remove_dir_all(dir);
+        }
+    }
+}
+
+/// Starts a server with `shard_count` shards, logging to a fresh directory
+/// if `wal`, and fills its store. The store is filled in transactions, each
+/// logged as a single record, so a logged store fills without syncing for
+/// every key.
+fn filled_server(shard_count: usize, wal: bool) -> Setup {
+    let wal_dir = wal.then(|| {
+        let dir = std::env::temp_dir()
+            .join(format!("store_scaling_{}_{}", std::process::id(), shard_count));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    });
+    let options = ServerOptions {
+        wal_file: wal_dir.as_ref().map(|d| d.join("bench.wal").to_string_lossy().into_owned()),
+        shard_count: Some(shard_count),
+        ..Default::default()
+    };
+    let server = ConstructCacheServer::with_options("127.0.0.1:0", STORE, options).unwrap();
+    let mut session = Session::new(STORE);
+    for start in (0..KEY_COUNT).step_by(FILL_BATCH as usize) {
+        let ops = (start..KEY_COUNT.min(start + FILL_BATCH)).map(|i| TxnOp {
+            op: Some(txn_op::Op::Create(TxnWrite {
+                pair: Some(GenericKeyValuePair {
+                    key: format!("key{}", i).into_bytes(),
+                    data_type: DataType::String as i32,
+                    value: b"value".to_vec()
+                }),
+                ttl_ms: None
+            }))
+        }).collect();
+        let req = TransactionReq { ops, store: None };
+        let resp = server.handle_request(ReqType::Exec, &req.encode_to_vec(), &mut session);
+        assert!(TransactionResp::decode(resp.as_slice()).unwrap().success);
+    }
+    let name = format!("{} shard{}{}", shard_count, if shard_count == 1 { "" } else { "s" },
+        if wal { " + WAL" } else { "" });
+    Setup { name, server, wal_dir }
+}
+
+/// Runs the workload on `threads` threads and returns requests per second
+fn run(server: &Arc<ConstructCacheServer>, threads: usize) -> f64 {
+    let barrier = Arc::new(Barrier::new(threads + 1));
+    let handles: Vec<_> = (0..threads)
+        .map(|t| {
+            let server = server.clone();
+            let barrier = barrier.clone();
+            thread::spawn(move || {
+                let mut session = Session::new(STORE);
+                // xorshift must not start at zero
+                let mut rng = (t as u64 + 1).wrapping_mul(0x9E37_79B9_7F4A_7C15);
+                let mut ops = 0;
+                barrier.wait();
+                let start = Instant::now();
+                while start.elapsed() < RUN_TIME {
+                    for _ in 0..BATCH {
+                        rng ^= rng << 13;
+                        rng ^= rng >> 7;
+                        rng ^= rng << 17;
+                        let key = format!("key{}", rng % KEY_COUNT);
+                        if (rng >> 32) % 10 < WRITES_PER_10 {
+                            let req = UpdateKvPairReq {
+                                pair: Some(KeyValuePair { key, value: String::from("new value") }),
+                                ..Default::default()
+                            };
+                            server.handle_request(ReqType::Update, &req.encode_to_vec(),
+                                &mut session);
+                        } else {
+                            let req = ReadKvPairReq {
+                                key: key.into_bytes(),
+                                ..Default::default()
+                            };
+                            server.handle_request(ReqType::Read, &req.encode_to_vec(),
+                                &mut session);
+                        }
+                    }
+                    ops += BATCH;
+                }
+                ops
+            })
+        })
+        .collect();
+    barrier.wait();
+    let total: u64 = handles.into_iter().map(|h| h.join().unwrap()).sum();
+    total as f64 / RUN_TIME.as_secs_f64()
+}
+
+fn main() {
+    let cores = thread::available_parallelism().map(|n| n.get()).unwrap_or(1);
+    let mut thread_counts = vec![1];
+    while *thread_counts.last().unwrap() < cores * 2 {
+        thread_counts.push(thread_counts.last().unwrap() * 2);
+    }
+    let setups = [
+        filled_server(1, false),
+        filled_server(DEFAULT_SHARD_COUNT, false),
+        filled_server(1, true),
+        filled_server(DEFAULT_SHARD_COUNT, true),
+    ];
+    println!("{} cores, {}% updates over {} keys", cores, WRITES_PER_10 * 10, KEY_COUNT);
+    print!("{:>8}", "threads");
+    for setup in &setups {
+        print!(" {:>18}", setup.name);
+    }
+    println!();
+    for threads in thread_counts {
+        print!("{:>8}", threads);
+        for setup in &setups {
+            print!(" {:>18.0}", run(&setup.server, threads));
+        }
+        println!();
+    }
+}
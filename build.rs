@@ -4,6 +4,7 @@ use std::io::Result;
 fn main() -> Result<()> {
     prost_build::compile_protos(
         &["src/proto/key_value_store.proto",
+                  "src/proto/key_value_messages.proto",
                   "src/proto/socket_messages.proto"],
         &["src/"]).unwrap();
     Ok(())
@@ -1,13 +1,17 @@
 use construct_cache::socket_interface::client_impl::ConstructCacheClient;
 use construct_cache::socket_interface::socket_errors::SocketError;
+use construct_cache::key_value_store::key_value_pair::{value_from_text, KeyValuePair};
+use construct_cache::proto::{cas_req, read_kv_pair_req, scan_req, txn_condition, txn_op, DataType,
+    ExportFormat, GenericKeyValuePair, ImportConflict, RestoreMode, TxnCondition, TxnOp, TxnWrite};
 use log4rs::{config::{Appender, Root}, encode::pattern::PatternEncoder};
 
 use std::io::{self, Write};
+use std::ops::Bound;
+use futures::StreamExt;
 use log::{error, info, LevelFilter};
 use std::{env, process::exit};
 use serde::Deserialize;
 use tokio::fs;
-use toml;
 
 
 #[derive(Deserialize)]
@@ -39,22 +43,197 @@ async fn parse_config(path: &str) -> Result<Config, Box<dyn std::error::Error>>
 
 fn print_basic_help() {
     println!("\n=====How to use this=====");
-    println!("c <key> <value>: Creates simple key value pair");
+    println!("c <key> <value> [ttl_ms]: Creates simple key value pair, optionally expiring");
+    println!("y <key> <type> <value> [ttl_ms]: Creates a typed key value pair, type being one of");
+    println!("    uint32, uint64, sint32, sint64, boolean, string or binary (as hex)");
     println!("d <key>: Deletes key value pair");
-    println!("g <key>: Gets the value of a key from the key value store");
-    println!("b <backup_id>: Backs up the key value store with the specific ID");
-    println!("r <backup_id>: Restores the key values store from a specified backup ID");
+    println!("g <key> [as_of]: Gets the value of a key from the key value store, as it was");
+    println!("    at version N if as_of is vN, or at T ms since the epoch if it is tT");
+    println!("b <backup_id> [base_id]: Starts backing up the key value store with the specific ID,");
+    println!("    only keeping what changed since the backup base_id if it is given");
+    println!("j <job_id>: Shows the progress of a backup started with b");
+    println!("f: Lists the backups the server has written");
+    println!("i <backup_id>: Shows the store, size and checksum of a backup");
+    println!("m <backup_id>: Deletes a backup");
+    println!("v <backup_id> <target_id>: Merges a backup and those it is based on into a full backup");
+    println!("r <backup_id> [mode] [prefix] [dry]: Restores the key value store from a backup,");
+    println!("    mode being replace (the default), backup-wins or live-wins. Only keys");
+    println!("    starting with prefix are restored, unless it is -. With dry, only shows");
+    println!("    how many keys would be added, overwritten and removed");
+    println!("E <file> [format]: Exports the key value store to a file in the server's export");
+    println!("    directory, format being json-lines or csv. Guessed from the file's");
+    println!("    extension if not given or -");
+    println!("I <file> [format] [on_conflict] [max_errors]: Imports a file exported with E,");
+    println!("    on_conflict being overwrite (the default), skip or fail. Nothing is");
+    println!("    imported if more than max_errors lines fail, 0 by default");
     println!("p <message>: Pings the key value store with a message");
-    println!("u <key> <value>: Updates the key value store with new value");
+    println!("u <key> <value> [ttl_ms]: Updates the key value store with new value");
+    println!("C <key> <version> <value> [ttl_ms]: Updates a key only if it is still at the");
+    println!("    version g showed");
+    println!("H <key> [limit]: Lists the past versions of a key, newest first");
+    println!("T <op>; <op>; ...: Runs operations together, applying all of them or none. Each");
+    println!("    is one of c <key> <value>, u <key> <value>, d <key>, v <key> <version>");
+    println!("    (the key is at that version), e <key> (the key exists) or n <key> (it");
+    println!("    does not)");
+    println!("W <key> [key...]: Watches keys until the next X, which applies nothing if any of");
+    println!("    them changed in the meantime");
+    println!("U: Stops watching every watched key");
+    println!("X <op>; <op>; ...: Like T, but only if no watched key changed");
+    println!("G <key> [key...]: Gets the values of many keys at once");
+    println!("S <key> <value> [key value...]: Creates or updates many pairs at once");
+    println!("D <key> [key...]: Deletes many keys at once");
+    println!("+ <key> [by] [ttl_ms]: Adds 1, or by, to the integer a key holds, creating it");
+    println!("    if needed. Only a new key is given the TTL");
+    println!("- <key> [by] [ttl_ms]: Subtracts 1, or by, from the integer a key holds, like +");
+    println!("t <key>: Gets the time left before a key expires");
+    println!("e <key> <ttl_ms>: Sets a key to expire after ttl_ms milliseconds");
+    println!("n <key>: Removes the expiry of a key so that it never expires");
+    println!("s: Shows key count, memory usage and eviction statistics");
+    println!("k <pattern> [v]: Lists keys matching a glob pattern, with values if v is given");
+    println!("l <start> <end> [limit] [rev]: Lists pairs with keys from start to end inclusive,");
+    println!("    in reverse order if rev is given. Use - to leave either end open");
+    println!("a <store>: Creates a new, empty store");
+    println!("z <store>: Drops a store and everything in it");
+    println!("w: Lists the stores on the server");
+    println!("o <store>: Makes later commands act on a store instead of the default one");
     println!("x: Exits the client");
     println!("=========================\n");
 }
 
+/// Parses the point in the past a read asks for: "v" followed by a version,
+/// or "t" followed by milliseconds since the Unix epoch
+fn parse_as_of(arg: &str) -> Option<read_kv_pair_req::AsOf> {
+    if let Some(v) = arg.strip_prefix('v') {
+        return v.parse::<u64>().ok().map(read_kv_pair_req::AsOf::AsOfVersion);
+    }
+    arg.strip_prefix('t')?.parse::<u64>().ok().map(read_kv_pair_req::AsOf::AsOfMs)
+}
+
+/// Parses one operation of a transaction, see `print_help`
+fn parse_txn_op(text: &str) -> Result<TxnOp, String> {
+    let mut split = text.split_whitespace();
+    let kind = split.next().ok_or("Expected operation!")?;
+    let key = split.next().ok_or("Expected key!")?.as_bytes().to_vec();
+    let write = |value: Option<&str>| -> Result<TxnWrite, String> {
+        let value = value.ok_or("Expected value!")?;
+        Ok(TxnWrite {
+            pair: Some(GenericKeyValuePair {
+                key: key.clone(),
+                value: value.as_bytes().to_vec(),
+                data_type: DataType::String.into()
+            }),
+            ttl_ms: None
+        })
+    };
+    let check = |expected| txn_op::Op::Check(TxnCondition {
+        key: key.clone(),
+        expected: Some(expected)
+    });
+    let op = match kind {
+        "c" => txn_op::Op::Create(write(split.next())?),
+        "u" => txn_op::Op::Update(write(split.next())?),
+        "d" => txn_op::Op::Delete(key.clone()),
+        "v" => match split.next().map(|x| x.parse::<u64>()) {
+            Some(Ok(v)) => check(txn_condition::Expected::ExpectedVersion(v)),
+            _ => return Err("Expected version!".to_string())
+        },
+        "e" => check(txn_condition::Expected::Exists(true)),
+        "n" => check(txn_condition::Expected::Exists(false)),
+        _ => return Err(format!("Unknown operation {:?}!", kind))
+    };
+    Ok(TxnOp { op: Some(op) })
+}
+
+/// Parses an optional TTL argument, printing an error if it is present but
+/// not a number of milliseconds.
+fn parse_optional_ttl(arg: Option<&str>) -> Result<Option<u64>, ()> {
+    match arg {
+        None => Ok(None),
+        Some(x) => match x.parse::<u64>() {
+            Ok(t) => Ok(Some(t)),
+            Err(_) => {
+                eprintln!("Expected TTL in milliseconds, got {:?}!", x);
+                Err(())
+            }
+        }
+    }
+}
+
+/// Parses a typed value given as a type name and its text form, printing an
+/// error if either is invalid.
+fn parse_typed_value(type_name: &str, text: &str) -> Result<(DataType, Vec<u8>), ()> {
+    let data_type = match DataType::from_str_name(&type_name.to_uppercase()) {
+        Some(t) => t,
+        None => {
+            eprintln!("Unknown type {:?}!", type_name);
+            return Err(());
+        }
+    };
+    match value_from_text(data_type, text) {
+        Ok(v) => Ok((data_type, v)),
+        Err(e) => {
+            eprintln!("{}!", e);
+            Err(())
+        }
+    }
+}
+
+/// Parses a restore mode such as "backup-wins", printing an error if it is
+/// not one.
+fn parse_restore_mode(arg: &str) -> Result<RestoreMode, ()> {
+    let name = format!("RESTORE_MODE_{}", arg.to_uppercase().replace('-', "_"));
+    match RestoreMode::from_str_name(&name) {
+        Some(m) => Ok(m),
+        None => {
+            eprintln!("Unknown restore mode {:?}!", arg);
+            Err(())
+        }
+    }
+}
+
+/// Parses an export format such as "json-lines", guessing it from the
+/// extension of `file_name` if it is not given or "-".
+fn parse_export_format(arg: Option<&str>, file_name: &str) -> Result<ExportFormat, ()> {
+    let arg = match arg {
+        Some(a) if a != "-" => a,
+        _ if file_name.ends_with(".csv") => return Ok(ExportFormat::Csv),
+        _ => return Ok(ExportFormat::JsonLines)
+    };
+    let name = format!("EXPORT_FORMAT_{}", arg.to_uppercase().replace('-', "_"));
+    match ExportFormat::from_str_name(&name) {
+        Some(f) => Ok(f),
+        None => {
+            eprintln!("Unknown export format {:?}!", arg);
+            Err(())
+        }
+    }
+}
+
+/// Parses what an import does with live keys, such as "skip".
+fn parse_import_conflict(arg: &str) -> Result<ImportConflict, ()> {
+    let name = format!("IMPORT_CONFLICT_{}", arg.to_uppercase());
+    match ImportConflict::from_str_name(&name) {
+        Some(c) => Ok(c),
+        None => {
+            eprintln!("Unknown conflict handling {:?}!", arg);
+            Err(())
+        }
+    }
+}
+
+/// Parses one end of a range to list, where "-" leaves that end open.
+fn parse_range_bound(arg: &str) -> Bound<Vec<u8>> {
+    match arg {
+        "-" => Bound::Unbounded,
+        k => Bound::Included(k.as_bytes().to_vec())
+    }
+}
+
 fn setup_logging(path: &str) {
     let log_level = LevelFilter::Trace;
     let file = log4rs::append::file::FileAppender::builder()
         .encoder(Box::new(PatternEncoder::new("{d(%Y-%m-%d %H:%M:%S)} [{l}] {m}{n}")))
-        .build(&path)
+        .build(path)
         .expect("Failed to create log file!");
 
     let config = log4rs::config::Config::builder()
@@ -99,7 +278,7 @@ async fn main() -> Result<(), SocketError> {
         io::stdin().read_line(&mut input)
             .expect("Failed to read line");
         let ip = input.trim();
-        let control_char = ip.chars().nth(0).unwrap();
+        let control_char = ip.chars().next().unwrap();
         let mut skip_input = false;
         match control_char {
             'x' => {
@@ -109,110 +288,574 @@ async fn main() -> Result<(), SocketError> {
             'c' => {
                 let mut split = ip.split(' ');
                 split.next();
-                let key: &str;
-                let val: &str;
-                match split.next() {
+                let key = match split.next() {
                     None => { 
                         eprintln!("Expected key!");
                         break;
                     },
-                    Some(x) => { key = x; }
-                }
-                match split.next() {
+                    Some(x) => x
+                };
+                let val = match split.next() {
                     None => {
                         eprintln!("Expected value!");
                         break;
                     },
-                    Some(x) => { val = x; }
-                }
-                client.send_create(key, val).await?;
+                    Some(x) => x
+                };
+                let ttl_ms = match parse_optional_ttl(split.next()) {
+                    Ok(t) => t,
+                    Err(_) => break
+                };
+                client.send_create_with_ttl(key, val, ttl_ms).await?;
+            },
+            'y' => {
+                let mut split = ip.split(' ');
+                split.next();
+                let key = match split.next() {
+                    None => {
+                        eprintln!("Expected key!");
+                        break;
+                    },
+                    Some(x) => x
+                };
+                let type_name = match split.next() {
+                    None => {
+                        eprintln!("Expected type!");
+                        break;
+                    },
+                    Some(x) => x
+                };
+                let val = match split.next() {
+                    None => {
+                        eprintln!("Expected value!");
+                        break;
+                    },
+                    Some(x) => x
+                };
+                let (data_type, bytes) = match parse_typed_value(type_name, val) {
+                    Ok(v) => v,
+                    Err(_) => break
+                };
+                let ttl_ms = match parse_optional_ttl(split.next()) {
+                    Ok(t) => t,
+                    Err(_) => break
+                };
+                client.send_create_typed(key.as_bytes(), data_type, bytes, ttl_ms).await?;
             },
             'b' => {
                 let mut split = ip.split(' ');
                 split.next();
-                let backup_id: &str;
-                match split.next() {
+                let backup_id = match split.next() {
                     None => {
                         eprintln!("Expected backup ID!");
                         break;
                     }
-                    Some(x) => {backup_id = x; }
-                }
-                client.send_backup(backup_id).await?;
+                    Some(x) => x
+                };
+                client.send_backup_with_base(backup_id, split.next()).await?;
+            },
+            'j' => {
+                let mut split = ip.split(' ');
+                split.next();
+                let job_id = match split.next().map(|x| x.parse::<u64>()) {
+                    Some(Ok(id)) => id,
+                    _ => {
+                        eprintln!("Expected backup job ID!");
+                        break;
+                    }
+                };
+                client.send_backup_status(job_id).await?;
+            },
+            'f' => {
+                client.send_list_backups().await?;
+            },
+            'i' => {
+                let mut split = ip.split(' ');
+                split.next();
+                let backup_id = match split.next() {
+                    None => {
+                        eprintln!("Expected backup ID!");
+                        break;
+                    }
+                    Some(x) => x
+                };
+                client.send_backup_info(backup_id).await?;
+            },
+            'm' => {
+                let mut split = ip.split(' ');
+                split.next();
+                let backup_id = match split.next() {
+                    None => {
+                        eprintln!("Expected backup ID!");
+                        break;
+                    }
+                    Some(x) => x
+                };
+                client.send_delete_backup(backup_id).await?;
+            },
+            'E' => {
+                let mut split = ip.split(' ');
+                split.next();
+                let file_name = match split.next() {
+                    None => {
+                        eprintln!("Expected file name!");
+                        break;
+                    }
+                    Some(x) => x
+                };
+                let format = match parse_export_format(split.next(), file_name) {
+                    Ok(f) => f,
+                    Err(_) => break
+                };
+                client.send_export(file_name, format).await?;
+            },
+            'I' => {
+                let mut split = ip.split(' ');
+                split.next();
+                let file_name = match split.next() {
+                    None => {
+                        eprintln!("Expected file name!");
+                        break;
+                    }
+                    Some(x) => x
+                };
+                let format = match parse_export_format(split.next(), file_name) {
+                    Ok(f) => f,
+                    Err(_) => break
+                };
+                let on_conflict = match split.next().map(parse_import_conflict) {
+                    None => ImportConflict::Overwrite,
+                    Some(Ok(c)) => c,
+                    Some(Err(_)) => break
+                };
+                let max_errors = match split.next().map(|x| x.parse::<u64>()) {
+                    None => 0,
+                    Some(Ok(m)) => m,
+                    Some(Err(_)) => {
+                        eprintln!("Expected the number of lines allowed to fail!");
+                        break;
+                    }
+                };
+                client.send_import(file_name, format, on_conflict, max_errors).await?;
+            },
+            'v' => {
+                let mut split = ip.split(' ');
+                split.next();
+                let (backup_id, target_id) = match (split.next(), split.next()) {
+                    (Some(b), Some(t)) => (b, t),
+                    _ => {
+                        eprintln!("Expected backup ID and target backup ID!");
+                        break;
+                    }
+                };
+                client.send_compact_backup(backup_id, target_id).await?;
             },
             'p' => {
                 let mut split = ip.split(' ');
                 split.next();
-                let ping_msg: &str;
-                match split.next() {
+                let ping_msg = match split.next() {
                     None => {
                         eprintln!("Expected message to ping!");
                         break;
                     },
-                    Some(x) => { ping_msg = x; }
-                }
+                    Some(x) => x
+                };
                 client.send_ping(ping_msg).await?;
             },
             'r' => {
                 let mut split = ip.split(' ');
                 split.next();
-                let backup_id: &str;
-                match split.next() {
+                let backup_id = match split.next() {
                     None => {
                         eprintln!("Expected backup ID!");
                         break;
                     }
-                    Some(x) => {backup_id = x; }
-                }
-                client.send_restore(backup_id).await?;
+                    Some(x) => x
+                };
+                let mode = match split.next().map(parse_restore_mode) {
+                    None => RestoreMode::Replace,
+                    Some(Ok(m)) => m,
+                    Some(Err(_)) => break
+                };
+                let prefix = match split.next() {
+                    None | Some("-") => None,
+                    Some(p) => Some(p.as_bytes())
+                };
+                let dry_run = split.next() == Some("dry");
+                client.send_restore_with_mode(backup_id, mode, prefix, dry_run).await?;
             }
             'g' => {
                 let mut split = ip.split(' ');
                 split.next();
-                let read_key: &str;
-                match split.next() {
+                let read_key = match split.next() {
                     None => {
                         eprintln!("Expected key to read!");
                         break;
                     }
-                    Some(x) => {read_key = x; }
-                }
-                client.send_read(read_key).await?;
+                    Some(x) => x
+                };
+                let as_of = match split.next() {
+                    None => None,
+                    Some(x) => match parse_as_of(x) {
+                        Some(a) => Some(a),
+                        None => {
+                            eprintln!("Expected v<version> or t<ms since epoch>, got {:?}!", x);
+                            break;
+                        }
+                    }
+                };
+                match as_of {
+                    None => client.send_read(read_key).await?,
+                    Some(a) => client.send_read_as_of(read_key.as_bytes(), a).await?
+                };
             },
             'u' => {
                 let mut split = ip.split(' ');
                 split.next();
-                let key: &str;
-                let val: &str;
-                match split.next() {
+                let key = match split.next() {
                     None => { 
                         eprintln!("Expected key!");
                         break;
                     },
-                    Some(x) => { key = x; }
-                }
-                match split.next() {
+                    Some(x) => x
+                };
+                let val = match split.next() {
                     None => {
                         eprintln!("Expected value!");
                         break;
                     },
-                    Some(x) => { val = x; }
+                    Some(x) => x
+                };
+                let ttl_ms = match parse_optional_ttl(split.next()) {
+                    Ok(t) => t,
+                    Err(_) => break
+                };
+                client.send_update_with_ttl(key, val, ttl_ms).await?;
+            },
+            '+' | '-' => {
+                let mut split = ip.split(' ');
+                split.next();
+                let key = match split.next() {
+                    None => {
+                        eprintln!("Expected key!");
+                        break;
+                    },
+                    Some(x) => x
+                };
+                let by = match split.next().map(|x| x.parse::<i64>()) {
+                    None => 1,
+                    Some(Ok(b)) => b,
+                    Some(Err(_)) => {
+                        eprintln!("Expected a 64-bit signed integer to add!");
+                        break;
+                    }
+                };
+                let delta = if control_char == '-' {
+                    match by.checked_neg() {
+                        Some(d) => d,
+                        None => {
+                            eprintln!("Cannot subtract {}!", by);
+                            break;
+                        }
+                    }
+                } else {
+                    by
+                };
+                let ttl_ms = match parse_optional_ttl(split.next()) {
+                    Ok(t) => t,
+                    Err(_) => break
+                };
+                client.send_incr_by(key.as_bytes(), delta, ttl_ms).await?;
+            },
+            'T' | 'X' => {
+                let ops: Result<Vec<TxnOp>, String> = ip[1..].split(';')
+                    .filter(|op| !op.trim().is_empty())
+                    .map(parse_txn_op)
+                    .collect();
+                let ops = match ops {
+                    Ok(ops) => ops,
+                    Err(e) => {
+                        eprintln!("{}", e);
+                        break;
+                    }
+                };
+                if control_char == 'X' {
+                    client.send_exec(ops).await?;
+                } else {
+                    client.send_transaction(ops).await?;
+                }
+            },
+            'W' => {
+                let keys: Vec<&str> = ip.split(' ').skip(1).filter(|k| !k.is_empty()).collect();
+                if keys.is_empty() {
+                    eprintln!("Expected keys to watch!");
+                    break;
+                }
+                client.send_watch(&keys).await?;
+            },
+            'U' => {
+                client.send_unwatch().await?;
+            },
+            'G' | 'D' => {
+                let keys: Vec<&str> = ip.split(' ').skip(1).filter(|k| !k.is_empty()).collect();
+                if keys.is_empty() {
+                    eprintln!("Expected keys!");
+                    break;
+                }
+                if control_char == 'G' {
+                    client.send_mget(&keys).await?;
+                } else {
+                    client.send_mdel(&keys).await?;
+                }
+            },
+            'S' => {
+                let words: Vec<&str> = ip.split(' ').skip(1).filter(|k| !k.is_empty()).collect();
+                if words.is_empty() || !words.len().is_multiple_of(2) {
+                    eprintln!("Expected keys and values!");
+                    break;
                 }
-                client.send_update(key, val).await?;
+                let pairs: Vec<(&str, &str)> = words.chunks(2).map(|kv| (kv[0], kv[1])).collect();
+                client.send_mset(&pairs, None).await?;
+            },
+            'H' => {
+                let mut split = ip.split(' ');
+                split.next();
+                let key = match split.next() {
+                    None => {
+                        eprintln!("Expected key!");
+                        break;
+                    },
+                    Some(x) => x
+                };
+                let limit = match split.next().map(|x| x.parse::<u32>()) {
+                    None => None,
+                    Some(Ok(l)) => Some(l),
+                    Some(Err(_)) => {
+                        eprintln!("Expected limit!");
+                        break;
+                    }
+                };
+                client.send_history(key.as_bytes(), limit).await?;
+            },
+            'C' => {
+                let mut split = ip.split(' ');
+                split.next();
+                let key = match split.next() {
+                    None => {
+                        eprintln!("Expected key!");
+                        break;
+                    },
+                    Some(x) => x
+                };
+                let version = match split.next().map(|x| x.parse::<u64>()) {
+                    Some(Ok(v)) => v,
+                    _ => {
+                        eprintln!("Expected version!");
+                        break;
+                    }
+                };
+                let val = match split.next() {
+                    None => {
+                        eprintln!("Expected value!");
+                        break;
+                    },
+                    Some(x) => x
+                };
+                let ttl_ms = match parse_optional_ttl(split.next()) {
+                    Ok(t) => t,
+                    Err(_) => break
+                };
+                client.send_cas(key.as_bytes(), DataType::String, val.as_bytes().to_vec(),
+                    cas_req::Expected::ExpectedVersion(version), ttl_ms).await?;
             },
             'd' => {
                 let mut split = ip.split(' ');
                 split.next();
-                let key: &str;
-                match split.next() {
+                let key = match split.next() {
                     None => {
                         eprintln!("Expected key to delete!");
                         break;
                     },
-                    Some(x) => { key = x; }
-                }
+                    Some(x) => x
+                };
                 client.send_delete(key).await?;
+            },
+            't' => {
+                let mut split = ip.split(' ');
+                split.next();
+                let key = match split.next() {
+                    None => {
+                        eprintln!("Expected key!");
+                        break;
+                    },
+                    Some(x) => x
+                };
+                client.send_get_ttl(key).await?;
+            },
+            'e' => {
+                let mut split = ip.split(' ');
+                split.next();
+                let key = match split.next() {
+                    None => {
+                        eprintln!("Expected key!");
+                        break;
+                    },
+                    Some(x) => x
+                };
+                let ttl_ms = match parse_optional_ttl(split.next()) {
+                    Ok(Some(t)) => t,
+                    Ok(None) => {
+                        eprintln!("Expected TTL in milliseconds!");
+                        break;
+                    },
+                    Err(_) => break
+                };
+                client.send_set_ttl(key, ttl_ms).await?;
+            },
+            'n' => {
+                let mut split = ip.split(' ');
+                split.next();
+                let key = match split.next() {
+                    None => {
+                        eprintln!("Expected key!");
+                        break;
+                    },
+                    Some(x) => x
+                };
+                client.send_remove_ttl(key).await?;
             }
+            'l' => {
+                let mut split = ip.split(' ');
+                split.next();
+                let start = match split.next() {
+                    None => {
+                        eprintln!("Expected start of range!");
+                        break;
+                    },
+                    Some(x) => parse_range_bound(x)
+                };
+                let end = match split.next() {
+                    None => {
+                        eprintln!("Expected end of range!");
+                        break;
+                    },
+                    Some(x) => parse_range_bound(x)
+                };
+                let mut limit = None;
+                let mut reverse = false;
+                for arg in split {
+                    match arg {
+                        "rev" => reverse = true,
+                        x => match x.parse::<usize>() {
+                            Ok(l) => limit = Some(l),
+                            Err(_) => eprintln!("Ignoring unexpected argument {:?}", x)
+                        }
+                    }
+                }
+                let mut pairs = client.scan_range(start, end, limit, reverse);
+                let mut count = 0;
+                while let Some(res) = pairs.next().await {
+                    match res {
+                        Ok(p) => {
+                            let data_type = p.data_type();
+                            match KeyValuePair::new_typed(&p.key, data_type, p.value) {
+                                Ok(kvp) => println!("<< {} = {}", kvp.key_text(), kvp.value()),
+                                Err(e) => eprintln!("<! {}", e)
+                            }
+                            count += 1;
+                        },
+                        Err(e) => eprintln!("<! {}", e)
+                    }
+                }
+                println!("<< {} pairs", count);
+                skip_input = true;
+            },
+            'k' => {
+                let mut split = ip.split(' ');
+                split.next();
+                let pattern = match split.next() {
+                    None => {
+                        eprintln!("Expected pattern!");
+                        break;
+                    },
+                    Some(x) => scan_req::Pattern::Glob(x.as_bytes().to_vec())
+                };
+                let with_values = split.next() == Some("v");
+                let mut cursor = Vec::new();
+                let mut count = 0;
+                loop {
+                    let page = match client.scan_page(
+                            Some(pattern.clone()), &cursor, 0, with_values).await {
+                        Ok(p) => p,
+                        Err(e) => {
+                            eprintln!("<! {}", e);
+                            break;
+                        }
+                    };
+                    if with_values {
+                        for p in page.pairs {
+                            let data_type = p.data_type();
+                            match KeyValuePair::new_typed(&p.key, data_type, p.value) {
+                                Ok(kvp) => println!("<< {} = {}", kvp.key_text(), kvp.value()),
+                                Err(e) => eprintln!("<! {}", e)
+                            }
+                        }
+                    } else {
+                        for k in &page.keys {
+                            println!("<< {}", String::from_utf8_lossy(k));
+                        }
+                    }
+                    count += page.keys.len();
+                    if page.cursor.is_empty() {
+                        break;
+                    }
+                    cursor = page.cursor;
+                }
+                println!("<< {} keys", count);
+                skip_input = true;
+            },
+            's' => {
+                client.send_stats().await?;
+            },
+            'a' => {
+                let mut split = ip.split(' ');
+                split.next();
+                let name = match split.next() {
+                    None => {
+                        eprintln!("Expected store to create!");
+                        break;
+                    }
+                    Some(x) => x
+                };
+                client.send_create_store(name).await?;
+            },
+            'z' => {
+                let mut split = ip.split(' ');
+                split.next();
+                let name = match split.next() {
+                    None => {
+                        eprintln!("Expected store to drop!");
+                        break;
+                    }
+                    Some(x) => x
+                };
+                client.send_drop_store(name).await?;
+            },
+            'o' => {
+                let mut split = ip.split(' ');
+                split.next();
+                let name = match split.next() {
+                    None => {
+                        eprintln!("Expected store to select!");
+                        break;
+                    }
+                    Some(x) => x
+                };
+                client.send_select_store(name).await?;
+            },
+            'w' => {
+                client.send_list_stores().await?;
+            },
             'h' => {
                 print_basic_help();
                 skip_input = true;
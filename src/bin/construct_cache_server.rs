@@ -1,18 +1,26 @@
-use construct_cache::socket_interface::server_impl::ConstructCacheServer;
+use construct_cache::key_value_store::eviction::EvictionPolicy;
+use construct_cache::key_value_store::history::Retention;
+use construct_cache::key_value_store::storage_engine::EngineKind;
+use construct_cache::socket_interface::server_impl::{
+    ConstructCacheServer, MemoryLimit, ServerOptions};
 use log4rs::{config::{Appender, Root}, encode::pattern::PatternEncoder};
 
+use std::collections::HashMap;
 use std::io;
 use log::{error, info, trace, warn, LevelFilter};
 use std::{env, process::exit};
 use serde::Deserialize;
 use tokio::fs;
-use toml;
 
 
 #[derive(Deserialize)]
 struct Config {
     net_config: NetConfig,
-    log_info: LogInfo
+    log_info: LogInfo,
+    persistence: Option<Persistence>,
+    memory: Option<Memory>,
+    storage: Option<Storage>,
+    history: Option<History>
 }
 
 #[derive(Deserialize)]
@@ -26,11 +34,54 @@ struct LogInfo {
     log_file: String
 }
 
+#[derive(Deserialize)]
+struct Persistence {
+    wal_file: String,
+    backup_catalog: Option<String>,
+    backup_dir: Option<String>,
+    export_dir: Option<String>
+}
+
+#[derive(Deserialize)]
+struct Memory {
+    max_bytes: u64,
+    eviction_policy: String
+}
+
+#[derive(Deserialize)]
+struct Storage {
+    shards: Option<usize>,
+    engine: Option<String>,
+    data_dir: Option<String>
+}
+
+#[derive(Deserialize)]
+struct History {
+    // Keep history for every store if either is set, and only for the
+    // stores listed otherwise
+    max_versions: Option<usize>,
+    max_age_ms: Option<u64>,
+    #[serde(default)]
+    stores: HashMap<String, HistoryLimits>
+}
+
+#[derive(Deserialize)]
+struct HistoryLimits {
+    max_versions: Option<usize>,
+    max_age_ms: Option<u64>
+}
+
+impl HistoryLimits {
+    fn retention(&self) -> Retention {
+        Retention { max_versions: self.max_versions, max_age_ms: self.max_age_ms }
+    }
+}
+
 fn setup_logging(path: &str) {
     let log_level = LevelFilter::Trace;
     let file = log4rs::append::file::FileAppender::builder()
         .encoder(Box::new(PatternEncoder::new("{d(%Y-%m-%d %H:%M:%S)} [{l}] {m}{n}")))
-        .build(&path)
+        .build(path)
         .expect("Failed to create log file!");
 
     let config = log4rs::config::Config::builder()
@@ -72,8 +123,59 @@ async fn main() -> io::Result<()> {
     setup_logging(&log_file);
     let listen_addr = format!("{}:{}", addr, port);
     trace!("Hello, server!");
-    let server = ConstructCacheServer::new(&listen_addr,
-    "default");
+    let mut options = ServerOptions::default();
+    if let Some(p) = config.persistence {
+        options.wal_file = Some(p.wal_file);
+        options.backup_catalog = p.backup_catalog;
+        options.backup_dir = p.backup_dir;
+        options.export_dir = p.export_dir;
+    }
+    if let Some(m) = config.memory {
+        let policy = match m.eviction_policy.parse::<EvictionPolicy>() {
+            Ok(p) => p,
+            Err(e) => {
+                error!("{}", e);
+                exit(1);
+            }
+        };
+        options.memory_limit = Some(MemoryLimit {
+            max_bytes: m.max_bytes,
+            policy
+        });
+    }
+    if let Some(s) = config.storage {
+        options.shard_count = s.shards;
+        if let Some(engine) = s.engine {
+            options.engine = match engine.parse::<EngineKind>() {
+                Ok(e) => e,
+                Err(e) => {
+                    error!("{}", e);
+                    exit(1);
+                }
+            };
+        }
+        options.data_dir = s.data_dir;
+    }
+    if let Some(h) = config.history {
+        if h.max_versions.is_some() || h.max_age_ms.is_some() {
+            options.history = Some(Retention {
+                max_versions: h.max_versions,
+                max_age_ms: h.max_age_ms
+            });
+        }
+        options.store_history = h.stores.iter()
+            .map(|(name, limits)| (name.clone(), limits.retention()))
+            .collect();
+    }
+    info!("Keeping stores in the {} engine", options.engine);
+    let server = match ConstructCacheServer::with_options(
+            &listen_addr, "default", options) {
+        Ok(s) => s,
+        Err(e) => {
+            error!("Cannot start server: {:?}", e);
+            exit(1);
+        }
+    };
     match server.main_loop().await {
         Ok(_) => {}
         Err(e) => { warn!("Got error {:?}", e)}
@@ -0,0 +1,612 @@
+use std::collections::BTreeSet;
+use std::fs::{self, File, OpenOptions};
+use std::io::prelude::*;
+use std::io::BufReader;
+use std::ops::Bound;
+use std::os::unix::fs::FileExt;
+use std::sync::Arc;
+
+use super::filestore;
+use super::key_pattern::KeyPattern;
+use super::key_value_pair::KeyValuePair;
+use super::key_value_store::{
+    entry_size_from_lens, first_version, is_empty_range, now_ms, KeyValueStore, ScanPage};
+use super::storage_engine::{FrozenEngine, SnapshotEntry, StorageEngine};
+use super::write_ahead_log::{decode_frame, encode_frame};
+
+use crate::{
+    key_value_store::errors::{ErrorKind, RWError},
+    proto::{DataType, WalOp, WalRecord},
+};
+use imbl::OrdMap;
+use log::{info, trace, warn};
+
+/// The log is never compacted while it is smaller than this
+const COMPACTION_MIN_BYTES: u64 = 4 * 1024 * 1024;
+/// The log is compacted once it is this many times the size of the records
+/// still in use
+const COMPACTION_RATIO: u64 = 2;
+
+/// Where the latest record for a key sits in the log, along with what is
+/// needed to answer questions about it without reading it.
+#[derive(Clone)]
+struct IndexEntry {
+    offset: u64,
+    frame_len: u64,
+    value_len: usize,
+    // Set even if the expiry has passed
+    expires_at: Option<u64>,
+    version: u64,
+}
+
+/// A store that keeps its pairs on disk, in an append-only log of the same
+/// records and frames as `WriteAheadLog`. Only the keys are kept in memory,
+/// each pointing at the latest record for it; values are read back from the
+/// log when asked for.
+///
+/// Every mutation is synced to disk before it returns. The log is rewritten
+/// with only the latest record for each key once enough of it is taken up by
+/// records that have been overwritten or deleted.
+pub struct DiskStore {
+    name_: String,
+    path_: String,
+    // Shared with frozen copies of the store, which keep reading the file
+    // they were made from even once compaction has replaced it
+    file_: Arc<File>,
+    file_len_: u64,
+    // Like `KeyValueStore`, shared with frozen copies until either changes
+    index_: OrdMap<Vec<u8>, IndexEntry>,
+    // Every (expiry time, key) with an expiry, see `KeyValueStore`
+    expiry_index_: BTreeSet<(u64, Vec<u8>)>,
+    // Sum of `entry_size` over every pair, so that it can be compared with
+    // the in-memory store
+    memory_usage_: u64,
+    // Sum of the frame lengths in the index, to tell when to compact
+    live_bytes_: u64,
+    next_version_: u64,
+    // Set once the file has been deleted, after which mutations fail rather
+    // than bringing the file back.
+    removed_: bool,
+}
+
+fn write_error(e: std::io::Error) -> RWError {
+    RWError {
+        kind_: ErrorKind::FileWriteError,
+        context_: e.to_string(),
+    }
+}
+
+fn open_log(path: &str) -> Result<File, RWError> {
+    OpenOptions::new().create(true).read(true).append(true).open(path)
+        .map_err(|e| RWError {
+            kind_: ErrorKind::FileOpenError,
+            context_: e.to_string(),
+        })
+}
+
+fn decode_pair(record: WalRecord) -> Result<KeyValuePair, RWError> {
+    KeyValuePair::from_stored(&record.key, record.data_type, record.value)
+        .map_err(|e| RWError {
+            kind_: ErrorKind::DataDecodeError,
+            context_: e,
+        })
+}
+
+fn put_record(pair: &KeyValuePair, expires_at: Option<u64>, version: u64) -> WalRecord {
+    WalRecord {
+        op: WalOp::Create.into(),
+        key: pair.key().to_vec(),
+        value: pair.value_bytes().to_vec(),
+        expires_at_ms: expires_at,
+        data_type: match pair.data_type() {
+            DataType::String => None,
+            t => Some(t.into()),
+        },
+        version,
+        batch: Vec::new(),
+    }
+}
+
+fn read_frame(file: &File, entry: &IndexEntry) -> Result<Vec<u8>, RWError> {
+    let mut buf = vec![0; entry.frame_len as usize];
+    if let Err(e) = file.read_exact_at(&mut buf, entry.offset) {
+        return Err(RWError {
+            kind_: ErrorKind::FileReadError,
+            context_: e.to_string(),
+        });
+    }
+    Ok(buf)
+}
+
+fn read_pair(file: &File, path: &str, entry: &IndexEntry) -> Result<KeyValuePair, RWError> {
+    let buf = read_frame(file, entry)?;
+    match decode_frame(&buf) {
+        Some((record, _)) => decode_pair(record),
+        None => Err(RWError {
+            kind_: ErrorKind::ChecksumMismatchError,
+            context_: format!("Bad record at offset {} of {}", entry.offset, path),
+        }),
+    }
+}
+
+/// The index of a `DiskStore` at the time it was frozen, along with the file
+/// it points into. The store only ever appends to that file or replaces it
+/// with a new one, so the records the index points at stay put.
+struct FrozenDiskStore {
+    path_: String,
+    file_: Arc<File>,
+    index_: OrdMap<Vec<u8>, IndexEntry>,
+}
+
+impl FrozenEngine for FrozenDiskStore {
+    fn len(&self) -> usize {
+        self.index_.len()
+    }
+
+    fn snapshot(&self) -> Box<dyn Iterator<Item = SnapshotEntry> + '_> {
+        Box::new(self.index_.values()
+            .map(|e| read_pair(&self.file_, &self.path_, e).map(|pair| (pair, e.expires_at))))
+    }
+}
+
+impl DiskStore {
+    /// Opens the store kept at `path`, creating it if it does not exist.
+    /// Like `WriteAheadLog::replay`, a partially written record at the end
+    /// of the log is discarded.
+    pub fn open(name: &str, path: &str) -> Result<DiskStore, RWError> {
+        let file = open_log(path)?;
+        let mut buf = Vec::new();
+        if let Err(e) = BufReader::new(&file).read_to_end(&mut buf) {
+            return Err(RWError {
+                kind_: ErrorKind::FileReadError,
+                context_: e.to_string(),
+            });
+        }
+        let mut store = DiskStore {
+            name_: name.to_string(),
+            path_: path.to_string(),
+            file_: Arc::new(file),
+            file_len_: 0,
+            index_: OrdMap::new(),
+            expiry_index_: BTreeSet::new(),
+            memory_usage_: 0,
+            live_bytes_: 0,
+            next_version_: first_version(),
+            removed_: false,
+        };
+        let mut offset = 0;
+        while let Some((record, frame_len)) = decode_frame(&buf[offset..]) {
+            match record.op() {
+                WalOp::Create | WalOp::Update => {
+                    store.insert_index(record.key, IndexEntry {
+                        offset: offset as u64,
+                        frame_len: frame_len as u64,
+                        value_len: record.value.len(),
+                        expires_at: record.expires_at_ms,
+                        version: record.version,
+                    });
+                }
+                WalOp::Delete => {
+                    store.remove_index(&record.key);
+                }
+                // Only the write-ahead log holds batches
+                WalOp::Batch => {
+                    return Err(RWError {
+                        kind_: ErrorKind::DataDecodeError,
+                        context_: format!("Unexpected batch record in {}", path),
+                    });
+                }
+            }
+            offset += frame_len;
+        }
+        if offset < buf.len() {
+            warn!(
+                "Discarding {:?} trailing bytes of incomplete record in {:?}",
+                buf.len() - offset,
+                path
+            );
+            store.file_.set_len(offset as u64).map_err(write_error)?;
+        }
+        store.file_len_ = offset as u64;
+        info!("Opened {:?} with {:?} keys", path, store.index_.len());
+        Ok(store)
+    }
+
+    pub fn path(&self) -> &str {
+        self.path_.as_str()
+    }
+
+    fn check_not_removed(&self) -> Result<(), RWError> {
+        if self.removed_ {
+            return Err(RWError {
+                kind_: ErrorKind::FileWriteError,
+                context_: format!("{} has been removed", self.path_),
+            });
+        }
+        Ok(())
+    }
+
+    fn is_live(entry: &IndexEntry, now: u64) -> bool {
+        entry.expires_at.is_none_or(|t| t > now)
+    }
+
+    fn live_entry(&self, key: &[u8]) -> Option<&IndexEntry> {
+        self.index_.get(key).filter(|e| DiskStore::is_live(e, now_ms()))
+    }
+
+    fn insert_index(&mut self, key: Vec<u8>, entry: IndexEntry) {
+        self.remove_index(&key);
+        self.memory_usage_ += entry_size_from_lens(key.len(), entry.value_len);
+        self.live_bytes_ += entry.frame_len;
+        self.next_version_ = self.next_version_.max(entry.version + 1);
+        if let Some(t) = entry.expires_at {
+            self.expiry_index_.insert((t, key.clone()));
+        }
+        self.index_.insert(key, entry);
+    }
+
+    fn remove_index(&mut self, key: &[u8]) -> Option<IndexEntry> {
+        let old = self.index_.remove(key)?;
+        self.memory_usage_ -= entry_size_from_lens(key.len(), old.value_len);
+        self.live_bytes_ -= old.frame_len;
+        if let Some(t) = old.expires_at {
+            self.expiry_index_.remove(&(t, key.to_vec()));
+        }
+        Some(old)
+    }
+
+    /// Appends a record to the log and syncs it, returning the offset and
+    /// length of its frame.
+    fn append(&mut self, record: &WalRecord) -> Result<(u64, u64), RWError> {
+        self.check_not_removed()?;
+        let frame = encode_frame(record);
+        let result = (&*self.file_).write_all(&frame)
+            .and_then(|_| self.file_.sync_data());
+        if let Err(e) = result {
+            // Cut off whatever part of the frame made it, so that the next
+            // append starts on a frame boundary
+            if let Err(trunc_err) = self.file_.set_len(self.file_len_) {
+                warn!("Cannot truncate {:?}: {:?}", self.path_, trunc_err);
+            }
+            return Err(write_error(e));
+        }
+        let offset = self.file_len_;
+        self.file_len_ += frame.len() as u64;
+        trace!("Appended {:?} bytes to {:?}", frame.len(), self.path_);
+        Ok((offset, frame.len() as u64))
+    }
+
+    fn read_pair(&self, entry: &IndexEntry) -> Result<KeyValuePair, RWError> {
+        read_pair(&self.file_, &self.path_, entry)
+    }
+
+    /// Rewrites the log with only the latest record for each key, if enough
+    /// of it is taken up by older ones. A failure leaves the log as it was,
+    /// so it is only logged.
+    fn maybe_compact(&mut self) {
+        if self.removed_ || self.file_len_ < COMPACTION_MIN_BYTES
+                || self.file_len_ < COMPACTION_RATIO * self.live_bytes_ {
+            return;
+        }
+        if let Err(e) = self.compact() {
+            warn!("Cannot compact {:?}: {:?}", self.path_, e.to_string());
+        }
+    }
+
+    fn compact(&mut self) -> Result<(), RWError> {
+        let before = self.file_len_;
+        let mut offsets = Vec::with_capacity(self.index_.len());
+        filestore::write_file_atomically(&self.path_, |out| {
+            let mut offset = 0;
+            for entry in self.index_.values() {
+                out.write_all(&read_frame(&self.file_, entry)?).map_err(write_error)?;
+                offsets.push(offset);
+                offset += entry.frame_len;
+            }
+            Ok(())
+        })?;
+        self.file_ = Arc::new(open_log(&self.path_)?);
+        self.index_ = self.index_.iter().zip(offsets)
+            .map(|((key, entry), offset)| (key.clone(), IndexEntry { offset, ..entry.clone() }))
+            .collect();
+        self.file_len_ = self.live_bytes_;
+        info!("Compacted {:?} from {:?} to {:?} bytes", self.path_, before, self.file_len_);
+        Ok(())
+    }
+}
+
+impl StorageEngine for DiskStore {
+    fn name(&self) -> &str {
+        self.name_.as_str()
+    }
+
+    fn get_versioned(&self, key: &[u8]) -> Result<Option<(KeyValuePair, u64)>, RWError> {
+        match self.live_entry(key) {
+            None => Ok(None),
+            Some(entry) => Ok(Some((self.read_pair(entry)?, entry.version))),
+        }
+    }
+
+    fn next_version(&self) -> u64 {
+        self.next_version_
+    }
+
+    fn expires_at(&self, key: &[u8]) -> Option<u64> {
+        self.live_entry(key)?.expires_at
+    }
+
+    fn put_versioned(&mut self, pair: KeyValuePair, expires_at: Option<u64>, version: u64)
+            -> Result<(), RWError> {
+        let (offset, frame_len) = self.append(&put_record(&pair, expires_at, version))?;
+        self.insert_index(pair.key().to_vec(), IndexEntry {
+            offset,
+            frame_len,
+            value_len: pair.value_bytes().len(),
+            expires_at,
+            version,
+        });
+        self.maybe_compact();
+        Ok(())
+    }
+
+    fn delete(&mut self, key: &[u8]) -> Result<bool, RWError> {
+        if !self.index_.contains_key(key) {
+            return Ok(false);
+        }
+        let live = self.live_entry(key).is_some();
+        self.append(&WalRecord {
+            op: WalOp::Delete.into(),
+            key: key.to_vec(),
+            ..Default::default()
+        })?;
+        self.remove_index(key);
+        self.maybe_compact();
+        Ok(live)
+    }
+
+    fn set_expires_at(&mut self, key: &[u8], expires_at: Option<u64>) -> Result<bool, RWError> {
+        // Records hold a pair's full state, so the value is written again
+        let (pair, version) = match self.get_versioned(key)? {
+            None => return Ok(false),
+            Some(p) => p,
+        };
+        self.put_versioned(pair, expires_at, version)?;
+        Ok(true)
+    }
+
+    fn scan_range(&self, start: Bound<&[u8]>, end: Bound<&[u8]>, limit: usize,
+            reverse: bool) -> Result<Vec<KeyValuePair>, RWError> {
+        if is_empty_range(start, end) || limit == 0 {
+            return Ok(Vec::new());
+        }
+        let now = now_ms();
+        let range = self.index_.range::<_, [u8]>((start, end));
+        let live = |(_, e): &(&Vec<u8>, &IndexEntry)| DiskStore::is_live(e, now);
+        let entries: Vec<&IndexEntry> = if reverse {
+            range.rev().filter(live).take(limit).map(|(_, e)| e).collect()
+        } else {
+            range.filter(live).take(limit).map(|(_, e)| e).collect()
+        };
+        entries.into_iter().map(|e| self.read_pair(e)).collect()
+    }
+
+    fn scan(&self, pattern: &KeyPattern, resume_after: Option<&[u8]>, count: usize,
+            max_examined: usize) -> Result<ScanPage, RWError> {
+        // Walks the keys exactly like `KeyValueStore::scan`
+        let prefix = pattern.literal_prefix();
+        let start = match resume_after {
+            Some(k) if k >= prefix.as_slice() => Bound::Excluded(k),
+            _ => Bound::Included(prefix.as_slice())
+        };
+        let now = now_ms();
+        let mut pairs = Vec::new();
+        let mut last: Option<&[u8]> = resume_after;
+        let range = self.index_.range::<_, [u8]>((start, Bound::Unbounded));
+        for (examined, (k, e)) in range.enumerate() {
+            if !k.starts_with(&prefix) {
+                break;
+            }
+            if pairs.len() == count || examined == max_examined {
+                return Ok(ScanPage { pairs, resume_after: last.map(|l| l.to_vec()) });
+            }
+            last = Some(k.as_slice());
+            if DiskStore::is_live(e, now) && pattern.matches(k) {
+                pairs.push(self.read_pair(e)?);
+            }
+        }
+        Ok(ScanPage { pairs, resume_after: None })
+    }
+
+    fn remove_expired(&mut self, now: u64, limit: usize) -> Result<Vec<Vec<u8>>, RWError> {
+        // Nothing is logged; the records themselves say when they expire
+        let mut removed = Vec::new();
+        while removed.len() < limit {
+            match self.expiry_index_.first() {
+                Some((t, _)) if *t <= now => {},
+                _ => break
+            }
+            let (_, key) = self.expiry_index_.pop_first().unwrap();
+            self.remove_index(&key);
+            removed.push(key);
+        }
+        if !removed.is_empty() {
+            self.maybe_compact();
+        }
+        Ok(removed)
+    }
+
+    fn keys(&self) -> Box<dyn Iterator<Item = Result<Vec<u8>, RWError>> + '_> {
+        Box::new(self.index_.keys().map(|k| Ok(k.clone())))
+    }
+
+    fn keys_by_expiry(&self) -> Box<dyn Iterator<Item = &[u8]> + '_> {
+        Box::new(self.expiry_index_.iter().map(|(_, k)| k.as_slice()))
+    }
+
+    fn snapshot(&self) -> Box<dyn Iterator<Item = SnapshotEntry> + '_> {
+        Box::new(self.index_.values()
+            .map(|e| self.read_pair(e).map(|pair| (pair, e.expires_at))))
+    }
+
+    fn freeze(&self) -> Result<Box<dyn FrozenEngine>, RWError> {
+        Ok(Box::new(FrozenDiskStore {
+            path_: self.path_.clone(),
+            file_: self.file_.clone(),
+            index_: self.index_.clone(),
+        }))
+    }
+
+    fn len(&self) -> usize {
+        self.index_.len()
+    }
+
+    fn memory_usage(&self) -> u64 {
+        self.memory_usage_
+    }
+
+    fn load(&mut self, store: &KeyValueStore) -> Result<(), RWError> {
+        self.check_not_removed()?;
+        let mut index = Vec::with_capacity(store.len());
+        filestore::write_file_atomically(&self.path_, |out| {
+            let mut offset = 0;
+            for entry in store.iter() {
+                let frame = encode_frame(&WalRecord {
+                    op: WalOp::Create.into(),
+                    key: entry.key.to_vec(),
+                    value: entry.value.to_vec(),
+                    expires_at_ms: entry.expires_at,
+                    data_type: match entry.data_type {
+                        DataType::String => None,
+                        t => Some(t.into()),
+                    },
+                    version: entry.version,
+                    batch: Vec::new(),
+                });
+                out.write_all(&frame).map_err(write_error)?;
+                index.push((entry.key.to_vec(), IndexEntry {
+                    offset,
+                    frame_len: frame.len() as u64,
+                    value_len: entry.value.len(),
+                    expires_at: entry.expires_at,
+                    version: entry.version,
+                }));
+                offset += frame.len() as u64;
+            }
+            Ok(())
+        })?;
+        self.file_ = Arc::new(open_log(&self.path_)?);
+        self.index_.clear();
+        self.expiry_index_.clear();
+        self.memory_usage_ = 0;
+        self.live_bytes_ = 0;
+        for (key, entry) in index {
+            self.insert_index(key, entry);
+        }
+        self.file_len_ = self.live_bytes_;
+        Ok(())
+    }
+
+    fn destroy(&mut self) -> Result<(), RWError> {
+        self.check_not_removed()?;
+        fs::remove_file(&self.path_).map_err(write_error)?;
+        self.removed_ = true;
+        filestore::sync_parent_dir(&self.path_)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::key_value_store::storage_engine::conformance;
+    use std::cell::Cell;
+
+    fn fresh_store(path: &str) -> DiskStore {
+        let _ = fs::remove_file(path);
+        DiskStore::open("test", path).expect("Cannot open store!")
+    }
+
+    #[test]
+    fn test_conformance() {
+        let run = Cell::new(0);
+        let path = || format!("/tmp/test_disk_conformance_{}.log", run.get());
+        conformance::run_reopening(&mut || {
+            run.set(run.get() + 1);
+            Box::new(fresh_store(&path()))
+        }, &mut || Box::new(DiskStore::open("test", &path()).unwrap()));
+    }
+
+    #[test]
+    fn test_reopen() {
+        let path = "/tmp/test_disk_reopen.log";
+        let expiry = now_ms() + 60_000;
+        let mut store = fresh_store(path);
+        store.put(KeyValuePair::new("one", "uno"), None).unwrap();
+        store.put(KeyValuePair::new("two", "dos"), Some(expiry)).unwrap();
+        store.put(KeyValuePair::new("one", "eins"), None).unwrap();
+        store.put(KeyValuePair::new("three", "tres"), None).unwrap();
+        store.delete(b"three").unwrap();
+        let usage = store.memory_usage();
+        let version = store.get_versioned(b"one").unwrap().unwrap().1;
+        drop(store);
+
+        let reopened = DiskStore::open("test", path).unwrap();
+        assert_eq!(reopened.len(), 2);
+        assert_eq!(reopened.get(b"one").unwrap().unwrap().value(), "eins");
+        assert_eq!(reopened.get_versioned(b"one").unwrap().unwrap().1, version);
+        assert!(reopened.next_version() > version);
+        assert_eq!(reopened.expires_at(b"two"), Some(expiry));
+        assert_eq!(reopened.get(b"three").unwrap(), None);
+        assert_eq!(reopened.memory_usage(), usage);
+    }
+
+    #[test]
+    fn test_open_discards_torn_record() {
+        let path = "/tmp/test_disk_torn.log";
+        let mut store = fresh_store(path);
+        store.put(KeyValuePair::new("one", "uno"), None).unwrap();
+        let full_len = fs::metadata(path).unwrap().len();
+        store.put(KeyValuePair::new("two", "dos"), None).unwrap();
+        drop(store);
+        let torn_len = fs::metadata(path).unwrap().len() - 2;
+        OpenOptions::new().write(true).open(path).unwrap()
+            .set_len(torn_len).unwrap();
+
+        let mut reopened = DiskStore::open("test", path).unwrap();
+        assert_eq!(reopened.get(b"two").unwrap(), None);
+        assert_eq!(fs::metadata(path).unwrap().len(), full_len);
+        reopened.put(KeyValuePair::new("three", "tres"), None).unwrap();
+        drop(reopened);
+        let again = DiskStore::open("test", path).unwrap();
+        assert_eq!(again.get(b"one").unwrap().unwrap().value(), "uno");
+        assert_eq!(again.get(b"three").unwrap().unwrap().value(), "tres");
+    }
+
+    #[test]
+    fn test_compaction() {
+        let path = "/tmp/test_disk_compaction.log";
+        let mut store = fresh_store(path);
+        let value = "v".repeat(1024);
+        // Overwrite the same few keys until the log has to be compacted
+        for i in 0..(2 * COMPACTION_MIN_BYTES / 1024) {
+            store.put(KeyValuePair::new(&format!("key{}", i % 10), &value), None).unwrap();
+        }
+        assert!(fs::metadata(path).unwrap().len() < COMPACTION_MIN_BYTES);
+        assert_eq!(store.len(), 10);
+        drop(store);
+        let reopened = DiskStore::open("test", path).unwrap();
+        assert_eq!(reopened.len(), 10);
+        assert_eq!(reopened.get(b"key7").unwrap().unwrap().value(), value);
+    }
+
+    #[test]
+    fn test_destroy() {
+        let path = "/tmp/test_disk_destroy.log";
+        let mut store = fresh_store(path);
+        store.put(KeyValuePair::new("one", "uno"), None).unwrap();
+        store.destroy().unwrap();
+        assert!(!std::path::Path::new(path).exists());
+        assert!(store.put(KeyValuePair::new("two", "dos"), None).is_err());
+        assert!(store.load(&KeyValueStore::new("test")).is_err());
+        assert!(!std::path::Path::new(path).exists());
+    }
+}
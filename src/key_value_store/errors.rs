@@ -7,6 +7,11 @@ pub enum ErrorKind {
     FileWriteError,
     FileReadError,
     DataDecodeError,
+    TruncatedDataError,
+    ChecksumMismatchError,
+    UnsupportedVersionError,
+    PartialWriteError,
+    SyncError,
 }
 
 pub struct RWError {
@@ -51,15 +56,17 @@ impl Debug for RWError {
 }
 
 fn error_kind_to_str(ek: ErrorKind) -> String {
-    let ret: &str;
-    match ek {
-        ErrorKind::ErrorNone => {
-            ret = "";
-        }
-        ErrorKind::FileOpenError => ret = "Cannot open file",
-        ErrorKind::FileReadError => ret = "Cannot read file",
-        ErrorKind::FileWriteError => ret = "Cannot write to file",
-        ErrorKind::DataDecodeError => ret = "Data decode error",
-    }
-    return String::from(ret);
+    let ret = match ek {
+        ErrorKind::ErrorNone => "",
+        ErrorKind::FileOpenError => "Cannot open file",
+        ErrorKind::FileReadError => "Cannot read file",
+        ErrorKind::FileWriteError => "Cannot write to file",
+        ErrorKind::DataDecodeError => "Data decode error",
+        ErrorKind::TruncatedDataError => "File ended unexpectedly",
+        ErrorKind::ChecksumMismatchError => "Checksum mismatch",
+        ErrorKind::UnsupportedVersionError => "Unsupported format version",
+        ErrorKind::PartialWriteError => "Write did not complete",
+        ErrorKind::SyncError => "Cannot sync to disk",
+    };
+    String::from(ret)
 }
@@ -0,0 +1,341 @@
+use std::collections::{BTreeSet, HashMap};
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+
+use super::key_value_store::now_ms;
+use super::errors::RWError;
+use super::storage_engine::StorageEngine;
+
+/// Random keys a random eviction tries before looking for a key that can be
+/// evicted one by one
+const RANDOM_PICKS: usize = 16;
+
+/// Decides which keys to drop when a store outgrows its memory budget.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum EvictionPolicy {
+    /// Evict the least recently used key
+    Lru,
+    /// Evict the least frequently used key, oldest access breaking ties
+    Lfu,
+    /// Evict a key picked uniformly at random
+    Random,
+    /// Evict the key closest to expiring. Keys without a TTL are never
+    /// evicted.
+    VolatileTtl,
+    /// Never evict; refuse writes that would exceed the budget instead
+    NoEviction,
+}
+
+impl FromStr for EvictionPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "lru" => Ok(EvictionPolicy::Lru),
+            "lfu" => Ok(EvictionPolicy::Lfu),
+            "random" => Ok(EvictionPolicy::Random),
+            "volatile-ttl" => Ok(EvictionPolicy::VolatileTtl),
+            "noeviction" => Ok(EvictionPolicy::NoEviction),
+            _ => Err(format!("Unknown eviction policy: {}", s)),
+        }
+    }
+}
+
+impl Display for EvictionPolicy {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        let name = match self {
+            EvictionPolicy::Lru => "lru",
+            EvictionPolicy::Lfu => "lfu",
+            EvictionPolicy::Random => "random",
+            EvictionPolicy::VolatileTtl => "volatile-ttl",
+            EvictionPolicy::NoEviction => "noeviction",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+struct KeyStats {
+    last_access: u64,
+    hits: u64,
+    // Index into `Evictor::slots_`
+    slot: usize,
+}
+
+/// Tracks how keys are used so that victims can be picked under the
+/// configured policy without scanning the whole store.
+///
+/// The evictor only keeps bookkeeping; the caller is responsible for telling
+/// it about every insert, access and removal, for deciding when the store is
+/// over its budget, and for actually removing the victims it picks.
+pub struct Evictor {
+    policy_: EvictionPolicy,
+    keys_: HashMap<Vec<u8>, KeyStats>,
+    // Every tracked key, so that a random one can be picked in O(1)
+    slots_: Vec<Vec<u8>>,
+    // (rank, tiebreak, key), lowest first. The rank is the last access for
+    // LRU and the hit count for LFU.
+    order_: BTreeSet<(u64, u64, Vec<u8>)>,
+    clock_: u64,
+    rng_state_: u64,
+    evicted_: u64,
+}
+
+impl Evictor {
+    pub fn new(policy: EvictionPolicy) -> Evictor {
+        Evictor {
+            policy_: policy,
+            keys_: HashMap::new(),
+            slots_: Vec::new(),
+            order_: BTreeSet::new(),
+            clock_: 0,
+            // xorshift must not start at zero
+            rng_state_: now_ms() | 1,
+            evicted_: 0,
+        }
+    }
+
+    pub fn policy(&self) -> EvictionPolicy {
+        self.policy_
+    }
+
+    /// The number of keys evicted since the evictor was created
+    pub fn evicted(&self) -> u64 {
+        self.evicted_
+    }
+
+    /// Forgets everything it knew and starts tracking the keys in `store` as
+    /// if they had just been inserted. Used when the store is replaced.
+    pub fn reset(&mut self, store: &dyn StorageEngine) -> Result<(), RWError> {
+        self.keys_.clear();
+        self.slots_.clear();
+        self.order_.clear();
+        for key in store.keys() {
+            self.record_access(&key?);
+        }
+        Ok(())
+    }
+
+    fn order_key(&self, key: &[u8], stats: &KeyStats) -> (u64, u64, Vec<u8>) {
+        match self.policy_ {
+            EvictionPolicy::Lfu => (stats.hits, stats.last_access, key.to_vec()),
+            _ => (stats.last_access, 0, key.to_vec()),
+        }
+    }
+
+    fn uses_order(&self) -> bool {
+        matches!(self.policy_, EvictionPolicy::Lru | EvictionPolicy::Lfu)
+    }
+
+    // Volatile-TTL works off the store's own expiry index, and no-eviction
+    // never picks anything, so neither needs per-key bookkeeping.
+    fn tracks_keys(&self) -> bool {
+        self.uses_order() || self.policy_ == EvictionPolicy::Random
+    }
+
+    /// Records a read or write of `key`, starting to track it if it is new.
+    pub fn record_access(&mut self, key: &[u8]) {
+        if !self.tracks_keys() {
+            return;
+        }
+        self.clock_ += 1;
+        let now = self.clock_;
+        if let Some(stats) = self.keys_.get(key) {
+            if self.uses_order() {
+                let old = self.order_key(key, stats);
+                self.order_.remove(&old);
+            }
+        } else {
+            self.slots_.push(key.to_vec());
+            self.keys_.insert(key.to_vec(), KeyStats {
+                last_access: 0,
+                hits: 0,
+                slot: self.slots_.len() - 1,
+            });
+        }
+        let stats = self.keys_.get_mut(key).unwrap();
+        stats.last_access = now;
+        stats.hits = stats.hits.saturating_add(1);
+        if self.uses_order() {
+            let stats = self.keys_.get(key).unwrap();
+            let new = self.order_key(key, stats);
+            self.order_.insert(new);
+        }
+    }
+
+    /// Stops tracking `key` after it was removed from the store.
+    pub fn record_remove(&mut self, key: &[u8]) {
+        let stats = match self.keys_.remove(key) {
+            Some(s) => s,
+            None => return,
+        };
+        if self.uses_order() {
+            let old = self.order_key(key, &stats);
+            self.order_.remove(&old);
+        }
+        self.slots_.swap_remove(stats.slot);
+        if let Some(moved) = self.slots_.get(stats.slot) {
+            self.keys_.get_mut(moved).unwrap().slot = stats.slot;
+        }
+    }
+
+    fn next_random(&mut self) -> u64 {
+        let mut x = self.rng_state_;
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        self.rng_state_ = x;
+        x
+    }
+
+    /// Picks the next key to evict from `store` under the policy, never
+    /// picking `exclude` (the key being written). Returns None if the policy
+    /// does not allow evicting anything.
+    pub fn pick_victim(&mut self, store: &dyn StorageEngine, exclude: &[u8]) -> Option<Vec<u8>> {
+        self.pick_victim_excluding(store, &|k| k == exclude)
+    }
+
+    /// Like `pick_victim`, never picking a key for which `excluded` returns
+    /// true, e.g. any of the keys a transaction touches.
+    pub fn pick_victim_excluding(&mut self, store: &dyn StorageEngine,
+            excluded: &dyn Fn(&[u8]) -> bool) -> Option<Vec<u8>> {
+        match self.policy_ {
+            EvictionPolicy::NoEviction => None,
+            EvictionPolicy::Lru | EvictionPolicy::Lfu => self
+                .order_
+                .iter()
+                .map(|(_, _, k)| k)
+                .find(|k| !excluded(k.as_slice()))
+                .cloned(),
+            EvictionPolicy::Random => {
+                let candidates = self.slots_.len();
+                if candidates == 0 {
+                    return None;
+                }
+                for _ in 0..RANDOM_PICKS {
+                    let idx = (self.next_random() % candidates as u64) as usize;
+                    if !excluded(&self.slots_[idx]) {
+                        return Some(self.slots_[idx].clone());
+                    }
+                }
+                // Nearly every key is excluded; settle for the first one that
+                // is not, if any
+                self.slots_.iter().find(|k| !excluded(k.as_slice())).cloned()
+            }
+            EvictionPolicy::VolatileTtl => store
+                .keys_by_expiry()
+                .find(|k| !excluded(k))
+                .map(|k| k.to_vec()),
+        }
+    }
+
+    /// Records that a key picked by `pick_victim` was evicted.
+    pub fn record_eviction(&mut self, key: &[u8]) {
+        self.record_remove(key);
+        self.evicted_ += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::key_value_store::key_value_pair::KeyValuePair;
+    use crate::key_value_store::key_value_store::KeyValueStore;
+
+    fn store_with_keys(keys: &[&str]) -> KeyValueStore {
+        let mut store = KeyValueStore::new("test");
+        for k in keys {
+            store.add(KeyValuePair::new(k, "value"));
+        }
+        store
+    }
+
+    #[test]
+    fn test_policy_names() {
+        for name in ["lru", "lfu", "random", "volatile-ttl", "noeviction"] {
+            let policy = EvictionPolicy::from_str(name).unwrap();
+            assert_eq!(policy.to_string(), name);
+        }
+        assert!(EvictionPolicy::from_str("fifo").is_err());
+    }
+
+    #[test]
+    fn test_lru() {
+        let store = store_with_keys(&["a", "b", "c"]);
+        let mut evictor = Evictor::new(EvictionPolicy::Lru);
+        evictor.reset(&store).unwrap();
+        // Access every key so that the order does not depend on the order
+        // reset found them in
+        for key in [b"b", b"c", b"a"] {
+            evictor.record_access(key);
+        }
+        assert_eq!(evictor.pick_victim(&store, b"z"), Some(b"b".to_vec()));
+        assert_eq!(evictor.pick_victim(&store, b"b"), Some(b"c".to_vec()));
+        evictor.record_eviction(b"b");
+        assert_eq!(evictor.pick_victim(&store, b"z"), Some(b"c".to_vec()));
+        assert_eq!(evictor.evicted(), 1);
+    }
+
+    #[test]
+    fn test_lfu() {
+        let store = store_with_keys(&["a", "b", "c"]);
+        let mut evictor = Evictor::new(EvictionPolicy::Lfu);
+        evictor.reset(&store).unwrap();
+        // b and c are used as often, but b was used first
+        for key in [b"b", b"c", b"a", b"a"] {
+            evictor.record_access(key);
+        }
+        assert_eq!(evictor.pick_victim(&store, b"z"), Some(b"b".to_vec()));
+        evictor.record_eviction(b"b");
+        assert_eq!(evictor.pick_victim(&store, b"z"), Some(b"c".to_vec()));
+    }
+
+    #[test]
+    fn test_random() {
+        let store = store_with_keys(&["a", "b"]);
+        let mut evictor = Evictor::new(EvictionPolicy::Random);
+        evictor.reset(&store).unwrap();
+        for _ in 0..10 {
+            assert_eq!(evictor.pick_victim(&store, b"a"), Some(b"b".to_vec()));
+        }
+        evictor.record_eviction(b"b");
+        assert_eq!(evictor.pick_victim(&store, b"a"), None);
+    }
+
+    #[test]
+    fn test_pick_victim_excluding() {
+        let keys = ["a", "b", "c", "d"];
+        let store = store_with_keys(&keys);
+        let protected = |k: &[u8]| k != b"c";
+        for policy in [EvictionPolicy::Lru, EvictionPolicy::Lfu, EvictionPolicy::Random] {
+            let mut evictor = Evictor::new(policy);
+            evictor.reset(&store).unwrap();
+            for _ in 0..10 {
+                assert_eq!(evictor.pick_victim_excluding(&store, &protected), Some(b"c".to_vec()));
+            }
+            assert_eq!(evictor.pick_victim_excluding(&store, &|_| true), None);
+        }
+    }
+
+    #[test]
+    fn test_volatile_ttl() {
+        let mut store = store_with_keys(&["forever"]);
+        let now = now_ms();
+        store.add_with_expiry(KeyValuePair::new("late", "v"), Some(now + 20_000));
+        store.add_with_expiry(KeyValuePair::new("soon", "v"), Some(now + 10_000));
+        let mut evictor = Evictor::new(EvictionPolicy::VolatileTtl);
+        evictor.reset(&store).unwrap();
+        assert_eq!(evictor.pick_victim(&store, b"z"), Some(b"soon".to_vec()));
+        assert_eq!(evictor.pick_victim(&store, b"soon"), Some(b"late".to_vec()));
+        store.delete("soon");
+        store.delete("late");
+        assert_eq!(evictor.pick_victim(&store, b"z"), None);
+    }
+
+    #[test]
+    fn test_noeviction() {
+        let store = store_with_keys(&["a"]);
+        let mut evictor = Evictor::new(EvictionPolicy::NoEviction);
+        evictor.reset(&store).unwrap();
+        assert_eq!(evictor.pick_victim(&store, b"z"), None);
+    }
+}
@@ -1,24 +1,113 @@
 use super::errors;
-use super::key_value_store::KeyValueStore;
-
-
-use std::fs::File;
-use std::io::prelude::*;
-
-use crate::{
-    key_value_store::errors::{ErrorKind, RWError},
-    proto::KeyValueStoreMsg,
-};
-use prost::Message;
-use log::trace;
-
-pub fn write_to_file(store: KeyValueStore, target_file: &str) -> Result<(), errors::RWError> {
-    let msg = store.data();
-    // Encoding to a Vec instead of a raw slice allows for custom files sizes
-    // not bound by the buffer size. Does this have a performance cost though?
-    let bytes = msg.encode_to_vec();
-    let mut file;
-    match File::create(target_file) {
+use super::key_value_pair::KeyValuePair;
+use super::key_value_store::{KeyValueStore, StoredEntry};
+use super::restore::plan_restore;
+use super::snapshot::{SnapshotReader, SnapshotWriter};
+use super::storage_engine::{SnapshotEntry, StorageEngine};
+use super::text_format::{self, ImportOptions, ImportReport, OnConflict, ParsedImport, TextFormat};
+
+
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::key_value_store::errors::{ErrorKind, RWError};
+use log::{trace, warn};
+
+/// Counts the bytes that make it to the inner writer, so that a failed write
+/// can report how far it got.
+struct CountingWriter<W: Write> {
+    inner_: W,
+    count_: u64,
+}
+
+impl<W: Write> Write for CountingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner_.write(buf)?;
+        self.count_ += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner_.flush()
+    }
+}
+
+/// How far the writing of a backup has got, readable from other threads
+/// while it is being written
+#[derive(Default)]
+pub struct WriteProgress {
+    total_records_: AtomicU64,
+    records_: AtomicU64,
+    bytes_: AtomicU64,
+}
+
+impl WriteProgress {
+    /// The number of records the backup will hold, 0 until that is known
+    pub fn total_records(&self) -> u64 {
+        self.total_records_.load(Ordering::Relaxed)
+    }
+
+    pub fn records(&self) -> u64 {
+        self.records_.load(Ordering::Relaxed)
+    }
+
+    /// Bytes handed to the file so far, which may not have reached the disk
+    pub fn bytes(&self) -> u64 {
+        self.bytes_.load(Ordering::Relaxed)
+    }
+}
+
+/// Adds the bytes written through it to a `WriteProgress`
+struct ProgressWriter<'a> {
+    inner_: &'a mut dyn Write,
+    progress_: &'a WriteProgress,
+}
+
+impl Write for ProgressWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner_.write(buf)?;
+        self.progress_.bytes_.fetch_add(n as u64, Ordering::Relaxed);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner_.flush()
+    }
+}
+
+fn sync_error(e: io::Error) -> RWError {
+    RWError {
+        kind_: ErrorKind::SyncError,
+        context_: e.to_string(),
+    }
+}
+
+/// Syncs the directory holding `path`, making the creation, removal or
+/// renaming of `path` durable.
+pub fn sync_parent_dir(path: &str) -> Result<(), RWError> {
+    let dir = match Path::new(path).parent() {
+        Some(p) if !p.as_os_str().is_empty() => p,
+        _ => Path::new("."),
+    };
+    File::open(dir).and_then(|d| d.sync_all()).map_err(sync_error)
+}
+
+/// Replaces `target_file` with whatever `write_contents` writes, such that a
+/// crash or failure at any point leaves either the old file or the complete
+/// new one in place, never a partial file.
+///
+/// The contents go to a temporary file in the same directory, which is synced
+/// and then renamed over the target. The directory is synced last so that the
+/// rename itself is durable.
+pub fn write_file_atomically<F>(target_file: &str, write_contents: F) -> Result<(), RWError>
+where
+    F: FnOnce(&mut dyn Write) -> Result<(), RWError>,
+{
+    let tmp_file = format!("{}.tmp", target_file);
+    let file;
+    match File::create(&tmp_file) {
         Ok(f) => {
             file = f;
         }
@@ -29,26 +118,134 @@ pub fn write_to_file(store: KeyValueStore, target_file: &str) -> Result<(), erro
             })
         }
     };
-    match file.write_all(&bytes) {
-        Ok(_) => {
-            trace!("Backup length: {:?}", bytes.len());
-        }
-        Err(e) => {
-            return Err(RWError {
+    let mut writer = CountingWriter {
+        inner_: BufWriter::new(file),
+        count_: 0,
+    };
+    let result = write_contents(&mut writer)
+        .and_then(|_| writer.flush().map_err(|e| RWError {
+            kind_: ErrorKind::FileWriteError,
+            context_: e.to_string(),
+        }))
+        .and_then(|_| match writer.inner_.into_inner() {
+            Ok(f) => f.sync_all().map_err(sync_error),
+            Err(e) => Err(RWError {
                 kind_: ErrorKind::FileWriteError,
                 context_: e.to_string(),
-            })
+            }),
+        });
+    if let Err(e) = result {
+        if let Err(rm_err) = fs::remove_file(&tmp_file) {
+            warn!("Cannot remove {:?}: {:?}", tmp_file, rm_err);
         }
-    };
+        if e.kind_ == ErrorKind::FileWriteError {
+            return Err(RWError {
+                kind_: ErrorKind::PartialWriteError,
+                context_: format!("{} bytes written before failure: {}",
+                    writer.count_, e.context_),
+            });
+        }
+        return Err(e);
+    }
+    if let Err(e) = fs::rename(&tmp_file, target_file) {
+        let _ = fs::remove_file(&tmp_file);
+        return Err(RWError {
+            kind_: ErrorKind::FileWriteError,
+            context_: e.to_string(),
+        });
+    }
+    sync_parent_dir(target_file)?;
+    trace!("Wrote {:?} bytes to {:?}", writer.count_, target_file);
     Ok(())
 }
 
-pub fn read_from_file(src_file: &str) -> Result<KeyValueStore, errors::RWError> {
-    let mut file;
-    match File::open(src_file) {
-        Ok(f) => {
-            file = f;
+pub fn write_to_file(store: &KeyValueStore, target_file: &str) -> Result<(), errors::RWError> {
+    write_entries_to_file(target_file, store.name(), store.len() as u64, store.snapshot(),
+        &WriteProgress::default())
+}
+
+/// Writes a backup of a store called `name` holding exactly `record_count`
+/// entries, which may come from more than one `StorageEngine`, keeping
+/// `progress` up to date as it goes.
+pub fn write_entries_to_file(target_file: &str, name: &str, record_count: u64,
+        entries: impl Iterator<Item = SnapshotEntry>, progress: &WriteProgress)
+        -> Result<(), errors::RWError> {
+    progress.total_records_.store(record_count, Ordering::Relaxed);
+    write_file_atomically(target_file, |out| {
+        let out = ProgressWriter { inner_: out, progress_: progress };
+        // Records are streamed out one at a time so the size of a backup is
+        // not bound by how much we are willing to buffer in memory.
+        let mut writer = SnapshotWriter::new(out, name, record_count)?;
+        for entry in entries {
+            let (pair, expires_at) = entry?;
+            writer.write_record(pair.key(), pair.value_bytes(), pair.data_type(), expires_at)?;
+            progress.records_.fetch_add(1, Ordering::Relaxed);
+        }
+        writer.finish()?;
+        Ok(())
+    })?;
+    trace!("Backup records: {:?}", record_count);
+    Ok(())
+}
+
+/// Writes an incremental backup of a store called `name` holding `entries`,
+/// based on the backup `base_id` which holds `base` once its own chain is
+/// replayed, see `read_backup_chain`. Only the entries that differ from
+/// `base` are written, followed by a deletion record for each key of `base`
+/// missing from `entries`.
+pub fn write_incremental_to_file(target_file: &str, name: &str, base_id: &str,
+        base: &KeyValueStore, entries: impl Iterator<Item = SnapshotEntry>,
+        progress: &WriteProgress) -> Result<(), errors::RWError> {
+    // Whatever is left once every current key has been taken out was
+    // deleted. The copy shares its pairs with `base`.
+    let mut deleted = base.clone();
+    let mut changed = Vec::new();
+    for entry in entries {
+        let (pair, expires_at) = entry?;
+        let unchanged = base.entry(pair.key()).is_some_and(|e| e.value == pair.value_bytes()
+            && e.data_type == pair.data_type() && e.expires_at == expires_at);
+        deleted.delete(pair.key());
+        if !unchanged {
+            changed.push((pair, expires_at));
+        }
+    }
+    let record_count = (changed.len() + deleted.len()) as u64;
+    progress.total_records_.store(record_count, Ordering::Relaxed);
+    write_file_atomically(target_file, |out| {
+        let out = ProgressWriter { inner_: out, progress_: progress };
+        let mut writer = SnapshotWriter::new_incremental(out, name, base_id, record_count)?;
+        for (pair, expires_at) in &changed {
+            writer.write_record(pair.key(), pair.value_bytes(), pair.data_type(), *expires_at)?;
+            progress.records_.fetch_add(1, Ordering::Relaxed);
+        }
+        for entry in deleted.iter() {
+            writer.write_deletion(entry.key)?;
+            progress.records_.fetch_add(1, Ordering::Relaxed);
         }
+        writer.finish()?;
+        Ok(())
+    })?;
+    trace!("Incremental backup records: {:?} changed, {:?} deleted",
+        changed.len(), deleted.len());
+    Ok(())
+}
+
+/// What the header and trailer of a backup file say about it
+#[derive(Debug, PartialEq)]
+pub struct BackupFileInfo {
+    pub name: String,
+    /// The backup this one is based on, None for a full backup
+    pub base: Option<String>,
+    pub record_count: u64,
+    pub size_bytes: u64,
+    /// The crc32 stored at the end of the file, which is not checked here
+    pub checksum: u32,
+}
+
+/// Describes the backup in `src_file` without reading its records
+pub fn read_backup_info(src_file: &str) -> Result<BackupFileInfo, errors::RWError> {
+    let mut file = match File::open(src_file) {
+        Ok(f) => f,
         Err(e) => {
             return Err(RWError {
                 kind_: ErrorKind::FileOpenError,
@@ -56,35 +253,158 @@ pub fn read_from_file(src_file: &str) -> Result<KeyValueStore, errors::RWError>
             })
         }
     };
-    let mut buf = vec![0; 1024];
-    // n_bytes will override the default 1024 byte buffer size to allow us to
-    // properly read the protobuf file.
-    let n_bytes: usize;
-    match file.read(&mut buf) {
-        Ok(n_b) => {
-            if n_b == 0 {
-                return Err(RWError { kind_: {ErrorKind::FileReadError},
-                    context_: String::from("Empty file!") })
-            } else {
-                n_bytes = n_b;
-            }
+    let (name, base, record_count) = {
+        let reader = SnapshotReader::new(BufReader::new(&file))?;
+        (reader.name().to_string(), reader.base().map(String::from), reader.record_count())
+    };
+    let read_error = |e: io::Error| RWError {
+        kind_: ErrorKind::FileReadError,
+        context_: e.to_string(),
+    };
+    // The header has been read, so seeking back from the end for the
+    // trailer stays inside the file
+    let size_bytes = file.metadata().map_err(read_error)?.len();
+    let mut trailer = [0u8; 4];
+    file.seek(SeekFrom::End(-4))
+        .and_then(|_| file.read_exact(&mut trailer))
+        .map_err(read_error)?;
+    Ok(BackupFileInfo {
+        name,
+        base,
+        record_count,
+        size_bytes,
+        checksum: u32::from_le_bytes(trailer),
+    })
+}
+
+/// The longest chain of backups `read_backup_chain` follows, which also
+/// stops it going round a chain that loops back on itself
+const MAX_CHAIN_LEN: usize = 1000;
+
+fn open_snapshot(src_file: &str) -> Result<SnapshotReader<BufReader<File>>, errors::RWError> {
+    match File::open(src_file) {
+        Ok(f) => SnapshotReader::new(BufReader::new(f)),
+        Err(e) => Err(RWError {
+            kind_: ErrorKind::FileOpenError,
+            context_: e.to_string(),
+        }),
+    }
+}
+
+/// Applies every record left in `reader` to `store`, deletions included
+fn apply_records(reader: &mut SnapshotReader<BufReader<File>>, store: &mut KeyValueStore)
+        -> Result<(), errors::RWError> {
+    while let Some(record) = reader.next_record()? {
+        if record.deleted {
+            store.delete(&record.key);
+            continue;
         }
-        Err(e) => {
+        let pair = KeyValuePair::from_stored(&record.key, record.data_type, record.value)
+            .map_err(|e| RWError {
+                kind_: ErrorKind::DataDecodeError,
+                context_: e,
+            })?;
+        store.put(pair, record.expires_at_ms);
+    }
+    Ok(())
+}
+
+/// Reads a full backup. Fails on an incremental one, which needs
+/// `read_backup_chain`.
+pub fn read_from_file(src_file: &str) -> Result<KeyValueStore, errors::RWError> {
+    let mut reader = open_snapshot(src_file)?;
+    if let Some(base) = reader.base() {
+        return Err(RWError {
+            kind_: ErrorKind::DataDecodeError,
+            context_: format!("{} is an incremental backup based on {}", src_file, base),
+        });
+    }
+    let mut store = KeyValueStore::new(reader.name());
+    apply_records(&mut reader, &mut store)?;
+    trace!("Restored records: {:?}", store.len());
+    Ok(store)
+}
+
+/// Reads a backup that may be incremental by replaying its chain, from the
+/// full backup the chain starts at to `src_file`. `resolve` turns the ID of a
+/// base backup into the file holding it.
+pub fn read_backup_chain(src_file: &str, resolve: &dyn Fn(&str) -> Result<String, RWError>)
+        -> Result<KeyValueStore, errors::RWError> {
+    // Newest first
+    let mut chain = vec![src_file.to_string()];
+    while let Some(base) = open_snapshot(chain.last().unwrap())?.base().map(String::from) {
+        if chain.len() == MAX_CHAIN_LEN {
             return Err(RWError {
-                kind_: ErrorKind::FileReadError,
-                context_: e.to_string(),
-            })
+                kind_: ErrorKind::DataDecodeError,
+                context_: format!("{} is based on a chain of over {} backups",
+                    src_file, MAX_CHAIN_LEN),
+            });
         }
-    };
-    match KeyValueStoreMsg::decode(&buf[..n_bytes]) {
-        Ok(msg) => Ok(KeyValueStore::from(msg)),
+        chain.push(resolve(&base)?);
+    }
+    let mut store = read_from_file(&chain.pop().unwrap())?;
+    while let Some(file) = chain.pop() {
+        let mut reader = open_snapshot(&file)?;
+        store.set_name(reader.name());
+        apply_records(&mut reader, &mut store)?;
+    }
+    trace!("Restored records: {:?}", store.len());
+    Ok(store)
+}
+
+/// Exports the pairs of `store` that have not expired to `target_file` as
+/// text, see `text_format`, returning how many were written
+pub fn export_to_file(store: &KeyValueStore, target_file: &str, format: TextFormat)
+        -> Result<u64, errors::RWError> {
+    export_entries_to_file(target_file, format, store.snapshot())
+}
+
+/// Exports the pairs in `entries` that have not expired, which may come from
+/// more than one `StorageEngine`, to `target_file` as text
+pub fn export_entries_to_file(target_file: &str, format: TextFormat,
+        entries: impl Iterator<Item = SnapshotEntry>) -> Result<u64, errors::RWError> {
+    let mut count = 0;
+    write_file_atomically(target_file, |out| {
+        count = text_format::write_text(out, format, entries)?;
+        Ok(())
+    })?;
+    trace!("Exported records: {:?}", count);
+    Ok(count)
+}
+
+/// Reads a text file for an import, see `text_format::read_text`
+pub fn read_text_file(src_file: &str, format: TextFormat) -> Result<ParsedImport, errors::RWError> {
+    match File::open(src_file) {
+        Ok(f) => text_format::read_text(BufReader::new(f), format),
         Err(e) => Err(RWError {
-            kind_: ErrorKind::DataDecodeError,
+            kind_: ErrorKind::FileOpenError,
             context_: e.to_string(),
         }),
     }
 }
 
+/// Imports the text file `src_file` into `store` as `options` say. Lines that
+/// fail are reported rather than returned as errors, and nothing is imported
+/// if more of them fail than `options.max_errors` allows.
+pub fn import_from_file(src_file: &str, store: &mut KeyValueStore, options: &ImportOptions)
+        -> Result<ImportReport, errors::RWError> {
+    let mut parsed = read_text_file(src_file, options.format)?;
+    if options.on_conflict == OnConflict::Fail {
+        parsed.fail_live_keys(|key| Ok(store.get(key).is_some()))?;
+    }
+    if parsed.too_many_errors(options) {
+        return Ok(parsed.abandon());
+    }
+    let plan = plan_restore(store, &parsed.store, &options.restore_options())?;
+    let counts = plan.counts;
+    for entry in plan.puts {
+        // Imported pairs are new writes to the store
+        let version = store.next_version();
+        store.put_entry(StoredEntry { version, ..entry });
+    }
+    Ok(parsed.finish(&counts))
+}
+
 #[cfg(test)]
 mod tests {
     /// Test cases added:
@@ -92,25 +412,28 @@ mod tests {
     /// 2. File I/O
     /// 3. Attempt reading from invalid file, check error
     /// 4. Attempt reading bad data, check error
+    /// 5. Stores larger than a single read buffer
+    /// 6. Truncated and corrupted files
+    /// 7. Failed writes leave the previous file in place
+    ///
     /// Remaining:
-    /// 5. Permissions check
+    /// 8. Permissions check
     use super::*;
-    use crate::key_value_store::key_value_pair::KeyValuePair;
 
     fn create_simple_kv_store() -> KeyValueStore {
         let mut kvs = KeyValueStore::new("test");
         kvs.add(KeyValuePair::new("Hello", "Value1"));
         kvs.add(KeyValuePair::new("Goodbye", "Value2"));
-        return kvs;
+        kvs
     }
 
     fn equality_test(lhs: KeyValueStore, rhs: KeyValueStore) {
         assert_eq!(lhs.name(), rhs.name(), "Name Mismatch!");
         for (k, v) in lhs.all() {
-            if let Some(val) = rhs.get(&k.clone()) {
+            if let Some(val) = rhs.get(&k) {
                 assert_eq!(v, val.value(), "Mismatch for key: {:?}", k);
             } else {
-                assert!(false, "No value found for key {:?}!", k);
+                panic!("No value found for key {:?}!", k);
             }
         }
     }
@@ -128,21 +451,18 @@ mod tests {
     fn test_file_io() {
         let kvs = create_simple_kv_store();
         let file_name = "/tmp/test.buf";
-        match write_to_file(kvs.clone(), file_name) {
+        match write_to_file(&kvs, file_name) {
             Ok(_) => {}
             Err(e) => {
                 panic!("Write error: {:?}!", e);
             }
         }
-        let kvs2: KeyValueStore;
-        match read_from_file(file_name) {
-            Ok(k) => {
-                kvs2 = k;
-            }
+        let kvs2 = match read_from_file(file_name) {
+            Ok(k) => k,
             Err(e) => {
                 panic!("Read error: {:?}!", e);
             }
-        }
+        };
         equality_test(kvs, kvs2);
     }
 
@@ -151,7 +471,7 @@ mod tests {
         let file_name = "/dev/null";
         match read_from_file(file_name) {
             Ok(_) => {
-                assert!(false, "Expected failure!")
+                panic!("Expected failure!")
             }
             Err(e) => {
                 assert_eq!(e.kind_, ErrorKind::FileReadError);
@@ -164,7 +484,7 @@ mod tests {
         let file_name = "/tmp/file_does_not_exist";
         match read_from_file(file_name) {
             Ok(_) => {
-                assert!(false, "Expected failure!")
+                panic!("Expected failure!")
             }
             Err(e) => {
                 assert_eq!(e.kind_, ErrorKind::FileOpenError);
@@ -186,7 +506,7 @@ mod tests {
             panic!("Test cannot create file! {:?}", e);
         }
         };
-        match file.write_all(&bytes) {
+        match file.write_all(bytes) {
             Ok(_) => {
                 trace!("bytes: {:?}, len: {:?}", bytes, bytes.len());
             }
@@ -196,11 +516,232 @@ mod tests {
         }
         match read_from_file(file_name) {
             Ok(_) => {
-                assert!(false, "Expected failure!");
+                panic!("Expected failure!");
             }
             Err(e) => {
                 assert_eq!(e.kind_, ErrorKind::DataDecodeError);
             }
         }
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_file_io_large_store() {
+        let mut kvs = KeyValueStore::new("large");
+        for i in 0..10000 {
+            kvs.add(KeyValuePair::new(&format!("key_{}", i), &"v".repeat(64)));
+        }
+        let file_name = "/tmp/test_large.buf";
+        write_to_file(&kvs, file_name).expect("Write error!");
+        assert!(std::fs::metadata(file_name).unwrap().len() > 1024 * 512);
+        let kvs2 = read_from_file(file_name).expect("Read error!");
+        assert_eq!(kvs2.len(), kvs.len());
+        equality_test(kvs, kvs2);
+    }
+
+    #[test]
+    fn test_file_io_read_truncated_file() {
+        let kvs = create_simple_kv_store();
+        let file_name = "/tmp/test_truncated.buf";
+        write_to_file(&kvs, file_name).expect("Write error!");
+        let len = std::fs::metadata(file_name).unwrap().len();
+        std::fs::OpenOptions::new().write(true).open(file_name).unwrap()
+            .set_len(len - 3).unwrap();
+        match read_from_file(file_name) {
+            Ok(_) => {
+                panic!("Expected failure!")
+            }
+            Err(e) => {
+                assert_eq!(e.kind_, ErrorKind::TruncatedDataError);
+            }
+        }
+    }
+
+    #[test]
+    fn test_file_io_read_corrupted_file() {
+        let kvs = create_simple_kv_store();
+        let file_name = "/tmp/test_corrupted.buf";
+        write_to_file(&kvs, file_name).expect("Write error!");
+        let mut bytes = std::fs::read(file_name).unwrap();
+        let idx = bytes.len() - 6;
+        bytes[idx] ^= 0x01;
+        std::fs::write(file_name, &bytes).unwrap();
+        match read_from_file(file_name) {
+            Ok(_) => {
+                panic!("Expected failure!")
+            }
+            Err(e) => {
+                assert_eq!(e.kind_, ErrorKind::ChecksumMismatchError);
+            }
+        }
+    }
+
+    #[test]
+    fn test_failed_write_keeps_previous_file() {
+        let kvs = create_simple_kv_store();
+        let file_name = "/tmp/test_atomic.buf";
+        write_to_file(&kvs, file_name).expect("Write error!");
+        let before = std::fs::read(file_name).unwrap();
+
+        let res = write_file_atomically(file_name, |out| {
+            out.write_all(b"half a backup").unwrap();
+            Err(RWError {
+                kind_: ErrorKind::FileWriteError,
+                context_: String::from("No space left on device"),
+            })
+        });
+        match res {
+            Ok(_) => {
+                panic!("Expected failure!")
+            }
+            Err(e) => {
+                assert_eq!(e.kind_, ErrorKind::PartialWriteError);
+            }
+        }
+        assert_eq!(std::fs::read(file_name).unwrap(), before);
+        assert!(!Path::new("/tmp/test_atomic.buf.tmp").exists());
+        equality_test(kvs, read_from_file(file_name).expect("Read error!"));
+    }
+
+    #[test]
+    fn test_write_to_missing_directory() {
+        let kvs = create_simple_kv_store();
+        match write_to_file(&kvs, "/tmp/dir_does_not_exist/test.buf") {
+            Ok(_) => {
+                panic!("Expected failure!")
+            }
+            Err(e) => {
+                assert_eq!(e.kind_, ErrorKind::FileOpenError);
+            }
+        }
+    }
+
+    #[test]
+    fn test_read_backup_info() {
+        let file_name = "/tmp/test_backup_info.buf";
+        write_to_file(&create_simple_kv_store(), file_name).unwrap();
+        let bytes = std::fs::read(file_name).unwrap();
+        let info = read_backup_info(file_name).unwrap();
+        assert_eq!(info, BackupFileInfo {
+            name: String::from("test"),
+            base: None,
+            record_count: 2,
+            size_bytes: bytes.len() as u64,
+            checksum: u32::from_le_bytes(bytes[bytes.len() - 4..].try_into().unwrap()),
+        });
+
+        match read_backup_info("/dev/null") {
+            Ok(_) => panic!("Expected failure!"),
+            Err(e) => assert_eq!(e.kind_, ErrorKind::FileReadError),
+        }
+    }
+
+    #[test]
+    fn test_backup_chain() {
+        let full_file = "/tmp/test_chain_full.buf";
+        let monday_file = "/tmp/test_chain_monday.buf";
+        let tuesday_file = "/tmp/test_chain_tuesday.buf";
+        let resolve = |id: &str| Ok(format!("/tmp/test_chain_{}.buf", id));
+        let mut kvs = create_simple_kv_store();
+        write_to_file(&kvs, full_file).unwrap();
+
+        let base = read_from_file(full_file).unwrap();
+        kvs.update(KeyValuePair::new("Hello", "Changed"));
+        kvs.delete("Goodbye");
+        kvs.add(KeyValuePair::new("New", "Value3"));
+        write_incremental_to_file(monday_file, "test", "full", &base, kvs.snapshot(),
+            &WriteProgress::default()).unwrap();
+        // Only the changes are kept
+        assert_eq!(read_backup_info(monday_file).unwrap().record_count, 3);
+        assert_eq!(read_backup_info(monday_file).unwrap().base.as_deref(), Some("full"));
+
+        let base = read_backup_chain(monday_file, &resolve).unwrap();
+        kvs.add(KeyValuePair::new("Goodbye", "Back"));
+        let progress = WriteProgress::default();
+        write_incremental_to_file(tuesday_file, "test", "monday", &base, kvs.snapshot(),
+            &progress).unwrap();
+        assert_eq!(progress.total_records(), 1);
+
+        let restored = read_backup_chain(tuesday_file, &resolve).unwrap();
+        assert_eq!(restored.len(), 3);
+        equality_test(kvs, restored);
+
+        // An incremental backup is no use on its own
+        match read_from_file(tuesday_file) {
+            Ok(_) => panic!("Expected failure!"),
+            Err(e) => assert_eq!(e.kind_, ErrorKind::DataDecodeError),
+        }
+    }
+
+    #[test]
+    fn test_export_import() {
+        let file_name = "/tmp/test_export_import.jsonl";
+        let exported = create_simple_kv_store();
+        assert_eq!(export_to_file(&exported, file_name, TextFormat::JsonLines).unwrap(), 2);
+
+        let mut store = KeyValueStore::new("test");
+        store.add(KeyValuePair::new("Hello", "Live"));
+        store.add(KeyValuePair::new("Other", "Live"));
+        let mut options = ImportOptions {
+            on_conflict: OnConflict::Skip,
+            ..Default::default()
+        };
+        let report = import_from_file(file_name, &mut store, &options).unwrap();
+        assert_eq!((report.added, report.overwritten, report.skipped), (1, 0, 1));
+        assert_eq!(store.get("Hello").unwrap().value(), "Live");
+
+        // Conflicts fail their lines, which abandons the import unless allowed
+        options.on_conflict = OnConflict::Fail;
+        store.delete("Goodbye");
+        let report = import_from_file(file_name, &mut store, &options).unwrap();
+        assert!(report.abandoned);
+        assert_eq!(report.errors.len(), 1);
+        assert_eq!(report.errors[0].line, 2);
+        assert!(store.get("Goodbye").is_none());
+        options.max_errors = 1;
+        let report = import_from_file(file_name, &mut store, &options).unwrap();
+        assert!(!report.abandoned);
+        assert_eq!(report.added, 1);
+        assert_eq!(store.get("Goodbye").unwrap().value(), "Value2");
+
+        options.on_conflict = OnConflict::Overwrite;
+        let report = import_from_file(file_name, &mut store, &options).unwrap();
+        assert_eq!((report.added, report.overwritten), (0, 2));
+        assert_eq!(store.get("Hello").unwrap().value(), "Value1");
+        assert_eq!(store.get("Other").unwrap().value(), "Live");
+    }
+
+    #[test]
+    fn test_backup_chain_loop() {
+        let file_name = "/tmp/test_chain_loop.buf";
+        write_incremental_to_file(file_name, "test", "loop", &KeyValueStore::new("test"),
+            std::iter::empty(), &WriteProgress::default()).unwrap();
+        match read_backup_chain(file_name, &|_| Ok(file_name.to_string())) {
+            Ok(_) => panic!("Expected failure!"),
+            Err(e) => assert_eq!(e.kind_, ErrorKind::DataDecodeError),
+        }
+    }
+
+    #[test]
+    fn test_file_io_keeps_expiry() {
+        let mut kvs = create_simple_kv_store();
+        let expiry = crate::key_value_store::key_value_store::now_ms() + 60_000;
+        kvs.set_expires_at("Hello", Some(expiry));
+        let file_name = "/tmp/test_expiry.buf";
+        write_to_file(&kvs, file_name).expect("Write error!");
+        let kvs2 = read_from_file(file_name).expect("Read error!");
+        assert_eq!(kvs2.expires_at("Hello"), Some(expiry));
+        assert_eq!(kvs2.expires_at("Goodbye"), None);
+    }
+
+    #[test]
+    fn test_file_io_binary_keys_and_values() {
+        let mut kvs = create_simple_kv_store();
+        let key = [0x00, 0xff, 0x80];
+        kvs.add(KeyValuePair::new_binary(&key, &[0xde, 0xad]));
+        let file_name = "/tmp/test_binary.buf";
+        write_to_file(&kvs, file_name).expect("Write error!");
+        let kvs2 = read_from_file(file_name).expect("Read error!");
+        assert_eq!(kvs2.get(key).unwrap().value_bytes(), [0xde, 0xad]);
+        assert_eq!(kvs2, kvs);
+    }
+}
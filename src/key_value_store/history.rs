@@ -0,0 +1,300 @@
+use std::collections::{HashMap, VecDeque};
+
+use super::key_value_pair::KeyValuePair;
+
+/// How long a store keeps the past versions of its pairs. A past version is
+/// dropped as soon as either limit is exceeded; with neither set, nothing is
+/// ever dropped.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Retention {
+    /// The most past versions kept for each key
+    pub max_versions: Option<usize>,
+    /// How long a version is kept once it has been replaced or deleted
+    pub max_age_ms: Option<u64>,
+}
+
+impl Retention {
+    /// False if the limits rule out keeping any past version at all
+    pub fn keeps_history(&self) -> bool {
+        self.max_versions != Some(0) && self.max_age_ms != Some(0)
+    }
+}
+
+/// A point in a key's history, see `History::as_of`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PointInTime {
+    /// Just after the write given this version. Versions are only comparable
+    /// between keys in the same shard, which is all a lookup needs.
+    Version(u64),
+    /// This many milliseconds since the Unix epoch
+    Timestamp(u64),
+}
+
+/// One write of a key, or its deletion
+#[derive(Clone, Debug, PartialEq)]
+pub struct Revision {
+    /// The version the pair was written at. A deletion takes the version the
+    /// next write to its shard gets, as it happened before that write.
+    pub version: u64,
+    /// When it happened, or None if it happened before the history began
+    pub written_at: Option<u64>,
+    /// When the pair expires, if ever
+    pub expires_at: Option<u64>,
+    /// None for a deletion
+    pub pair: Option<KeyValuePair>,
+}
+
+impl Revision {
+    /// The pair as it was at `now`, or None if it was deleted or expired
+    fn live_at(&self, now: u64) -> Option<(KeyValuePair, u64)> {
+        match &self.pair {
+            Some(p) if self.expires_at.is_none_or(|t| t > now) => Some((p.clone(), self.version)),
+            _ => None
+        }
+    }
+}
+
+/// Keeps the writes made to each key of one shard so that past versions can
+/// be read, within a `Retention`.
+///
+/// A key's history runs from its first write after the history was created,
+/// or from the pair it held then, and its latest revision is always kept: it
+/// mirrors what the shard holds, or records that the key was deleted. Older
+/// revisions are dropped by `record` once there are too many, and by `trim`
+/// once they are too old. Expired keys are not recorded as deleted, since
+/// their revisions say when they expire.
+///
+/// Like the evictor, the history only keeps bookkeeping; the caller is
+/// responsible for telling it about every write and deletion.
+pub struct History {
+    retention_: Retention,
+    // Oldest first
+    keys_: HashMap<Vec<u8>, VecDeque<Revision>>,
+    // (when a revision can be dropped, key), soonest first. Entries are not
+    // removed when their revision goes for another reason, so `trim` checks
+    // each one again.
+    due_: VecDeque<(u64, Vec<u8>)>,
+}
+
+impl History {
+    pub fn new(retention: Retention) -> History {
+        History {
+            retention_: retention,
+            keys_: HashMap::new(),
+            due_: VecDeque::new(),
+        }
+    }
+
+    pub fn retention(&self) -> Retention {
+        self.retention_
+    }
+
+    /// True if the history holds any revision of `key`
+    pub fn is_tracked(&self, key: &[u8]) -> bool {
+        self.keys_.contains_key(key)
+    }
+
+    /// Starts the history of `key` with the pair it held before it was
+    /// first recorded, written at an unknown time. Does nothing if the key
+    /// is already tracked.
+    pub fn seed(&mut self, key: &[u8], pair: KeyValuePair, version: u64,
+            expires_at: Option<u64>) {
+        if !self.is_tracked(key) {
+            self.keys_.insert(key.to_vec(), VecDeque::from([Revision {
+                version,
+                written_at: None,
+                expires_at,
+                pair: Some(pair),
+            }]));
+        }
+    }
+
+    /// Records a write or deletion of `key` made at `now`, dropping the
+    /// oldest revisions if the key has too many
+    pub fn record(&mut self, key: &[u8], revision: Revision, now: u64) {
+        let revisions = self.keys_.entry(key.to_vec()).or_default();
+        // A deletion with nothing before it leaves nothing to read
+        if revisions.is_empty() && revision.pair.is_none() {
+            self.keys_.remove(key);
+            return;
+        }
+        revisions.push_back(revision);
+        if let Some(max) = self.retention_.max_versions {
+            while revisions.len() > max + 1 {
+                revisions.pop_front();
+            }
+        }
+        // The revision it replaced is now in the past, and a deletion can be
+        // forgotten with everything before it
+        if let Some(max_age) = self.retention_.max_age_ms {
+            self.due_.push_back((now.saturating_add(max_age), key.to_vec()));
+        }
+    }
+
+    /// Changes when the latest revision of `key` expires, if it is the
+    /// given version. The expiry of a pair is not part of its history.
+    pub fn set_expiry(&mut self, key: &[u8], version: u64, expires_at: Option<u64>) {
+        let latest = self.keys_.get_mut(key).and_then(|r| r.back_mut());
+        if let Some(r) = latest.filter(|r| r.version == version && r.pair.is_some()) {
+            r.expires_at = expires_at;
+        }
+    }
+
+    /// The revisions of `key`, oldest first
+    pub fn revisions(&self, key: &[u8]) -> impl DoubleEndedIterator<Item = &Revision> {
+        self.keys_.get(key).into_iter().flatten()
+    }
+
+    /// Returns None if `key` has no history, and Some(None) if it held no
+    /// pair at `point`, or the history no longer goes back that far.
+    /// Otherwise returns the pair it held, and the version it was written
+    /// at. A pair read by version is returned even if it had expired by
+    /// then.
+    pub fn as_of(&self, key: &[u8], point: PointInTime) -> Option<Option<(KeyValuePair, u64)>> {
+        let revisions = self.keys_.get(key)?;
+        let found = match point {
+            PointInTime::Version(v) => revisions.iter().rev().find(|r| r.version <= v)
+                .and_then(|r| r.live_at(0)),
+            PointInTime::Timestamp(t) => revisions.iter().rev()
+                .find(|r| r.written_at.is_none_or(|w| w <= t))
+                .and_then(|r| r.live_at(t)),
+        };
+        Some(found)
+    }
+
+    /// Drops at most `max` revisions that have been in the past for longer
+    /// than the retention allows, returning how many were dropped. A key
+    /// whose only revision left is an old deletion is forgotten entirely.
+    pub fn trim(&mut self, now: u64, max: usize) -> usize {
+        let max_age = match self.retention_.max_age_ms {
+            None => return 0,
+            Some(a) => a
+        };
+        let mut dropped = 0;
+        while dropped < max {
+            match self.due_.front() {
+                Some((due, _)) if *due <= now => {},
+                _ => break
+            }
+            let (_, key) = self.due_.pop_front().unwrap();
+            let revisions = match self.keys_.get_mut(&key) {
+                None => continue,
+                Some(r) => r
+            };
+            // A revision can go once the one after it is old enough
+            let past_due = |r: &Revision| r.written_at.is_some_and(|w| w.saturating_add(max_age) <= now);
+            if revisions.len() > 1 && past_due(&revisions[1]) {
+                revisions.pop_front();
+                dropped += 1;
+            }
+            if revisions.len() == 1 && revisions[0].pair.is_none() && past_due(&revisions[0]) {
+                self.keys_.remove(&key);
+                dropped += 1;
+            }
+        }
+        dropped
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write(version: u64, written_at: u64, value: &str) -> Revision {
+        Revision {
+            version,
+            written_at: Some(written_at),
+            expires_at: None,
+            pair: Some(KeyValuePair::new("k", value)),
+        }
+    }
+
+    fn delete(version: u64, written_at: u64) -> Revision {
+        Revision { version, written_at: Some(written_at), expires_at: None, pair: None }
+    }
+
+    fn value_as_of(history: &History, point: PointInTime) -> Option<String> {
+        history.as_of(b"k", point).unwrap().map(|(p, _)| p.value())
+    }
+
+    fn values(history: &History) -> Vec<Option<String>> {
+        history.revisions(b"k").map(|r| r.pair.as_ref().map(|p| p.value())).collect()
+    }
+
+    #[test]
+    fn test_as_of() {
+        let mut history = History::new(Retention::default());
+        assert!(history.as_of(b"k", PointInTime::Version(1)).is_none());
+        history.seed(b"k", KeyValuePair::new("k", "seeded"), 10, None);
+        history.record(b"k", write(12, 1000, "a"), 1000);
+        history.record(b"k", delete(15, 2000), 2000);
+        // Written again as the next write to the shard after the deletion
+        history.record(b"k", write(15, 3000, "b"), 3000);
+
+        assert_eq!(value_as_of(&history, PointInTime::Version(9)), None);
+        assert_eq!(value_as_of(&history, PointInTime::Version(10)), Some("seeded".to_string()));
+        assert_eq!(value_as_of(&history, PointInTime::Version(14)), Some("a".to_string()));
+        assert_eq!(value_as_of(&history, PointInTime::Version(15)), Some("b".to_string()));
+        assert_eq!(value_as_of(&history, PointInTime::Timestamp(0)), Some("seeded".to_string()));
+        assert_eq!(value_as_of(&history, PointInTime::Timestamp(1999)), Some("a".to_string()));
+        assert_eq!(value_as_of(&history, PointInTime::Timestamp(2500)), None);
+        assert_eq!(value_as_of(&history, PointInTime::Timestamp(3000)), Some("b".to_string()));
+    }
+
+    #[test]
+    fn test_as_of_expired() {
+        let mut history = History::new(Retention::default());
+        let mut revision = write(1, 1000, "a");
+        revision.expires_at = Some(5000);
+        history.record(b"k", revision, 1000);
+        assert_eq!(value_as_of(&history, PointInTime::Timestamp(4999)), Some("a".to_string()));
+        assert_eq!(value_as_of(&history, PointInTime::Timestamp(5000)), None);
+        // Reads by version ignore the expiry
+        assert_eq!(value_as_of(&history, PointInTime::Version(1)), Some("a".to_string()));
+
+        history.set_expiry(b"k", 1, None);
+        assert_eq!(value_as_of(&history, PointInTime::Timestamp(5000)), Some("a".to_string()));
+    }
+
+    #[test]
+    fn test_max_versions() {
+        let mut history = History::new(Retention { max_versions: Some(2), max_age_ms: None });
+        for (i, v) in ["a", "b", "c", "d"].iter().enumerate() {
+            history.record(b"k", write(i as u64, 0, v), 0);
+        }
+        // The latest revision is not a past version
+        assert_eq!(values(&history), vec![Some("b".to_string()), Some("c".to_string()),
+            Some("d".to_string())]);
+        assert_eq!(history.trim(u64::MAX, usize::MAX), 0);
+    }
+
+    #[test]
+    fn test_trim_by_age() {
+        let mut history = History::new(Retention { max_versions: None, max_age_ms: Some(100) });
+        history.record(b"k", write(1, 0, "a"), 0);
+        history.record(b"k", write(2, 50, "b"), 50);
+        history.record(b"k", write(3, 120, "c"), 120);
+        assert_eq!(history.trim(149, usize::MAX), 0);
+        assert_eq!(history.trim(150, usize::MAX), 1);
+        assert_eq!(values(&history), vec![Some("b".to_string()), Some("c".to_string())]);
+        // The latest revision stays however old it gets
+        assert_eq!(history.trim(10_000, usize::MAX), 1);
+        assert_eq!(values(&history), vec![Some("c".to_string())]);
+
+        history.record(b"k", delete(4, 10_000), 10_000);
+        assert_eq!(history.trim(10_099, usize::MAX), 0);
+        assert_eq!(history.trim(10_100, usize::MAX), 2);
+        assert!(!history.is_tracked(b"k"));
+    }
+
+    #[test]
+    fn test_trim_batch() {
+        let mut history = History::new(Retention { max_versions: None, max_age_ms: Some(10) });
+        for i in 0..5 {
+            history.record(b"k", write(i, i, "v"), i);
+        }
+        assert_eq!(history.trim(100, 2), 2);
+        assert_eq!(history.trim(100, 10), 2);
+        assert_eq!(history.revisions(b"k").count(), 1);
+    }
+}
@@ -0,0 +1,175 @@
+/// Selects keys by prefix or by glob pattern. Both work on raw key bytes.
+///
+/// Glob patterns support `*` (any run of bytes), `?` (any single byte),
+/// `[abc]`, `[a-z]` and `[^abc]` / `[!abc]` classes, and `\` to match the next
+/// byte literally.
+#[derive(Debug, PartialEq, Clone)]
+pub enum KeyPattern {
+    Prefix(Vec<u8>),
+    Glob(Vec<u8>),
+}
+
+impl KeyPattern {
+    pub fn matches(&self, key: &[u8]) -> bool {
+        match self {
+            KeyPattern::Prefix(p) => key.starts_with(p),
+            KeyPattern::Glob(g) => glob_match(g, key),
+        }
+    }
+
+    /// The bytes every matching key starts with, used to narrow down the
+    /// keys that have to be checked in an ordered store.
+    pub fn literal_prefix(&self) -> Vec<u8> {
+        match self {
+            KeyPattern::Prefix(p) => p.clone(),
+            KeyPattern::Glob(g) => {
+                let mut prefix = Vec::new();
+                let mut i = 0;
+                while i < g.len() {
+                    match g[i] {
+                        b'*' | b'?' | b'[' => break,
+                        b'\\' if i + 1 < g.len() => {
+                            prefix.push(g[i + 1]);
+                            i += 2;
+                        }
+                        c => {
+                            prefix.push(c);
+                            i += 1;
+                        }
+                    }
+                }
+                prefix
+            }
+        }
+    }
+}
+
+/// Matches a class starting just after its `[` against `c`. Returns whether
+/// it matched and the index just past the closing `]`, or None if the class
+/// is not closed, in which case the `[` is treated as a literal.
+fn match_class(pattern: &[u8], start: usize, c: u8) -> Option<(bool, usize)> {
+    let mut i = start;
+    let negated = matches!(pattern.get(i), Some(b'^') | Some(b'!'));
+    if negated {
+        i += 1;
+    }
+    let mut matched = false;
+    let mut first = true;
+    while i < pattern.len() {
+        // A `]` right after the opening bracket is a literal
+        if pattern[i] == b']' && !first {
+            return Some((matched != negated, i + 1));
+        }
+        first = false;
+        let mut lo = pattern[i];
+        if lo == b'\\' && i + 1 < pattern.len() {
+            i += 1;
+            lo = pattern[i];
+        }
+        if i + 2 < pattern.len() && pattern[i + 1] == b'-' && pattern[i + 2] != b']' {
+            let hi = pattern[i + 2];
+            if lo <= c && c <= hi {
+                matched = true;
+            }
+            i += 3;
+        } else {
+            if lo == c {
+                matched = true;
+            }
+            i += 1;
+        }
+    }
+    None
+}
+
+/// Matches `key` against a glob `pattern`, see `KeyPattern`.
+pub fn glob_match(pattern: &[u8], key: &[u8]) -> bool {
+    let (mut p, mut k) = (0, 0);
+    // Where to resume if the rest fails to match after the last `*`: the
+    // pattern index after the star, and the key index the star reached.
+    let mut backtrack: Option<(usize, usize)> = None;
+    while k < key.len() {
+        let step = match pattern.get(p) {
+            Some(b'*') => {
+                backtrack = Some((p + 1, k));
+                p += 1;
+                continue;
+            }
+            Some(b'?') => Some(p + 1),
+            Some(b'[') => match match_class(pattern, p + 1, key[k]) {
+                Some((true, next)) => Some(next),
+                Some((false, _)) => None,
+                None if key[k] == b'[' => Some(p + 1),
+                None => None,
+            },
+            Some(b'\\') if p + 1 < pattern.len() => {
+                if pattern[p + 1] == key[k] {
+                    Some(p + 2)
+                } else {
+                    None
+                }
+            }
+            Some(c) if *c == key[k] => Some(p + 1),
+            _ => None,
+        };
+        match (step, backtrack) {
+            (Some(next), _) => {
+                p = next;
+                k += 1;
+            }
+            (None, Some((star_p, star_k))) => {
+                // Let the last star swallow one more byte and try again
+                p = star_p;
+                k = star_k + 1;
+                backtrack = Some((star_p, star_k + 1));
+            }
+            (None, None) => return false,
+        }
+    }
+    pattern[p..].iter().all(|c| *c == b'*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_glob_match() {
+        let cases: &[(&str, &str, bool)] = &[
+            ("*", "", true),
+            ("*", "anything", true),
+            ("user:*", "user:1", true),
+            ("user:*", "users:1", false),
+            ("h?llo", "hello", true),
+            ("h?llo", "hllo", false),
+            ("h*llo", "heeeello", true),
+            ("h*llo*", "hello world", true),
+            ("*.txt", "a.txt.bak", false),
+            ("h[ae]llo", "hallo", true),
+            ("h[ae]llo", "hillo", false),
+            ("h[^e]llo", "hallo", true),
+            ("h[!e]llo", "hello", false),
+            ("h[a-c]llo", "hbllo", true),
+            ("h[a-c]llo", "hdllo", false),
+            ("[]]", "]", true),
+            ("a\\*b", "a*b", true),
+            ("a\\*b", "axb", false),
+            ("a[b", "a[b", true),
+            ("*a*b*c*", "xxaxxbxxcxx", true),
+            ("*a*b*c*", "xxaxxcxxbxx", false),
+        ];
+        for (pattern, key, expected) in cases {
+            assert_eq!(glob_match(pattern.as_bytes(), key.as_bytes()), *expected,
+                "{:?} against {:?}", pattern, key);
+        }
+        assert!(glob_match(b"\xff*", b"\xff\x00\x01"));
+    }
+
+    #[test]
+    fn test_literal_prefix() {
+        assert_eq!(KeyPattern::Prefix(b"abc".to_vec()).literal_prefix(), b"abc");
+        assert_eq!(KeyPattern::Glob(b"user:*:name".to_vec()).literal_prefix(), b"user:");
+        assert_eq!(KeyPattern::Glob(b"a\\*b?".to_vec()).literal_prefix(), b"a*b");
+        assert_eq!(KeyPattern::Glob(b"[ab]c".to_vec()).literal_prefix(), b"");
+    }
+}
@@ -1,29 +1,174 @@
-use std::str::FromStr;
+use crate::proto::DataType;
 
 #[derive(Debug, PartialEq, Clone)]
 pub struct KeyValuePair {
-    key_: String,
-    value_: String,
+    // Keys are arbitrary bytes; string keys are stored as their UTF-8 bytes
+    key_: Vec<u8>,
+    // Encoded according to `data_type_`, see key_value_messages.proto
+    value_: Vec<u8>,
+    data_type_: DataType,
+}
+
+/// Checks that `value` is a valid encoding of a value of `data_type`.
+pub fn validate_value(data_type: DataType, value: &[u8]) -> Result<(), String> {
+    let expected_len = match data_type {
+        DataType::Uint32 | DataType::Sint32 => Some(4),
+        DataType::Uint64 | DataType::Sint64 => Some(8),
+        DataType::Boolean => Some(1),
+        DataType::String | DataType::Binary => None,
+    };
+    if let Some(len) = expected_len {
+        if value.len() != len {
+            return Err(format!(
+                "{} value must be {} bytes, got {}",
+                data_type.as_str_name(), len, value.len()));
+        }
+    }
+    match data_type {
+        DataType::Boolean if value[0] > 1 => {
+            Err(format!("BOOLEAN value must be 0 or 1, got {}", value[0]))
+        }
+        DataType::String if std::str::from_utf8(value).is_err() => {
+            Err(String::from("STRING value must be valid UTF-8"))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Renders a valid value of `data_type` as text. BINARY values are rendered
+/// as lowercase hex.
+pub fn value_to_text(data_type: DataType, value: &[u8]) -> String {
+    match data_type {
+        DataType::Uint32 => u32::from_le_bytes(value.try_into().unwrap()).to_string(),
+        DataType::Uint64 => u64::from_le_bytes(value.try_into().unwrap()).to_string(),
+        DataType::Sint32 => i32::from_le_bytes(value.try_into().unwrap()).to_string(),
+        DataType::Sint64 => i64::from_le_bytes(value.try_into().unwrap()).to_string(),
+        DataType::Boolean => (value[0] == 1).to_string(),
+        DataType::String => String::from_utf8_lossy(value).into_owned(),
+        DataType::Binary => value.iter().map(|b| format!("{:02x}", b)).collect(),
+    }
+}
+
+/// Parses the text form of a value of `data_type`, the inverse of
+/// `value_to_text`.
+pub fn value_from_text(data_type: DataType, text: &str) -> Result<Vec<u8>, String> {
+    let err = |e: &dyn std::fmt::Display| {
+        format!("Cannot parse {:?} as {}: {}", text, data_type.as_str_name(), e)
+    };
+    match data_type {
+        DataType::Uint32 => text.parse::<u32>().map(|v| v.to_le_bytes().to_vec())
+            .map_err(|e| err(&e)),
+        DataType::Uint64 => text.parse::<u64>().map(|v| v.to_le_bytes().to_vec())
+            .map_err(|e| err(&e)),
+        DataType::Sint32 => text.parse::<i32>().map(|v| v.to_le_bytes().to_vec())
+            .map_err(|e| err(&e)),
+        DataType::Sint64 => text.parse::<i64>().map(|v| v.to_le_bytes().to_vec())
+            .map_err(|e| err(&e)),
+        DataType::Boolean => text.parse::<bool>().map(|v| vec![v as u8])
+            .map_err(|e| err(&e)),
+        DataType::String => Ok(text.as_bytes().to_vec()),
+        DataType::Binary => {
+            if !text.is_ascii() || !text.len().is_multiple_of(2) {
+                return Err(err(&"expected an even number of hex digits"));
+            }
+            (0..text.len())
+                .step_by(2)
+                .map(|i| u8::from_str_radix(&text[i..i + 2], 16).map_err(|e| err(&e)))
+                .collect()
+        }
+    }
 }
 
 impl KeyValuePair {
     pub fn new(k: &str, v: &str) -> KeyValuePair {
         KeyValuePair {
-            key_: String::from_str(k).expect("Cannot parse key string!"),
-            value_: String::from_str(v).expect("Cannot parse value string!"),
+            key_: k.as_bytes().to_vec(),
+            value_: v.as_bytes().to_vec(),
+            data_type_: DataType::String,
         }
     }
 
-    pub fn key(&self) -> &str {
-        self.key_.as_str()
+    /// Creates a pair holding arbitrary bytes as a BINARY value.
+    pub fn new_binary(k: &[u8], v: &[u8]) -> KeyValuePair {
+        KeyValuePair {
+            key_: k.to_vec(),
+            value_: v.to_vec(),
+            data_type_: DataType::Binary,
+        }
+    }
+
+    /// Creates a pair holding a value of any type, failing if `value` is not
+    /// a valid encoding of `data_type`.
+    pub fn new_typed(k: impl AsRef<[u8]>, data_type: DataType, value: Vec<u8>) -> Result<KeyValuePair, String> {
+        validate_value(data_type, &value)?;
+        Ok(KeyValuePair {
+            key_: k.as_ref().to_vec(),
+            value_: value,
+            data_type_: data_type,
+        })
+    }
+
+    /// Rebuilds a pair from its persisted form, where a missing type means
+    /// STRING (see key_value_store.proto).
+    pub fn from_stored(k: &[u8], data_type: Option<i32>, value: Vec<u8>) -> Result<KeyValuePair, String> {
+        let data_type = match data_type {
+            None => DataType::String,
+            Some(t) => DataType::try_from(t).map_err(|_| {
+                format!("Unknown data type {} for key {:?}", t, String::from_utf8_lossy(k))
+            })?,
+        };
+        KeyValuePair::new_typed(k, data_type, value)
+    }
+
+    pub fn key(&self) -> &[u8] {
+        self.key_.as_slice()
+    }
+
+    /// The key as text, with any bytes that are not valid UTF-8 replaced.
+    /// Meant for logging and display only.
+    pub fn key_text(&self) -> String {
+        String::from_utf8_lossy(&self.key_).into_owned()
+    }
+
+    /// The value as text. This is the value itself for STRING pairs and its
+    /// text form (see `value_to_text`) otherwise.
+    pub fn value(&self) -> String {
+        value_to_text(self.data_type_, &self.value_)
+    }
+
+    /// The encoded value, see key_value_messages.proto
+    pub fn value_bytes(&self) -> &[u8] {
+        self.value_.as_slice()
     }
 
-    pub fn value(&self) -> &str {
-        self.value_.as_str()
+    pub fn data_type(&self) -> DataType {
+        self.data_type_
     }
 
     pub fn update_value(&mut self, new_val: &str) {
-        self.value_ = String::from_str(new_val).expect("Cannot parse new value!");
+        self.value_ = new_val.as_bytes().to_vec();
+        self.data_type_ = DataType::String;
+    }
+
+    /// The value as a 64-bit signed integer, if it is a SINT64 or a STRING
+    /// holding one in decimal. Other integer types do not count, as adding
+    /// to them could not keep their type.
+    pub fn integer_value(&self) -> Option<i64> {
+        match self.data_type_ {
+            DataType::Sint64 => Some(i64::from_le_bytes(self.value_.as_slice().try_into().ok()?)),
+            DataType::String => std::str::from_utf8(&self.value_).ok()?.parse().ok(),
+            _ => None,
+        }
+    }
+
+    /// A pair with the same key holding `value`, as a STRING if this pair is
+    /// one and as a SINT64 otherwise. See `integer_value`.
+    pub fn with_integer_value(&self, value: i64) -> KeyValuePair {
+        let (value_, data_type_) = match self.data_type_ {
+            DataType::String => (value.to_string().into_bytes(), DataType::String),
+            _ => (value.to_le_bytes().to_vec(), DataType::Sint64),
+        };
+        KeyValuePair { key_: self.key_.clone(), value_, data_type_ }
     }
 }
 
@@ -36,7 +181,7 @@ mod tests {
         let key = "Hello";
         let value = "World";
         let item = KeyValuePair::new(key, value);
-        assert_eq!(item.key(), key);
+        assert_eq!(item.key(), key.as_bytes());
         assert_eq!(item.value(), value);
     }
 
@@ -47,8 +192,81 @@ mod tests {
         let value_2 = "Curry";
         let mut item = KeyValuePair::new(key, value);
         item.update_value(value_2);
-        assert_eq!(item.key(), key);
+        assert_eq!(item.key(), key.as_bytes());
         assert_eq!(item.value(), value_2);
     }
-}
 
+    #[test]
+    fn test_typed_key_value_pair() {
+        let item = KeyValuePair::new_typed(
+            "count", DataType::Sint64, (-5i64).to_le_bytes().to_vec()).unwrap();
+        assert_eq!(item.data_type(), DataType::Sint64);
+        assert_eq!(item.value(), "-5");
+        assert_eq!(item.value_bytes(), (-5i64).to_le_bytes());
+
+        let flag = KeyValuePair::new_typed("flag", DataType::Boolean, vec![1]).unwrap();
+        assert_eq!(flag.value(), "true");
+    }
+
+    #[test]
+    fn test_integer_value() {
+        let count = KeyValuePair::new_typed(
+            "count", DataType::Sint64, (-5i64).to_le_bytes().to_vec()).unwrap();
+        assert_eq!(count.integer_value(), Some(-5));
+        let next = count.with_integer_value(7);
+        assert_eq!(next.data_type(), DataType::Sint64);
+        assert_eq!(next.value(), "7");
+
+        let text = KeyValuePair::new("count", "42");
+        assert_eq!(text.integer_value(), Some(42));
+        let next = text.with_integer_value(43);
+        assert_eq!(next.data_type(), DataType::String);
+        assert_eq!(next.value(), "43");
+
+        assert_eq!(KeyValuePair::new("k", "4.2").integer_value(), None);
+        assert_eq!(KeyValuePair::new("k", "99999999999999999999").integer_value(), None);
+        let small = KeyValuePair::new_typed("k", DataType::Uint32, vec![1, 0, 0, 0]).unwrap();
+        assert_eq!(small.integer_value(), None);
+    }
+
+    #[test]
+    fn test_binary_key_value_pair() {
+        let key = [0x00, 0xff, 0x10];
+        let value = [0xde, 0xad, 0xbe, 0xef];
+        let item = KeyValuePair::new_binary(&key, &value);
+        assert_eq!(item.key(), key);
+        assert_eq!(item.value_bytes(), value);
+        assert_eq!(item.data_type(), DataType::Binary);
+        assert_eq!(item.value(), "deadbeef");
+        assert_eq!(item.key_text(), "\u{0}\u{fffd}\u{10}");
+    }
+
+    #[test]
+    fn test_typed_value_mismatch_rejected() {
+        assert!(KeyValuePair::new_typed("k", DataType::Uint32, vec![1, 2, 3]).is_err());
+        assert!(KeyValuePair::new_typed("k", DataType::Uint64, vec![0; 4]).is_err());
+        assert!(KeyValuePair::new_typed("k", DataType::Boolean, vec![2]).is_err());
+        assert!(KeyValuePair::new_typed("k", DataType::String, vec![0xff, 0xfe]).is_err());
+        assert!(KeyValuePair::new_typed("k", DataType::Binary, vec![0xff, 0xfe]).is_ok());
+    }
+
+    #[test]
+    fn test_value_text_round_trip() {
+        let cases = [
+            (DataType::Uint32, "4000000000"),
+            (DataType::Uint64, "18446744073709551615"),
+            (DataType::Sint32, "-42"),
+            (DataType::Sint64, "-9000000000"),
+            (DataType::Boolean, "false"),
+            (DataType::String, "hello"),
+            (DataType::Binary, "00ff10"),
+        ];
+        for (data_type, text) in cases {
+            let bytes = value_from_text(data_type, text).unwrap();
+            validate_value(data_type, &bytes).unwrap();
+            assert_eq!(value_to_text(data_type, &bytes), text);
+        }
+        assert!(value_from_text(DataType::Uint32, "-1").is_err());
+        assert!(value_from_text(DataType::Binary, "abc").is_err());
+    }
+}
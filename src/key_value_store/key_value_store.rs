@@ -1,171 +1,613 @@
-use log::error;
 use std::str::FromStr;
-use std::collections::HashMap;
-use std::collections::hash_map::Entry;
-use std::sync::Arc;
-use crate::key_value_store::key_value_pair::KeyValuePair;
-use super::filestore::write_to_file;
-use crate::proto::KeyValueStoreMsg;
-
-use prost::Message;
-use std::fs::File;
+use std::collections::{BTreeSet, HashMap};
+use std::ops::Bound;
+use std::time::{SystemTime, UNIX_EPOCH};
+use crate::key_value_store::key_pattern::KeyPattern;
+use crate::key_value_store::key_value_pair::{value_to_text, KeyValuePair};
+use super::filestore;
+use crate::proto::{DataType, KeyValueStoreMsg, SnapshotRecord};
+
 use crate::key_value_store::errors::{ErrorKind, RWError};
-use std::io::prelude::*;
-use log::trace;
+use crate::key_value_store::storage_engine::{FrozenEngine, SnapshotEntry, StorageEngine};
+use imbl::OrdMap;
+
+/// Milliseconds since the unix epoch. Expiry times are stored in this unit so
+/// that they keep their meaning across backups and restarts.
+pub fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("System clock is before the unix epoch!")
+        .as_millis() as u64
+}
+
+/// Versions count up from the time a store was created or opened, shifted
+/// left by this many bits, leaving room for a million writes a millisecond
+/// before they could catch up with the clock. This keeps them growing across
+/// restarts without having to remember the last version handed out.
+const VERSION_CLOCK_SHIFT: u32 = 20;
+
+/// The version a store created or opened now starts counting from, see
+/// `StorageEngine::next_version`
+pub fn first_version() -> u64 {
+    now_ms() << VERSION_CLOCK_SHIFT
+}
+
+/// A rough per-pair cost of the map and index entries around the key and
+/// value bytes, used when estimating memory usage.
+const ENTRY_OVERHEAD_BYTES: u64 = 64;
+
+/// The estimated memory used by a single pair
+pub fn entry_size(key: &[u8], value: &[u8]) -> u64 {
+    entry_size_from_lens(key.len(), value.len())
+}
+
+/// Like `entry_size`, for when only the lengths are at hand
+pub fn entry_size_from_lens(key_len: usize, value_len: usize) -> u64 {
+    key_len as u64 + value_len as u64 + ENTRY_OVERHEAD_BYTES
+}
+
+/// Whether `start` comes after `end`, or they exclude each other, so that no
+/// key can fall between them. Ordered maps panic on such ranges.
+pub fn is_empty_range(start: Bound<&[u8]>, end: Bound<&[u8]>) -> bool {
+    match (start, end) {
+        (Bound::Included(s), Bound::Included(e)) => s > e,
+        (Bound::Included(s), Bound::Excluded(e))
+            | (Bound::Excluded(s), Bound::Included(e))
+            | (Bound::Excluded(s), Bound::Excluded(e)) => s >= e,
+        _ => false
+    }
+}
+
+/// A borrowed view of a pair in the store along with everything needed to
+/// persist it.
+pub struct StoredEntry<'a> {
+    pub key: &'a [u8],
+    pub value: &'a [u8],
+    pub data_type: DataType,
+    // Set even if the expiry has passed
+    pub expires_at: Option<u64>,
+    pub version: u64,
+}
+
+/// One page of a `KeyValueStore::scan`
+#[derive(Debug, PartialEq)]
+pub struct ScanPage {
+    pub pairs: Vec<KeyValuePair>,
+    // The last key examined, to pass back in for the next page. None once
+    // every matching key has been seen.
+    pub resume_after: Option<Vec<u8>>,
+}
+
+#[derive(Debug, Clone)]
+struct StoredValue {
+    // Encoded according to `data_type`, see key_value_messages.proto
+    value: Vec<u8>,
+    data_type: DataType,
+    // Unix time in milliseconds, None if the pair never expires
+    expires_at: Option<u64>,
+    version: u64,
+}
 
-#[derive(Debug, PartialEq, Clone)]
+// Versions are left out, so that stores holding the same pairs compare equal
+// however they came to hold them
+impl PartialEq for StoredValue {
+    fn eq(&self, other: &Self) -> bool {
+        self.value == other.value && self.data_type == other.data_type
+            && self.expires_at == other.expires_at
+    }
+}
+
+#[derive(Debug, Clone)]
 pub struct KeyValueStore {
-    data_: KeyValueStoreMsg,
+    name_: String,
+    // Ordered by key so that ranges of keys can be scanned, see `scan_range`.
+    // Clones share their nodes until either side changes them, which is what
+    // makes `freeze` cheap.
+    data_: OrdMap<Vec<u8>, StoredValue>,
+    // Every (expiry time, key) with an expiry, ordered so that keys due for
+    // removal can be found without scanning the whole store.
+    expiry_index_: BTreeSet<(u64, Vec<u8>)>,
+    // Sum of `entry_size` over every pair, kept up to date on each mutation
+    memory_usage_: u64,
+    // The version the next write gets, see `StorageEngine::next_version`
+    next_version_: u64,
+}
+
+impl PartialEq for KeyValueStore {
+    fn eq(&self, other: &Self) -> bool {
+        self.name_ == other.name_ && self.data_ == other.data_
+    }
 }
 
 impl KeyValueStore {
     pub fn new(name: &str) -> KeyValueStore {
-        let mut data = KeyValueStoreMsg::default();
-        data.name = String::from_str(name).expect("Cannot accept name");
         KeyValueStore {
-            data_: data
+            name_: String::from_str(name).expect("Cannot accept name"),
+            data_: OrdMap::new(),
+            expiry_index_: BTreeSet::new(),
+            memory_usage_: 0,
+            next_version_: first_version()
         }
     }
 
     pub fn from(store: KeyValueStoreMsg) -> KeyValueStore {
-        KeyValueStore { data_: store }
-    }
-
-    pub fn get(&self, key: &str) -> Option<KeyValuePair> {
-        let returnable: Option<KeyValuePair>;
-        match self.data_.values.get(key) {
-            Some(x) => {
-                returnable = Some(
-                    KeyValuePair::new(key, x));
-            },
-            None => {
-                returnable = None;
+        let mut kvs = KeyValueStore::new(&store.name);
+        for record in store.pairs {
+            let data_type = match record.data_type {
+                None => DataType::String,
+                Some(t) => DataType::try_from(t).unwrap_or(DataType::Binary)
+            };
+            let version = kvs.next_version_;
+            kvs.insert_unchecked(record.key, record.value, data_type, record.expires_at_ms,
+                version);
+        }
+        kvs
+    }
+
+    /// A key is live if it is in the store and has not expired. Expired keys
+    /// stay in the store until `remove_expired` gets to them, but are
+    /// otherwise treated as absent.
+    fn is_live(&self, key: &[u8], now: u64) -> bool {
+        match self.data_.get(key) {
+            Some(v) => v.expires_at.is_none_or(|t| t > now),
+            None => false
+        }
+    }
+
+    fn insert_unchecked(&mut self, key: Vec<u8>, value: Vec<u8>, data_type: DataType,
+            expires_at: Option<u64>, version: u64) {
+        self.memory_usage_ += entry_size(&key, &value);
+        self.next_version_ = self.next_version_.max(version + 1);
+        if let Some(t) = expires_at {
+            self.expiry_index_.insert((t, key.clone()));
+        }
+        let new = StoredValue { value, data_type, expires_at, version };
+        if let Some(old) = self.data_.insert(key.clone(), new) {
+            self.memory_usage_ -= entry_size(&key, &old.value);
+            if let Some(t) = old.expires_at {
+                if Some(t) != expires_at {
+                    self.expiry_index_.remove(&(t, key));
+                }
             }
         }
-        return returnable;
+    }
+
+    fn remove_unchecked(&mut self, key: &[u8]) -> Option<StoredValue> {
+        let old = self.data_.remove(key)?;
+        self.memory_usage_ -= entry_size(key, &old.value);
+        if let Some(t) = old.expires_at {
+            self.expiry_index_.remove(&(t, key.to_vec()));
+        }
+        Some(old)
+    }
+
+    pub fn get(&self, key: impl AsRef<[u8]>) -> Option<KeyValuePair> {
+        self.get_versioned(key).map(|(pair, _)| pair)
+    }
+
+    /// Like `get`, along with the pair's version
+    pub fn get_versioned(&self, key: impl AsRef<[u8]>) -> Option<(KeyValuePair, u64)> {
+        let key = key.as_ref();
+        if !self.is_live(key, now_ms()) {
+            return None;
+        }
+        let stored = self.data_.get(key)?;
+        // Values are validated on the way in, so this only fails if the
+        // store was built from a message that bypassed that.
+        let pair = KeyValuePair::new_typed(key, stored.data_type, stored.value.clone()).ok()?;
+        Some((pair, stored.version))
     }
 
     pub fn add(&mut self, pair: KeyValuePair) -> bool {
-        if let Entry::Vacant(
-            v) = self.data_.values.entry(
-            pair.key().to_string()) {
-            v.insert(pair.value().to_string());
-            return true;
+        self.add_with_expiry(pair, None)
+    }
+
+    /// Adds a pair that expires at `expires_at` (see `now_ms`), or never if
+    /// None. Returns false if the key is already live in the store.
+    pub fn add_with_expiry(&mut self, pair: KeyValuePair, expires_at: Option<u64>) -> bool {
+        if self.is_live(pair.key(), now_ms()) {
+            return false;
         }
-        return false;
+        self.put(pair, expires_at);
+        true
     }
 
     pub fn update(&mut self, pair: KeyValuePair) -> bool {
-        if let Entry::Vacant(_) = self.data_.values.entry(pair.key().to_string()) {
+        self.update_with_expiry(pair, None)
+    }
+
+    /// Updates the value of a live key. The key's expiry is replaced if
+    /// `expires_at` is given and kept as it was otherwise.
+    pub fn update_with_expiry(&mut self, pair: KeyValuePair, expires_at: Option<u64>) -> bool {
+        if !self.is_live(pair.key(), now_ms()) {
             return false;
         }
-        self.data_.values.insert(
-            pair.key().to_string(),
-            pair.value().to_string()
+        let new_expiry = expires_at.or(self.expires_at(pair.key()));
+        self.put(pair, new_expiry);
+        true
+    }
+
+    /// Inserts or overwrites a pair regardless of what is in the store, and
+    /// sets its expiry to exactly `expires_at`. This is what replaying a log
+    /// or loading a backup needs, where the recorded state is authoritative.
+    /// Returns the version the pair was given.
+    pub fn put(&mut self, pair: KeyValuePair, expires_at: Option<u64>) -> u64 {
+        let version = self.next_version_;
+        self.put_versioned(pair, expires_at, version);
+        version
+    }
+
+    /// Like `put`, giving the pair `version`, see
+    /// `StorageEngine::put_versioned`
+    pub fn put_versioned(&mut self, pair: KeyValuePair, expires_at: Option<u64>,
+            version: u64) {
+        self.insert_unchecked(
+            pair.key().to_vec(),
+            pair.value_bytes().to_vec(),
+            pair.data_type(),
+            expires_at,
+            version
         );
-        return true;
     }
 
-    pub fn delete(&mut self, key: &str) -> bool {
-        match self.data_.values.remove(key) {
-            Some(_) => true,
-            None => false
+    /// Copies an entry from another store as is, version included, see `put`
+    pub fn put_entry(&mut self, entry: StoredEntry<'_>) {
+        self.insert_unchecked(
+            entry.key.to_vec(),
+            entry.value.to_vec(),
+            entry.data_type,
+            entry.expires_at,
+            entry.version
+        );
+    }
+
+    /// The version the next write gets, see `StorageEngine::next_version`
+    pub fn next_version(&self) -> u64 {
+        self.next_version_
+    }
+
+    /// Makes sure later writes get versions of at least `version`, for a
+    /// store holding some of the pairs of another that has handed out more
+    /// versions than it holds
+    pub fn advance_next_version(&mut self, version: u64) {
+        self.next_version_ = self.next_version_.max(version);
+    }
+
+    /// A copy of the store with every pair given a new version, counting up
+    /// in key order from `first`, for bringing the pairs into an engine as
+    /// new writes, see `StorageEngine::load`
+    pub fn restamped(&self, first: u64) -> KeyValueStore {
+        let mut copy = KeyValueStore::new(&self.name_);
+        for (version, entry) in (first..).zip(self.iter()) {
+            copy.put_entry(StoredEntry { version, ..entry });
         }
+        // Not left to the clock a new store starts from, so that the copy
+        // hands out versions right after those of its pairs
+        copy.next_version_ = first + self.len() as u64;
+        copy
     }
 
-    pub fn name(&self) -> &str {
-        &self.data_.name.as_str()
+    pub fn delete(&mut self, key: impl AsRef<[u8]>) -> bool {
+        let key = key.as_ref();
+        let live = self.is_live(key, now_ms());
+        self.remove_unchecked(key).is_some() && live
     }
 
-    pub fn all(&self) -> HashMap<String, String> {
-        // This is _such_ a waste of space.
-        return self.data_.values.clone();
+    /// Returns when a live key expires, or None if it never does or is not
+    /// in the store.
+    pub fn expires_at(&self, key: impl AsRef<[u8]>) -> Option<u64> {
+        let key = key.as_ref();
+        if !self.is_live(key, now_ms()) {
+            return None;
+        }
+        self.data_.get(key)?.expires_at
     }
 
-    pub fn data(&self) -> KeyValueStoreMsg {
-        return self.data_.clone();
+    /// Sets or, given None, clears the expiry of a live key, which keeps its
+    /// version. Returns false if the key is not live.
+    pub fn set_expires_at(&mut self, key: impl AsRef<[u8]>, expires_at: Option<u64>) -> bool {
+        let key = key.as_ref();
+        if !self.is_live(key, now_ms()) {
+            return false;
+        }
+        let stored = self.data_.get_mut(key).unwrap();
+        if let Some(old) = stored.expires_at {
+            self.expiry_index_.remove(&(old, key.to_vec()));
+        }
+        if let Some(t) = expires_at {
+            self.expiry_index_.insert((t, key.to_vec()));
+        }
+        stored.expires_at = expires_at;
+        true
     }
 
-    pub fn write_to_file(&self, target_file: &str) -> Result<(), RWError> {
-        let msg = self.data();
-        // This is the same thing from filestore. We will need to deprecate
-        // that.
-        let bytes = msg.encode_to_vec();
-        let mut file;
-        match File::create(target_file) {
-            Ok(f) => {
-                file = f;
-            }
-            Err(e) => {
-                return Err(RWError {
-                    kind_: ErrorKind::FileOpenError,
-                    context_: e.to_string(),
-                })
-            }
-        };
-        match file.write_all(&bytes) {
-            Ok(_) => {
-                trace!("Backup length {:?}", bytes.len());
-            }
-            Err(e) => {
-                return Err(RWError {
-                    kind_: ErrorKind::FileWriteError,
-                    context_: e.to_string(),
-                })
+    /// Returns up to `limit` live pairs with keys between `start` and `end`,
+    /// in key order, or in reverse key order if `reverse` is set. Keys are
+    /// compared byte by byte.
+    pub fn scan_range(&self, start: Bound<&[u8]>, end: Bound<&[u8]>, limit: usize,
+            reverse: bool) -> Vec<KeyValuePair> {
+        if is_empty_range(start, end) || limit == 0 {
+            return Vec::new();
+        }
+        let now = now_ms();
+        let range = self.data_.range::<_, [u8]>((start, end));
+        let live = |(k, v): (&Vec<u8>, &StoredValue)| {
+            if v.expires_at.is_some_and(|t| t <= now) {
+                return None;
             }
+            KeyValuePair::new_typed(k, v.data_type, v.value.clone()).ok()
         };
-        Ok(())
+        if reverse {
+            range.rev().filter_map(live).take(limit).collect()
+        } else {
+            range.filter_map(live).take(limit).collect()
+        }
     }
 
-    pub fn read_from_file(&mut self, src_file: &str) -> Result<(), RWError> {
-        let mut file;
-        match File::open(src_file) {
-            Ok(f) => {
-                file = f;
+    /// Returns the live pairs matching `pattern` among the keys after
+    /// `resume_after` (or from the first key if None), stopping once `count`
+    /// pairs have matched or `max_examined` keys have been looked at.
+    ///
+    /// Keys are visited in order, so a scan made of successive pages sees
+    /// every key that was in the store for the whole scan, however the store
+    /// changes in between. Keys added or removed during the scan may or may
+    /// not be seen.
+    pub fn scan(&self, pattern: &KeyPattern, resume_after: Option<&[u8]>, count: usize,
+            max_examined: usize) -> ScanPage {
+        let prefix = pattern.literal_prefix();
+        let start = match resume_after {
+            Some(k) if k >= prefix.as_slice() => Bound::Excluded(k),
+            _ => Bound::Included(prefix.as_slice())
+        };
+        let now = now_ms();
+        let mut pairs = Vec::new();
+        let mut last: Option<&[u8]> = resume_after;
+        let range = self.data_.range::<_, [u8]>((start, Bound::Unbounded));
+        for (examined, (k, v)) in range.enumerate() {
+            if !k.starts_with(&prefix) {
+                break;
             }
-            Err(e) => {
-                return Err(RWError {
-                    kind_: ErrorKind::FileOpenError,
-                    context_: e.to_string(),
-                })
+            // Only stop once another key is known to be left, so that the
+            // final page does not need an empty page after it.
+            if pairs.len() == count || examined == max_examined {
+                return ScanPage { pairs, resume_after: last.map(|l| l.to_vec()) };
             }
-        };
-        let mut buf = vec![0; 1024];
-        // n_bytes will override the default 1024 byte buffer size to allow us to
-        // properly read the protobuf file.
-        let n_bytes: usize;
-        match file.read(&mut buf) {
-            Ok(n_b) => {
-                if n_b == 0 {
-                    return Err(RWError { kind_: {ErrorKind::FileReadError},
-                        context_: String::from("Empty file!") })
-                } else {
-                    n_bytes = n_b;
+            last = Some(k.as_slice());
+            if v.expires_at.is_none_or(|t| t > now) && pattern.matches(k) {
+                if let Ok(pair) = KeyValuePair::new_typed(k, v.data_type, v.value.clone()) {
+                    pairs.push(pair);
                 }
             }
-            Err(e) => {
-                return Err(RWError {
-                    kind_: ErrorKind::FileReadError,
-                    context_: e.to_string(),
-                })
+        }
+        ScanPage { pairs, resume_after: None }
+    }
+
+    /// Removes up to `limit` keys that expired at or before `now`, soonest
+    /// first, and returns them.
+    pub fn remove_expired(&mut self, now: u64, limit: usize) -> Vec<Vec<u8>> {
+        let mut removed = Vec::new();
+        while removed.len() < limit {
+            match self.expiry_index_.first() {
+                Some((t, _)) if *t <= now => {},
+                _ => break
             }
-        };
-        match KeyValueStoreMsg::decode(&buf[..n_bytes]) {
-            Ok(msg) => {
-                self.data_ = msg;
-                Ok(())
-            },
-            Err(e) => Err(RWError {
-                kind_: ErrorKind::DataDecodeError,
-                context_: e.to_string(),
-            }),
+            let (_, key) = self.expiry_index_.pop_first().unwrap();
+            self.remove_unchecked(&key);
+            removed.push(key);
         }
+        removed
+    }
+
+    /// Keys that have an expiry, soonest to expire first
+    pub fn keys_by_expiry(&self) -> impl Iterator<Item = &[u8]> {
+        self.expiry_index_.iter().map(|(_, k)| k.as_slice())
+    }
+
+    /// The estimated memory used by the pairs in the store, see `entry_size`
+    pub fn memory_usage(&self) -> u64 {
+        self.memory_usage_
+    }
+
+    pub fn name(&self) -> &str {
+        self.name_.as_str()
+    }
+
+    pub fn set_name(&mut self, name: &str) {
+        self.name_ = name.to_string();
+    }
+
+    /// The number of pairs in the store, including expired ones that have
+    /// not been removed yet.
+    pub fn len(&self) -> usize {
+        self.data_.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.data_.is_empty()
+    }
+
+    /// Looks up a pair without copying it, even if it has expired
+    pub fn entry(&self, key: impl AsRef<[u8]>) -> Option<StoredEntry<'_>> {
+        let (k, v) = self.data_.get_key_value(key.as_ref())?;
+        Some(StoredEntry {
+            key: k.as_slice(),
+            value: v.value.as_slice(),
+            data_type: v.data_type,
+            expires_at: v.expires_at,
+            version: v.version,
+        })
+    }
+
+    /// Iterates over the pairs in the store in key order without copying
+    /// them. Like `len`, this includes expired pairs that have not been
+    /// removed yet.
+    pub fn iter(&self) -> impl Iterator<Item = StoredEntry<'_>> {
+        self.data_.iter().map(|(k, v)| StoredEntry {
+            key: k.as_slice(),
+            value: v.value.as_slice(),
+            data_type: v.data_type,
+            expires_at: v.expires_at,
+            version: v.version,
+        })
+    }
+
+    /// Every pair in the store with its key and value as text, see
+    /// `KeyValuePair::key_text` and `KeyValuePair::value`.
+    pub fn all(&self) -> HashMap<String, String> {
+        // This is _such_ a waste of space.
+        self.iter()
+            .map(|e| (
+                String::from_utf8_lossy(e.key).into_owned(),
+                value_to_text(e.data_type, e.value)
+            ))
+            .collect()
+    }
+
+    pub fn data(&self) -> KeyValueStoreMsg {
+        KeyValueStoreMsg {
+            name: self.name_.clone(),
+            pairs: self.iter().map(|e| SnapshotRecord {
+                key: e.key.to_vec(),
+                value: e.value.to_vec(),
+                expires_at_ms: e.expires_at,
+                data_type: match e.data_type {
+                    DataType::String => None,
+                    t => Some(t.into())
+                },
+                deleted: false
+            }).collect()
+        }
+    }
+
+    pub fn write_to_file(&self, target_file: &str) -> Result<(), RWError> {
+        filestore::write_to_file(self, target_file)
+    }
+
+    pub fn read_from_file(&mut self, src_file: &str) -> Result<(), RWError> {
+        *self = filestore::read_from_file(src_file)?;
+        Ok(())
+    }
+}
+
+/// The in-memory engine. Nothing it holds survives a restart unless the
+/// caller also keeps a write-ahead log.
+fn snapshot_entry(key: &[u8], stored: &StoredValue) -> SnapshotEntry {
+    let pair = KeyValuePair::new_typed(key, stored.data_type, stored.value.clone())
+        .map_err(|err| RWError {
+            kind_: ErrorKind::DataDecodeError,
+            context_: err,
+        })?;
+    Ok((pair, stored.expires_at))
+}
+
+/// The pairs of a `KeyValueStore` at the time it was frozen, sharing
+/// whatever has not changed since with the store
+struct FrozenStore {
+    data_: OrdMap<Vec<u8>, StoredValue>,
+}
+
+impl FrozenEngine for FrozenStore {
+    fn len(&self) -> usize {
+        self.data_.len()
+    }
+
+    fn snapshot(&self) -> Box<dyn Iterator<Item = SnapshotEntry> + '_> {
+        Box::new(self.data_.iter().map(|(k, v)| snapshot_entry(k, v)))
+    }
+}
+
+impl StorageEngine for KeyValueStore {
+    fn name(&self) -> &str {
+        KeyValueStore::name(self)
+    }
+
+    fn get_versioned(&self, key: &[u8]) -> Result<Option<(KeyValuePair, u64)>, RWError> {
+        Ok(KeyValueStore::get_versioned(self, key))
+    }
+
+    fn next_version(&self) -> u64 {
+        KeyValueStore::next_version(self)
+    }
+
+    fn expires_at(&self, key: &[u8]) -> Option<u64> {
+        KeyValueStore::expires_at(self, key)
+    }
+
+    fn put_versioned(&mut self, pair: KeyValuePair, expires_at: Option<u64>, version: u64)
+            -> Result<(), RWError> {
+        KeyValueStore::put_versioned(self, pair, expires_at, version);
+        Ok(())
+    }
+
+    fn delete(&mut self, key: &[u8]) -> Result<bool, RWError> {
+        Ok(KeyValueStore::delete(self, key))
+    }
+
+    fn set_expires_at(&mut self, key: &[u8], expires_at: Option<u64>) -> Result<bool, RWError> {
+        Ok(KeyValueStore::set_expires_at(self, key, expires_at))
+    }
+
+    fn scan_range(&self, start: Bound<&[u8]>, end: Bound<&[u8]>, limit: usize,
+            reverse: bool) -> Result<Vec<KeyValuePair>, RWError> {
+        Ok(KeyValueStore::scan_range(self, start, end, limit, reverse))
+    }
+
+    fn scan(&self, pattern: &KeyPattern, resume_after: Option<&[u8]>, count: usize,
+            max_examined: usize) -> Result<ScanPage, RWError> {
+        Ok(KeyValueStore::scan(self, pattern, resume_after, count, max_examined))
+    }
+
+    fn remove_expired(&mut self, now: u64, limit: usize) -> Result<Vec<Vec<u8>>, RWError> {
+        Ok(KeyValueStore::remove_expired(self, now, limit))
+    }
+
+    fn keys(&self) -> Box<dyn Iterator<Item = Result<Vec<u8>, RWError>> + '_> {
+        Box::new(self.data_.keys().map(|k| Ok(k.clone())))
+    }
+
+    fn keys_by_expiry(&self) -> Box<dyn Iterator<Item = &[u8]> + '_> {
+        Box::new(KeyValueStore::keys_by_expiry(self))
+    }
+
+    fn snapshot(&self) -> Box<dyn Iterator<Item = SnapshotEntry> + '_> {
+        Box::new(self.data_.iter().map(|(k, v)| snapshot_entry(k, v)))
+    }
+
+    fn freeze(&self) -> Result<Box<dyn FrozenEngine>, RWError> {
+        Ok(Box::new(FrozenStore { data_: self.data_.clone() }))
+    }
+
+    fn len(&self) -> usize {
+        KeyValueStore::len(self)
+    }
+
+    fn memory_usage(&self) -> u64 {
+        KeyValueStore::memory_usage(self)
+    }
+
+    fn load(&mut self, store: &KeyValueStore) -> Result<(), RWError> {
+        let name = self.name_.clone();
+        let next_version = self.next_version_;
+        *self = store.clone();
+        self.name_ = name;
+        self.advance_next_version(next_version);
+        Ok(())
+    }
+
+    fn destroy(&mut self) -> Result<(), RWError> {
+        Ok(())
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::key_value_store::storage_engine::conformance;
+
+    #[test]
+    fn test_conformance() {
+        conformance::run(&mut || Box::new(KeyValueStore::new("test_store")));
+    }
 
     #[test]
     fn test_crud() {
@@ -195,11 +637,11 @@ mod tests {
             let duplicate_add = store.add(KeyValuePair::new(
                 first_key, "uno again"
             ));
-            assert_eq!(duplicate_add, false);
+            assert!(!duplicate_add);
             let acceptable_add = store.add(KeyValuePair::new(
                 "two", "dos"
             ));
-            assert_eq!(acceptable_add, true);
+            assert!(acceptable_add);
         }
 
         // test update
@@ -224,13 +666,174 @@ mod tests {
         {
             // on a key that exists
             let res = store.delete(first_key);
-            assert_eq!(res, true);
+            assert!(res);
 
             // on a key that does not exist
             let res_2 = store.delete("404");
-            assert_eq!(res_2, false);
+            assert!(!res_2);
         }
 
         assert_eq!(store.name(), store_name);
     }
+
+    #[test]
+    fn test_expiry() {
+        let mut store = KeyValueStore::new("test_store");
+        let now = now_ms();
+        assert!(store.add_with_expiry(KeyValuePair::new("past", "gone"), Some(now - 1)));
+        assert!(store.add_with_expiry(
+            KeyValuePair::new("future", "here"), Some(now + 60_000)));
+        store.add(KeyValuePair::new("forever", "here"));
+
+        // expired keys are never returned, and behave as absent
+        assert_eq!(store.get("past"), None);
+        assert_eq!(store.expires_at("past"), None);
+        assert!(!store.update(KeyValuePair::new("past", "again")));
+        assert_eq!(store.get("future").unwrap().value(), "here");
+        assert_eq!(store.expires_at("future"), Some(now + 60_000));
+        assert_eq!(store.expires_at("forever"), None);
+
+        // updates keep the expiry unless a new one is given
+        store.update(KeyValuePair::new("future", "still here"));
+        assert_eq!(store.expires_at("future"), Some(now + 60_000));
+        assert!(store.set_expires_at("future", None));
+        assert_eq!(store.expires_at("future"), None);
+        assert!(store.set_expires_at("forever", Some(now - 1)));
+        assert_eq!(store.get("forever"), None);
+
+        // only expired keys are removed
+        let mut removed = store.remove_expired(now, 10);
+        removed.sort();
+        assert_eq!(removed, vec![b"forever".to_vec(), b"past".to_vec()]);
+        assert_eq!(store.len(), 1);
+        assert!(store.remove_expired(now, 10).is_empty());
+
+        // an expired key can be created again
+        assert!(store.add_with_expiry(KeyValuePair::new("again", "1"), Some(now - 1)));
+        assert!(store.add(KeyValuePair::new("again", "2")));
+        assert_eq!(store.get("again").unwrap().value(), "2");
+        assert!(store.remove_expired(now, 10).is_empty());
+    }
+
+    #[test]
+    fn test_memory_usage() {
+        let mut store = KeyValueStore::new("test_store");
+        assert_eq!(store.memory_usage(), 0);
+        store.add(KeyValuePair::new("key", "value"));
+        assert_eq!(store.memory_usage(), entry_size(b"key", b"value"));
+        store.update(KeyValuePair::new("key", "longer value"));
+        assert_eq!(store.memory_usage(), entry_size(b"key", b"longer value"));
+        store.add_with_expiry(KeyValuePair::new("gone", "v"), Some(now_ms() - 1));
+        store.remove_expired(now_ms(), 10);
+        store.delete("key");
+        assert_eq!(store.memory_usage(), 0);
+        assert_eq!(KeyValueStore::from(store.data()).memory_usage(), 0);
+    }
+
+    #[test]
+    fn test_typed_values() {
+        let mut store = KeyValueStore::new("test_store");
+        let count = KeyValuePair::new_typed(
+            "count", DataType::Uint64, 7u64.to_le_bytes().to_vec()).unwrap();
+        assert!(store.add(count.clone()));
+        assert_eq!(store.get("count"), Some(count));
+
+        // a STRING update replaces the type along with the value
+        store.update(KeyValuePair::new("count", "seven"));
+        let val = store.get("count").unwrap();
+        assert_eq!(val.data_type(), DataType::String);
+        assert_eq!(val.value(), "seven");
+
+        let restored = KeyValueStore::from(store.data());
+        assert_eq!(restored.get("count").unwrap().data_type(), DataType::String);
+        store.update(KeyValuePair::new_typed(
+            "count", DataType::Boolean, vec![1]).unwrap());
+        let restored = KeyValueStore::from(store.data());
+        assert_eq!(restored.get("count").unwrap().value(), "true");
+    }
+
+    #[test]
+    fn test_binary_keys() {
+        let mut store = KeyValueStore::new("test_store");
+        let key = [0xff, 0x00, 0xfe];
+        assert!(store.add(KeyValuePair::new_binary(&key, &[1, 2, 3])));
+        // a different key that is lossily rendered the same way as text
+        assert!(store.add(KeyValuePair::new_binary(&[0xfe, 0x00, 0xff], &[4])));
+        assert_eq!(store.get(key).unwrap().value_bytes(), [1, 2, 3]);
+        assert_eq!(store.get(key).unwrap().key(), key);
+
+        let restored = KeyValueStore::from(store.data());
+        assert_eq!(restored, store);
+        assert!(store.delete(key));
+        assert_eq!(store.get(key), None);
+        assert_eq!(store.len(), 1);
+    }
+
+    #[test]
+    fn test_scan_range() {
+        let mut store = KeyValueStore::new("test_store");
+        for k in ["a", "b", "c", "d", "e"] {
+            store.add(KeyValuePair::new(k, k));
+        }
+        store.add_with_expiry(KeyValuePair::new("bb", "gone"), Some(now_ms() - 1));
+        let keys = |pairs: Vec<KeyValuePair>| {
+            pairs.iter().map(|p| p.key_text()).collect::<Vec<_>>()
+        };
+
+        let all = store.scan_range(Bound::Unbounded, Bound::Unbounded, 10, false);
+        assert_eq!(keys(all), ["a", "b", "c", "d", "e"]);
+        let middle = store.scan_range(
+            Bound::Included(b"b"), Bound::Excluded(b"d"), 10, false);
+        assert_eq!(keys(middle), ["b", "c"]);
+        let reversed = store.scan_range(
+            Bound::Excluded(b"a"), Bound::Included(b"d"), 2, true);
+        assert_eq!(keys(reversed), ["d", "c"]);
+        let inverted = store.scan_range(
+            Bound::Included(b"d"), Bound::Included(b"b"), 10, false);
+        assert!(inverted.is_empty());
+        assert!(store.scan_range(Bound::Unbounded, Bound::Unbounded, 0, false).is_empty());
+    }
+
+    #[test]
+    fn test_scan_pages() {
+        let mut store = KeyValueStore::new("test_store");
+        for i in 0..10 {
+            store.add(KeyValuePair::new(&format!("user:{}", i), "v"));
+        }
+        store.add(KeyValuePair::new("other", "v"));
+        store.add_with_expiry(KeyValuePair::new("user:gone", "v"), Some(now_ms() - 1));
+
+        let pattern = KeyPattern::Glob(b"user:*".to_vec());
+        let mut seen = Vec::new();
+        let mut cursor: Option<Vec<u8>> = None;
+        loop {
+            let page = store.scan(&pattern, cursor.as_deref(), 3, 100);
+            assert!(page.pairs.len() <= 3);
+            seen.extend(page.pairs.iter().map(|p| p.key_text()));
+            // keys added and removed mid-scan do not disturb the others
+            if seen.len() == 3 {
+                store.delete("user:5");
+                store.add(KeyValuePair::new("user:55", "v"));
+            }
+            match page.resume_after {
+                None => break,
+                Some(c) => cursor = Some(c)
+            }
+        }
+        assert_eq!(seen, ["user:0", "user:1", "user:2", "user:3", "user:4",
+            "user:55", "user:6", "user:7", "user:8", "user:9"]);
+
+        // a page can come back short if too few keys match among those it
+        // is allowed to examine
+        let sparse = KeyPattern::Glob(b"*9".to_vec());
+        let page = store.scan(&sparse, None, 10, 4);
+        assert!(page.pairs.is_empty());
+        let page = store.scan(&sparse, page.resume_after.as_deref(), 10, 100);
+        assert_eq!(page.pairs[0].key_text(), "user:9");
+        assert_eq!(page.resume_after, None);
+
+        let page = store.scan(&KeyPattern::Prefix(b"oth".to_vec()), None, 10, 100);
+        assert_eq!(page.pairs.len(), 1);
+        assert_eq!(page.resume_after, None);
+    }
 }
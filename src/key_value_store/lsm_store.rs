@@ -0,0 +1,1126 @@
+use std::collections::BTreeSet;
+use std::fs;
+use std::ops::Bound;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+
+use super::filestore;
+use super::key_pattern::KeyPattern;
+use super::key_value_pair::KeyValuePair;
+use super::key_value_store::{
+    entry_size, entry_size_from_lens, first_version, is_empty_range, now_ms, KeyValueStore,
+    ScanPage};
+use super::sstable::{self, Table, TableIter};
+use super::storage_engine::{FrozenEngine, SnapshotEntry, StorageEngine};
+use super::write_ahead_log::WriteAheadLog;
+
+use crate::{
+    key_value_store::errors::{ErrorKind, RWError},
+    proto::{DataType, LsmManifest, LsmTable, WalOp, WalRecord},
+};
+use imbl::OrdMap;
+use log::{error, info, trace, warn};
+use prost::Message;
+
+/// Levels a store's tables are kept in. The last one has no size limit.
+const LEVEL_COUNT: usize = 7;
+/// How many times more bytes each level past the first may hold than the
+/// one before it
+const LEVEL_SIZE_RATIO: u64 = 10;
+const MANIFEST_FILE: &str = "MANIFEST";
+const WAL_FILE: &str = "memtable.wal";
+
+/// Sizes that decide when an `LsmStore` writes out and compacts tables
+#[derive(Clone, Debug)]
+pub struct LsmOptions {
+    /// The memtable is written out to a table once this many bytes of
+    /// records have been written to it
+    pub memtable_bytes: u64,
+    /// Level 0 is compacted into level 1 once it holds this many tables
+    pub level0_tables: usize,
+    /// Tables written by compactions are cut at about this size
+    pub table_bytes: u64,
+    /// The bytes level 1 may hold before some of it is compacted into the
+    /// next level
+    pub level1_bytes: u64,
+}
+
+impl Default for LsmOptions {
+    fn default() -> Self {
+        LsmOptions {
+            memtable_bytes: 4 * 1024 * 1024,
+            level0_tables: 4,
+            table_bytes: 2 * 1024 * 1024,
+            level1_bytes: 10 * 1024 * 1024,
+        }
+    }
+}
+
+type RecordIter<'a> = Box<dyn Iterator<Item = Result<WalRecord, RWError>> + 'a>;
+
+fn is_put(record: &WalRecord) -> bool {
+    record.op() != WalOp::Delete
+}
+
+fn is_live(record: &WalRecord, now: u64) -> bool {
+    is_put(record) && record.expires_at_ms.is_none_or(|t| t > now)
+}
+
+fn decode_pair(record: WalRecord) -> Result<KeyValuePair, RWError> {
+    KeyValuePair::from_stored(&record.key, record.data_type, record.value)
+        .map_err(|e| RWError {
+            kind_: ErrorKind::DataDecodeError,
+            context_: e,
+        })
+}
+
+fn put_record(key: &[u8], value: &[u8], data_type: DataType, expires_at: Option<u64>,
+        version: u64) -> WalRecord {
+    WalRecord {
+        op: WalOp::Create.into(),
+        key: key.to_vec(),
+        value: value.to_vec(),
+        expires_at_ms: expires_at,
+        data_type: match data_type {
+            DataType::String => None,
+            t => Some(t.into()),
+        },
+        version,
+        batch: Vec::new(),
+    }
+}
+
+/// Merges sources of records that are each in key order, or each in reverse
+/// key order, into one. Where several sources hold the same key, only the
+/// record from the earliest source is kept, so sources go newest first.
+struct MergeIter<'a> {
+    sources: Vec<(RecordIter<'a>, Option<WalRecord>)>,
+    reverse: bool,
+}
+
+impl<'a> MergeIter<'a> {
+    fn new(sources: Vec<RecordIter<'a>>, reverse: bool) -> MergeIter<'a> {
+        MergeIter {
+            sources: sources.into_iter().map(|s| (s, None)).collect(),
+            reverse,
+        }
+    }
+}
+
+impl Iterator for MergeIter<'_> {
+    type Item = Result<WalRecord, RWError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        for (iter, head) in self.sources.iter_mut() {
+            if head.is_none() {
+                match iter.next() {
+                    Some(Ok(r)) => *head = Some(r),
+                    Some(Err(e)) => return Some(Err(e)),
+                    None => {}
+                }
+            }
+        }
+        let mut best: Option<(usize, &[u8])> = None;
+        for (i, (_, head)) in self.sources.iter().enumerate() {
+            let key = match head {
+                None => continue,
+                Some(r) => r.key.as_slice(),
+            };
+            let better = match best {
+                None => true,
+                Some((_, b)) if self.reverse => key > b,
+                Some((_, b)) => key < b,
+            };
+            if better {
+                best = Some((i, key));
+            }
+        }
+        let best = best?.0;
+        let record = self.sources[best].1.take()?;
+        for (_, head) in self.sources.iter_mut() {
+            if head.as_ref().is_some_and(|h| h.key == record.key) {
+                *head = None;
+            }
+        }
+        Some(Ok(record))
+    }
+}
+
+/// The tables making up a store at one point in time. Level 0 holds tables
+/// written out from the memtable, newest first, whose keys may overlap. Each
+/// other level holds tables in key order whose keys do not overlap.
+#[derive(Clone)]
+struct Version {
+    levels: Vec<Vec<Arc<Table>>>,
+}
+
+impl Version {
+    fn level_bytes(&self, level: usize) -> u64 {
+        self.levels[level].iter().map(|t| t.size()).sum()
+    }
+
+    /// The newest record for `key` in any table, which may be a delete
+    fn get(&self, key: &[u8]) -> Result<Option<WalRecord>, RWError> {
+        for table in &self.levels[0] {
+            if let Some(r) = table.get(key)? {
+                return Ok(Some(r));
+            }
+        }
+        for level in &self.levels[1..] {
+            let i = level.partition_point(|t| t.last_key() < key);
+            if let Some(table) = level.get(i) {
+                if let Some(r) = table.get(key)? {
+                    return Ok(Some(r));
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    /// One source of records per table of level 0 and per other level,
+    /// newest first, see `MergeIter`
+    fn sources(&self, start: &Bound<Vec<u8>>, end: &Bound<Vec<u8>>, reverse: bool)
+            -> Vec<RecordIter<'static>> {
+        let mut sources: Vec<RecordIter> = self.levels[0].iter()
+            .map(|t| Box::new(TableIter::new(t.clone(), start.clone(), end.clone(), reverse))
+                as RecordIter)
+            .collect();
+        for level in &self.levels[1..] {
+            sources.push(level_source(level.clone(), start, end, reverse));
+        }
+        sources
+    }
+
+    /// Whether no level after `level` holds any tables
+    fn is_last_level(&self, level: usize) -> bool {
+        self.levels[level + 1..].iter().all(|l| l.is_empty())
+    }
+
+    fn manifest(&self, next_table_id: u64) -> LsmManifest {
+        LsmManifest {
+            tables: self.levels.iter().enumerate()
+                .flat_map(|(level, tables)| tables.iter().map(move |t| LsmTable {
+                    id: t.id(),
+                    level: level as u32,
+                }))
+                .collect(),
+            next_table_id,
+        }
+    }
+}
+
+/// Reads the tables of a level other than 0 one after the other
+fn level_source(mut tables: Vec<Arc<Table>>, start: &Bound<Vec<u8>>, end: &Bound<Vec<u8>>,
+        reverse: bool) -> RecordIter<'static> {
+    if reverse {
+        tables.reverse();
+    }
+    let (start, end) = (start.clone(), end.clone());
+    Box::new(tables.into_iter()
+        .flat_map(move |t| TableIter::new(t, start.clone(), end.clone(), reverse)))
+}
+
+/// The tables taking part in one compaction
+struct Compaction {
+    level: usize,
+    // From `level`
+    inputs: Vec<Arc<Table>>,
+    // The tables of the next level that overlap the inputs
+    overlapping: Vec<Arc<Table>>,
+}
+
+#[derive(Default)]
+struct CompactorSignal {
+    work: bool,
+    shutdown: bool,
+}
+
+/// What the store and its background compactor share
+struct Shared {
+    dir: String,
+    options: LsmOptions,
+    // Also held while writing the manifest, so that changes to the tables
+    // are written out in the order they are made
+    version: Mutex<Arc<Version>>,
+    next_table_id: AtomicU64,
+    // Where the last compaction of each level stopped, so that the next one
+    // carries on from there
+    compact_pointers: Mutex<Vec<Vec<u8>>>,
+    signal: Mutex<CompactorSignal>,
+    wake: Condvar,
+}
+
+impl Shared {
+    fn current(&self) -> Arc<Version> {
+        self.version.lock().unwrap().clone()
+    }
+
+    fn table_path(&self, id: u64) -> String {
+        format!("{}/{:06}.sst", self.dir, id)
+    }
+
+    fn manifest_path(&self) -> String {
+        format!("{}/{}", self.dir, MANIFEST_FILE)
+    }
+
+    fn level_limit(&self, level: usize) -> u64 {
+        self.options.level1_bytes * LEVEL_SIZE_RATIO.pow(level as u32 - 1)
+    }
+
+    /// Must be called while holding the version lock
+    fn write_manifest(&self, version: &Version) -> Result<(), RWError> {
+        let manifest = version.manifest(self.next_table_id.load(Ordering::Relaxed));
+        filestore::write_file_atomically(&self.manifest_path(), |out| {
+            out.write_all(&manifest.encode_to_vec()).map_err(|e| RWError {
+                kind_: ErrorKind::FileWriteError,
+                context_: e.to_string(),
+            })
+        })
+    }
+
+    /// Writes `records` to as many new tables as it takes, cutting them at
+    /// `max_bytes`
+    fn write_tables<I>(&self, records: I, max_bytes: u64) -> Result<Vec<Arc<Table>>, RWError>
+    where
+        I: Iterator<Item = Result<WalRecord, RWError>>,
+    {
+        let mut records = records.peekable();
+        let mut tables = Vec::new();
+        loop {
+            let id = self.next_table_id.fetch_add(1, Ordering::Relaxed);
+            let path = self.table_path(id);
+            let written = sstable::write_table(&path, &mut records, max_bytes)
+                .and_then(|written| match written {
+                    true => Table::open(id, &path).map(Some),
+                    false => Ok(None),
+                });
+            match written {
+                Ok(Some(t)) => tables.push(Arc::new(t)),
+                Ok(None) => return Ok(tables),
+                Err(e) => {
+                    for t in &tables {
+                        t.mark_obsolete();
+                    }
+                    return Err(e);
+                }
+            }
+        }
+    }
+
+    /// Installs the version made by `edit` from the current one, writing it
+    /// to the manifest first. Tables that are no longer in use once it is
+    /// installed are deleted when their last reader lets go of them. Returns
+    /// false if `edit` made no version.
+    fn install<F>(&self, edit: F) -> Result<bool, RWError>
+    where
+        F: FnOnce(&Version) -> Option<Version>,
+    {
+        let mut current = self.version.lock().unwrap();
+        let new = match edit(&current) {
+            None => return Ok(false),
+            Some(v) => v,
+        };
+        self.write_manifest(&new)?;
+        let kept: BTreeSet<u64> = new.levels.iter().flatten().map(|t| t.id()).collect();
+        for table in current.levels.iter().flatten() {
+            if !kept.contains(&table.id()) {
+                table.mark_obsolete();
+            }
+        }
+        *current = Arc::new(new);
+        Ok(true)
+    }
+
+    /// Picks the tables to compact next: every table of level 0 once there
+    /// are enough of them, or else a table of the first level over its size
+    /// limit.
+    fn pick_compaction(&self, version: &Version) -> Option<Compaction> {
+        let (level, inputs) = if version.levels[0].len() >= self.options.level0_tables {
+            (0, version.levels[0].clone())
+        } else {
+            let level = (1..LEVEL_COUNT - 1)
+                .find(|l| version.level_bytes(*l) > self.level_limit(*l))?;
+            let mut pointers = self.compact_pointers.lock().unwrap();
+            let tables = &version.levels[level];
+            let table = tables.iter()
+                .find(|t| t.first_key() > pointers[level].as_slice())
+                .unwrap_or(&tables[0])
+                .clone();
+            pointers[level] = table.last_key().to_vec();
+            (level, vec![table])
+        };
+        let start = inputs.iter().map(|t| t.first_key()).min()?.to_vec();
+        let end = inputs.iter().map(|t| t.last_key()).max()?.to_vec();
+        let overlapping = version.levels[level + 1].iter()
+            .filter(|t| t.overlaps(&start, &end))
+            .cloned()
+            .collect();
+        Some(Compaction { level, inputs, overlapping })
+    }
+
+    /// Runs one compaction, if there is one to run. Returns whether it did.
+    fn compact_once(&self) -> Result<bool, RWError> {
+        let version = self.current();
+        let Compaction { level, inputs, overlapping } = match self.pick_compaction(&version) {
+            None => return Ok(false),
+            Some(c) => c,
+        };
+        let target = level + 1;
+        let mut sources: Vec<RecordIter> = if level == 0 {
+            inputs.iter()
+                .map(|t| Box::new(TableIter::new(
+                    t.clone(), Bound::Unbounded, Bound::Unbounded, false)) as RecordIter)
+                .collect()
+        } else {
+            vec![level_source(inputs.clone(), &Bound::Unbounded, &Bound::Unbounded, false)]
+        };
+        sources.push(level_source(
+            overlapping.clone(), &Bound::Unbounded, &Bound::Unbounded, false));
+        // Deletes only have to be kept while there may be older versions of
+        // their keys further down
+        let keep_deletes = !version.is_last_level(target);
+        let merged = MergeIter::new(sources, false)
+            .filter(|r| keep_deletes || r.as_ref().map_or(true, is_put));
+        let outputs = self.write_tables(merged, self.options.table_bytes)?;
+        let replaced: BTreeSet<u64> = inputs.iter().chain(overlapping.iter())
+            .map(|t| t.id())
+            .collect();
+        let installed = self.install(|current| {
+            // The tables may have been replaced wholesale in the meantime,
+            // see `LsmStore::load`
+            let present = current.levels.iter().flatten()
+                .filter(|t| replaced.contains(&t.id()))
+                .count();
+            if present != replaced.len() {
+                return None;
+            }
+            let mut new = current.clone();
+            for tables in &mut new.levels[level..=target] {
+                tables.retain(|t| !replaced.contains(&t.id()));
+            }
+            new.levels[target].extend(outputs.iter().cloned());
+            new.levels[target].sort_by(|a, b| a.first_key().cmp(b.first_key()));
+            Some(new)
+        });
+        if !matches!(installed, Ok(true)) {
+            for t in &outputs {
+                t.mark_obsolete();
+            }
+        }
+        if !installed? {
+            return Ok(true);
+        }
+        info!("Compacted {:?} tables of level {:?} into {:?} tables of level {:?}",
+            replaced.len(), level, outputs.len(), target);
+        Ok(true)
+    }
+
+    fn request_compaction(&self) {
+        self.signal.lock().unwrap().work = true;
+        self.wake.notify_all();
+    }
+
+    fn run_compactor(self: Arc<Self>) {
+        loop {
+            {
+                let mut signal = self.signal.lock().unwrap();
+                while !signal.work && !signal.shutdown {
+                    signal = self.wake.wait(signal).unwrap();
+                }
+                if signal.shutdown {
+                    return;
+                }
+                signal.work = false;
+            }
+            loop {
+                match self.compact_once() {
+                    Ok(true) => {
+                        if self.signal.lock().unwrap().shutdown {
+                            return;
+                        }
+                    }
+                    Ok(false) => break,
+                    // Tried again the next time a table is written out
+                    Err(e) => {
+                        error!("Compaction failed in {:?}: {:?}", self.dir, e.to_string());
+                        break;
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// A store kept on disk as a log-structured merge tree, for datasets larger
+/// than memory.
+///
+/// Writes go to the memtable, an ordered map in memory backed by its own
+/// write-ahead log, which is written out as a table of level 0 once it grows
+/// large enough. A background thread merges tables down through the levels,
+/// see `Version`, dropping overwritten records and deletes on the way. Reads
+/// look at the memtable and then at each level in turn, newest first, using
+/// each table's bloom filter and block index to read as little as possible.
+///
+/// Besides the memtable, only the keys that have an expiry, the tables'
+/// indexes and their bloom filters are kept in memory. Opening a store reads
+/// it all once to count its pairs and find those keys.
+pub struct LsmStore {
+    name_: String,
+    shared_: Arc<Shared>,
+    // The latest record for each key written since the last table was
+    // written out, including deletes. Shared with frozen copies of the
+    // store, see `KeyValueStore`.
+    memtable_: OrdMap<Vec<u8>, WalRecord>,
+    memtable_bytes_: u64,
+    wal_: WriteAheadLog,
+    compactor_: Option<JoinHandle<()>>,
+    // Every (expiry time, key) with an expiry, see `KeyValueStore`
+    expiry_index_: BTreeSet<(u64, Vec<u8>)>,
+    len_: usize,
+    // Sum of `entry_size` over every pair
+    memory_usage_: u64,
+    next_version_: u64,
+    removed_: bool,
+}
+
+impl LsmStore {
+    pub fn open(name: &str, dir: &str) -> Result<LsmStore, RWError> {
+        LsmStore::open_with(name, dir, LsmOptions::default())
+    }
+
+    /// Opens the store kept in `dir`, creating it if it does not exist, and
+    /// starts its compactor
+    pub fn open_with(name: &str, dir: &str, options: LsmOptions) -> Result<LsmStore, RWError> {
+        if let Err(e) = fs::create_dir_all(dir) {
+            return Err(RWError {
+                kind_: ErrorKind::FileOpenError,
+                context_: e.to_string(),
+            });
+        }
+        let manifest_path = format!("{}/{}", dir, MANIFEST_FILE);
+        let manifest = match fs::read(&manifest_path) {
+            Ok(bytes) => LsmManifest::decode(bytes.as_slice()).map_err(|e| RWError {
+                kind_: ErrorKind::DataDecodeError,
+                context_: e.to_string(),
+            })?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => LsmManifest::default(),
+            Err(e) => return Err(RWError {
+                kind_: ErrorKind::FileReadError,
+                context_: e.to_string(),
+            }),
+        };
+        let shared = Arc::new(Shared {
+            dir: dir.to_string(),
+            options,
+            version: Mutex::new(Arc::new(Version { levels: vec![Vec::new(); LEVEL_COUNT] })),
+            next_table_id: AtomicU64::new(manifest.next_table_id.max(1)),
+            compact_pointers: Mutex::new(vec![Vec::new(); LEVEL_COUNT]),
+            signal: Mutex::new(CompactorSignal::default()),
+            wake: Condvar::new(),
+        });
+        let mut levels = vec![Vec::new(); LEVEL_COUNT];
+        for table in &manifest.tables {
+            let opened = Table::open(table.id, &shared.table_path(table.id))?;
+            levels[(table.level as usize).min(LEVEL_COUNT - 1)].push(Arc::new(opened));
+        }
+        for level in &mut levels[1..] {
+            level.sort_by(|a, b| a.first_key().cmp(b.first_key()));
+        }
+        remove_stray_files(dir, &manifest);
+        *shared.version.lock().unwrap() = Arc::new(Version { levels });
+
+        let mut wal = WriteAheadLog::open(&format!("{}/{}", dir, WAL_FILE))?;
+        filestore::sync_parent_dir(dir)?;
+        let mut memtable = OrdMap::new();
+        let mut memtable_bytes = 0;
+        let replayed = wal.replay_records(|record| {
+            memtable_bytes += entry_size(&record.key, &record.value);
+            memtable.insert(record.key.clone(), record);
+            Ok(())
+        })?;
+        let mut store = LsmStore {
+            name_: name.to_string(),
+            shared_: shared.clone(),
+            memtable_: memtable,
+            memtable_bytes_: memtable_bytes,
+            wal_: wal,
+            compactor_: None,
+            expiry_index_: BTreeSet::new(),
+            len_: 0,
+            memory_usage_: 0,
+            next_version_: first_version(),
+            removed_: false,
+        };
+        let mut counted = Vec::new();
+        let mut next_version = store.next_version_;
+        for record in store.merged(Bound::Unbounded, Bound::Unbounded, false) {
+            let record = record?;
+            if is_put(&record) {
+                next_version = next_version.max(record.version + 1);
+                counted.push((record.key, record.value.len(), record.expires_at_ms));
+            }
+        }
+        store.next_version_ = next_version;
+        for (key, value_len, expires_at) in counted {
+            store.count(&key, value_len, expires_at);
+        }
+        store.compactor_ = Some(thread::spawn(move || shared.run_compactor()));
+        store.shared_.request_compaction();
+        info!("Opened {:?} with {:?} keys, replaying {:?} records", dir, store.len_, replayed);
+        Ok(store)
+    }
+
+    fn check_not_removed(&self) -> Result<(), RWError> {
+        if self.removed_ {
+            return Err(RWError {
+                kind_: ErrorKind::FileWriteError,
+                context_: format!("{} has been removed", self.shared_.dir),
+            });
+        }
+        Ok(())
+    }
+
+    fn merged(&self, start: Bound<Vec<u8>>, end: Bound<Vec<u8>>, reverse: bool)
+            -> MergeIter<'_> {
+        merge(&self.memtable_, &self.shared_.current(), start, end, reverse)
+    }
+
+    /// The newest record for `key`, which may be a delete
+    fn lookup(&self, key: &[u8]) -> Result<Option<WalRecord>, RWError> {
+        match self.memtable_.get(key) {
+            Some(r) => Ok(Some(r.clone())),
+            None => self.shared_.current().get(key),
+        }
+    }
+
+    /// The pair stored under `key`, even if it has expired
+    fn lookup_put(&self, key: &[u8]) -> Result<Option<WalRecord>, RWError> {
+        Ok(self.lookup(key)?.filter(is_put))
+    }
+
+    fn count(&mut self, key: &[u8], value_len: usize, expires_at: Option<u64>) {
+        self.len_ += 1;
+        self.memory_usage_ += entry_size_from_lens(key.len(), value_len);
+        if let Some(t) = expires_at {
+            self.expiry_index_.insert((t, key.to_vec()));
+        }
+    }
+
+    fn uncount(&mut self, old: &WalRecord) {
+        self.len_ -= 1;
+        self.memory_usage_ -= entry_size(&old.key, &old.value);
+        if let Some(t) = old.expires_at_ms {
+            self.expiry_index_.remove(&(t, old.key.clone()));
+        }
+    }
+
+    /// Makes a record durable and adds it to the memtable, writing the
+    /// memtable out if it has grown large enough
+    fn write(&mut self, record: WalRecord) -> Result<(), RWError> {
+        self.check_not_removed()?;
+        self.wal_.append(&record)?;
+        self.memtable_bytes_ += entry_size(&record.key, &record.value);
+        self.memtable_.insert(record.key.clone(), record);
+        if self.memtable_bytes_ >= self.shared_.options.memtable_bytes {
+            // The record is already durable, so this is retried on the next
+            // write rather than failing this one
+            if let Err(e) = self.flush() {
+                warn!("Cannot write out the memtable of {:?}: {:?}",
+                    self.shared_.dir, e.to_string());
+            }
+        }
+        Ok(())
+    }
+
+    fn remove(&mut self, old: WalRecord) -> Result<(), RWError> {
+        self.write(WalRecord {
+            op: WalOp::Delete.into(),
+            key: old.key.clone(),
+            ..Default::default()
+        })?;
+        self.uncount(&old);
+        Ok(())
+    }
+
+    /// Writes the memtable out as a new table of level 0 and empties it
+    fn flush(&mut self) -> Result<(), RWError> {
+        if self.memtable_.is_empty() {
+            return Ok(());
+        }
+        let tables = self.shared_.write_tables(
+            self.memtable_.values().map(|r| Ok(r.clone())), u64::MAX)?;
+        let installed = self.shared_.install(|current| {
+            let mut new = current.clone();
+            for table in tables.iter().rev() {
+                new.levels[0].insert(0, table.clone());
+            }
+            Some(new)
+        });
+        if let Err(e) = installed {
+            for t in &tables {
+                t.mark_obsolete();
+            }
+            return Err(e);
+        }
+        trace!("Wrote out {:?} records from the memtable of {:?}",
+            self.memtable_.len(), self.shared_.dir);
+        self.memtable_.clear();
+        self.memtable_bytes_ = 0;
+        self.wal_.clear()?;
+        if self.shared_.current().levels[0].len() >= self.shared_.options.level0_tables {
+            self.shared_.request_compaction();
+        }
+        Ok(())
+    }
+
+    fn stop_compactor(&mut self) {
+        self.shared_.signal.lock().unwrap().shutdown = true;
+        self.shared_.wake.notify_all();
+        if let Some(handle) = self.compactor_.take() {
+            if handle.join().is_err() {
+                error!("Compactor of {:?} panicked", self.shared_.dir);
+            }
+        }
+    }
+
+    #[cfg(test)]
+    fn table_counts(&self) -> Vec<usize> {
+        self.shared_.current().levels.iter().map(|l| l.len()).collect()
+    }
+}
+
+/// Every record from `memtable` and the tables of `version` with keys from
+/// `start` to `end`, newest only, deletes included
+fn merge<'a>(memtable: &'a OrdMap<Vec<u8>, WalRecord>, version: &Version,
+        start: Bound<Vec<u8>>, end: Bound<Vec<u8>>, reverse: bool) -> MergeIter<'a> {
+    let as_slices = (start.as_ref().map(|k| k.as_slice()), end.as_ref().map(|k| k.as_slice()));
+    if is_empty_range(as_slices.0, as_slices.1) {
+        return MergeIter::new(Vec::new(), reverse);
+    }
+    let range = memtable.range::<_, [u8]>(as_slices).map(|(_, r)| Ok(r.clone()));
+    let mut sources: Vec<RecordIter> = if reverse {
+        vec![Box::new(range.rev())]
+    } else {
+        vec![Box::new(range)]
+    };
+    sources.extend(version.sources(&start, &end, reverse));
+    MergeIter::new(sources, reverse)
+}
+
+fn snapshot_of(merged: MergeIter<'_>) -> Box<dyn Iterator<Item = SnapshotEntry> + '_> {
+    Box::new(merged
+        .filter(|r| r.as_ref().map_or(true, is_put))
+        .map(|r| {
+            let record = r?;
+            let expires_at = record.expires_at_ms;
+            Ok((decode_pair(record)?, expires_at))
+        }))
+}
+
+/// The memtable and tables of an `LsmStore` at the time it was frozen. The
+/// tables are only deleted once nothing uses them, so they stay readable
+/// however the store is compacted in the meantime.
+struct FrozenLsmStore {
+    memtable_: OrdMap<Vec<u8>, WalRecord>,
+    version_: Arc<Version>,
+    len_: usize,
+}
+
+impl FrozenEngine for FrozenLsmStore {
+    fn len(&self) -> usize {
+        self.len_
+    }
+
+    fn snapshot(&self) -> Box<dyn Iterator<Item = SnapshotEntry> + '_> {
+        snapshot_of(merge(&self.memtable_, &self.version_, Bound::Unbounded, Bound::Unbounded,
+            false))
+    }
+}
+
+/// Removes tables left behind by a write that did not make it into the
+/// manifest, and temporary files from interrupted rewrites
+fn remove_stray_files(dir: &str, manifest: &LsmManifest) {
+    let known: BTreeSet<String> = manifest.tables.iter()
+        .map(|t| format!("{:06}.sst", t.id))
+        .collect();
+    let entries = match fs::read_dir(dir) {
+        Ok(e) => e,
+        Err(_) => return,
+    };
+    for entry in entries.flatten() {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if (name.ends_with(".sst") && !known.contains(&name)) || name.ends_with(".tmp") {
+            warn!("Removing stray file {:?} from {:?}", name, dir);
+            if let Err(e) = fs::remove_file(entry.path()) {
+                warn!("Cannot remove {:?}: {:?}", name, e);
+            }
+        }
+    }
+}
+
+impl Drop for LsmStore {
+    fn drop(&mut self) {
+        self.stop_compactor();
+    }
+}
+
+impl StorageEngine for LsmStore {
+    fn name(&self) -> &str {
+        self.name_.as_str()
+    }
+
+    fn get_versioned(&self, key: &[u8]) -> Result<Option<(KeyValuePair, u64)>, RWError> {
+        match self.lookup(key)? {
+            Some(r) if is_live(&r, now_ms()) => {
+                let version = r.version;
+                Ok(Some((decode_pair(r)?, version)))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    fn next_version(&self) -> u64 {
+        self.next_version_
+    }
+
+    fn expires_at(&self, key: &[u8]) -> Option<u64> {
+        match self.lookup(key) {
+            Ok(Some(r)) if is_live(&r, now_ms()) => r.expires_at_ms,
+            Ok(_) => None,
+            Err(e) => {
+                error!("Cannot look up {:?}: {:?}", String::from_utf8_lossy(key), e.to_string());
+                None
+            }
+        }
+    }
+
+    fn put_versioned(&mut self, pair: KeyValuePair, expires_at: Option<u64>, version: u64)
+            -> Result<(), RWError> {
+        let old = self.lookup_put(pair.key())?;
+        self.write(put_record(pair.key(), pair.value_bytes(), pair.data_type(), expires_at,
+            version))?;
+        self.next_version_ = self.next_version_.max(version + 1);
+        if let Some(old) = old {
+            self.uncount(&old);
+        }
+        self.count(pair.key(), pair.value_bytes().len(), expires_at);
+        Ok(())
+    }
+
+    fn delete(&mut self, key: &[u8]) -> Result<bool, RWError> {
+        let old = match self.lookup_put(key)? {
+            None => return Ok(false),
+            Some(r) => r,
+        };
+        let live = is_live(&old, now_ms());
+        self.remove(old)?;
+        Ok(live)
+    }
+
+    fn set_expires_at(&mut self, key: &[u8], expires_at: Option<u64>) -> Result<bool, RWError> {
+        let (pair, version) = match self.get_versioned(key)? {
+            None => return Ok(false),
+            Some(p) => p,
+        };
+        self.put_versioned(pair, expires_at, version)?;
+        Ok(true)
+    }
+
+    fn scan_range(&self, start: Bound<&[u8]>, end: Bound<&[u8]>, limit: usize,
+            reverse: bool) -> Result<Vec<KeyValuePair>, RWError> {
+        let now = now_ms();
+        let mut pairs = Vec::new();
+        if limit == 0 {
+            return Ok(pairs);
+        }
+        let merged = self.merged(start.map(|k| k.to_vec()), end.map(|k| k.to_vec()), reverse);
+        for record in merged {
+            let record = record?;
+            if is_live(&record, now) {
+                pairs.push(decode_pair(record)?);
+                if pairs.len() == limit {
+                    break;
+                }
+            }
+        }
+        Ok(pairs)
+    }
+
+    fn scan(&self, pattern: &KeyPattern, resume_after: Option<&[u8]>, count: usize,
+            max_examined: usize) -> Result<ScanPage, RWError> {
+        // Walks the keys like `KeyValueStore::scan`, deletes counting as
+        // examined keys so that a page of them does not take forever
+        let prefix = pattern.literal_prefix();
+        let start = match resume_after {
+            Some(k) if k >= prefix.as_slice() => Bound::Excluded(k.to_vec()),
+            _ => Bound::Included(prefix.clone())
+        };
+        let now = now_ms();
+        let mut pairs = Vec::new();
+        let mut last: Option<Vec<u8>> = resume_after.map(|k| k.to_vec());
+        let merged = self.merged(start, Bound::Unbounded, false);
+        for (examined, record) in merged.enumerate() {
+            let record = record?;
+            if !record.key.starts_with(&prefix) {
+                break;
+            }
+            if pairs.len() == count || examined == max_examined {
+                return Ok(ScanPage { pairs, resume_after: last });
+            }
+            last = Some(record.key.clone());
+            if is_live(&record, now) && pattern.matches(&record.key) {
+                pairs.push(decode_pair(record)?);
+            }
+        }
+        Ok(ScanPage { pairs, resume_after: None })
+    }
+
+    fn remove_expired(&mut self, now: u64, limit: usize) -> Result<Vec<Vec<u8>>, RWError> {
+        // Unlike the in-memory engines, removing a key means writing a
+        // delete, so that compactions can drop it from the tables
+        let mut removed = Vec::new();
+        while removed.len() < limit {
+            let key = match self.expiry_index_.first() {
+                Some((t, k)) if *t <= now => k.clone(),
+                _ => break
+            };
+            match self.lookup_put(&key)? {
+                Some(old) => self.remove(old)?,
+                // Should not happen, but must not stall removal
+                None => {
+                    self.expiry_index_.pop_first();
+                }
+            }
+            removed.push(key);
+        }
+        Ok(removed)
+    }
+
+    fn keys(&self) -> Box<dyn Iterator<Item = Result<Vec<u8>, RWError>> + '_> {
+        Box::new(self.merged(Bound::Unbounded, Bound::Unbounded, false)
+            .filter(|r| r.as_ref().map_or(true, is_put))
+            .map(|r| r.map(|r| r.key)))
+    }
+
+    fn keys_by_expiry(&self) -> Box<dyn Iterator<Item = &[u8]> + '_> {
+        Box::new(self.expiry_index_.iter().map(|(_, k)| k.as_slice()))
+    }
+
+    fn snapshot(&self) -> Box<dyn Iterator<Item = SnapshotEntry> + '_> {
+        snapshot_of(self.merged(Bound::Unbounded, Bound::Unbounded, false))
+    }
+
+    fn freeze(&self) -> Result<Box<dyn FrozenEngine>, RWError> {
+        Ok(Box::new(FrozenLsmStore {
+            memtable_: self.memtable_.clone(),
+            version_: self.shared_.current(),
+            len_: self.len_,
+        }))
+    }
+
+    fn len(&self) -> usize {
+        self.len_
+    }
+
+    fn memory_usage(&self) -> u64 {
+        self.memory_usage_
+    }
+
+    fn load(&mut self, store: &KeyValueStore) -> Result<(), RWError> {
+        self.check_not_removed()?;
+        // With the memtable written out, a crash at any point below leaves
+        // either the old contents or the new ones
+        self.flush()?;
+        let tables = self.shared_.write_tables(
+            store.iter().map(|e| Ok(put_record(e.key, e.value, e.data_type, e.expires_at,
+                e.version))),
+            self.shared_.options.table_bytes)?;
+        let installed = self.shared_.install(|_| {
+            let mut levels = vec![Vec::new(); LEVEL_COUNT];
+            levels[LEVEL_COUNT - 1] = tables.clone();
+            Some(Version { levels })
+        });
+        if let Err(e) = installed {
+            for t in &tables {
+                t.mark_obsolete();
+            }
+            return Err(e);
+        }
+        self.expiry_index_.clear();
+        self.len_ = 0;
+        self.memory_usage_ = 0;
+        for entry in store.iter() {
+            self.count(entry.key, entry.value.len(), entry.expires_at);
+            self.next_version_ = self.next_version_.max(entry.version + 1);
+        }
+        Ok(())
+    }
+
+    fn destroy(&mut self) -> Result<(), RWError> {
+        self.check_not_removed()?;
+        self.stop_compactor();
+        self.wal_.remove()?;
+        self.removed_ = true;
+        if let Err(e) = fs::remove_dir_all(&self.shared_.dir) {
+            return Err(RWError {
+                kind_: ErrorKind::FileWriteError,
+                context_: e.to_string(),
+            });
+        }
+        filestore::sync_parent_dir(&self.shared_.dir)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::key_value_store::storage_engine::conformance;
+    use std::cell::Cell;
+    use std::time::{Duration, Instant};
+
+    /// Small enough that a handful of writes fill the memtable and a few
+    /// tables fill level 1
+    fn tiny() -> LsmOptions {
+        LsmOptions {
+            memtable_bytes: 512,
+            level0_tables: 2,
+            table_bytes: 1024,
+            level1_bytes: 2048,
+        }
+    }
+
+    fn fresh_store(dir: &str, options: LsmOptions) -> LsmStore {
+        let _ = fs::remove_dir_all(dir);
+        LsmStore::open_with("test", dir, options).expect("Cannot open store!")
+    }
+
+    fn wait_for_compaction(store: &LsmStore) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while store.table_counts()[0] >= store.shared_.options.level0_tables {
+            assert!(Instant::now() < deadline, "Compaction never ran");
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    #[test]
+    fn test_conformance() {
+        let run = Cell::new(0);
+        let dir = || format!("/tmp/test_lsm_conformance_{}", run.get());
+        conformance::run_reopening(&mut || {
+            run.set(run.get() + 1);
+            Box::new(fresh_store(&dir(), LsmOptions::default()))
+        }, &mut || Box::new(LsmStore::open_with("test", &dir(), LsmOptions::default()).unwrap()));
+    }
+
+    #[test]
+    fn test_conformance_with_tables() {
+        let run = Cell::new(0);
+        let dir = || format!("/tmp/test_lsm_conformance_tiny_{}", run.get());
+        conformance::run_reopening(&mut || {
+            run.set(run.get() + 1);
+            Box::new(fresh_store(&dir(), tiny()))
+        }, &mut || Box::new(LsmStore::open_with("test", &dir(), tiny()).unwrap()));
+    }
+
+    #[test]
+    fn test_reopen() {
+        let dir = "/tmp/test_lsm_reopen";
+        let expiry = now_ms() + 60_000;
+        let mut store = fresh_store(dir, tiny());
+        for i in 0..200 {
+            store.put(KeyValuePair::new(&format!("key{:03}", i), "old"), None).unwrap();
+        }
+        for i in (0..200).step_by(2) {
+            store.put(KeyValuePair::new(&format!("key{:03}", i), "new"), Some(expiry)).unwrap();
+        }
+        for i in (0..200).step_by(5) {
+            store.delete(format!("key{:03}", i).as_bytes()).unwrap();
+        }
+        wait_for_compaction(&store);
+        assert!(store.table_counts()[1..].iter().sum::<usize>() > 0);
+        let (len, usage) = (store.len(), store.memory_usage());
+        let version = store.get_versioned(b"key198").unwrap().unwrap().1;
+        drop(store);
+
+        let reopened = LsmStore::open_with("test", dir, tiny()).unwrap();
+        assert_eq!(reopened.len(), len);
+        assert_eq!(reopened.get_versioned(b"key198").unwrap().unwrap().1, version);
+        assert!(reopened.next_version() > version);
+        assert_eq!(reopened.len(), 160);
+        assert_eq!(reopened.memory_usage(), usage);
+        assert_eq!(reopened.get(b"key000").unwrap(), None);
+        assert_eq!(reopened.get(b"key001").unwrap().unwrap().value(), "old");
+        assert_eq!(reopened.get(b"key002").unwrap().unwrap().value(), "new");
+        assert_eq!(reopened.expires_at(b"key002"), Some(expiry));
+        assert_eq!(reopened.keys_by_expiry().count(), 80);
+        let all = reopened.scan_range(Bound::Unbounded, Bound::Unbounded, 1000, true).unwrap();
+        assert_eq!(all.len(), 160);
+        assert_eq!(all[0].key_text(), "key199");
+    }
+
+    #[test]
+    fn test_compaction_drops_old_records() {
+        let dir = "/tmp/test_lsm_compaction";
+        let mut store = fresh_store(dir, tiny());
+        for round in 0..20 {
+            for i in 0..20 {
+                store.put(KeyValuePair::new(&format!("key{:02}", i), &format!("v{}", round)),
+                    None).unwrap();
+            }
+        }
+        for i in 0..10 {
+            store.delete(format!("key{:02}", i).as_bytes()).unwrap();
+        }
+        store.flush().unwrap();
+        store.shared_.request_compaction();
+        wait_for_compaction(&store);
+        // 400 puts and 10 deletes were written, but only the latest version
+        // of each key is left in the tables once merged all the way down
+        let deadline = Instant::now() + Duration::from_secs(5);
+        loop {
+            let version = store.shared_.current();
+            let records: u64 = version.levels.iter().flatten().map(|t| t.record_count()).sum();
+            if records <= 30 {
+                break;
+            }
+            assert!(Instant::now() < deadline, "{} records left", records);
+            store.shared_.request_compaction();
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(store.get(b"key05").unwrap(), None);
+        assert_eq!(store.get(b"key15").unwrap().unwrap().value(), "v19");
+        // Otherwise a compaction still running may write or remove tables
+        // while they are counted
+        wait_for_compaction(&store);
+        store.stop_compactor();
+        let tables = fs::read_dir(dir).unwrap()
+            .filter(|e| e.as_ref().unwrap().file_name().to_string_lossy().ends_with(".sst"))
+            .count();
+        assert_eq!(tables, store.table_counts().iter().sum::<usize>());
+    }
+
+    #[test]
+    fn test_stray_tables_are_removed() {
+        let dir = "/tmp/test_lsm_stray";
+        let mut store = fresh_store(dir, tiny());
+        store.put(KeyValuePair::new("one", "uno"), None).unwrap();
+        drop(store);
+        fs::write(format!("{}/999999.sst", dir), b"half a table").unwrap();
+        let reopened = LsmStore::open("test", dir).unwrap();
+        assert!(!std::path::Path::new(&format!("{}/999999.sst", dir)).exists());
+        assert_eq!(reopened.get(b"one").unwrap().unwrap().value(), "uno");
+    }
+
+    #[test]
+    fn test_destroy() {
+        let dir = "/tmp/test_lsm_destroy";
+        let mut store = fresh_store(dir, tiny());
+        for i in 0..50 {
+            store.put(KeyValuePair::new(&format!("key{}", i), "value"), None).unwrap();
+        }
+        store.destroy().unwrap();
+        assert!(!std::path::Path::new(dir).exists());
+        assert!(store.put(KeyValuePair::new("more", "value"), None).is_err());
+    }
+}
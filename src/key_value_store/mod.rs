@@ -1,4 +1,18 @@
 pub mod key_value_pair;
+#[allow(clippy::module_inception)]
 pub mod key_value_store;
 pub mod filestore;
-mod errors;
\ No newline at end of file
+pub mod snapshot;
+pub mod write_ahead_log;
+pub mod errors;
+pub mod eviction;
+pub mod key_pattern;
+pub mod sharded_store;
+pub mod storage_engine;
+pub mod disk_store;
+pub mod sstable;
+pub mod lsm_store;
+pub mod restore;
+pub mod text_format;
+pub mod history;
+
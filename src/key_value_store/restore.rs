@@ -0,0 +1,169 @@
+use super::key_value_store::{now_ms, KeyValueStore, StoredEntry};
+use super::storage_engine::StorageEngine;
+use crate::key_value_store::errors::RWError;
+
+/// How a backup is combined with the pairs already in a store
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum RestoreMode {
+    /// The store ends up holding exactly what the backup holds
+    #[default]
+    Replace,
+    /// Pairs in the backup are written over live pairs with the same key, and
+    /// every other live pair is kept
+    MergeBackupWins,
+    /// Only pairs whose keys are not live are brought back
+    MergeLiveWins,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct RestoreOptions {
+    pub mode: RestoreMode,
+    /// Only restores keys starting with this, leaving every other key in the
+    /// store as it is, even when replacing
+    pub prefix: Option<Vec<u8>>,
+}
+
+impl RestoreOptions {
+    fn covers(&self, key: &[u8]) -> bool {
+        self.prefix.as_ref().is_none_or(|p| key.starts_with(p))
+    }
+}
+
+/// How many live keys a restore adds, overwrites and removes
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct RestoreCounts {
+    pub added: u64,
+    pub overwritten: u64,
+    pub removed: u64,
+}
+
+impl RestoreCounts {
+    pub fn add(&mut self, other: &RestoreCounts) {
+        self.added += other.added;
+        self.overwritten += other.overwritten;
+        self.removed += other.removed;
+    }
+}
+
+/// What restoring a backup changes in one engine, see `plan_restore`
+pub struct RestorePlan<'a> {
+    /// Pairs from the backup to write, in key order
+    pub puts: Vec<StoredEntry<'a>>,
+    /// Live keys to delete, in key order
+    pub deletes: Vec<Vec<u8>>,
+    pub counts: RestoreCounts,
+}
+
+/// Works out what restoring `backup` into `live` would change, without
+/// changing anything. Neither side's expired pairs count: they are not
+/// restored, and are not counted as removed.
+pub fn plan_restore<'a>(live: &dyn StorageEngine, backup: &'a KeyValueStore,
+        options: &RestoreOptions) -> Result<RestorePlan<'a>, RWError> {
+    let now = now_ms();
+    let mut plan = RestorePlan {
+        puts: Vec::new(),
+        deletes: Vec::new(),
+        counts: RestoreCounts::default(),
+    };
+    for entry in backup.iter() {
+        if !options.covers(entry.key) || entry.expires_at.is_some_and(|t| t <= now) {
+            continue;
+        }
+        if live.get(entry.key)?.is_none() {
+            plan.counts.added += 1;
+        } else if options.mode == RestoreMode::MergeLiveWins {
+            continue;
+        } else {
+            plan.counts.overwritten += 1;
+        }
+        plan.puts.push(entry);
+    }
+    if options.mode == RestoreMode::Replace {
+        for key in live.keys() {
+            let key = key?;
+            // Checking the backup first saves reading most live values
+            if options.covers(&key) && backup.get(&key).is_none()
+                    && live.get(&key)?.is_some() {
+                plan.counts.removed += 1;
+                plan.deletes.push(key);
+            }
+        }
+    }
+    Ok(plan)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::key_value_store::key_value_pair::KeyValuePair;
+
+    fn store(pairs: &[(&str, &str)]) -> KeyValueStore {
+        let mut store = KeyValueStore::new("test");
+        for (k, v) in pairs {
+            store.add(KeyValuePair::new(k, v));
+        }
+        store
+    }
+
+    fn counts(added: u64, overwritten: u64, removed: u64) -> RestoreCounts {
+        RestoreCounts { added, overwritten, removed }
+    }
+
+    fn put_keys(plan: &RestorePlan) -> Vec<String> {
+        plan.puts.iter().map(|e| String::from_utf8_lossy(e.key).into_owned()).collect()
+    }
+
+    #[test]
+    fn test_plan_modes() {
+        let live = store(&[("a", "live"), ("b", "live"), ("c", "live")]);
+        let backup = store(&[("b", "backup"), ("c", "backup"), ("d", "backup")]);
+
+        let plan = plan_restore(&live, &backup, &RestoreOptions::default()).unwrap();
+        assert_eq!(plan.counts, counts(1, 2, 1));
+        assert_eq!(put_keys(&plan), vec!["b", "c", "d"]);
+        assert_eq!(plan.deletes, vec![b"a".to_vec()]);
+
+        let plan = plan_restore(&live, &backup, &RestoreOptions {
+            mode: RestoreMode::MergeBackupWins,
+            prefix: None,
+        }).unwrap();
+        assert_eq!(plan.counts, counts(1, 2, 0));
+        assert_eq!(put_keys(&plan), vec!["b", "c", "d"]);
+        assert!(plan.deletes.is_empty());
+
+        let plan = plan_restore(&live, &backup, &RestoreOptions {
+            mode: RestoreMode::MergeLiveWins,
+            prefix: None,
+        }).unwrap();
+        assert_eq!(plan.counts, counts(1, 0, 0));
+        assert_eq!(put_keys(&plan), vec!["d"]);
+    }
+
+    #[test]
+    fn test_plan_prefix() {
+        let live = store(&[("user:1", "live"), ("user:2", "live"), ("order:1", "live")]);
+        let backup = store(&[("user:1", "backup"), ("user:3", "backup"), ("order:2", "backup")]);
+        let plan = plan_restore(&live, &backup, &RestoreOptions {
+            mode: RestoreMode::Replace,
+            prefix: Some(b"user:".to_vec()),
+        }).unwrap();
+        // "order:" keys are left alone on both sides
+        assert_eq!(plan.counts, counts(1, 1, 1));
+        assert_eq!(put_keys(&plan), vec!["user:1", "user:3"]);
+        assert_eq!(plan.deletes, vec![b"user:2".to_vec()]);
+    }
+
+    #[test]
+    fn test_plan_skips_expired() {
+        let mut live = store(&[("a", "live")]);
+        live.put(KeyValuePair::new("gone", "live"), Some(1));
+        let mut backup = store(&[("b", "backup")]);
+        backup.put(KeyValuePair::new("a", "backup"), Some(1));
+        let plan = plan_restore(&live, &backup, &RestoreOptions::default()).unwrap();
+        // The backup's "a" has expired, so the live one is removed, and the
+        // expired live "gone" is neither removed nor counted
+        assert_eq!(plan.counts, counts(1, 0, 1));
+        assert_eq!(put_keys(&plan), vec!["b"]);
+        assert_eq!(plan.deletes, vec![b"a".to_vec()]);
+    }
+}
@@ -0,0 +1,454 @@
+use std::fs;
+use std::ops::Bound;
+use std::path::Path;
+use std::sync::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+use super::disk_store::DiskStore;
+use super::filestore::{self, WriteProgress};
+use super::key_pattern::KeyPattern;
+use super::key_value_pair::KeyValuePair;
+use super::key_value_store::{KeyValueStore, ScanPage};
+use super::lsm_store::LsmStore;
+use super::storage_engine::{EngineKind, FrozenEngine, StorageEngine};
+use super::text_format::TextFormat;
+use crate::key_value_store::errors::{ErrorKind, RWError};
+use log::warn;
+
+/// The number of shards a store is split into unless asked otherwise
+pub const DEFAULT_SHARD_COUNT: usize = 16;
+
+/// The file, or directory for the lsm engine, a shard of an on-disk store is
+/// kept in, inside the store's directory
+fn shard_path(dir: &str, engine: &EngineKind, index: usize) -> String {
+    match engine {
+        EngineKind::Lsm => format!("{}/shard-{}", dir, index),
+        _ => format!("{}/shard-{}.log", dir, index),
+    }
+}
+
+type Shard = RwLock<Box<dyn StorageEngine>>;
+
+/// A key value store split by key hash into shards, each its own
+/// `StorageEngine` behind its own lock, so that requests for keys in
+/// different shards do not wait on each other.
+///
+/// Requests for a single key lock only that key's shard. Scans lock one
+/// shard at a time and merge the results, so they are not a point-in-time
+/// view across shards. Anything that needs every shard at once (backups,
+/// restores) locks them all in index order, which is the only order more than
+/// one shard may be locked in.
+pub struct ShardedStore {
+    name_: String,
+    shards_: Vec<Shard>,
+}
+
+impl ShardedStore {
+    /// Creates an empty store held in memory
+    pub fn new(name: &str, shard_count: usize) -> ShardedStore {
+        ShardedStore {
+            name_: name.to_string(),
+            shards_: (0..shard_count.max(1))
+                .map(|_| RwLock::new(Box::new(KeyValueStore::new(name)) as Box<dyn StorageEngine>))
+                .collect(),
+        }
+    }
+
+    /// Splits the pairs in `store` across `shard_count` shards held in
+    /// memory, keeping their versions
+    pub fn from_store(store: &KeyValueStore, shard_count: usize) -> ShardedStore {
+        let mut shards: Vec<KeyValueStore> = (0..shard_count.max(1))
+            .map(|_| {
+                let mut shard = KeyValueStore::new(store.name());
+                shard.advance_next_version(store.next_version());
+                shard
+            })
+            .collect();
+        for entry in store.iter() {
+            let index = crc32fast::hash(entry.key) as usize % shards.len();
+            shards[index].put_entry(entry);
+        }
+        ShardedStore {
+            name_: store.name().to_string(),
+            shards_: shards.into_iter()
+                .map(|s| RwLock::new(Box::new(s) as Box<dyn StorageEngine>))
+                .collect(),
+        }
+    }
+
+    /// Opens the store kept on disk in `dir`, one `DiskStore` or `LsmStore`
+    /// per shard depending on `engine`, creating it if it does not exist. A
+    /// store already in `dir` keeps the shard count it was created with,
+    /// since keys cannot move between shards.
+    pub fn open_on_disk(name: &str, dir: &str, engine: EngineKind, shard_count: usize)
+            -> Result<ShardedStore, RWError> {
+        if engine == EngineKind::Memory {
+            return Err(RWError {
+                kind_: ErrorKind::FileOpenError,
+                context_: "The memory engine is not kept on disk".to_string(),
+            });
+        }
+        if let Err(e) = fs::create_dir_all(dir) {
+            return Err(RWError {
+                kind_: ErrorKind::FileOpenError,
+                context_: e.to_string(),
+            });
+        }
+        let mut existing = 0;
+        while Path::new(&shard_path(dir, &engine, existing)).exists() {
+            existing += 1;
+        }
+        let count = match existing {
+            0 => shard_count.max(1),
+            n => {
+                if n != shard_count {
+                    warn!("Store {:?} has {:?} shards rather than {:?}; keeping {:?}",
+                        name, n, shard_count, n);
+                }
+                n
+            }
+        };
+        let mut shards = Vec::with_capacity(count);
+        for i in 0..count {
+            let path = shard_path(dir, &engine, i);
+            let shard: Box<dyn StorageEngine> = match engine {
+                EngineKind::Lsm => Box::new(LsmStore::open(name, &path)?),
+                _ => Box::new(DiskStore::open(name, &path)?),
+            };
+            shards.push(RwLock::new(shard));
+        }
+        // The store exists for as long as its files do
+        filestore::sync_parent_dir(&shard_path(dir, &engine, 0))?;
+        filestore::sync_parent_dir(dir)?;
+        Ok(ShardedStore {
+            name_: name.to_string(),
+            shards_: shards,
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        self.name_.as_str()
+    }
+
+    pub fn shard_count(&self) -> usize {
+        self.shards_.len()
+    }
+
+    /// The index of the shard holding `key`. Stable across restarts for a
+    /// given shard count.
+    pub fn shard_index(&self, key: &[u8]) -> usize {
+        crc32fast::hash(key) as usize % self.shards_.len()
+    }
+
+    pub fn shard(&self, index: usize) -> &Shard {
+        &self.shards_[index]
+    }
+
+    pub fn shard_for(&self, key: &[u8]) -> &Shard {
+        self.shard(self.shard_index(key))
+    }
+
+    /// Read locks every shard, in index order
+    pub fn read_all(&self) -> Vec<RwLockReadGuard<'_, Box<dyn StorageEngine>>> {
+        self.shards_.iter().map(|s| s.read().unwrap()).collect()
+    }
+
+    /// Write locks every shard, in index order
+    pub fn write_all(&self) -> Vec<RwLockWriteGuard<'_, Box<dyn StorageEngine>>> {
+        self.shards_.iter().map(|s| s.write().unwrap()).collect()
+    }
+
+    /// Write locks the shards at `indices`, which must be in increasing
+    /// order, so that locks are always taken in index order
+    pub fn write_shards(&self, indices: &[usize])
+            -> Vec<RwLockWriteGuard<'_, Box<dyn StorageEngine>>> {
+        indices.iter().map(|&i| self.shards_[i].write().unwrap()).collect()
+    }
+
+    /// Splits the pairs in `store` into one store per shard, holding the
+    /// pairs that belong in that shard
+    pub fn split(&self, store: &KeyValueStore) -> Vec<KeyValueStore> {
+        let mut parts: Vec<KeyValueStore> = self.shards_.iter()
+            .map(|_| KeyValueStore::new(&self.name_))
+            .collect();
+        for entry in store.iter() {
+            parts[self.shard_index(entry.key)].put_entry(entry);
+        }
+        parts
+    }
+
+    /// Replaces the contents of every shard with its part of a store split
+    /// by `split`. Takes the guards returned by `write_all`.
+    pub fn replace_with(&self, shards: &mut [RwLockWriteGuard<'_, Box<dyn StorageEngine>>],
+            parts: &[KeyValueStore]) -> Result<(), RWError> {
+        for (shard, part) in shards.iter_mut().zip(parts) {
+            shard.load(part)?;
+        }
+        Ok(())
+    }
+
+    /// Deletes anything the shards keep outside of memory, see
+    /// `StorageEngine::destroy`. Waits for any request still holding a shard
+    /// to finish first.
+    pub fn destroy(&self) -> Result<(), RWError> {
+        for mut shard in self.write_all() {
+            shard.destroy()?;
+        }
+        Ok(())
+    }
+
+    /// The number of pairs in every shard, see `StorageEngine::len`
+    pub fn len(&self) -> usize {
+        self.shards_.iter().map(|s| s.read().unwrap().len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The estimated memory used by every shard, see
+    /// `StorageEngine::memory_usage`
+    pub fn memory_usage(&self) -> u64 {
+        self.shards_.iter().map(|s| s.read().unwrap().memory_usage()).sum()
+    }
+
+    pub fn get(&self, key: impl AsRef<[u8]>) -> Result<Option<KeyValuePair>, RWError> {
+        let key = key.as_ref();
+        self.shard_for(key).read().unwrap().get(key)
+    }
+
+    /// Works like `KeyValueStore::scan_range` over every shard
+    pub fn scan_range(&self, start: Bound<&[u8]>, end: Bound<&[u8]>, limit: usize,
+            reverse: bool) -> Result<Vec<KeyValuePair>, RWError> {
+        let mut pairs = Vec::new();
+        for shard in &self.shards_ {
+            pairs.extend(shard.read().unwrap().scan_range(start, end, limit, reverse)?);
+        }
+        if reverse {
+            pairs.sort_by(|a, b| b.key().cmp(a.key()));
+        } else {
+            pairs.sort_by(|a, b| a.key().cmp(b.key()));
+        }
+        pairs.truncate(limit);
+        Ok(pairs)
+    }
+
+    /// Works like `KeyValueStore::scan` over every shard. `max_examined` is
+    /// split evenly between the shards.
+    pub fn scan(&self, pattern: &KeyPattern, resume_after: Option<&[u8]>, count: usize,
+            max_examined: usize) -> Result<ScanPage, RWError> {
+        let per_shard = (max_examined / self.shards_.len()).max(1);
+        let mut pages = Vec::with_capacity(self.shards_.len());
+        for shard in &self.shards_ {
+            pages.push(shard.read().unwrap().scan(pattern, resume_after, count, per_shard)?);
+        }
+        // Every shard has looked at all of its keys up to the smallest key a
+        // shard stopped at, so only matches up to there can be returned
+        // without skipping any.
+        let examined_up_to = pages.iter()
+            .filter_map(|p| p.resume_after.clone())
+            .min();
+        let mut pairs: Vec<KeyValuePair> = pages.into_iter()
+            .flat_map(|p| p.pairs)
+            .filter(|p| examined_up_to.as_ref().is_none_or(|k| p.key() <= k.as_slice()))
+            .collect();
+        pairs.sort_by(|a, b| a.key().cmp(b.key()));
+        if pairs.len() > count {
+            pairs.truncate(count);
+            let last = pairs.last().map(|p| p.key().to_vec());
+            return Ok(ScanPage { pairs, resume_after: last });
+        }
+        Ok(ScanPage { pairs, resume_after: examined_up_to })
+    }
+
+    /// Freezes every shard at the same point in time, see
+    /// `StorageEngine::freeze`. Writes to the store only wait while the
+    /// shards are frozen, which is cheap.
+    pub fn freeze(&self) -> Result<FrozenShards, RWError> {
+        let shards = self.read_all();
+        let mut frozen = Vec::with_capacity(shards.len());
+        for shard in shards.iter() {
+            frozen.push(shard.freeze()?);
+        }
+        Ok(FrozenShards {
+            name_: self.name_.clone(),
+            shards_: frozen,
+        })
+    }
+
+    /// Writes every shard to a single backup, see
+    /// `KeyValueStore::write_to_file`, as of the time it was called. Writes
+    /// to the store go ahead while the backup is written.
+    pub fn write_to_file(&self, target_file: &str) -> Result<(), RWError> {
+        self.freeze()?.write_to_file(target_file, &WriteProgress::default())
+    }
+}
+
+/// Every shard of a store as it was at one point in time, see
+/// `ShardedStore::freeze`
+pub struct FrozenShards {
+    name_: String,
+    shards_: Vec<Box<dyn FrozenEngine>>,
+}
+
+impl FrozenShards {
+    /// The number of pairs, including expired ones
+    pub fn len(&self) -> usize {
+        self.shards_.iter().map(|s| s.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Writes every shard to a single backup, see
+    /// `KeyValueStore::write_to_file`, keeping `progress` up to date
+    pub fn write_to_file(&self, target_file: &str, progress: &WriteProgress)
+            -> Result<(), RWError> {
+        filestore::write_entries_to_file(target_file, &self.name_, self.len() as u64,
+            self.shards_.iter().flat_map(|s| s.snapshot()), progress)
+    }
+
+    /// Exports every shard to a single text file, see
+    /// `filestore::export_entries_to_file`
+    pub fn export_to_file(&self, target_file: &str, format: TextFormat) -> Result<u64, RWError> {
+        filestore::export_entries_to_file(target_file, format,
+            self.shards_.iter().flat_map(|s| s.snapshot()))
+    }
+
+    /// Writes what changed since `base`, the contents of the backup
+    /// `base_id`, to an incremental backup, see
+    /// `filestore::write_incremental_to_file`
+    pub fn write_incremental_to_file(&self, target_file: &str, base_id: &str,
+            base: &KeyValueStore, progress: &WriteProgress) -> Result<(), RWError> {
+        filestore::write_incremental_to_file(target_file, &self.name_, base_id, base,
+            self.shards_.iter().flat_map(|s| s.snapshot()), progress)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filled(shard_count: usize, keys: usize) -> ShardedStore {
+        let store = ShardedStore::new("test", shard_count);
+        for i in 0..keys {
+            let key = format!("key{:04}", i);
+            store.shard_for(key.as_bytes()).write().unwrap()
+                .put(KeyValuePair::new(&key, "value"), None).unwrap();
+        }
+        store
+    }
+
+    #[test]
+    fn test_sharding() {
+        let store = filled(8, 1000);
+        assert_eq!(store.len(), 1000);
+        assert!(store.read_all().iter().all(|s| !s.is_empty()));
+        assert_eq!(store.get("key0042").unwrap().unwrap().value(), "value");
+        assert_eq!(store.get("key1000").unwrap(), None);
+
+        let mut single = KeyValueStore::new("test");
+        for entry in store.read_all().iter().flat_map(|s| s.snapshot()) {
+            let (pair, expires_at) = entry.unwrap();
+            single.put(pair, expires_at);
+        }
+        let resharded = ShardedStore::from_store(&single, 3);
+        assert_eq!(resharded.len(), 1000);
+        assert_eq!(resharded.memory_usage(), store.memory_usage());
+    }
+
+    #[test]
+    fn test_scan_range_merges_shards() {
+        let store = filled(8, 100);
+        let keys = |pairs: Result<Vec<KeyValuePair>, RWError>| pairs.unwrap().iter()
+            .map(|p| p.key_text())
+            .collect::<Vec<String>>();
+        assert_eq!(
+            keys(store.scan_range(Bound::Included(b"key0010"), Bound::Excluded(b"key0014"),
+                10, false)),
+            vec!["key0010", "key0011", "key0012", "key0013"]);
+        assert_eq!(
+            keys(store.scan_range(Bound::Unbounded, Bound::Unbounded, 3, true)),
+            vec!["key0099", "key0098", "key0097"]);
+    }
+
+    #[test]
+    fn test_scan_pages_across_shards() {
+        let store = filled(8, 500);
+        let pattern = KeyPattern::Glob(b"key*7".to_vec());
+        for (count, max_examined) in [(10, 10_000), (7, 16), (1000, 8)] {
+            let mut seen = Vec::new();
+            let mut cursor: Option<Vec<u8>> = None;
+            loop {
+                let page = store.scan(&pattern, cursor.as_deref(), count, max_examined)
+                    .unwrap();
+                assert!(page.pairs.len() <= count);
+                seen.extend(page.pairs.iter().map(|p| p.key_text()));
+                match page.resume_after {
+                    None => break,
+                    Some(k) => cursor = Some(k)
+                }
+            }
+            let expected: Vec<String> = (0..500)
+                .map(|i| format!("key{:04}", i))
+                .filter(|k| k.ends_with('7'))
+                .collect();
+            assert_eq!(seen, expected);
+        }
+    }
+
+    #[test]
+    fn test_freeze() {
+        let path = "/tmp/test_sharded_freeze.snap";
+        let store = filled(4, 200);
+        let frozen = store.freeze().unwrap();
+        for i in 0..200 {
+            let key = format!("key{:04}", i);
+            store.shard_for(key.as_bytes()).write().unwrap()
+                .put(KeyValuePair::new(&key, "changed"), None).unwrap();
+        }
+        store.shard_for(b"extra").write().unwrap()
+            .put(KeyValuePair::new("extra", "pair"), None).unwrap();
+
+        let progress = WriteProgress::default();
+        frozen.write_to_file(path, &progress).unwrap();
+        let restored = filestore::read_from_file(path).unwrap();
+        assert_eq!(restored.len(), 200);
+        assert_eq!(restored.get("extra"), None);
+        assert!(restored.iter().all(|e| e.value != b"changed"));
+        assert_eq!(progress.records(), 200);
+        assert_eq!(progress.bytes(), fs::metadata(path).unwrap().len());
+    }
+
+    #[test]
+    fn test_open_on_disk() {
+        for engine in [EngineKind::Disk, EngineKind::Lsm] {
+            let dir = format!("/tmp/test_sharded_on_disk_{}", engine);
+            let dir = dir.as_str();
+            let _ = fs::remove_dir_all(dir);
+            let store = ShardedStore::open_on_disk("test", dir, engine.clone(), 4).unwrap();
+            for i in 0..100 {
+                let key = format!("key{:04}", i);
+                store.shard_for(key.as_bytes()).write().unwrap()
+                    .put(KeyValuePair::new(&key, "value"), None).unwrap();
+            }
+            drop(store);
+
+            // The shard count the store was created with wins
+            let reopened = ShardedStore::open_on_disk("test", dir, engine.clone(), 8).unwrap();
+            assert_eq!(reopened.shard_count(), 4);
+            assert_eq!(reopened.len(), 100);
+            assert_eq!(reopened.get("key0042").unwrap().unwrap().value(), "value");
+
+            let mut replacement = KeyValueStore::new("other");
+            replacement.add(KeyValuePair::new("only", "one"));
+            let parts = reopened.split(&replacement);
+            let mut shards = reopened.write_all();
+            reopened.replace_with(&mut shards, &parts).unwrap();
+            drop(shards);
+            assert_eq!(reopened.len(), 1);
+            reopened.destroy().unwrap();
+            assert!(!Path::new(&shard_path(dir, &engine, 0)).exists());
+        }
+    }
+}
@@ -0,0 +1,398 @@
+//! Streaming snapshot format for key value stores.
+//!
+//! A snapshot file is laid out as:
+//!
+//! ```text
+//! magic         8 bytes    "CCSNAPSH"
+//! version       u32 LE     FORMAT_VERSION
+//! name length   u32 LE
+//! name          UTF-8 bytes
+//! base length   u32 LE     (from version 2)
+//! base          UTF-8 bytes
+//! record count  u64 LE
+//! records       [length: u32 LE][SnapshotRecord protobuf] * record count
+//! checksum      u32 LE     crc32 of every byte before it
+//! ```
+//!
+//! Records are written and read one at a time, so neither side ever needs to
+//! hold the encoded form of the whole store in memory.
+//!
+//! A full backup has an empty base. An incremental backup names the backup it
+//! is based on, and holds only the pairs that changed since along with a
+//! deletion record for each key that was removed.
+
+use std::io::{self, Read, Write};
+
+use crate::{
+    key_value_store::errors::{ErrorKind, RWError},
+    proto::{DataType, SnapshotRecord},
+};
+use prost::Message;
+
+pub const MAGIC: &[u8; 8] = b"CCSNAPSH";
+pub const FORMAT_VERSION: u32 = 2;
+/// The last version without a base backup in the header
+const FULL_ONLY_VERSION: u32 = 1;
+
+fn write_error(e: io::Error) -> RWError {
+    RWError {
+        kind_: ErrorKind::FileWriteError,
+        context_: e.to_string(),
+    }
+}
+
+fn read_error(e: io::Error) -> RWError {
+    if e.kind() == io::ErrorKind::UnexpectedEof {
+        return RWError {
+            kind_: ErrorKind::TruncatedDataError,
+            context_: e.to_string(),
+        };
+    }
+    RWError {
+        kind_: ErrorKind::FileReadError,
+        context_: e.to_string(),
+    }
+}
+
+/// Writes a snapshot to any `Write` implementation. The number of records has
+/// to be known up front since it is part of the header; `finish` checks that
+/// exactly that many were written.
+pub struct SnapshotWriter<W: Write> {
+    inner_: W,
+    hasher_: crc32fast::Hasher,
+    expected_records_: u64,
+    written_records_: u64,
+}
+
+impl<W: Write> SnapshotWriter<W> {
+    pub fn new(inner: W, name: &str, record_count: u64) -> Result<Self, RWError> {
+        Self::new_incremental(inner, name, "", record_count)
+    }
+
+    /// Starts an incremental backup based on the backup `base`, or a full
+    /// one if `base` is empty
+    pub fn new_incremental(inner: W, name: &str, base: &str, record_count: u64)
+            -> Result<Self, RWError> {
+        let mut writer = SnapshotWriter {
+            inner_: inner,
+            hasher_: crc32fast::Hasher::new(),
+            expected_records_: record_count,
+            written_records_: 0,
+        };
+        writer.write_hashed(MAGIC)?;
+        writer.write_hashed(&FORMAT_VERSION.to_le_bytes())?;
+        writer.write_hashed(&(name.len() as u32).to_le_bytes())?;
+        writer.write_hashed(name.as_bytes())?;
+        writer.write_hashed(&(base.len() as u32).to_le_bytes())?;
+        writer.write_hashed(base.as_bytes())?;
+        writer.write_hashed(&record_count.to_le_bytes())?;
+        Ok(writer)
+    }
+
+    fn write_hashed(&mut self, bytes: &[u8]) -> Result<(), RWError> {
+        self.hasher_.update(bytes);
+        self.inner_.write_all(bytes).map_err(write_error)
+    }
+
+    pub fn write_record(
+        &mut self,
+        key: &[u8],
+        value: &[u8],
+        data_type: DataType,
+        expires_at: Option<u64>,
+    ) -> Result<(), RWError> {
+        let record = SnapshotRecord {
+            key: key.to_vec(),
+            value: value.to_vec(),
+            expires_at_ms: expires_at,
+            // STRING is by far the most common type, so leave it implied
+            data_type: match data_type {
+                DataType::String => None,
+                t => Some(t.into()),
+            },
+            deleted: false,
+        };
+        self.write_snapshot_record(&record)
+    }
+
+    /// Records that `key` was deleted since the base backup
+    pub fn write_deletion(&mut self, key: &[u8]) -> Result<(), RWError> {
+        self.write_snapshot_record(&SnapshotRecord {
+            key: key.to_vec(),
+            deleted: true,
+            ..Default::default()
+        })
+    }
+
+    fn write_snapshot_record(&mut self, record: &SnapshotRecord) -> Result<(), RWError> {
+        let payload = record.encode_to_vec();
+        self.write_hashed(&(payload.len() as u32).to_le_bytes())?;
+        self.write_hashed(&payload)?;
+        self.written_records_ += 1;
+        Ok(())
+    }
+
+    /// Writes the trailing checksum and flushes the underlying writer,
+    /// returning it so the caller can sync or close it.
+    pub fn finish(mut self) -> Result<W, RWError> {
+        if self.written_records_ != self.expected_records_ {
+            return Err(RWError {
+                kind_: ErrorKind::FileWriteError,
+                context_: format!(
+                    "Header promised {} records but {} were written",
+                    self.expected_records_, self.written_records_
+                ),
+            });
+        }
+        let checksum = self.hasher_.clone().finalize();
+        self.inner_
+            .write_all(&checksum.to_le_bytes())
+            .map_err(write_error)?;
+        self.inner_.flush().map_err(write_error)?;
+        Ok(self.inner_)
+    }
+}
+
+/// Reads a snapshot from any `Read` implementation. The header is validated
+/// in `new`; records are then pulled one at a time with `next_record`, and the
+/// checksum is verified once the last record has been read.
+pub struct SnapshotReader<R: Read> {
+    inner_: R,
+    hasher_: crc32fast::Hasher,
+    name_: String,
+    base_: String,
+    record_count_: u64,
+    read_records_: u64,
+}
+
+impl<R: Read> SnapshotReader<R> {
+    pub fn new(inner: R) -> Result<Self, RWError> {
+        let mut reader = SnapshotReader {
+            inner_: inner,
+            hasher_: crc32fast::Hasher::new(),
+            name_: String::new(),
+            base_: String::new(),
+            record_count_: 0,
+            read_records_: 0,
+        };
+        let mut magic = [0u8; 8];
+        match reader.inner_.read(&mut magic) {
+            Ok(0) => {
+                return Err(RWError {
+                    kind_: ErrorKind::FileReadError,
+                    context_: String::from("Empty file!"),
+                })
+            }
+            Ok(n) => {
+                reader.inner_.read_exact(&mut magic[n..]).map_err(read_error)?;
+            }
+            Err(e) => return Err(read_error(e)),
+        }
+        if &magic != MAGIC {
+            return Err(RWError {
+                kind_: ErrorKind::DataDecodeError,
+                context_: String::from("Not a snapshot file"),
+            });
+        }
+        reader.hasher_.update(&magic);
+
+        let version = reader.read_u32()?;
+        if version != FORMAT_VERSION && version != FULL_ONLY_VERSION {
+            return Err(RWError {
+                kind_: ErrorKind::UnsupportedVersionError,
+                context_: format!("Snapshot format version {}", version),
+            });
+        }
+        reader.name_ = reader.read_string()?;
+        if version != FULL_ONLY_VERSION {
+            reader.base_ = reader.read_string()?;
+        }
+        reader.record_count_ = reader.read_u64()?;
+        Ok(reader)
+    }
+
+    fn read_hashed(&mut self, len: usize) -> Result<Vec<u8>, RWError> {
+        // Read through `take` rather than allocating `len` bytes up front so
+        // that a corrupt length cannot make us allocate gigabytes.
+        let mut buf = Vec::new();
+        let n = (&mut self.inner_)
+            .take(len as u64)
+            .read_to_end(&mut buf)
+            .map_err(read_error)?;
+        if n < len {
+            return Err(RWError {
+                kind_: ErrorKind::TruncatedDataError,
+                context_: format!("Expected {} bytes, got {}", len, n),
+            });
+        }
+        self.hasher_.update(&buf);
+        Ok(buf)
+    }
+
+    fn read_string(&mut self) -> Result<String, RWError> {
+        let len = self.read_u32()? as usize;
+        let bytes = self.read_hashed(len)?;
+        String::from_utf8(bytes).map_err(|e| RWError {
+            kind_: ErrorKind::DataDecodeError,
+            context_: e.to_string(),
+        })
+    }
+
+    fn read_u32(&mut self) -> Result<u32, RWError> {
+        let bytes = self.read_hashed(4)?;
+        Ok(u32::from_le_bytes(bytes.try_into().unwrap()))
+    }
+
+    fn read_u64(&mut self) -> Result<u64, RWError> {
+        let bytes = self.read_hashed(8)?;
+        Ok(u64::from_le_bytes(bytes.try_into().unwrap()))
+    }
+
+    pub fn name(&self) -> &str {
+        self.name_.as_str()
+    }
+
+    /// The backup this one is based on, None for a full backup
+    pub fn base(&self) -> Option<&str> {
+        match self.base_.as_str() {
+            "" => None,
+            b => Some(b),
+        }
+    }
+
+    pub fn record_count(&self) -> u64 {
+        self.record_count_
+    }
+
+    /// Returns the next record, or None once every record has been read and
+    /// the trailing checksum has been verified.
+    pub fn next_record(&mut self) -> Result<Option<SnapshotRecord>, RWError> {
+        if self.read_records_ == self.record_count_ {
+            self.verify_checksum()?;
+            return Ok(None);
+        }
+        let len = self.read_u32()? as usize;
+        let payload = self.read_hashed(len)?;
+        self.read_records_ += 1;
+        match SnapshotRecord::decode(payload.as_slice()) {
+            Ok(record) => Ok(Some(record)),
+            Err(e) => Err(RWError {
+                kind_: ErrorKind::DataDecodeError,
+                context_: e.to_string(),
+            }),
+        }
+    }
+
+    fn verify_checksum(&mut self) -> Result<(), RWError> {
+        let mut trailer = [0u8; 4];
+        self.inner_.read_exact(&mut trailer).map_err(read_error)?;
+        let expected = u32::from_le_bytes(trailer);
+        let actual = self.hasher_.clone().finalize();
+        if expected != actual {
+            return Err(RWError {
+                kind_: ErrorKind::ChecksumMismatchError,
+                context_: format!("Expected {:#010x}, computed {:#010x}", expected, actual),
+            });
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write_snapshot(pairs: &[(&str, &str)]) -> Vec<u8> {
+        let mut writer = SnapshotWriter::new(Vec::new(), "test", pairs.len() as u64)
+            .expect("Cannot write header!");
+        for (k, v) in pairs {
+            writer.write_record(k.as_bytes(), v.as_bytes(), DataType::String, None).expect("Cannot write record!");
+        }
+        writer.finish().expect("Cannot finish snapshot!")
+    }
+
+    fn read_all(bytes: &[u8]) -> Result<Vec<SnapshotRecord>, RWError> {
+        let mut reader = SnapshotReader::new(bytes)?;
+        let mut records = Vec::new();
+        while let Some(r) = reader.next_record()? {
+            records.push(r);
+        }
+        Ok(records)
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let bytes = write_snapshot(&[("one", "uno"), ("two", "dos")]);
+        let reader = SnapshotReader::new(bytes.as_slice()).unwrap();
+        assert_eq!(reader.name(), "test");
+        assert_eq!(reader.record_count(), 2);
+        let records = read_all(&bytes).unwrap();
+        assert_eq!(records[0].key, b"one");
+        assert_eq!(records[1].value, b"dos");
+        assert_eq!(records[1].data_type, None);
+    }
+
+    #[test]
+    fn test_incremental() {
+        let mut writer = SnapshotWriter::new_incremental(Vec::new(), "test", "monday", 2).unwrap();
+        writer.write_record(b"one", b"uno", DataType::String, None).unwrap();
+        writer.write_deletion(b"two").unwrap();
+        let bytes = writer.finish().unwrap();
+        let reader = SnapshotReader::new(bytes.as_slice()).unwrap();
+        assert_eq!(reader.base(), Some("monday"));
+        let records = read_all(&bytes).unwrap();
+        assert!(!records[0].deleted);
+        assert!(records[1].deleted);
+        assert_eq!(records[1].key, b"two");
+
+        assert_eq!(SnapshotReader::new(write_snapshot(&[]).as_slice()).unwrap().base(), None);
+    }
+
+    #[test]
+    fn test_version_1() {
+        // Written before backups had a base
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend_from_slice(&FULL_ONLY_VERSION.to_le_bytes());
+        bytes.extend_from_slice(&4u32.to_le_bytes());
+        bytes.extend_from_slice(b"test");
+        bytes.extend_from_slice(&0u64.to_le_bytes());
+        bytes.extend_from_slice(&crc32fast::hash(&bytes).to_le_bytes());
+        let reader = SnapshotReader::new(bytes.as_slice()).unwrap();
+        assert_eq!(reader.name(), "test");
+        assert_eq!(reader.base(), None);
+        assert!(read_all(&bytes).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_truncated_snapshot() {
+        let bytes = write_snapshot(&[("one", "uno"), ("two", "dos")]);
+        // Cutting anywhere, including just the checksum, must be detected
+        for cut in [bytes.len() - 1, bytes.len() - 6, 12] {
+            match read_all(&bytes[..cut]) {
+                Ok(_) => panic!("Expected failure for cut at {}!", cut),
+                Err(e) => assert_eq!(e.kind_, ErrorKind::TruncatedDataError),
+            }
+        }
+    }
+
+    #[test]
+    fn test_corrupted_snapshot() {
+        let mut bytes = write_snapshot(&[("one", "uno"), ("two", "dos")]);
+        let last_value_byte = bytes.len() - 5;
+        bytes[last_value_byte] ^= 0x01;
+        match read_all(&bytes) {
+            Ok(_) => panic!("Expected failure!"),
+            Err(e) => assert_eq!(e.kind_, ErrorKind::ChecksumMismatchError),
+        }
+    }
+
+    #[test]
+    fn test_unsupported_version() {
+        let mut bytes = write_snapshot(&[]);
+        bytes[MAGIC.len()] = 0xff;
+        match read_all(&bytes) {
+            Ok(_) => panic!("Expected failure!"),
+            Err(e) => assert_eq!(e.kind_, ErrorKind::UnsupportedVersionError),
+        }
+    }
+}
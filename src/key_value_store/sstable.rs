@@ -0,0 +1,517 @@
+//! Sorted, immutable table files for `LsmStore`.
+//!
+//! A table file is laid out as:
+//!
+//! ```text
+//! blocks        [WAL frame of a WalRecord] * n, cut into blocks of about
+//!               BLOCK_BYTES, records in key order with one per key
+//! index         SstableIndex protobuf
+//! index offset  u64 LE
+//! index length  u32 LE
+//! checksum      u32 LE     crc32 of the index
+//! magic         8 bytes    "CCSSTAB1"
+//! ```
+//!
+//! Records are framed exactly like the write-ahead log's, so every record
+//! carries its own checksum. Deletes are kept as WAL_OP_DELETE records so
+//! that they hide older versions of the key in other tables.
+//!
+//! A reader keeps the index and bloom filter in memory and reads blocks from
+//! the file as they are needed.
+
+use std::collections::VecDeque;
+use std::fs::{self, File};
+use std::iter::Peekable;
+use std::ops::Bound;
+use std::os::unix::fs::FileExt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use super::filestore;
+use super::write_ahead_log::{decode_frame, encode_frame};
+use crate::{
+    key_value_store::errors::{ErrorKind, RWError},
+    proto::{SstableBlock, SstableIndex, WalRecord},
+};
+use log::{trace, warn};
+use prost::Message;
+
+pub const MAGIC: &[u8; 8] = b"CCSSTAB1";
+/// Index offset, index length, checksum and magic
+const FOOTER_LEN: usize = 24;
+/// Blocks are cut once they grow past this
+const BLOCK_BYTES: usize = 4096;
+/// Bloom filter bits per key, giving about a 1% false positive rate
+const BLOOM_BITS_PER_KEY: usize = 10;
+const BLOOM_HASHES: u32 = 7;
+
+fn write_error(e: std::io::Error) -> RWError {
+    RWError {
+        kind_: ErrorKind::FileWriteError,
+        context_: e.to_string(),
+    }
+}
+
+fn corrupt(path: &str, what: &str) -> RWError {
+    RWError {
+        kind_: ErrorKind::ChecksumMismatchError,
+        context_: format!("{} in {}", what, path),
+    }
+}
+
+/// The two hashes every probe position is derived from
+fn bloom_hashes(key: &[u8]) -> (u64, u64) {
+    let h1 = crc32fast::hash(key) as u64;
+    let mut hasher = crc32fast::Hasher::new_with_initial(0x9E37_79B9);
+    hasher.update(key);
+    // Odd, so that the probes cover every bit
+    (h1, hasher.finalize() as u64 | 1)
+}
+
+fn bloom_bit(h1: u64, h2: u64, i: u32, bit_count: u64) -> u64 {
+    h1.wrapping_add((i as u64).wrapping_mul(h2)) % bit_count
+}
+
+fn build_bloom(keys: &[Vec<u8>]) -> Vec<u8> {
+    // Readers take the bit count from the length of the filter, so it has to
+    // fill whole bytes
+    let bit_count = (keys.len() * BLOOM_BITS_PER_KEY).max(64).next_multiple_of(8) as u64;
+    let mut bits = vec![0u8; (bit_count / 8) as usize];
+    for key in keys {
+        let (h1, h2) = bloom_hashes(key);
+        for i in 0..BLOOM_HASHES {
+            let bit = bloom_bit(h1, h2, i, bit_count);
+            bits[(bit / 8) as usize] |= 1 << (bit % 8);
+        }
+    }
+    bits
+}
+
+/// Writes records from `records` to a new table at `path` until they run out
+/// or the table has grown past `max_bytes`, so that a long run of records
+/// can be split over several tables. Records must come in key order with one
+/// per key. Writes nothing and returns false if there are no records left.
+pub fn write_table<I>(path: &str, records: &mut Peekable<I>, max_bytes: u64)
+        -> Result<bool, RWError>
+where
+    I: Iterator<Item = Result<WalRecord, RWError>>,
+{
+    if records.peek().is_none() {
+        return Ok(false);
+    }
+    filestore::write_file_atomically(path, |out| {
+        let mut index = SstableIndex {
+            bloom_hashes: BLOOM_HASHES,
+            ..Default::default()
+        };
+        let mut keys = Vec::new();
+        let mut offset = 0u64;
+        let mut block = Vec::with_capacity(BLOCK_BYTES * 2);
+        let mut last_key = Vec::new();
+        while offset + (block.len() as u64) < max_bytes {
+            let record = match records.next() {
+                None => break,
+                Some(r) => r?,
+            };
+            if keys.is_empty() {
+                index.first_key = record.key.clone();
+            }
+            block.extend_from_slice(&encode_frame(&record));
+            last_key = record.key.clone();
+            keys.push(record.key);
+            if block.len() >= BLOCK_BYTES {
+                out.write_all(&block).map_err(write_error)?;
+                index.blocks.push(SstableBlock {
+                    last_key: last_key.clone(),
+                    offset,
+                    length: block.len() as u32,
+                });
+                offset += block.len() as u64;
+                block.clear();
+            }
+        }
+        if !block.is_empty() {
+            out.write_all(&block).map_err(write_error)?;
+            index.blocks.push(SstableBlock {
+                last_key,
+                offset,
+                length: block.len() as u32,
+            });
+            offset += block.len() as u64;
+        }
+        index.record_count = keys.len() as u64;
+        index.bloom_bits = build_bloom(&keys);
+        let encoded = index.encode_to_vec();
+        out.write_all(&encoded).map_err(write_error)?;
+        out.write_all(&offset.to_le_bytes()).map_err(write_error)?;
+        out.write_all(&(encoded.len() as u32).to_le_bytes()).map_err(write_error)?;
+        out.write_all(&crc32fast::hash(&encoded).to_le_bytes()).map_err(write_error)?;
+        out.write_all(MAGIC).map_err(write_error)?;
+        trace!("Wrote {:?} records to {:?}", keys.len(), path);
+        Ok(())
+    })?;
+    Ok(true)
+}
+
+/// An open table file. Once a table is no longer part of its store it can
+/// be marked obsolete, and the file is deleted when the last reader lets go
+/// of it.
+pub struct Table {
+    id_: u64,
+    path_: String,
+    file_: File,
+    file_len_: u64,
+    index_: SstableIndex,
+    obsolete_: AtomicBool,
+}
+
+impl Table {
+    pub fn open(id: u64, path: &str) -> Result<Table, RWError> {
+        let file = File::open(path).map_err(|e| RWError {
+            kind_: ErrorKind::FileOpenError,
+            context_: e.to_string(),
+        })?;
+        let file_len = file.metadata().map_err(|e| RWError {
+            kind_: ErrorKind::FileReadError,
+            context_: e.to_string(),
+        })?.len();
+        if file_len < FOOTER_LEN as u64 {
+            return Err(RWError {
+                kind_: ErrorKind::TruncatedDataError,
+                context_: format!("{} is too short to be a table", path),
+            });
+        }
+        let mut footer = [0u8; FOOTER_LEN];
+        read_at(&file, path, &mut footer, file_len - FOOTER_LEN as u64)?;
+        if &footer[16..24] != MAGIC {
+            return Err(RWError {
+                kind_: ErrorKind::DataDecodeError,
+                context_: format!("{} is not a table", path),
+            });
+        }
+        let index_offset = u64::from_le_bytes(footer[0..8].try_into().unwrap());
+        let index_len = u32::from_le_bytes(footer[8..12].try_into().unwrap()) as u64;
+        let crc = u32::from_le_bytes(footer[12..16].try_into().unwrap());
+        if index_offset + index_len + FOOTER_LEN as u64 != file_len {
+            return Err(corrupt(path, "Bad index position"));
+        }
+        let mut encoded = vec![0u8; index_len as usize];
+        read_at(&file, path, &mut encoded, index_offset)?;
+        if crc32fast::hash(&encoded) != crc {
+            return Err(corrupt(path, "Index checksum mismatch"));
+        }
+        let index = SstableIndex::decode(encoded.as_slice()).map_err(|e| RWError {
+            kind_: ErrorKind::DataDecodeError,
+            context_: e.to_string(),
+        })?;
+        Ok(Table {
+            id_: id,
+            path_: path.to_string(),
+            file_: file,
+            file_len_: file_len,
+            index_: index,
+            obsolete_: AtomicBool::new(false),
+        })
+    }
+
+    pub fn id(&self) -> u64 {
+        self.id_
+    }
+
+    /// The size of the file in bytes
+    pub fn size(&self) -> u64 {
+        self.file_len_
+    }
+
+    pub fn first_key(&self) -> &[u8] {
+        &self.index_.first_key
+    }
+
+    pub fn last_key(&self) -> &[u8] {
+        self.index_.blocks.last().map(|b| b.last_key.as_slice()).unwrap_or(&[])
+    }
+
+    pub fn record_count(&self) -> u64 {
+        self.index_.record_count
+    }
+
+    /// Whether any key from `start` to `end`, both included, may be in the
+    /// table
+    pub fn overlaps(&self, start: &[u8], end: &[u8]) -> bool {
+        self.first_key() <= end && self.last_key() >= start
+    }
+
+    /// Has the file deleted once nothing uses the table any more
+    pub fn mark_obsolete(&self) {
+        self.obsolete_.store(true, Ordering::Relaxed);
+    }
+
+    fn may_contain(&self, key: &[u8]) -> bool {
+        let bits = &self.index_.bloom_bits;
+        if bits.is_empty() {
+            return true;
+        }
+        let bit_count = bits.len() as u64 * 8;
+        let (h1, h2) = bloom_hashes(key);
+        (0..self.index_.bloom_hashes).all(|i| {
+            let bit = bloom_bit(h1, h2, i, bit_count);
+            bits[(bit / 8) as usize] & (1 << (bit % 8)) != 0
+        })
+    }
+
+    fn read_block(&self, index: usize) -> Result<Vec<WalRecord>, RWError> {
+        let handle = &self.index_.blocks[index];
+        let mut buf = vec![0u8; handle.length as usize];
+        read_at(&self.file_, &self.path_, &mut buf, handle.offset)?;
+        let mut records = Vec::new();
+        let mut pos = 0;
+        while pos < buf.len() {
+            match decode_frame(&buf[pos..]) {
+                Some((record, frame_len)) => {
+                    records.push(record);
+                    pos += frame_len;
+                }
+                None => return Err(corrupt(&self.path_,
+                    &format!("Bad record in block at offset {}", handle.offset))),
+            }
+        }
+        Ok(records)
+    }
+
+    /// The record for `key`, which may be a delete, if the table has one
+    pub fn get(&self, key: &[u8]) -> Result<Option<WalRecord>, RWError> {
+        if key < self.first_key() || !self.may_contain(key) {
+            return Ok(None);
+        }
+        let block = self.index_.blocks.partition_point(|b| b.last_key.as_slice() < key);
+        if block == self.index_.blocks.len() {
+            return Ok(None);
+        }
+        Ok(self.read_block(block)?.into_iter().find(|r| r.key == key))
+    }
+}
+
+impl Drop for Table {
+    fn drop(&mut self) {
+        if self.obsolete_.load(Ordering::Relaxed) {
+            if let Err(e) = fs::remove_file(&self.path_) {
+                warn!("Cannot remove obsolete table {:?}: {:?}", self.path_, e);
+            }
+        }
+    }
+}
+
+fn read_at(file: &File, path: &str, buf: &mut [u8], offset: u64) -> Result<(), RWError> {
+    file.read_exact_at(buf, offset).map_err(|e| RWError {
+        kind_: ErrorKind::FileReadError,
+        context_: format!("{}: {}", path, e),
+    })
+}
+
+/// Whether `key` is at or after `start`
+pub fn after_start(key: &[u8], start: &Bound<Vec<u8>>) -> bool {
+    match start {
+        Bound::Included(s) => key >= s.as_slice(),
+        Bound::Excluded(s) => key > s.as_slice(),
+        Bound::Unbounded => true,
+    }
+}
+
+/// Whether `key` is at or before `end`
+pub fn before_end(key: &[u8], end: &Bound<Vec<u8>>) -> bool {
+    match end {
+        Bound::Included(e) => key <= e.as_slice(),
+        Bound::Excluded(e) => key < e.as_slice(),
+        Bound::Unbounded => true,
+    }
+}
+
+/// Iterates over the records of a table with keys from `start` to `end`, in
+/// key order or in reverse, reading one block at a time.
+pub struct TableIter {
+    table_: Arc<Table>,
+    start_: Bound<Vec<u8>>,
+    end_: Bound<Vec<u8>>,
+    reverse_: bool,
+    // The next block to read; past either end once there are none left
+    next_block_: isize,
+    records_: VecDeque<WalRecord>,
+    done_: bool,
+}
+
+impl TableIter {
+    pub fn new(table: Arc<Table>, start: Bound<Vec<u8>>, end: Bound<Vec<u8>>, reverse: bool)
+            -> TableIter {
+        let blocks = &table.index_.blocks;
+        let next_block = if reverse {
+            let first_past = match &end {
+                Bound::Unbounded => blocks.len(),
+                Bound::Included(e) | Bound::Excluded(e) => {
+                    blocks.partition_point(|b| b.last_key < *e) + 1
+                }
+            };
+            first_past.min(blocks.len()) as isize - 1
+        } else {
+            match &start {
+                Bound::Unbounded => 0,
+                Bound::Included(s) | Bound::Excluded(s) => {
+                    blocks.partition_point(|b| b.last_key < *s) as isize
+                }
+            }
+        };
+        TableIter {
+            table_: table,
+            start_: start,
+            end_: end,
+            reverse_: reverse,
+            next_block_: next_block,
+            records_: VecDeque::new(),
+            done_: false,
+        }
+    }
+}
+
+impl Iterator for TableIter {
+    type Item = Result<WalRecord, RWError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while !self.done_ {
+            let record = if self.reverse_ {
+                self.records_.pop_back()
+            } else {
+                self.records_.pop_front()
+            };
+            let record = match record {
+                Some(r) => r,
+                None => {
+                    if self.next_block_ < 0
+                            || self.next_block_ as usize >= self.table_.index_.blocks.len() {
+                        self.done_ = true;
+                        return None;
+                    }
+                    match self.table_.read_block(self.next_block_ as usize) {
+                        Ok(records) => self.records_ = records.into(),
+                        Err(e) => {
+                            self.done_ = true;
+                            return Some(Err(e));
+                        }
+                    }
+                    self.next_block_ += if self.reverse_ { -1 } else { 1 };
+                    continue;
+                }
+            };
+            let (in_range, past_range) = if self.reverse_ {
+                (before_end(&record.key, &self.end_), !after_start(&record.key, &self.start_))
+            } else {
+                (after_start(&record.key, &self.start_), !before_end(&record.key, &self.end_))
+            };
+            if past_range {
+                self.done_ = true;
+                return None;
+            }
+            if in_range {
+                return Some(Ok(record));
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proto::WalOp;
+
+    fn record(key: &str, value: &str) -> WalRecord {
+        WalRecord {
+            op: WalOp::Create.into(),
+            key: key.as_bytes().to_vec(),
+            value: value.as_bytes().to_vec(),
+            ..Default::default()
+        }
+    }
+
+    fn write(path: &str, count: usize) -> Table {
+        let mut records = (0..count)
+            .map(|i| Ok(record(&format!("key{:05}", i), &"v".repeat(100))))
+            .peekable();
+        assert!(write_table(path, &mut records, u64::MAX).unwrap());
+        Table::open(1, path).unwrap()
+    }
+
+    fn keys(iter: TableIter) -> Vec<String> {
+        iter.map(|r| String::from_utf8(r.unwrap().key).unwrap()).collect()
+    }
+
+    #[test]
+    fn test_write_and_get() {
+        let table = write("/tmp/test_sstable_get.sst", 1000);
+        assert!(table.index_.blocks.len() > 10);
+        assert_eq!(table.record_count(), 1000);
+        assert_eq!(table.first_key(), b"key00000");
+        assert_eq!(table.last_key(), b"key00999");
+        for i in [0, 1, 500, 999] {
+            let key = format!("key{:05}", i);
+            assert_eq!(table.get(key.as_bytes()).unwrap().unwrap().key, key.as_bytes());
+        }
+        assert_eq!(table.get(b"key01000").unwrap(), None);
+        assert_eq!(table.get(b"a").unwrap(), None);
+        // The bloom filter turns away most keys that are not there
+        let passed = (0..1000)
+            .filter(|i| table.may_contain(format!("other{}", i).as_bytes()))
+            .count();
+        assert!(passed < 50, "{} false positives", passed);
+    }
+
+    #[test]
+    fn test_iterate_ranges() {
+        let table = Arc::new(write("/tmp/test_sstable_iter.sst", 1000));
+        let all = TableIter::new(table.clone(), Bound::Unbounded, Bound::Unbounded, false);
+        assert_eq!(all.count(), 1000);
+        let some = TableIter::new(table.clone(), Bound::Excluded(b"key00100".to_vec()),
+            Bound::Included(b"key00103".to_vec()), false);
+        assert_eq!(keys(some), vec!["key00101", "key00102", "key00103"]);
+        let reversed = TableIter::new(table.clone(), Bound::Included(b"key00498".to_vec()),
+            Bound::Excluded(b"key00501".to_vec()), true);
+        assert_eq!(keys(reversed), vec!["key00500", "key00499", "key00498"]);
+        let tail = TableIter::new(table.clone(), Bound::Unbounded, Bound::Unbounded, true);
+        assert_eq!(keys(tail).first().unwrap(), "key00999");
+        let none = TableIter::new(table, Bound::Included(b"z".to_vec()), Bound::Unbounded, false);
+        assert_eq!(none.count(), 0);
+    }
+
+    #[test]
+    fn test_split_and_corruption() {
+        let path = "/tmp/test_sstable_split.sst";
+        let mut records = (0..1000)
+            .map(|i| Ok(record(&format!("key{:05}", i), &"v".repeat(100))))
+            .peekable();
+        assert!(write_table(path, &mut records, 16 * 1024).unwrap());
+        let first = Table::open(1, path).unwrap();
+        assert!(first.record_count() < 1000);
+        assert_eq!(records.count() as u64, 1000 - first.record_count());
+
+        let mut bytes = fs::read(path).unwrap();
+        bytes[10] ^= 0x01;
+        fs::write(path, &bytes).unwrap();
+        let damaged = Table::open(1, path).unwrap();
+        assert_eq!(damaged.get(b"key00000").unwrap_err().kind_,
+            ErrorKind::ChecksumMismatchError);
+        let len = bytes.len();
+        bytes[len - 30] ^= 0x01;
+        fs::write(path, &bytes).unwrap();
+        assert!(Table::open(1, path).is_err());
+    }
+
+    #[test]
+    fn test_obsolete_tables_are_removed() {
+        let path = "/tmp/test_sstable_obsolete.sst";
+        let table = Arc::new(write(path, 10));
+        let reader = table.clone();
+        table.mark_obsolete();
+        drop(table);
+        assert!(std::path::Path::new(path).exists());
+        drop(reader);
+        assert!(!std::path::Path::new(path).exists());
+    }
+}
@@ -0,0 +1,429 @@
+use std::fmt::{Display, Formatter};
+use std::ops::Bound;
+use std::str::FromStr;
+
+use super::key_pattern::KeyPattern;
+use super::key_value_pair::KeyValuePair;
+use super::key_value_store::{KeyValueStore, ScanPage};
+use crate::key_value_store::errors::RWError;
+
+/// A pair handed out by `StorageEngine::snapshot`, with its expiry
+pub type SnapshotEntry = Result<(KeyValuePair, Option<u64>), RWError>;
+
+/// Where a store keeps its pairs. Every engine holds pairs ordered by key,
+/// treats pairs whose expiry has passed as absent, and keeps the same
+/// estimate of memory usage, so that the server and the tools built on it can
+/// use any of them the same way.
+///
+/// Every pair carries a version, which changes each time its value is
+/// written. The versions an engine hands out only ever grow, across restarts
+/// too, so a key written again never gets back a version it had before, even
+/// once deleted in between.
+///
+/// Engines do no locking of their own; mutations take `&mut self`, and the
+/// caller is expected to put the engine behind a lock, see `ShardedStore`.
+pub trait StorageEngine: Send + Sync {
+    fn name(&self) -> &str;
+
+    /// The live pair stored under `key`, if any
+    fn get(&self, key: &[u8]) -> Result<Option<KeyValuePair>, RWError> {
+        Ok(self.get_versioned(key)?.map(|(pair, _)| pair))
+    }
+
+    /// Like `get`, along with the pair's version
+    fn get_versioned(&self, key: &[u8]) -> Result<Option<(KeyValuePair, u64)>, RWError>;
+
+    /// The version `put` gives the next pair written. Greater than any
+    /// version the engine holds or has handed out.
+    fn next_version(&self) -> u64;
+
+    /// When a live key expires, or None if it never does or is not stored
+    fn expires_at(&self, key: &[u8]) -> Option<u64>;
+
+    /// Inserts or overwrites a pair, giving it the next version, and sets
+    /// its expiry to exactly `expires_at`, see `KeyValueStore::put`. Returns
+    /// the version.
+    fn put(&mut self, pair: KeyValuePair, expires_at: Option<u64>) -> Result<u64, RWError> {
+        let version = self.next_version();
+        self.put_versioned(pair, expires_at, version)?;
+        Ok(version)
+    }
+
+    /// Like `put`, giving the pair `version`, after which later versions
+    /// carry on from there if it is the greatest. This is how a write
+    /// recorded elsewhere first, e.g. in a write-ahead log, gets the version
+    /// it was recorded with.
+    fn put_versioned(&mut self, pair: KeyValuePair, expires_at: Option<u64>, version: u64)
+        -> Result<(), RWError>;
+
+    /// Removes a key. Returns false if it was not live.
+    fn delete(&mut self, key: &[u8]) -> Result<bool, RWError>;
+
+    /// Sets or, given None, clears the expiry of a live key, which keeps its
+    /// version. Returns false if the key is not live.
+    fn set_expires_at(&mut self, key: &[u8], expires_at: Option<u64>) -> Result<bool, RWError>;
+
+    /// See `KeyValueStore::scan_range`
+    fn scan_range(&self, start: Bound<&[u8]>, end: Bound<&[u8]>, limit: usize,
+        reverse: bool) -> Result<Vec<KeyValuePair>, RWError>;
+
+    /// See `KeyValueStore::scan`
+    fn scan(&self, pattern: &KeyPattern, resume_after: Option<&[u8]>, count: usize,
+        max_examined: usize) -> Result<ScanPage, RWError>;
+
+    /// Removes up to `limit` keys that expired at or before `now`, soonest
+    /// first, and returns them.
+    fn remove_expired(&mut self, now: u64, limit: usize) -> Result<Vec<Vec<u8>>, RWError>;
+
+    /// Every key in order, including expired ones not removed yet. Engines
+    /// that do not keep their keys in memory may fail to read them.
+    fn keys(&self) -> Box<dyn Iterator<Item = Result<Vec<u8>, RWError>> + '_>;
+
+    /// Keys that have an expiry, soonest to expire first
+    fn keys_by_expiry(&self) -> Box<dyn Iterator<Item = &[u8]> + '_>;
+
+    /// Every pair in key order along with its expiry, including expired
+    /// pairs not removed yet. Nothing can change the engine while the
+    /// iterator is alive, so it sees the engine as of a single point in time.
+    fn snapshot(&self) -> Box<dyn Iterator<Item = SnapshotEntry> + '_>;
+
+    /// A copy of the engine's pairs as they are now that outlives the
+    /// engine's lock, for work too slow to do while holding it. Engines share
+    /// what they can with the copy rather than copying it, so this is cheap.
+    fn freeze(&self) -> Result<Box<dyn FrozenEngine>, RWError>;
+
+    /// The number of pairs, including expired ones not removed yet
+    fn len(&self) -> usize;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The estimated memory used by the pairs, see `entry_size`
+    fn memory_usage(&self) -> u64;
+
+    /// Replaces everything in the engine with the pairs in `store`, keeping
+    /// their versions. Bringing pairs in as new writes means giving them
+    /// versions from `next_version` first, see `KeyValueStore::restamped`.
+    fn load(&mut self, store: &KeyValueStore) -> Result<(), RWError>;
+
+    /// Deletes anything the engine keeps outside of memory. The engine must
+    /// not be used afterwards.
+    fn destroy(&mut self) -> Result<(), RWError>;
+}
+
+/// A point-in-time copy of a `StorageEngine`'s pairs, see
+/// `StorageEngine::freeze`. Later changes to the engine do not show up in it.
+pub trait FrozenEngine: Send + Sync {
+    /// The number of pairs, including expired ones
+    fn len(&self) -> usize;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Every pair in key order along with its expiry, like
+    /// `StorageEngine::snapshot`
+    fn snapshot(&self) -> Box<dyn Iterator<Item = SnapshotEntry> + '_>;
+}
+
+/// Picks the `StorageEngine` stores are kept in
+#[derive(Clone, Debug, PartialEq, Eq, Default)]
+pub enum EngineKind {
+    /// `KeyValueStore`, made durable by a write-ahead log if there is one
+    #[default]
+    Memory,
+    /// `DiskStore`, which persists every mutation itself
+    Disk,
+    /// `LsmStore`, which keeps little more than recent writes in memory
+    Lsm,
+}
+
+impl FromStr for EngineKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "memory" => Ok(EngineKind::Memory),
+            "disk" => Ok(EngineKind::Disk),
+            "lsm" => Ok(EngineKind::Lsm),
+            _ => Err(format!("Unknown storage engine: {}", s)),
+        }
+    }
+}
+
+impl Display for EngineKind {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        let name = match self {
+            EngineKind::Memory => "memory",
+            EngineKind::Disk => "disk",
+            EngineKind::Lsm => "lsm",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Checks that behave the same for every `StorageEngine`. Each engine's tests
+/// call `run` with a function that makes a fresh, empty engine, and engines
+/// that keep their pairs call `run_reopening` as well.
+#[cfg(test)]
+pub mod conformance {
+    use super::*;
+    use crate::key_value_store::key_value_store::{entry_size, now_ms};
+    use crate::proto::DataType;
+
+    pub fn run(make: &mut dyn FnMut() -> Box<dyn StorageEngine>) {
+        check_crud(make().as_mut());
+        check_expiry(make().as_mut());
+        check_typed_and_binary(make().as_mut());
+        check_scan_range(make().as_mut());
+        check_scan(make().as_mut());
+        check_snapshot_and_load(make().as_mut());
+        check_freeze(make().as_mut());
+        check_versions(make().as_mut());
+    }
+
+    /// Like `run`, and then checks that versions survive the engine being
+    /// closed. `reopen` opens again the engine `make` made last.
+    pub fn run_reopening(make: &mut dyn FnMut() -> Box<dyn StorageEngine>,
+            reopen: &mut dyn FnMut() -> Box<dyn StorageEngine>) {
+        run(make);
+        check_versions_after_reopen(make(), reopen);
+    }
+
+    fn pair(key: &str, value: &str) -> KeyValuePair {
+        KeyValuePair::new(key, value)
+    }
+
+    fn keys(pairs: &[KeyValuePair]) -> Vec<String> {
+        pairs.iter().map(|p| p.key_text()).collect()
+    }
+
+    fn check_crud(engine: &mut dyn StorageEngine) {
+        assert!(engine.is_empty());
+        assert_eq!(engine.get(b"one").unwrap(), None);
+        engine.put(pair("one", "uno"), None).unwrap();
+        engine.put(pair("two", "dos"), None).unwrap();
+        assert_eq!(engine.get(b"one").unwrap().unwrap().value(), "uno");
+        assert_eq!(engine.len(), 2);
+
+        engine.put(pair("one", "eins"), None).unwrap();
+        assert_eq!(engine.get(b"one").unwrap().unwrap().value(), "eins");
+        assert_eq!(engine.len(), 2);
+        assert_eq!(engine.memory_usage(),
+            entry_size(b"one", b"eins") + entry_size(b"two", b"dos"));
+
+        assert!(engine.delete(b"one").unwrap());
+        assert!(!engine.delete(b"one").unwrap());
+        assert_eq!(engine.get(b"one").unwrap(), None);
+        assert_eq!(engine.len(), 1);
+        assert_eq!(engine.memory_usage(), entry_size(b"two", b"dos"));
+        assert_eq!(engine.keys().collect::<Result<Vec<_>, _>>().unwrap(), vec![b"two".to_vec()]);
+    }
+
+    fn check_expiry(engine: &mut dyn StorageEngine) {
+        let now = now_ms();
+        engine.put(pair("gone", "v"), Some(now - 1)).unwrap();
+        engine.put(pair("late", "v"), Some(now + 20_000)).unwrap();
+        engine.put(pair("soon", "v"), Some(now + 10_000)).unwrap();
+        engine.put(pair("never", "v"), None).unwrap();
+
+        assert_eq!(engine.get(b"gone").unwrap(), None);
+        assert_eq!(engine.expires_at(b"gone"), None);
+        assert_eq!(engine.expires_at(b"soon"), Some(now + 10_000));
+        assert_eq!(engine.expires_at(b"never"), None);
+        assert_eq!(engine.keys_by_expiry().collect::<Vec<&[u8]>>(),
+            vec![b"gone".as_slice(), b"soon".as_slice(), b"late".as_slice()]);
+
+        assert!(engine.set_expires_at(b"never", Some(now + 5_000)).unwrap());
+        assert!(engine.set_expires_at(b"soon", None).unwrap());
+        assert!(!engine.set_expires_at(b"missing", None).unwrap());
+        assert_eq!(engine.expires_at(b"never"), Some(now + 5_000));
+        assert_eq!(engine.expires_at(b"soon"), None);
+        assert_eq!(engine.get(b"never").unwrap().unwrap().value(), "v");
+
+        // Only the expired key is removed, and an unexpired one once its time
+        // has come
+        assert_eq!(engine.remove_expired(now, 10).unwrap(), vec![b"gone".to_vec()]);
+        assert_eq!(engine.len(), 3);
+        assert_eq!(engine.remove_expired(now + 30_000, 1).unwrap(), vec![b"never".to_vec()]);
+        assert_eq!(engine.remove_expired(now + 30_000, 10).unwrap(), vec![b"late".to_vec()]);
+        assert_eq!(engine.len(), 1);
+        assert_eq!(engine.keys_by_expiry().count(), 0);
+
+        // Deleting an expired key removes it, but it was not live
+        engine.put(pair("gone", "v"), Some(now - 1)).unwrap();
+        assert!(!engine.delete(b"gone").unwrap());
+        assert_eq!(engine.len(), 1);
+    }
+
+    fn check_typed_and_binary(engine: &mut dyn StorageEngine) {
+        let typed = KeyValuePair::new_typed(b"\x00count\xff", DataType::Uint64,
+            7u64.to_le_bytes().to_vec()).unwrap();
+        engine.put(typed.clone(), None).unwrap();
+        engine.put(KeyValuePair::new_binary(b"blob", &[0, 159, 146, 150]), None).unwrap();
+        assert_eq!(engine.get(b"\x00count\xff").unwrap(), Some(typed));
+        let blob = engine.get(b"blob").unwrap().unwrap();
+        assert_eq!(blob.data_type(), DataType::Binary);
+        assert_eq!(blob.value_bytes(), &[0, 159, 146, 150]);
+    }
+
+    fn check_scan_range(engine: &mut dyn StorageEngine) {
+        for k in ["a", "b", "c", "d", "e"] {
+            engine.put(pair(k, k), None).unwrap();
+        }
+        engine.put(pair("bb", "expired"), Some(now_ms() - 1)).unwrap();
+        let all = engine.scan_range(Bound::Unbounded, Bound::Unbounded, 10, false).unwrap();
+        assert_eq!(keys(&all), vec!["a", "b", "c", "d", "e"]);
+        let some = engine.scan_range(Bound::Excluded(b"a"), Bound::Included(b"d"), 2, true)
+            .unwrap();
+        assert_eq!(keys(&some), vec!["d", "c"]);
+        assert!(engine.scan_range(Bound::Included(b"d"), Bound::Excluded(b"b"), 10, false)
+            .unwrap().is_empty());
+    }
+
+    fn check_scan(engine: &mut dyn StorageEngine) {
+        for i in 0..50 {
+            engine.put(pair(&format!("user:{:02}", i), "v"), None).unwrap();
+        }
+        engine.put(pair("other", "v"), None).unwrap();
+        let pattern = KeyPattern::Glob(b"user:*5".to_vec());
+        let mut seen = Vec::new();
+        let mut cursor: Option<Vec<u8>> = None;
+        loop {
+            let page = engine.scan(&pattern, cursor.as_deref(), 2, 7).unwrap();
+            assert!(page.pairs.len() <= 2);
+            seen.extend(keys(&page.pairs));
+            match page.resume_after {
+                None => break,
+                Some(k) => cursor = Some(k),
+            }
+        }
+        assert_eq!(seen, vec!["user:05", "user:15", "user:25", "user:35", "user:45"]);
+    }
+
+    fn check_snapshot_and_load(engine: &mut dyn StorageEngine) {
+        let expiry = now_ms() + 60_000;
+        engine.put(pair("b", "2"), Some(expiry)).unwrap();
+        engine.put(pair("a", "1"), None).unwrap();
+        let snapshot: Vec<(KeyValuePair, Option<u64>)> =
+            engine.snapshot().map(|e| e.unwrap()).collect();
+        assert_eq!(snapshot, vec![(pair("a", "1"), None), (pair("b", "2"), Some(expiry))]);
+
+        let mut replacement = KeyValueStore::new("replacement");
+        replacement.add(pair("c", "3"));
+        replacement.add_with_expiry(pair("d", "4"), Some(expiry));
+        engine.load(&replacement).unwrap();
+        assert_eq!(engine.get(b"a").unwrap(), None);
+        assert_eq!(engine.get(b"c").unwrap().unwrap().value(), "3");
+        assert_eq!(engine.expires_at(b"d"), Some(expiry));
+        assert_eq!(engine.len(), 2);
+        assert_eq!(engine.memory_usage(), replacement.memory_usage());
+    }
+
+    fn check_versions(engine: &mut dyn StorageEngine) {
+        let version = |engine: &dyn StorageEngine, key: &[u8]| {
+            engine.get_versioned(key).unwrap().map(|(_, v)| v)
+        };
+        let first = engine.next_version();
+        assert_eq!(engine.put(pair("a", "1"), None).unwrap(), first);
+        assert_eq!(engine.get_versioned(b"a").unwrap(), Some((pair("a", "1"), first)));
+        let second = engine.put(pair("a", "2"), None).unwrap();
+        assert!(second > first);
+
+        // Only writing the value changes the version
+        engine.set_expires_at(b"a", Some(now_ms() + 60_000)).unwrap();
+        assert_eq!(version(engine, b"a"), Some(second));
+
+        // A key created again does not get back a version it had
+        engine.delete(b"a").unwrap();
+        assert_eq!(version(engine, b"a"), None);
+        let third = engine.put(pair("a", "3"), None).unwrap();
+        assert!(third > second);
+
+        // Given versions are kept, and later ones carry on from them
+        engine.put_versioned(pair("b", "1"), None, third + 100).unwrap();
+        assert_eq!(version(engine, b"b"), Some(third + 100));
+        assert!(engine.put(pair("c", "1"), None).unwrap() > third + 100);
+
+        let mut replacement = KeyValueStore::new("replacement");
+        replacement.add(pair("d", "1"));
+        replacement.add(pair("e", "1"));
+        let next = engine.next_version();
+        engine.load(&replacement.restamped(next)).unwrap();
+        assert_eq!(version(engine, b"d"), Some(next));
+        assert_eq!(version(engine, b"e"), Some(next + 1));
+        assert_eq!(engine.next_version(), next + 2);
+    }
+
+    fn wait_for_clock() {
+        let start = now_ms();
+        while now_ms() == start {
+            std::thread::sleep(std::time::Duration::from_millis(1));
+        }
+    }
+
+    fn check_versions_after_reopen(mut engine: Box<dyn StorageEngine>,
+            reopen: &mut dyn FnMut() -> Box<dyn StorageEngine>) {
+        let version = |engine: &dyn StorageEngine, key: &[u8]| {
+            engine.get_versioned(key).unwrap().map(|(_, v)| v)
+        };
+        let a = engine.put(pair("a", "1"), None).unwrap();
+        engine.put_versioned(pair("b", "1"), None, a + 100).unwrap();
+        let deleted = engine.put(pair("c", "1"), None).unwrap();
+        engine.delete(b"c").unwrap();
+        let next = engine.next_version();
+        drop(engine);
+
+        // An engine reopened in the same millisecond may start from a
+        // version it handed out before, see `first_version`
+        wait_for_clock();
+        let mut engine = reopen();
+        assert_eq!(version(engine.as_ref(), b"a"), Some(a));
+        assert_eq!(version(engine.as_ref(), b"b"), Some(a + 100));
+        assert_eq!(version(engine.as_ref(), b"c"), None);
+        assert!(engine.next_version() >= next);
+        // Not even a version only a deleted key had is handed out again
+        assert!(engine.put(pair("c", "2"), None).unwrap() > deleted);
+
+        let mut replacement = KeyValueStore::new("replacement");
+        replacement.add(pair("d", "1"));
+        let next = engine.next_version();
+        engine.load(&replacement.restamped(next)).unwrap();
+        drop(engine);
+
+        wait_for_clock();
+        let engine = reopen();
+        assert_eq!(version(engine.as_ref(), b"d"), Some(next));
+        assert!(engine.next_version() > next);
+    }
+
+    fn check_freeze(engine: &mut dyn StorageEngine) {
+        for i in 0..100 {
+            engine.put(pair(&format!("key{:03}", i), "before"), None).unwrap();
+        }
+        let frozen = engine.freeze().unwrap();
+        // Enough writes for the engines that write out or rewrite their
+        // files to do so while the copy is alive
+        for round in 0..5 {
+            for i in 0..100 {
+                engine.put(pair(&format!("key{:03}", i), &format!("after{}", round)), None)
+                    .unwrap();
+            }
+        }
+        engine.delete(b"key000").unwrap();
+        engine.put(pair("new", "pair"), None).unwrap();
+        assert_eq!(frozen.len(), 100);
+        let entries: Vec<(KeyValuePair, Option<u64>)> =
+            frozen.snapshot().map(|e| e.unwrap()).collect();
+        assert_eq!(entries.len(), 100);
+        assert!(entries.iter().all(|(p, _)| p.value() == "before"));
+        assert_eq!(entries[0].0.key_text(), "key000");
+
+        let mut replacement = KeyValueStore::new("replacement");
+        replacement.add(pair("only", "one"));
+        engine.load(&replacement).unwrap();
+        assert_eq!(frozen.snapshot().count(), 100);
+        assert_eq!(engine.freeze().unwrap().snapshot().count(), 1);
+    }
+}
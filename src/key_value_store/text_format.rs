@@ -0,0 +1,441 @@
+//! JSON Lines and CSV forms of a store, for people to read and edit, unlike
+//! the binary backups of `snapshot`. Each pair is one record with the fields:
+//!
+//! ```text
+//! key            the key, if it is valid UTF-8
+//! key_hex        otherwise the key as lowercase hex
+//! type           the lowercase name of a DataType, "string" if left out
+//! value          the value in its text form, see `value_to_text`
+//! expires_at_ms  Unix time in milliseconds at which the pair expires, if it does
+//! ```
+//!
+//! In JSON Lines each record is an object on a line of its own, and numeric
+//! and boolean values may be given as JSON numbers and booleans. A CSV file
+//! starts with a header naming its columns, of which only `value` and one of
+//! `key` and `key_hex` are needed. Expired pairs are neither written nor
+//! imported.
+
+use std::collections::HashMap;
+use std::io::{BufRead, Write};
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use super::errors::{ErrorKind, RWError};
+use super::key_value_pair::{value_from_text, value_to_text, KeyValuePair};
+use super::key_value_store::{now_ms, KeyValueStore};
+use super::restore::{RestoreCounts, RestoreMode, RestoreOptions};
+use super::storage_engine::SnapshotEntry;
+use crate::proto::DataType;
+
+const CSV_HEADER: [&str; 5] = ["key", "key_hex", "type", "value", "expires_at_ms"];
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum TextFormat {
+    #[default]
+    JsonLines,
+    Csv,
+}
+
+/// What an import does with a pair whose key is already live
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum OnConflict {
+    /// The imported pair replaces the live one
+    #[default]
+    Overwrite,
+    /// The live pair is kept
+    Skip,
+    /// The line fails, see `ImportOptions::max_errors`
+    Fail,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct ImportOptions {
+    pub format: TextFormat,
+    pub on_conflict: OnConflict,
+    /// How many lines may fail before the import is abandoned, leaving the
+    /// store unchanged. Lines that fail are otherwise left out.
+    pub max_errors: u64,
+}
+
+impl ImportOptions {
+    /// How the pairs read are merged into the store, see `plan_restore`
+    pub fn restore_options(&self) -> RestoreOptions {
+        RestoreOptions {
+            mode: match self.on_conflict {
+                OnConflict::Overwrite => RestoreMode::MergeBackupWins,
+                // Failed keys are left out already, so this only keeps keys
+                // that became live since
+                OnConflict::Skip | OnConflict::Fail => RestoreMode::MergeLiveWins,
+            },
+            prefix: None,
+        }
+    }
+}
+
+/// Why a line of an import failed
+#[derive(Clone, Debug, PartialEq)]
+pub struct LineError {
+    /// Counting from 1, including a CSV header
+    pub line: u64,
+    pub message: String,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ImportReport {
+    /// Set if more lines failed than allowed, in which case nothing was
+    /// imported and only `errors` is filled in
+    pub abandoned: bool,
+    pub added: u64,
+    pub overwritten: u64,
+    /// Pairs left out because their key was live, see `OnConflict::Skip`
+    pub skipped: u64,
+    /// Pairs left out because they had expired
+    pub expired: u64,
+    /// Sorted by line
+    pub errors: Vec<LineError>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TextRecord<V> {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    key: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    key_hex: Option<String>,
+    #[serde(rename = "type", default, skip_serializing_if = "Option::is_none")]
+    data_type: Option<String>,
+    value: V,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    expires_at_ms: Option<u64>,
+}
+
+impl<V> TextRecord<V> {
+    fn new(pair: &KeyValuePair, expires_at: Option<u64>, value: V) -> TextRecord<V> {
+        let (key, key_hex) = match std::str::from_utf8(pair.key()) {
+            Ok(k) => (Some(k.to_string()), None),
+            Err(_) => (None, Some(value_to_text(DataType::Binary, pair.key()))),
+        };
+        TextRecord {
+            key,
+            key_hex,
+            data_type: Some(pair.data_type().as_str_name().to_lowercase()),
+            value,
+            expires_at_ms: expires_at,
+        }
+    }
+
+    fn into_pair(self, value_text: impl FnOnce(V) -> Result<String, String>)
+            -> Result<(KeyValuePair, Option<u64>), String> {
+        let key = match (self.key, self.key_hex) {
+            (Some(k), None) => k.into_bytes(),
+            (None, Some(h)) => value_from_text(DataType::Binary, &h)?,
+            _ => return Err(String::from("Expected exactly one of key and key_hex")),
+        };
+        let data_type = match self.data_type {
+            None => DataType::String,
+            Some(t) => DataType::from_str_name(&t.to_uppercase())
+                .ok_or_else(|| format!("Unknown type {:?}", t))?,
+        };
+        let value = value_from_text(data_type, &value_text(self.value)?)?;
+        Ok((KeyValuePair::new_typed(key, data_type, value)?, self.expires_at_ms))
+    }
+}
+
+/// A value as JSON, where numbers and booleans are not quoted
+fn json_value(data_type: DataType, text: String) -> Value {
+    match data_type {
+        DataType::String | DataType::Binary => Value::String(text),
+        _ => match serde_json::from_str(&text) {
+            Ok(v) => v,
+            Err(_) => Value::String(text),
+        },
+    }
+}
+
+fn json_text(value: Value) -> Result<String, String> {
+    match value {
+        Value::String(s) => Ok(s),
+        Value::Number(n) => Ok(n.to_string()),
+        Value::Bool(b) => Ok(b.to_string()),
+        v => Err(format!("Expected a string, number or boolean value, got {}", v)),
+    }
+}
+
+fn write_error(e: impl ToString) -> RWError {
+    RWError {
+        kind_: ErrorKind::FileWriteError,
+        context_: e.to_string(),
+    }
+}
+
+fn read_error(e: impl ToString) -> RWError {
+    RWError {
+        kind_: ErrorKind::FileReadError,
+        context_: e.to_string(),
+    }
+}
+
+enum TextWriter<'a> {
+    JsonLines(&'a mut dyn Write),
+    // Boxed since the writer holds its own buffer
+    Csv(Box<csv::Writer<&'a mut dyn Write>>),
+}
+
+impl TextWriter<'_> {
+    fn write(&mut self, pair: &KeyValuePair, expires_at: Option<u64>) -> Result<(), RWError> {
+        match self {
+            TextWriter::JsonLines(out) => {
+                let value = json_value(pair.data_type(), pair.value());
+                serde_json::to_writer(&mut **out, &TextRecord::new(pair, expires_at, value))
+                    .map_err(write_error)?;
+                out.write_all(b"\n").map_err(write_error)
+            }
+            TextWriter::Csv(writer) => {
+                let record = TextRecord::new(pair, expires_at, pair.value());
+                writer.write_record([
+                    record.key.unwrap_or_default(),
+                    record.key_hex.unwrap_or_default(),
+                    record.data_type.unwrap_or_default(),
+                    record.value,
+                    record.expires_at_ms.map(|t| t.to_string()).unwrap_or_default(),
+                ]).map_err(write_error)
+            }
+        }
+    }
+}
+
+/// Writes the pairs in `entries` that have not expired to `out`, returning
+/// how many were written
+pub fn write_text(out: &mut dyn Write, format: TextFormat,
+        entries: impl Iterator<Item = SnapshotEntry>) -> Result<u64, RWError> {
+    let mut writer = match format {
+        TextFormat::JsonLines => TextWriter::JsonLines(out),
+        TextFormat::Csv => {
+            let mut writer = csv::Writer::from_writer(out);
+            writer.write_record(CSV_HEADER).map_err(write_error)?;
+            TextWriter::Csv(Box::new(writer))
+        }
+    };
+    let now = now_ms();
+    let mut count = 0;
+    for entry in entries {
+        let (pair, expires_at) = entry?;
+        if expires_at.is_some_and(|t| t <= now) {
+            continue;
+        }
+        writer.write(&pair, expires_at)?;
+        count += 1;
+    }
+    if let TextWriter::Csv(mut writer) = writer {
+        writer.flush().map_err(write_error)?;
+    }
+    Ok(count)
+}
+
+/// The pairs read by `read_text`, along with the lines that failed
+pub struct ParsedImport {
+    /// The pairs read that have not expired
+    pub store: KeyValueStore,
+    // The line each key was read from, to report duplicates and conflicts
+    lines_: HashMap<Vec<u8>, u64>,
+    expired_: u64,
+    errors_: Vec<LineError>,
+}
+
+impl ParsedImport {
+    fn add(&mut self, line: u64, parsed: Result<(KeyValuePair, Option<u64>), String>, now: u64) {
+        let (pair, expires_at) = match parsed {
+            Ok(p) => p,
+            Err(message) => {
+                self.errors_.push(LineError { line, message });
+                return;
+            }
+        };
+        if let Some(first) = self.lines_.get(pair.key()) {
+            self.errors_.push(LineError {
+                line,
+                message: format!("Key already read on line {}", first),
+            });
+            return;
+        }
+        self.lines_.insert(pair.key().to_vec(), line);
+        if expires_at.is_some_and(|t| t <= now) {
+            self.expired_ += 1;
+            return;
+        }
+        self.store.put(pair, expires_at);
+    }
+
+    /// Fails the line of every pair whose key `is_live` says is live, and
+    /// leaves the pair out, for `OnConflict::Fail`
+    pub fn fail_live_keys(&mut self, is_live: impl Fn(&[u8]) -> Result<bool, RWError>)
+            -> Result<(), RWError> {
+        let mut live = Vec::new();
+        for entry in self.store.iter() {
+            if is_live(entry.key)? {
+                live.push(entry.key.to_vec());
+            }
+        }
+        for key in live {
+            self.store.delete(&key);
+            self.errors_.push(LineError {
+                line: self.lines_[&key],
+                message: String::from("Key already exists"),
+            });
+        }
+        self.errors_.sort_by_key(|e| e.line);
+        Ok(())
+    }
+
+    /// Whether the import has to be abandoned, see `ImportOptions::max_errors`
+    pub fn too_many_errors(&self, options: &ImportOptions) -> bool {
+        self.errors_.len() as u64 > options.max_errors
+    }
+
+    /// Reports on an import abandoned because of its errors
+    pub fn abandon(self) -> ImportReport {
+        ImportReport {
+            abandoned: true,
+            errors: self.errors_,
+            ..Default::default()
+        }
+    }
+
+    /// Reports on an import that merged `store` into the live store, changing
+    /// as much as `counts` says
+    pub fn finish(self, counts: &RestoreCounts) -> ImportReport {
+        let merged = counts.added + counts.overwritten;
+        ImportReport {
+            abandoned: false,
+            added: counts.added,
+            overwritten: counts.overwritten,
+            skipped: (self.store.len() as u64).saturating_sub(merged),
+            expired: self.expired_,
+            errors: self.errors_,
+        }
+    }
+}
+
+/// Reads every record in `input`. Only failing to read `input` or a CSV
+/// header is an error; records that cannot be used fail their line instead.
+pub fn read_text(mut input: impl BufRead, format: TextFormat) -> Result<ParsedImport, RWError> {
+    let now = now_ms();
+    let mut parsed = ParsedImport {
+        store: KeyValueStore::new("import"),
+        lines_: HashMap::new(),
+        expired_: 0,
+        errors_: Vec::new(),
+    };
+    match format {
+        TextFormat::JsonLines => {
+            let mut buf = Vec::new();
+            let mut line = 0;
+            loop {
+                buf.clear();
+                if input.read_until(b'\n', &mut buf).map_err(read_error)? == 0 {
+                    break;
+                }
+                line += 1;
+                let record = match std::str::from_utf8(&buf) {
+                    Ok(t) if t.trim().is_empty() => continue,
+                    Ok(t) => serde_json::from_str::<TextRecord<Value>>(t)
+                        .map_err(|e| e.to_string())
+                        .and_then(|r| r.into_pair(json_text)),
+                    Err(_) => Err(String::from("Line is not valid UTF-8")),
+                };
+                parsed.add(line, record, now);
+            }
+        }
+        TextFormat::Csv => {
+            let mut reader = csv::Reader::from_reader(input);
+            let header = reader.headers().map_err(read_error)?.clone();
+            for result in reader.records() {
+                match result {
+                    Ok(r) => {
+                        let line = r.position().map_or(0, |p| p.line());
+                        let record = r.deserialize::<TextRecord<String>>(Some(&header))
+                            .map_err(|e| e.to_string())
+                            .and_then(|r| r.into_pair(Ok));
+                        parsed.add(line, record, now);
+                    }
+                    Err(e) if e.is_io_error() => return Err(read_error(e)),
+                    Err(e) => {
+                        let line = e.position().map_or(0, |p| p.line());
+                        parsed.add(line, Err(e.to_string()), now);
+                    }
+                }
+            }
+        }
+    }
+    Ok(parsed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::key_value_store::storage_engine::StorageEngine;
+
+    fn sample() -> KeyValueStore {
+        let mut store = KeyValueStore::new("test");
+        store.put(KeyValuePair::new("greeting", "Hello, \"world\"\nand more"), None);
+        store.put(KeyValuePair::new_typed("count", DataType::Uint64, 42u64.to_le_bytes().to_vec())
+            .unwrap(), Some(u64::MAX));
+        store.put(KeyValuePair::new_binary(&[0xff, 0x00], &[1, 2, 3]), None);
+        store.put(KeyValuePair::new("old", "gone"), Some(1));
+        store
+    }
+
+    fn round_trip(format: TextFormat) {
+        let store = sample();
+        let mut out = Vec::new();
+        assert_eq!(write_text(&mut out, format, store.snapshot()).unwrap(), 3);
+        let parsed = read_text(out.as_slice(), format).unwrap();
+        assert!(parsed.errors_.is_empty());
+        let pairs: Vec<_> = parsed.store.snapshot().map(|e| e.unwrap()).collect();
+        let expected: Vec<_> = store.snapshot().map(|e| e.unwrap())
+            .filter(|(p, _)| p.key() != b"old")
+            .collect();
+        assert_eq!(pairs, expected);
+    }
+
+    #[test]
+    fn test_json_lines_round_trip() {
+        round_trip(TextFormat::JsonLines);
+    }
+
+    #[test]
+    fn test_csv_round_trip() {
+        round_trip(TextFormat::Csv);
+    }
+
+    #[test]
+    fn test_json_lines_errors() {
+        let input = concat!(
+            "{\"key\": \"a\", \"value\": \"1\"}\n",
+            "\n",
+            "{\"key\": \"b\", \"type\": \"uint32\", \"value\": 7}\n",
+            "not json\n",
+            "{\"key\": \"c\", \"type\": \"uint32\", \"value\": \"seven\"}\n",
+            "{\"key\": \"a\", \"value\": \"again\"}\n",
+            "{\"key\": \"d\", \"key_hex\": \"64\", \"value\": \"x\"}\n",
+            "{\"key\": \"e\", \"value\": \"x\", \"colour\": \"red\"}\n",
+        );
+        let parsed = read_text(input.as_bytes(), TextFormat::JsonLines).unwrap();
+        assert_eq!(parsed.store.len(), 2);
+        assert_eq!(parsed.store.get("b").unwrap().value(), "7");
+        let lines: Vec<u64> = parsed.errors_.iter().map(|e| e.line).collect();
+        assert_eq!(lines, vec![4, 5, 6, 7, 8]);
+        assert_eq!(parsed.errors_[2].message, "Key already read on line 1");
+    }
+
+    #[test]
+    fn test_csv_errors() {
+        let input = "key,value,type\na,1,\nb,2,uint32,extra\nc,3,colour\n";
+        let parsed = read_text(input.as_bytes(), TextFormat::Csv).unwrap();
+        assert_eq!(parsed.store.len(), 1);
+        assert_eq!(parsed.store.get("a").unwrap().value(), "1");
+        let lines: Vec<u64> = parsed.errors_.iter().map(|e| e.line).collect();
+        assert_eq!(lines, vec![3, 4]);
+    }
+}
@@ -0,0 +1,597 @@
+use super::filestore;
+use super::key_value_pair::KeyValuePair;
+use super::key_value_store::KeyValueStore;
+
+use std::fs::{File, OpenOptions};
+use std::io::prelude::*;
+use std::io::BufReader;
+
+use crate::{
+    key_value_store::errors::{ErrorKind, RWError},
+    proto::{DataType, WalOp, WalRecord},
+};
+use prost::Message;
+use log::{trace, warn};
+
+/// Every record is framed as `[payload length: u32 LE][crc32: u32 LE][payload]`
+/// so that a torn write at the tail of the log can be detected on replay.
+const FRAME_HEADER_LEN: usize = 8;
+
+/// An append-only log of mutations made to a key value store. Each record is
+/// synced to disk before `append` returns, so a mutation that has been
+/// acknowledged to a client survives a crash.
+pub struct WriteAheadLog {
+    path_: String,
+    file_: File,
+    // The length of the file up to the end of its last whole frame
+    file_len_: u64,
+    // Set once the file has been deleted, after which appending to or
+    // resetting the log fails rather than bringing the file back.
+    removed_: bool,
+    // Set if a failed append could not be cut off, after which the file may
+    // end in a torn frame and every later append fails, since replay would
+    // throw it away along with the torn frame
+    failed_: bool,
+}
+
+impl WriteAheadLog {
+    /// Opens the log at `path`, creating it if it does not exist. New records
+    /// are appended to whatever is already in the file.
+    pub fn open(path: &str) -> Result<WriteAheadLog, RWError> {
+        let opened = OpenOptions::new().create(true).append(true).open(path)
+            .and_then(|f| f.metadata().map(|m| (f, m.len())));
+        match opened {
+            Ok((f, len)) => Ok(WriteAheadLog {
+                path_: String::from(path),
+                file_: f,
+                file_len_: len,
+                removed_: false,
+                failed_: false,
+            }),
+            Err(e) => Err(RWError {
+                kind_: ErrorKind::FileOpenError,
+                context_: e.to_string(),
+            }),
+        }
+    }
+
+    pub fn path(&self) -> &str {
+        self.path_.as_str()
+    }
+
+    /// The size of the log in bytes
+    pub fn len(&self) -> u64 {
+        self.file_len_
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.file_len_ == 0
+    }
+
+    /// Deletes the log file. Later appends and resets fail.
+    pub fn remove(&mut self) -> Result<(), RWError> {
+        self.check_not_removed()?;
+        if let Err(e) = std::fs::remove_file(&self.path_) {
+            return Err(RWError {
+                kind_: ErrorKind::FileWriteError,
+                context_: e.to_string(),
+            });
+        }
+        self.removed_ = true;
+        filestore::sync_parent_dir(&self.path_)
+    }
+
+    fn check_not_removed(&self) -> Result<(), RWError> {
+        if self.removed_ {
+            return Err(RWError {
+                kind_: ErrorKind::FileWriteError,
+                context_: format!("{} has been removed", self.path_),
+            });
+        }
+        Ok(())
+    }
+
+    /// Fails if the log has been removed, or a failed append could not be
+    /// cut off. Replacing the contents of the log whole fixes the latter.
+    fn check_appendable(&self) -> Result<(), RWError> {
+        self.check_not_removed()?;
+        if self.failed_ {
+            return Err(RWError {
+                kind_: ErrorKind::FileWriteError,
+                context_: format!("{} may end in a torn record", self.path_),
+            });
+        }
+        Ok(())
+    }
+
+    pub fn append(&mut self, record: &WalRecord) -> Result<(), RWError> {
+        self.append_all(std::slice::from_ref(record))
+    }
+
+    /// Appends every record in `records` and syncs once, for mutations that
+    /// are acknowledged together
+    pub fn append_all(&mut self, records: &[WalRecord]) -> Result<(), RWError> {
+        let frame: Vec<u8> = records.iter().flat_map(encode_frame).collect();
+        self.append_frames(&frame, |file, frames| {
+            file.write_all(frames).and_then(|_| file.sync_data())
+        })
+    }
+
+    /// Appends `frames` to the file with `write`. If that fails, cuts off
+    /// whatever part of them made it, so that the next append starts on a
+    /// frame boundary rather than after a torn frame that replay would stop
+    /// at.
+    fn append_frames<W>(&mut self, frames: &[u8], write: W) -> Result<(), RWError>
+    where
+        W: FnOnce(&mut File, &[u8]) -> std::io::Result<()>,
+    {
+        self.check_appendable()?;
+        if let Err(e) = write(&mut self.file_, frames) {
+            if let Err(trunc_err) = self.file_.set_len(self.file_len_) {
+                warn!("Cannot truncate {:?}, refusing later appends: {:?}", self.path_,
+                    trunc_err);
+                self.failed_ = true;
+            }
+            return Err(RWError {
+                kind_: ErrorKind::FileWriteError,
+                context_: e.to_string(),
+            });
+        }
+        self.file_len_ += frames.len() as u64;
+        trace!("Appended {:?} bytes to {:?}", frames.len(), self.path_);
+        Ok(())
+    }
+
+    /// Applies every intact record in the log to `store`, in order, and returns
+    /// the number of records applied. A partially written record at the end of
+    /// the log (e.g. from a crash in the middle of `append`) is discarded and
+    /// the file is truncated so that later appends start from a clean frame.
+    /// Any other record that fails its checksum or cannot be decoded is
+    /// corruption, and fails the replay without changing the file, since the
+    /// records after it would be lost.
+    pub fn replay(&mut self, store: &mut KeyValueStore) -> Result<usize, RWError> {
+        self.replay_records(|record| apply_record(store, record))
+    }
+
+    /// Like `replay`, handing each record to `apply` rather than applying it
+    /// to a store
+    pub fn replay_records<F>(&mut self, mut apply: F) -> Result<usize, RWError>
+    where
+        F: FnMut(WalRecord) -> Result<(), RWError>,
+    {
+        let file = match File::open(&self.path_) {
+            Ok(f) => f,
+            Err(e) => {
+                return Err(RWError {
+                    kind_: ErrorKind::FileOpenError,
+                    context_: e.to_string(),
+                })
+            }
+        };
+        let mut buf = Vec::new();
+        if let Err(e) = BufReader::new(file).read_to_end(&mut buf) {
+            return Err(RWError {
+                kind_: ErrorKind::FileReadError,
+                context_: e.to_string(),
+            });
+        }
+
+        let mut offset = 0;
+        let mut applied = 0;
+        while let Some((record, frame_len)) = decode_frame(&buf[offset..]) {
+            apply(record)?;
+            offset += frame_len;
+            applied += 1;
+        }
+        if offset < buf.len() && !is_incomplete_frame(&buf[offset..]) {
+            return Err(RWError {
+                kind_: ErrorKind::ChecksumMismatchError,
+                context_: format!("Corrupt record at byte {} of {}", offset, self.path_),
+            });
+        }
+        if offset < buf.len() {
+            warn!(
+                "Discarding {:?} trailing bytes of incomplete record in {:?}",
+                buf.len() - offset,
+                self.path_
+            );
+            if let Err(e) = self.file_.set_len(offset as u64) {
+                return Err(RWError {
+                    kind_: ErrorKind::FileWriteError,
+                    context_: e.to_string(),
+                });
+            }
+        }
+        self.file_len_ = offset as u64;
+        Ok(applied)
+    }
+
+    /// Empties the log, once everything in it is durable elsewhere
+    pub fn clear(&mut self) -> Result<(), RWError> {
+        self.check_not_removed()?;
+        if let Err(e) = self.file_.set_len(0).and_then(|_| self.file_.sync_all()) {
+            return Err(RWError {
+                kind_: ErrorKind::FileWriteError,
+                context_: e.to_string(),
+            });
+        }
+        self.file_len_ = 0;
+        self.failed_ = false;
+        Ok(())
+    }
+
+    /// Replaces the contents of the log with one create record per pair in
+    /// `stores`, e.g. the shards of a store. Used when the store is replaced
+    /// wholesale (e.g. a restore) so that a later replay rebuilds the new
+    /// contents instead of the old ones.
+    pub fn reset(&mut self, stores: &[KeyValueStore]) -> Result<(), RWError> {
+        self.rewrite(stores.iter().flat_map(|s| s.iter()).map(|entry| Ok(WalRecord {
+            op: WalOp::Create.into(),
+            key: entry.key.to_vec(),
+            value: entry.value.to_vec(),
+            expires_at_ms: entry.expires_at,
+            data_type: match entry.data_type {
+                DataType::String => None,
+                t => Some(t.into()),
+            },
+            version: entry.version,
+            batch: Vec::new(),
+        })))
+    }
+
+    /// Replaces the contents of the log with `records`, e.g. one create
+    /// record per live pair so that the log stops growing with every
+    /// mutation ever made. A failure leaves the log as it was.
+    pub fn rewrite<I>(&mut self, records: I) -> Result<(), RWError>
+    where
+        I: IntoIterator<Item = Result<WalRecord, RWError>>,
+    {
+        self.check_not_removed()?;
+        filestore::write_file_atomically(&self.path_, |out| {
+            for record in records {
+                if let Err(e) = out.write_all(&encode_frame(&record?)) {
+                    return Err(RWError {
+                        kind_: ErrorKind::FileWriteError,
+                        context_: e.to_string(),
+                    });
+                }
+            }
+            Ok(())
+        })?;
+        let reopened = WriteAheadLog::open(&self.path_)?;
+        self.file_ = reopened.file_;
+        self.file_len_ = reopened.file_len_;
+        self.failed_ = false;
+        Ok(())
+    }
+}
+
+/// Frames a record, see `FRAME_HEADER_LEN`. `DiskStore` keeps its records in
+/// the same frames.
+pub(super) fn encode_frame(record: &WalRecord) -> Vec<u8> {
+    let payload = record.encode_to_vec();
+    let mut frame = Vec::with_capacity(FRAME_HEADER_LEN + payload.len());
+    frame.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    frame.extend_from_slice(&crc32fast::hash(&payload).to_le_bytes());
+    frame.extend_from_slice(&payload);
+    frame
+}
+
+/// Decodes the frame at the start of `buf`, returning the record and the total
+/// length of the frame. Returns None if the frame is incomplete or corrupt.
+pub(super) fn decode_frame(buf: &[u8]) -> Option<(WalRecord, usize)> {
+    if buf.len() < FRAME_HEADER_LEN {
+        return None;
+    }
+    let len = u32::from_le_bytes(buf[0..4].try_into().unwrap()) as usize;
+    let crc = u32::from_le_bytes(buf[4..8].try_into().unwrap());
+    let end = FRAME_HEADER_LEN + len;
+    if buf.len() < end {
+        return None;
+    }
+    let payload = &buf[FRAME_HEADER_LEN..end];
+    if crc32fast::hash(payload) != crc {
+        return None;
+    }
+    match WalRecord::decode(payload) {
+        Ok(record) => Some((record, end)),
+        Err(_) => None,
+    }
+}
+
+/// Whether `buf` ends before the end of the frame it starts with, as it does
+/// after a crash in the middle of an append
+fn is_incomplete_frame(buf: &[u8]) -> bool {
+    if buf.len() < FRAME_HEADER_LEN {
+        return true;
+    }
+    let len = u32::from_le_bytes(buf[0..4].try_into().unwrap()) as usize;
+    buf.len() < FRAME_HEADER_LEN + len
+}
+
+fn apply_record(store: &mut KeyValueStore, record: WalRecord) -> Result<(), RWError> {
+    // Records hold the state a mutation left the pair in rather than the
+    // request that caused it, so they are applied unconditionally. Checking
+    // them against the store could wrongly skip e.g. an update that pushed
+    // back the expiry of a key that has since passed its original one.
+    match record.op() {
+        WalOp::Create | WalOp::Update => {
+            // The record passed its checksum, so a bad value is corruption
+            // that happened before it was written rather than a torn tail.
+            let pair = KeyValuePair::from_stored(&record.key, record.data_type, record.value)
+                .map_err(|e| RWError {
+                    kind_: ErrorKind::DataDecodeError,
+                    context_: e,
+                })?;
+            store.put_versioned(pair, record.expires_at_ms, record.version);
+        }
+        WalOp::Delete => {
+            store.delete(&record.key);
+        }
+        WalOp::Batch => {
+            for r in record.batch {
+                apply_record(store, r)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fresh_log(path: &str) -> WriteAheadLog {
+        let _ = std::fs::remove_file(path);
+        WriteAheadLog::open(path).expect("Cannot open log!")
+    }
+
+    fn record(op: WalOp, key: &str, value: &str) -> WalRecord {
+        WalRecord {
+            op: op.into(),
+            key: key.as_bytes().to_vec(),
+            value: value.as_bytes().to_vec(),
+            expires_at_ms: None,
+            data_type: None,
+            version: 0,
+            batch: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_append_and_replay() {
+        let path = "/tmp/test_wal_replay.log";
+        let mut wal = fresh_log(path);
+        wal.append(&record(WalOp::Create, "one", "uno")).unwrap();
+        wal.append(&record(WalOp::Create, "two", "dos")).unwrap();
+        wal.append(&record(WalOp::Update, "one", "eins")).unwrap();
+        wal.append(&record(WalOp::Delete, "two", "")).unwrap();
+
+        let mut store = KeyValueStore::new("test");
+        let mut reopened = WriteAheadLog::open(path).unwrap();
+        assert_eq!(reopened.replay(&mut store).unwrap(), 4);
+        assert_eq!(store.get("one").unwrap().value(), "eins");
+        assert_eq!(store.get("two"), None);
+    }
+
+    #[test]
+    fn test_replay_keeps_versions() {
+        let path = "/tmp/test_wal_versions.log";
+        let mut wal = fresh_log(path);
+        // Far past any version the store starts from
+        let next = 1 << 62;
+        wal.append(&WalRecord { version: next, ..record(WalOp::Create, "one", "uno") })
+            .unwrap();
+        wal.append(&WalRecord { version: next + 1, ..record(WalOp::Create, "two", "dos") })
+            .unwrap();
+
+        let mut store = KeyValueStore::new("test");
+        WriteAheadLog::open(path).unwrap().replay(&mut store).unwrap();
+        assert_eq!(store.get_versioned("one").unwrap().1, next);
+        assert_eq!(store.get_versioned("two").unwrap().1, next + 1);
+        assert_eq!(store.next_version(), next + 2);
+    }
+
+    #[test]
+    fn test_append_all() {
+        let path = "/tmp/test_wal_append_all.log";
+        let mut wal = fresh_log(path);
+        wal.append_all(&[
+            record(WalOp::Create, "one", "uno"),
+            record(WalOp::Create, "two", "dos"),
+            record(WalOp::Delete, "one", ""),
+        ]).unwrap();
+
+        let mut store = KeyValueStore::new("test");
+        assert_eq!(WriteAheadLog::open(path).unwrap().replay(&mut store).unwrap(), 3);
+        assert_eq!(store.get("one"), None);
+        assert_eq!(store.get("two").unwrap().value(), "dos");
+    }
+
+    #[test]
+    fn test_replay_batch() {
+        let path = "/tmp/test_wal_batch.log";
+        let mut wal = fresh_log(path);
+        wal.append(&record(WalOp::Create, "one", "uno")).unwrap();
+        let full_len = std::fs::metadata(path).unwrap().len();
+        wal.append(&WalRecord {
+            batch: vec![
+                record(WalOp::Delete, "one", ""),
+                record(WalOp::Create, "two", "dos"),
+            ],
+            ..record(WalOp::Batch, "", "")
+        }).unwrap();
+
+        let mut store = KeyValueStore::new("test");
+        assert_eq!(WriteAheadLog::open(path).unwrap().replay(&mut store).unwrap(), 2);
+        assert_eq!(store.get("one"), None);
+        assert_eq!(store.get("two").unwrap().value(), "dos");
+
+        // A torn batch is discarded whole
+        let torn_len = std::fs::metadata(path).unwrap().len() - 2;
+        OpenOptions::new().write(true).open(path).unwrap()
+            .set_len(torn_len).unwrap();
+        let mut store = KeyValueStore::new("test");
+        assert_eq!(WriteAheadLog::open(path).unwrap().replay(&mut store).unwrap(), 1);
+        assert_eq!(store.get("one").unwrap().value(), "uno");
+        assert_eq!(store.get("two"), None);
+        assert_eq!(std::fs::metadata(path).unwrap().len(), full_len);
+    }
+
+    #[test]
+    fn test_replay_discards_torn_record() {
+        let path = "/tmp/test_wal_torn.log";
+        let mut wal = fresh_log(path);
+        wal.append(&record(WalOp::Create, "one", "uno")).unwrap();
+        let full_len = std::fs::metadata(path).unwrap().len();
+        wal.append(&record(WalOp::Create, "two", "dos")).unwrap();
+        // Chop off the end of the second record to simulate a crash mid-write
+        let torn_len = std::fs::metadata(path).unwrap().len() - 2;
+        OpenOptions::new().write(true).open(path).unwrap()
+            .set_len(torn_len).unwrap();
+
+        let mut store = KeyValueStore::new("test");
+        let mut reopened = WriteAheadLog::open(path).unwrap();
+        assert_eq!(reopened.replay(&mut store).unwrap(), 1);
+        assert_eq!(store.get("one").unwrap().value(), "uno");
+        assert_eq!(store.get("two"), None);
+        assert_eq!(std::fs::metadata(path).unwrap().len(), full_len);
+
+        // Appends after a replay must land on a clean frame boundary
+        reopened.append(&record(WalOp::Create, "three", "tres")).unwrap();
+        let mut store_2 = KeyValueStore::new("test");
+        assert_eq!(WriteAheadLog::open(path).unwrap().replay(&mut store_2).unwrap(), 2);
+        assert_eq!(store_2.get("three").unwrap().value(), "tres");
+    }
+
+    #[test]
+    fn test_replay_fails_on_corrupt_record() {
+        let path = "/tmp/test_wal_corrupt.log";
+        let mut wal = fresh_log(path);
+        wal.append(&record(WalOp::Create, "one", "uno")).unwrap();
+        let first_len = std::fs::metadata(path).unwrap().len();
+        wal.append(&record(WalOp::Create, "two", "dos")).unwrap();
+        wal.append(&record(WalOp::Create, "three", "tres")).unwrap();
+        let full_len = std::fs::metadata(path).unwrap().len();
+        // Flip a bit in the middle of the second record's payload
+        let mut bytes = std::fs::read(path).unwrap();
+        bytes[first_len as usize + FRAME_HEADER_LEN + 2] ^= 1;
+        std::fs::write(path, &bytes).unwrap();
+
+        let mut store = KeyValueStore::new("test");
+        let err = WriteAheadLog::open(path).unwrap().replay(&mut store).unwrap_err();
+        assert_eq!(err.kind_, ErrorKind::ChecksumMismatchError);
+        assert!(err.context_.contains(&format!("byte {}", first_len)), "{}", err.context_);
+        // Nothing after the corruption is thrown away
+        assert_eq!(std::fs::metadata(path).unwrap().len(), full_len);
+
+        // Even if it is the last record, as long as the whole of it is there
+        let mut bytes = std::fs::read(path).unwrap();
+        bytes[first_len as usize + FRAME_HEADER_LEN + 2] ^= 1;
+        let last = bytes.len() - 1;
+        bytes[last] ^= 1;
+        std::fs::write(path, &bytes).unwrap();
+        assert!(WriteAheadLog::open(path).unwrap().replay(&mut store).is_err());
+        assert_eq!(std::fs::metadata(path).unwrap().len(), full_len);
+    }
+
+    #[test]
+    fn test_failed_append_is_cut_off() {
+        let path = "/tmp/test_wal_failed_append.log";
+        let mut wal = fresh_log(path);
+        wal.append(&record(WalOp::Create, "one", "uno")).unwrap();
+        let frame = encode_frame(&record(WalOp::Create, "two", "dos"));
+        // Part of the frame makes it to the file before the write fails
+        let result = wal.append_frames(&frame, |file, frames| {
+            file.write_all(&frames[..frames.len() / 2])?;
+            Err(std::io::Error::other("disk full"))
+        });
+        assert!(result.is_err());
+        wal.append(&record(WalOp::Create, "three", "tres")).unwrap();
+
+        let mut store = KeyValueStore::new("test");
+        assert_eq!(WriteAheadLog::open(path).unwrap().replay(&mut store).unwrap(), 2);
+        assert_eq!(store.get("one").unwrap().value(), "uno");
+        assert_eq!(store.get("two"), None);
+        assert_eq!(store.get("three").unwrap().value(), "tres");
+    }
+
+    #[test]
+    fn test_failed_truncate_refuses_appends() {
+        let path = "/tmp/test_wal_failed_truncate.log";
+        let mut wal = fresh_log(path);
+        wal.append(&record(WalOp::Create, "one", "uno")).unwrap();
+        let frame = encode_frame(&record(WalOp::Create, "two", "dos"));
+        let result = wal.append_frames(&frame, |file, frames| {
+            file.write_all(&frames[..frames.len() / 2])?;
+            // Cannot be truncated through a read-only handle
+            *file = File::open(path)?;
+            Err(std::io::Error::other("disk full"))
+        });
+        assert!(result.is_err());
+        assert!(wal.append(&record(WalOp::Create, "three", "tres")).is_err());
+
+        // Rewriting the log whole makes it usable again
+        wal.rewrite(vec![Ok(record(WalOp::Create, "one", "uno"))]).unwrap();
+        wal.append(&record(WalOp::Create, "three", "tres")).unwrap();
+        let mut store = KeyValueStore::new("test");
+        assert_eq!(WriteAheadLog::open(path).unwrap().replay(&mut store).unwrap(), 2);
+        assert_eq!(store.get("three").unwrap().value(), "tres");
+    }
+
+    #[test]
+    fn test_rewrite() {
+        let path = "/tmp/test_wal_rewrite.log";
+        let mut wal = fresh_log(path);
+        for i in 0..10 {
+            wal.append(&record(WalOp::Update, "one", &i.to_string())).unwrap();
+        }
+        let before = wal.len();
+        wal.rewrite(vec![Ok(record(WalOp::Create, "one", "9"))]).unwrap();
+        assert!(wal.len() < before);
+        assert_eq!(wal.len(), std::fs::metadata(path).unwrap().len());
+
+        // A failed rewrite leaves the log as it was
+        let failed = wal.rewrite(vec![
+            Ok(record(WalOp::Create, "two", "dos")),
+            Err(RWError { kind_: ErrorKind::DataDecodeError, context_: String::new() }),
+        ]);
+        assert!(failed.is_err());
+        wal.append(&record(WalOp::Create, "three", "tres")).unwrap();
+        let mut store = KeyValueStore::new("test");
+        assert_eq!(WriteAheadLog::open(path).unwrap().replay(&mut store).unwrap(), 2);
+        assert_eq!(store.get("one").unwrap().value(), "9");
+        assert_eq!(store.get("two"), None);
+    }
+
+    #[test]
+    fn test_reset() {
+        let path = "/tmp/test_wal_reset.log";
+        let mut wal = fresh_log(path);
+        wal.append(&record(WalOp::Create, "stale", "value")).unwrap();
+
+        let mut snapshot = KeyValueStore::new("test");
+        snapshot.add(KeyValuePair::new("fresh", "value"));
+        wal.reset(std::slice::from_ref(&snapshot)).unwrap();
+        wal.append(&record(WalOp::Update, "fresh", "newer")).unwrap();
+
+        let mut store = KeyValueStore::new("test");
+        assert_eq!(WriteAheadLog::open(path).unwrap().replay(&mut store).unwrap(), 2);
+        assert_eq!(store.get("stale"), None);
+        assert_eq!(store.get("fresh").unwrap().value(), "newer");
+    }
+
+    #[test]
+    fn test_remove() {
+        let path = "/tmp/test_wal_remove.log";
+        let mut wal = fresh_log(path);
+        wal.append(&record(WalOp::Create, "one", "uno")).unwrap();
+        wal.remove().unwrap();
+        assert!(!std::path::Path::new(path).exists());
+
+        // A removed log must not come back to life
+        assert!(wal.append(&record(WalOp::Create, "two", "dos")).is_err());
+        assert!(wal.reset(&[KeyValueStore::new("test")]).is_err());
+        assert!(!std::path::Path::new(path).exists());
+    }
+}
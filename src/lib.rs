@@ -1,7 +1,20 @@
 pub mod key_value_store;
 pub mod socket_interface;
 
+// Each protobuf package gets its own module so that references between
+// packages (e.g. `key_value_messages.DataType`) resolve, and is re-exported so
+// everything stays reachable directly under `proto`.
 pub mod proto {
-    include!(concat!(env!("OUT_DIR"), "/key_value_store.rs"));
-    include!(concat!(env!("OUT_DIR"), "/socket_messages.rs"));
+    pub mod key_value_store {
+        include!(concat!(env!("OUT_DIR"), "/key_value_store.rs"));
+    }
+    pub mod key_value_messages {
+        include!(concat!(env!("OUT_DIR"), "/key_value_messages.rs"));
+    }
+    pub mod socket_messages {
+        include!(concat!(env!("OUT_DIR"), "/socket_messages.rs"));
+    }
+    pub use key_value_store::*;
+    pub use key_value_messages::*;
+    pub use socket_messages::*;
 }
\ No newline at end of file
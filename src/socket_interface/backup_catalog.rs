@@ -0,0 +1,252 @@
+use std::collections::BTreeMap;
+use std::io::ErrorKind as IoErrorKind;
+use std::sync::Mutex;
+
+use crate::key_value_store::errors::{ErrorKind, RWError};
+use crate::key_value_store::filestore;
+use crate::proto::{BackupCatalogMsg, BackupInfo};
+use log::{info, warn};
+use prost::Message;
+
+use super::server_impl::validate_backup_id;
+
+/// The backups a server has written to its backup directory and not deleted,
+/// kept in a file so that they are remembered across restarts if the server
+/// was given one
+pub(super) struct BackupCatalog {
+    path_: Option<String>,
+    dir_: String,
+    // By backup ID. Locked while the file is rewritten, so that the file
+    // always ends up matching the map.
+    backups_: Mutex<BTreeMap<String, BackupInfo>>,
+}
+
+impl BackupCatalog {
+    /// Opens the catalog kept at `path` of the backups in `dir`, which starts
+    /// out empty if there is no file there yet. Without a path the catalog is
+    /// kept in memory only.
+    pub(super) fn open(path: Option<&str>, dir: &str) -> Result<BackupCatalog, RWError> {
+        let mut backups = BTreeMap::new();
+        if let Some(path) = path {
+            let bytes = match std::fs::read(path) {
+                Ok(b) => b,
+                Err(e) if e.kind() == IoErrorKind::NotFound => Vec::new(),
+                Err(e) => return Err(RWError {
+                    kind_: ErrorKind::FileReadError,
+                    context_: e.to_string()
+                })
+            };
+            let msg = match BackupCatalogMsg::decode(bytes.as_slice()) {
+                Ok(m) => m,
+                Err(e) => return Err(RWError {
+                    kind_: ErrorKind::DataDecodeError,
+                    context_: e.to_string()
+                })
+            };
+            for backup in msg.backups {
+                // Left by a server that wrote backups wherever it was told
+                if validate_backup_id(&backup.backup_id).is_err() {
+                    warn!("Forgetting backup {:?} outside the backup directory",
+                        backup.backup_id);
+                    continue;
+                }
+                backups.insert(backup.backup_id.clone(), backup);
+            }
+            info!("Found {:?} backups in {:?}", backups.len(), path);
+        }
+        Ok(BackupCatalog {
+            path_: path.map(String::from),
+            dir_: dir.to_string(),
+            backups_: Mutex::new(backups)
+        })
+    }
+
+    pub(super) fn dir(&self) -> &str {
+        self.dir_.as_str()
+    }
+
+    /// The file holding the backup `backup_id`, which has to be valid
+    pub(super) fn path(&self, backup_id: &str) -> String {
+        format!("{}/{}", self.dir_, backup_id)
+    }
+
+    /// The file holding the backup `backup_id` for a chain of backups being
+    /// read, see `filestore::read_backup_chain`. Fails unless the backup is
+    /// in the catalog, since the ID comes from another backup's file.
+    pub(super) fn resolve(&self, backup_id: &str) -> Result<String, RWError> {
+        if validate_backup_id(backup_id).is_err() || self.get(backup_id).is_none() {
+            return Err(RWError {
+                kind_: ErrorKind::FileOpenError,
+                context_: format!("Base backup {:?} is not in the catalog", backup_id)
+            });
+        }
+        Ok(self.path(backup_id))
+    }
+
+    /// The backups based directly on the backup `backup_id`, sorted by ID
+    pub(super) fn dependents(&self, backup_id: &str) -> Vec<String> {
+        self.backups_.lock().unwrap().values()
+            .filter(|b| b.base_backup_id == backup_id)
+            .map(|b| b.backup_id.clone())
+            .collect()
+    }
+
+    /// The backup `backup_id` followed by every backup its chain is based
+    /// on, nearest first, as far as the catalog knows them
+    pub(super) fn chain(&self, backup_id: &str) -> Vec<String> {
+        let backups = self.backups_.lock().unwrap();
+        let mut chain = vec![backup_id.to_string()];
+        let mut next = backups.get(backup_id);
+        while let Some(b) = next {
+            // Stop at a cycle, which reading the chain would fail on anyway
+            if b.base_backup_id.is_empty() || chain.contains(&b.base_backup_id) {
+                break;
+            }
+            chain.push(b.base_backup_id.clone());
+            next = backups.get(&b.base_backup_id);
+        }
+        chain
+    }
+
+    /// Every backup, sorted by ID
+    pub(super) fn list(&self) -> Vec<BackupInfo> {
+        self.backups_.lock().unwrap().values().cloned().collect()
+    }
+
+    pub(super) fn get(&self, backup_id: &str) -> Option<BackupInfo> {
+        self.backups_.lock().unwrap().get(backup_id).cloned()
+    }
+
+    /// Adds a backup that has just been written, replacing any earlier one
+    /// with the same ID
+    pub(super) fn insert(&self, backup: BackupInfo) -> Result<(), RWError> {
+        let mut backups = self.backups_.lock().unwrap();
+        let backup_id = backup.backup_id.clone();
+        let previous = backups.insert(backup_id.clone(), backup);
+        if let Err(e) = self.save(&backups) {
+            match previous {
+                Some(p) => backups.insert(backup_id, p),
+                None => backups.remove(&backup_id)
+            };
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    /// Deletes the file of the backup `backup_id` and then forgets it.
+    /// Returns false if there is no such backup.
+    pub(super) fn delete(&self, backup_id: &str) -> Result<bool, RWError> {
+        let mut backups = self.backups_.lock().unwrap();
+        if !backups.contains_key(backup_id) {
+            return Ok(false);
+        }
+        let path = self.path(backup_id);
+        match std::fs::remove_file(&path) {
+            Ok(_) => filestore::sync_parent_dir(&path)?,
+            // Deleted by something else, which leaves only the entry to remove
+            Err(e) if e.kind() == IoErrorKind::NotFound => {}
+            Err(e) => return Err(RWError {
+                kind_: ErrorKind::FileWriteError,
+                context_: e.to_string()
+            })
+        }
+        let removed = backups.remove(backup_id);
+        if let Err(e) = self.save(&backups) {
+            // The file is gone either way; keep listing the backup so that
+            // deleting it can be retried
+            if let Some(r) = removed {
+                backups.insert(backup_id.to_string(), r);
+            }
+            return Err(e);
+        }
+        Ok(true)
+    }
+
+    fn save(&self, backups: &BTreeMap<String, BackupInfo>) -> Result<(), RWError> {
+        let path = match &self.path_ {
+            Some(p) => p,
+            None => return Ok(())
+        };
+        let bytes = BackupCatalogMsg {
+            backups: backups.values().cloned().collect()
+        }.encode_to_vec();
+        filestore::write_file_atomically(path, |out| {
+            out.write_all(&bytes).map_err(|e| RWError {
+                kind_: ErrorKind::FileWriteError,
+                context_: e.to_string()
+            })
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn backup(backup_id: &str, base_backup_id: &str) -> BackupInfo {
+        BackupInfo {
+            backup_id: backup_id.to_string(),
+            store: String::from("store"),
+            base_backup_id: base_backup_id.to_string(),
+            ..Default::default()
+        }
+    }
+
+    fn fresh_catalog(dir: &str) -> BackupCatalog {
+        let _ = std::fs::remove_dir_all(dir);
+        std::fs::create_dir_all(dir).unwrap();
+        BackupCatalog::open(Some(&format!("{}/catalog", dir)), dir).unwrap()
+    }
+
+    #[test]
+    fn test_list_and_get() {
+        let dir = "/tmp/test_backup_catalog_list";
+        let catalog = fresh_catalog(dir);
+        catalog.insert(backup("b", "")).unwrap();
+        catalog.insert(backup("a", "")).unwrap();
+        catalog.insert(backup("c", "b")).unwrap();
+        let ids: Vec<String> = catalog.list().into_iter().map(|b| b.backup_id).collect();
+        assert_eq!(ids, vec!["a", "b", "c"]);
+        assert_eq!(catalog.get("c").unwrap().base_backup_id, "b");
+        assert!(catalog.get("missing").is_none());
+        assert_eq!(catalog.path("a"), format!("{}/a", dir));
+
+        // Remembered once opened again
+        let reopened = BackupCatalog::open(Some(&format!("{}/catalog", dir)), dir).unwrap();
+        assert_eq!(reopened.list(), catalog.list());
+    }
+
+    #[test]
+    fn test_chain_and_dependents() {
+        let catalog = fresh_catalog("/tmp/test_backup_catalog_chain");
+        catalog.insert(backup("full", "")).unwrap();
+        catalog.insert(backup("incr1", "full")).unwrap();
+        catalog.insert(backup("incr2", "incr1")).unwrap();
+        catalog.insert(backup("other", "full")).unwrap();
+        assert_eq!(catalog.chain("incr2"), vec!["incr2", "incr1", "full"]);
+        assert_eq!(catalog.chain("missing"), vec!["missing"]);
+        assert_eq!(catalog.dependents("full"), vec!["incr1", "other"]);
+        assert!(catalog.dependents("incr2").is_empty());
+        assert!(catalog.resolve("incr1").is_ok());
+        assert!(catalog.resolve("missing").is_err());
+        assert!(catalog.resolve("../full").is_err());
+    }
+
+    #[test]
+    fn test_delete() {
+        let dir = "/tmp/test_backup_catalog_delete";
+        let catalog = fresh_catalog(dir);
+        catalog.insert(backup("a", "")).unwrap();
+        catalog.insert(backup("b", "")).unwrap();
+        std::fs::write(catalog.path("a"), b"backup").unwrap();
+        assert!(catalog.delete("a").unwrap());
+        assert!(!std::path::Path::new(&catalog.path("a")).exists());
+        assert!(catalog.get("a").is_none());
+        // A backup whose file is already gone is still forgotten
+        assert!(catalog.delete("b").unwrap());
+        assert!(!catalog.delete("b").unwrap());
+        assert!(!catalog.delete("missing").unwrap());
+        let reopened = BackupCatalog::open(Some(&format!("{}/catalog", dir)), dir).unwrap();
+        assert!(reopened.list().is_empty());
+    }
+}
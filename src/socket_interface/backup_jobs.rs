@@ -0,0 +1,266 @@
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use crate::key_value_store::errors::{ErrorKind, RWError};
+use crate::key_value_store::filestore::{self, WriteProgress};
+use crate::key_value_store::key_value_store::now_ms;
+use crate::proto::{BackupInfo, BackupState, BackupStatusResp};
+use log::{error, info};
+
+use super::backup_catalog::BackupCatalog;
+
+/// How many finished backups are remembered for status requests
+const MAX_FINISHED_JOBS: usize = 100;
+
+/// Writes a backup to the given file, keeping the progress up to date
+pub(super) type WriteBackup = Box<dyn FnOnce(&str, &WriteProgress) -> Result<(), RWError> + Send>;
+
+fn create_dir(dir: &str) -> Result<(), RWError> {
+    std::fs::create_dir_all(dir).map_err(|e| RWError {
+        kind_: ErrorKind::FileOpenError,
+        context_: e.to_string(),
+    })
+}
+
+/// A backup being written in the background, see `BackupJobs`
+pub(super) struct BackupJob {
+    id_: u64,
+    store_: String,
+    backup_id_: String,
+    // The backups read to write this one, which must not change until it
+    // is written
+    reads_: Vec<String>,
+    progress_: WriteProgress,
+    // None while the backup is running, then why it failed if it did
+    outcome_: Mutex<Option<Result<(), String>>>,
+}
+
+impl BackupJob {
+    pub(super) fn id(&self) -> u64 {
+        self.id_
+    }
+
+    fn is_running(&self) -> bool {
+        self.outcome_.lock().unwrap().is_none()
+    }
+
+    fn run(&self, write: WriteBackup, catalog: &BackupCatalog) {
+        let started = Instant::now();
+        let path = catalog.path(&self.backup_id_);
+        let result = create_dir(catalog.dir())
+            .and_then(|_| write(&path, &self.progress_))
+            .and_then(|_| self.add_to_catalog(&path, catalog));
+        match &result {
+            Ok(_) => info!("Backup {:?} of {:?} wrote {:?} records in {:?}",
+                self.backup_id_, self.store_, self.progress_.records(), started.elapsed()),
+            Err(e) => error!("Backup {:?} of {:?} failed: {:?}",
+                self.backup_id_, self.store_, e.to_string()),
+        }
+        *self.outcome_.lock().unwrap() = Some(result.map_err(|e| e.to_string()));
+    }
+
+    /// Only backups that were written completely make it into the catalog
+    fn add_to_catalog(&self, path: &str, catalog: &BackupCatalog) -> Result<(), RWError> {
+        let file = filestore::read_backup_info(path)?;
+        catalog.insert(BackupInfo {
+            backup_id: self.backup_id_.clone(),
+            store: file.name,
+            created_at_ms: now_ms(),
+            record_count: file.record_count,
+            size_bytes: file.size_bytes,
+            checksum: file.checksum,
+            base_backup_id: file.base.unwrap_or_default(),
+        })
+    }
+
+    pub(super) fn status(&self) -> BackupStatusResp {
+        let (state, failure) = match &*self.outcome_.lock().unwrap() {
+            None => (BackupState::Running, String::new()),
+            Some(Ok(_)) => (BackupState::Succeeded, String::new()),
+            Some(Err(e)) => (BackupState::Failed, e.clone()),
+        };
+        BackupStatusResp {
+            success: true,
+            error: String::new(),
+            state: state.into(),
+            store: self.store_.clone(),
+            backup_id: self.backup_id_.clone(),
+            record_count: self.progress_.total_records(),
+            records_written: self.progress_.records(),
+            bytes_written: self.progress_.bytes(),
+            failure,
+        }
+    }
+}
+
+/// The backups a server has started, each written on the blocking pool, from
+/// a frozen copy of a store so that the store stays usable meanwhile or from
+/// other backups
+#[derive(Default)]
+pub(super) struct BackupJobs {
+    // By ID, which count up from 1 in the order the jobs were started
+    jobs_: Mutex<BTreeMap<u64, Arc<BackupJob>>>,
+}
+
+impl BackupJobs {
+    /// Starts writing the backup `backup_id` of `store` with `write` in the
+    /// background, adding it to `catalog` once it is written. `reads` are
+    /// the backups `write` reads, e.g. the chain an incremental backup is
+    /// based on; no other job may write over them until this one is done.
+    pub(super) fn start(&self, store: &str, backup_id: &str, reads: Vec<String>,
+            write: WriteBackup, catalog: Arc<BackupCatalog>) -> Result<Arc<BackupJob>, String> {
+        let mut jobs = self.jobs_.lock().unwrap();
+        // Both would write to the same temporary file
+        if Self::writing_to(&jobs, backup_id) {
+            return Err(String::from("A backup to that file is already running"));
+        }
+        if Self::reading(&jobs, backup_id) {
+            return Err(String::from("A running backup reads that backup"));
+        }
+        if let Some(r) = reads.iter().find(|r| Self::writing_to(&jobs, r)) {
+            return Err(format!("Backup {} is being written", r));
+        }
+        let id = jobs.keys().next_back().map_or(1, |last| last + 1);
+        let job = Arc::new(BackupJob {
+            id_: id,
+            store_: store.to_string(),
+            backup_id_: backup_id.to_string(),
+            reads_: reads,
+            progress_: WriteProgress::default(),
+            outcome_: Mutex::new(None),
+        });
+        jobs.insert(id, job.clone());
+        let finished: Vec<u64> = jobs.values()
+            .filter(|j| !j.is_running())
+            .map(|j| j.id_)
+            .collect();
+        for old in finished.iter().take(finished.len().saturating_sub(MAX_FINISHED_JOBS)) {
+            jobs.remove(old);
+        }
+        let runner = job.clone();
+        tokio::task::spawn_blocking(move || runner.run(write, &catalog));
+        Ok(job)
+    }
+
+    /// Whether a backup to `backup_id` is being written
+    pub(super) fn is_writing(&self, backup_id: &str) -> bool {
+        Self::writing_to(&self.jobs_.lock().unwrap(), backup_id)
+    }
+
+    fn writing_to(jobs: &BTreeMap<u64, Arc<BackupJob>>, backup_id: &str) -> bool {
+        jobs.values().any(|j| j.backup_id_ == backup_id && j.is_running())
+    }
+
+    /// Whether a backup being written reads the backup `backup_id`, which
+    /// then must not be deleted or written over
+    pub(super) fn is_reading(&self, backup_id: &str) -> bool {
+        Self::reading(&self.jobs_.lock().unwrap(), backup_id)
+    }
+
+    fn reading(jobs: &BTreeMap<u64, Arc<BackupJob>>, backup_id: &str) -> bool {
+        jobs.values().any(|j| j.reads_.iter().any(|r| r == backup_id) && j.is_running())
+    }
+
+    pub(super) fn get(&self, id: u64) -> Option<Arc<BackupJob>> {
+        self.jobs_.lock().unwrap().get(&id).cloned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::key_value_store::key_value_pair::KeyValuePair;
+    use crate::key_value_store::key_value_store::KeyValueStore;
+    use crate::key_value_store::storage_engine::StorageEngine;
+    use std::sync::mpsc;
+
+    fn finish_at_once() -> WriteBackup {
+        Box::new(|_, _| Ok(()))
+    }
+
+    async fn wait_for(job: &BackupJob) {
+        while job.is_running() {
+            tokio::time::sleep(std::time::Duration::from_millis(1)).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn test_job_states() {
+        let dir = "/tmp/test_backup_job_states";
+        let _ = std::fs::remove_dir_all(dir);
+        let catalog = Arc::new(BackupCatalog::open(None, dir).unwrap());
+        let jobs = BackupJobs::default();
+        let mut store = KeyValueStore::new("store");
+        store.add(KeyValuePair::new("key", "value"));
+        let (finish, finished) = mpsc::channel::<()>();
+        let write: WriteBackup = Box::new(move |path, progress| {
+            let _ = finished.recv();
+            filestore::write_entries_to_file(path, store.name(), store.len() as u64,
+                store.snapshot(), progress)
+        });
+        let job = jobs.start("store", "good", Vec::new(), write, catalog.clone()).unwrap();
+        assert_eq!(jobs.get(job.id()).unwrap().id(), job.id());
+        let status = job.status();
+        assert_eq!(status.state(), BackupState::Running);
+        assert_eq!((status.store.as_str(), status.backup_id.as_str()), ("store", "good"));
+        assert!(catalog.get("good").is_none());
+
+        finish.send(()).unwrap();
+        wait_for(&job).await;
+        let status = job.status();
+        assert_eq!(status.state(), BackupState::Succeeded);
+        assert_eq!((status.record_count, status.records_written), (1, 1));
+        assert!(status.failure.is_empty());
+        assert_eq!(catalog.get("good").unwrap().record_count, 1);
+
+        let write: WriteBackup = Box::new(|_, _| Err(RWError {
+            kind_: ErrorKind::FileWriteError,
+            context_: String::from("Disk full"),
+        }));
+        let failed = jobs.start("store", "bad", Vec::new(), write, catalog.clone()).unwrap();
+        assert_eq!(failed.id(), job.id() + 1);
+        wait_for(&failed).await;
+        let status = failed.status();
+        assert_eq!(status.state(), BackupState::Failed);
+        assert!(status.failure.contains("Disk full"), "{:?}", status.failure);
+        assert!(catalog.get("bad").is_none());
+        // A failed backup can be tried again
+        assert!(jobs.start("store", "bad", Vec::new(), finish_at_once(), catalog).is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_running_job_protects_what_it_reads() {
+        let catalog = Arc::new(BackupCatalog::open(None, "/tmp/test_backup_jobs").unwrap());
+        let jobs = BackupJobs::default();
+        // Runs until told to finish
+        let (finish, finished) = mpsc::channel::<()>();
+        let write: WriteBackup = Box::new(move |_, _| {
+            let _ = finished.recv();
+            Err(RWError {
+                kind_: ErrorKind::FileWriteError,
+                context_: String::from("Stopped"),
+            })
+        });
+        let job = jobs.start("store", "incr", vec![String::from("base"), String::from("full")],
+            write, catalog.clone()).unwrap();
+        assert!(jobs.is_reading("base"));
+        assert!(jobs.is_reading("full"));
+        assert!(!jobs.is_reading("incr"));
+
+        // Nothing it reads may be written over, and nothing may read what it
+        // writes
+        assert!(jobs.start("store", "full", Vec::new(), finish_at_once(), catalog.clone())
+            .is_err());
+        assert!(jobs.start("store", "next", vec![String::from("incr")], finish_at_once(),
+            catalog.clone()).is_err());
+        // Reading the same backups is fine
+        assert!(jobs.start("store", "other", vec![String::from("full")], finish_at_once(),
+            catalog.clone()).is_ok());
+
+        finish.send(()).unwrap();
+        wait_for(&job).await;
+        assert!(!jobs.is_reading("base"));
+        assert!(jobs.start("store", "full", Vec::new(), finish_at_once(), catalog).is_ok());
+    }
+}
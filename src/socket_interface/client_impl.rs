@@ -1,46 +1,80 @@
+use std::collections::VecDeque;
+use std::ops::Bound;
+use futures::stream::{self, BoxStream};
 use futures::{SinkExt, StreamExt};
 use tokio::net::TcpStream;
 use tokio_util::codec::{Framed, LengthDelimitedCodec};
 use prost::Message;
 use crate::proto::*;
-use super::decode_utils::parse_generic_response;
+use super::decode_utils::{
+    parse_cas_response_message, parse_generic_response, parse_generic_response_message,
+    parse_history_response_message, parse_incr_response_message, parse_mdel_response_message,
+    parse_mget_response_message, parse_mset_response_message, parse_read_response_message,
+    parse_scan_range_response_message, parse_scan_response_message,
+    parse_transaction_response_message, parse_unwatch_response_message,
+    parse_watch_response_message
+};
 use super::socket_errors::{SocketError, ErrorKind};
 use log::warn;
 
+/// Where a range scan has got to, see `ConstructCacheClient::scan_range`
+struct ScanState<'a> {
+    client: &'a mut ConstructCacheClient,
+    start: Bound<Vec<u8>>,
+    end: Bound<Vec<u8>>,
+    // None if the whole range is wanted
+    remaining: Option<usize>,
+    reverse: bool,
+    // Pairs received but not yet yielded
+    page: VecDeque<GenericKeyValuePair>,
+    // Set once the server has nothing more, or a request failed
+    done: bool
+}
+
 pub struct ConstructCacheClient {
     _server_addr: String,
-    _framed: Framed<TcpStream, LengthDelimitedCodec>
+    _framed: Framed<TcpStream, LengthDelimitedCodec>,
+    // Named in every request that acts on a store, see `use_store`
+    target_store_: Option<String>
 }
 
 impl ConstructCacheClient {
     pub async fn new(addr: &str) -> Result<Self, SocketError> {
-        let stream;
-        match TcpStream::connect(addr).await {
-            Ok(x) => stream = x,
+        let stream = match TcpStream::connect(addr).await {
+            Ok(x) => x,
             Err(e) => return Err(SocketError {
                 kind_: ErrorKind::ConnectError,
                 context_: e.to_string()
             })
-        }
+        };
         let framed = Framed::new(stream, LengthDelimitedCodec::new());
         Ok(Self { _server_addr: String::from(addr),
-                  _framed: framed })
+                  _framed: framed,
+                  target_store_: None })
+    }
+
+    /// Names `store` in every later request that acts on a store. With None,
+    /// requests leave the choice to the server, which uses the store selected
+    /// with `send_select_store`, or its default store.
+    pub fn use_store(&mut self, store: Option<&str>) {
+        self.target_store_ = store.map(String::from);
     }
 
     pub async fn send_message(
             &mut self, req: GenericRequest) -> Result<(), SocketError> {
         let bytes = req.encode_to_vec();
         match self._framed.send(bytes.into()).await {
-            Ok(_) => return Ok(()),
-            Err(e) => return Err(SocketError { kind_: ErrorKind::ConnectError,
+            Ok(_) => Ok(()),
+            Err(e) => Err(SocketError { kind_: ErrorKind::ConnectError,
                 context_: e.to_string() })
-        };
+        }
     }
 
     pub async fn send_ping(&mut self, message: &str) -> Result<bool, SocketError> {
         let mut request = GenericRequest::default();
-        let mut ping_request = PingRequest::default();
-        ping_request.ping_message = message.to_string();
+        let ping_request = PingRequest {
+            ping_message: message.to_string()
+        };
         request.set_req_type(ReqType::Ping);
         request.payload = ping_request.encode_to_vec();
         self.send_message(request).await?;
@@ -48,12 +82,52 @@ impl ConstructCacheClient {
     }
 
     pub async fn send_create(&mut self, key: &str, val: &str) -> Result<bool, SocketError> {
+        self.send_create_with_ttl(key, val, None).await
+    }
+
+    /// Creates a pair that expires `ttl_ms` milliseconds from now, or never if
+    /// `ttl_ms` is None.
+    pub async fn send_create_with_ttl(
+            &mut self, key: &str, val: &str, ttl_ms: Option<u64>) -> Result<bool, SocketError> {
         let mut request = GenericRequest::default();
-        let mut create_req = CreateKvPairReq::default();
-        let mut pair = KeyValuePair::default();
-        pair.key = String::from(key);
-        pair.value = String::from(val);
-        create_req.pair = Some(pair);
+        let create_req = CreateKvPairReq {
+            pair: Some(KeyValuePair {
+                key: String::from(key),
+                value: String::from(val)
+            }),
+            ttl_ms,
+            store: self.target_store_.clone(),
+            ..Default::default()
+        };
+        request.payload = create_req.encode_to_vec();
+        request.set_req_type(ReqType::Create);
+        self.send_message(request).await?;
+        Ok(true)
+    }
+
+    /// Creates a pair with an arbitrary binary key and value. The value is
+    /// stored as BINARY.
+    pub async fn send_create_bytes(
+            &mut self, key: &[u8], val: &[u8], ttl_ms: Option<u64>) -> Result<bool, SocketError> {
+        self.send_create_typed(key, DataType::Binary, val.to_vec(), ttl_ms).await
+    }
+
+    /// Creates a pair holding a value of `data_type`, encoded as described in
+    /// key_value_messages.proto.
+    pub async fn send_create_typed(
+            &mut self, key: &[u8], data_type: DataType, val: Vec<u8>,
+            ttl_ms: Option<u64>) -> Result<bool, SocketError> {
+        let mut request = GenericRequest::default();
+        let create_req = CreateKvPairReq {
+            typed_pair: Some(GenericKeyValuePair {
+                key: key.to_vec(),
+                data_type: data_type.into(),
+                value: val
+            }),
+            ttl_ms,
+            store: self.target_store_.clone(),
+            ..Default::default()
+        };
         request.payload = create_req.encode_to_vec();
         request.set_req_type(ReqType::Create);
         self.send_message(request).await?;
@@ -61,9 +135,15 @@ impl ConstructCacheClient {
     }
 
     pub async fn send_delete(&mut self, key: &str) -> Result<bool, SocketError> {
+        self.send_delete_bytes(key.as_bytes()).await
+    }
+
+    pub async fn send_delete_bytes(&mut self, key: &[u8]) -> Result<bool, SocketError> {
         let mut request = GenericRequest::default();
-        let mut delete_req = DeleteKvPairReq::default();
-        delete_req.key = String::from(key);
+        let delete_req = DeleteKvPairReq {
+            key: key.to_vec(),
+            store: self.target_store_.clone()
+        };
         request.payload = delete_req.encode_to_vec();
         request.set_req_type(ReqType::Delete);
         self.send_message(request).await?;
@@ -71,32 +151,280 @@ impl ConstructCacheClient {
     }
 
     pub async fn send_update(&mut self, key: &str, val: &str) -> Result<bool, SocketError> {
+        self.send_update_with_ttl(key, val, None).await
+    }
+
+    /// Updates a pair and resets its expiry to `ttl_ms` milliseconds from
+    /// now. The current expiry is kept if `ttl_ms` is None.
+    pub async fn send_update_with_ttl(
+            &mut self, key: &str, val: &str, ttl_ms: Option<u64>) -> Result<bool, SocketError> {
+        let mut request = GenericRequest::default();
+        let update_req = UpdateKvPairReq {
+            pair: Some(KeyValuePair {
+                key: String::from(key),
+                value: String::from(val)
+            }),
+            ttl_ms,
+            store: self.target_store_.clone(),
+            ..Default::default()
+        };
+        request.payload = update_req.encode_to_vec();
+        request.set_req_type(ReqType::Update);
+        self.send_message(request).await?;
+        Ok(true)
+    }
+
+    /// Updates a pair with an arbitrary binary key to hold an arbitrary
+    /// binary value, stored as BINARY.
+    pub async fn send_update_bytes(
+            &mut self, key: &[u8], val: &[u8], ttl_ms: Option<u64>) -> Result<bool, SocketError> {
+        self.send_update_typed(key, DataType::Binary, val.to_vec(), ttl_ms).await
+    }
+
+    /// Updates a pair to hold a value of `data_type`, encoded as described in
+    /// key_value_messages.proto.
+    pub async fn send_update_typed(
+            &mut self, key: &[u8], data_type: DataType, val: Vec<u8>,
+            ttl_ms: Option<u64>) -> Result<bool, SocketError> {
         let mut request = GenericRequest::default();
-        let mut update_req = UpdateKvPairReq::default();
-        let mut pair = KeyValuePair::default();
-        pair.key = String::from(key);
-        pair.value = String::from(val);
-        update_req.pair = Some(pair);
+        let update_req = UpdateKvPairReq {
+            typed_pair: Some(GenericKeyValuePair {
+                key: key.to_vec(),
+                data_type: data_type.into(),
+                value: val
+            }),
+            ttl_ms,
+            store: self.target_store_.clone(),
+            ..Default::default()
+        };
         request.payload = update_req.encode_to_vec();
         request.set_req_type(ReqType::Update);
         self.send_message(request).await?;
         Ok(true)
     }
 
+    /// Updates a pair to hold a value of any type, but only if it still holds
+    /// what `expected` says: the version it was read at, see
+    /// `receive_read_versioned`, or a value. The current expiry is kept if
+    /// `ttl_ms` is None. Use `receive_cas` to find out whether it did.
+    pub async fn send_cas(
+            &mut self, key: &[u8], data_type: DataType, val: Vec<u8>, expected: cas_req::Expected,
+            ttl_ms: Option<u64>) -> Result<bool, SocketError> {
+        let mut request = GenericRequest::default();
+        let mut pair = GenericKeyValuePair {
+            key: key.to_vec(),
+            value: val,
+            ..Default::default()
+        };
+        pair.set_data_type(data_type);
+        let cas_req = CasReq {
+            pair: Some(pair),
+            expected: Some(expected),
+            ttl_ms,
+            store: self.target_store_.clone()
+        };
+        request.payload = cas_req.encode_to_vec();
+        request.set_req_type(ReqType::Cas);
+        self.send_message(request).await?;
+        Ok(true)
+    }
+
+    /// Adds one to the integer a key holds, creating it if needed. Use
+    /// `receive_incr` to get the new value back.
+    pub async fn send_incr(&mut self, key: &[u8]) -> Result<bool, SocketError> {
+        self.send_incr_by(key, 1, None).await
+    }
+
+    /// Subtracts one from the integer a key holds, see `send_incr`
+    pub async fn send_decr(&mut self, key: &[u8]) -> Result<bool, SocketError> {
+        self.send_incr_by(key, -1, None).await
+    }
+
+    /// Adds `delta` to the integer a key holds, creating it holding `delta`
+    /// and expiring after `ttl_ms`, if given, when it is not live
+    pub async fn send_incr_by(&mut self, key: &[u8], delta: i64, ttl_ms: Option<u64>)
+            -> Result<bool, SocketError> {
+        let mut request = GenericRequest::default();
+        let incr_req = IncrReq {
+            key: key.to_vec(),
+            delta,
+            ttl_ms,
+            store: self.target_store_.clone()
+        };
+        request.payload = incr_req.encode_to_vec();
+        request.set_req_type(ReqType::Incr);
+        self.send_message(request).await?;
+        Ok(true)
+    }
+
+    /// Runs `ops` together, applying all of them or none. Use
+    /// `receive_transaction` to find out how each went.
+    pub async fn send_transaction(&mut self, ops: Vec<TxnOp>) -> Result<bool, SocketError> {
+        let mut request = GenericRequest::default();
+        let txn_req = TransactionReq {
+            ops,
+            store: self.target_store_.clone()
+        };
+        request.payload = txn_req.encode_to_vec();
+        request.set_req_type(ReqType::Transaction);
+        self.send_message(request).await?;
+        Ok(true)
+    }
+
+    /// Watches `keys` for the next `send_exec` on this connection, which
+    /// then applies nothing if any of them changed in the meantime
+    pub async fn send_watch(&mut self, keys: &[&str]) -> Result<bool, SocketError> {
+        let mut request = GenericRequest::default();
+        let watch_req = WatchReq {
+            keys: keys.iter().map(|k| k.as_bytes().to_vec()).collect(),
+            store: self.target_store_.clone()
+        };
+        request.payload = watch_req.encode_to_vec();
+        request.set_req_type(ReqType::Watch);
+        self.send_message(request).await?;
+        Ok(true)
+    }
+
+    pub async fn send_unwatch(&mut self) -> Result<bool, SocketError> {
+        let mut request = GenericRequest {
+            payload: UnwatchReq::default().encode_to_vec(),
+            ..Default::default()
+        };
+        request.set_req_type(ReqType::Unwatch);
+        self.send_message(request).await?;
+        Ok(true)
+    }
+
+    /// Like `send_transaction`, but runs `ops` only if none of the watched
+    /// keys changed, and stops watching them. Use `receive_exec` to find out
+    /// how it went.
+    pub async fn send_exec(&mut self, ops: Vec<TxnOp>) -> Result<bool, SocketError> {
+        let mut request = GenericRequest::default();
+        let txn_req = TransactionReq {
+            ops,
+            store: self.target_store_.clone()
+        };
+        request.payload = txn_req.encode_to_vec();
+        request.set_req_type(ReqType::Exec);
+        self.send_message(request).await?;
+        Ok(true)
+    }
+
+    /// Reads many keys in one request. Use `receive_mget` to get the pairs.
+    pub async fn send_mget(&mut self, keys: &[impl AsRef<[u8]>]) -> Result<bool, SocketError> {
+        let mut request = GenericRequest::default();
+        let mget_req = MGetReq {
+            keys: keys.iter().map(|k| k.as_ref().to_vec()).collect(),
+            store: self.target_store_.clone()
+        };
+        request.payload = mget_req.encode_to_vec();
+        request.set_req_type(ReqType::Mget);
+        self.send_message(request).await?;
+        Ok(true)
+    }
+
+    /// Writes many STRING pairs in one request, each created if its key is
+    /// not live and updated if it is. Each expires after the TTL if given.
+    pub async fn send_mset(&mut self, pairs: &[(&str, &str)], ttl_ms: Option<u64>)
+            -> Result<bool, SocketError> {
+        let mut request = GenericRequest::default();
+        let mset_req = MSetReq {
+            pairs: pairs.iter().map(|(key, val)| GenericKeyValuePair {
+                key: key.as_bytes().to_vec(),
+                data_type: DataType::String.into(),
+                value: val.as_bytes().to_vec()
+            }).collect(),
+            ttl_ms,
+            store: self.target_store_.clone()
+        };
+        request.payload = mset_req.encode_to_vec();
+        request.set_req_type(ReqType::Mset);
+        self.send_message(request).await?;
+        Ok(true)
+    }
+
+    /// Deletes many keys in one request
+    pub async fn send_mdel(&mut self, keys: &[impl AsRef<[u8]>]) -> Result<bool, SocketError> {
+        let mut request = GenericRequest::default();
+        let mdel_req = MDelReq {
+            keys: keys.iter().map(|k| k.as_ref().to_vec()).collect(),
+            store: self.target_store_.clone()
+        };
+        request.payload = mdel_req.encode_to_vec();
+        request.set_req_type(ReqType::Mdel);
+        self.send_message(request).await?;
+        Ok(true)
+    }
+
     pub async fn send_read(&mut self, key: &str) -> Result<bool, SocketError> {
+        self.send_read_bytes(key.as_bytes()).await
+    }
+
+    /// Reads a pair with an arbitrary binary key. Use `receive_read_bytes`
+    /// to get the value back as bytes.
+    pub async fn send_read_bytes(&mut self, key: &[u8]) -> Result<bool, SocketError> {
         let mut request = GenericRequest::default();
-        let mut read_req = ReadKvPairReq::default();
-        read_req.key = key.to_string();
+        let read_req = ReadKvPairReq {
+            key: key.to_vec(),
+            store: self.target_store_.clone(),
+            ..Default::default()
+        };
         request.payload = read_req.encode_to_vec();
         request.set_req_type(ReqType::Read);
         self.send_message(request).await?;
         Ok(true)
     }
 
+    /// Reads a pair as it was at a point in the past, which only works on
+    /// stores that keep history. Use `receive_read_versioned` to get the
+    /// pair back along with the version it was written at.
+    pub async fn send_read_as_of(&mut self, key: &[u8], as_of: read_kv_pair_req::AsOf)
+            -> Result<bool, SocketError> {
+        let mut request = GenericRequest::default();
+        let read_req = ReadKvPairReq {
+            key: key.to_vec(),
+            store: self.target_store_.clone(),
+            as_of: Some(as_of)
+        };
+        request.payload = read_req.encode_to_vec();
+        request.set_req_type(ReqType::Read);
+        self.send_message(request).await?;
+        Ok(true)
+    }
+
+    /// Asks for the past versions of a key, at most `limit` of them if given
+    pub async fn send_history(&mut self, key: &[u8], limit: Option<u32>)
+            -> Result<bool, SocketError> {
+        let mut request = GenericRequest::default();
+        let history_req = HistoryReq {
+            key: key.to_vec(),
+            store: self.target_store_.clone(),
+            limit
+        };
+        request.payload = history_req.encode_to_vec();
+        request.set_req_type(ReqType::History);
+        self.send_message(request).await?;
+        Ok(true)
+    }
+
     pub async fn send_restore(&mut self, backup_id: &str) -> Result<bool, SocketError> {
+        self.send_restore_with_mode(backup_id, RestoreMode::Replace, None, false).await
+    }
+
+    /// Restores the keys starting with `prefix`, or every key, combining the
+    /// backup with the store as `mode` says. With `dry_run`, only asks what
+    /// the restore would change.
+    pub async fn send_restore_with_mode(&mut self, backup_id: &str, mode: RestoreMode,
+            prefix: Option<&[u8]>, dry_run: bool) -> Result<bool, SocketError> {
         let mut request = GenericRequest::default();
-        let mut restore_req = RestoreReq::default();
-        restore_req.backup_id = backup_id.to_string();
+        let mut restore_req = RestoreReq {
+            backup_id: backup_id.to_string(),
+            store: self.target_store_.clone(),
+            prefix: prefix.map(|p| p.to_vec()),
+            dry_run,
+            ..Default::default()
+        };
+        restore_req.set_mode(mode);
         request.payload = restore_req.encode_to_vec();
         request.set_req_type(ReqType::Restore);
         self.send_message(request).await?;
@@ -104,15 +432,444 @@ impl ConstructCacheClient {
     }
 
     pub async fn send_backup(&mut self, backup_id: &str) -> Result<bool, SocketError> {
+        self.send_backup_with_base(backup_id, None).await
+    }
+
+    /// Starts a backup, holding only what changed since the backup
+    /// `base_backup_id` if one is given
+    pub async fn send_backup_with_base(&mut self, backup_id: &str, base_backup_id: Option<&str>)
+            -> Result<bool, SocketError> {
         let mut request = GenericRequest::default();
-        let mut read_req = BackupReq::default();
-        read_req.backup_id = backup_id.to_string();
-        request.payload = read_req.encode_to_vec();
+        let backup_req = BackupReq {
+            backup_id: backup_id.to_string(),
+            store: self.target_store_.clone(),
+            base_backup_id: base_backup_id.map(String::from)
+        };
+        request.payload = backup_req.encode_to_vec();
         request.set_req_type(ReqType::Backup);
         self.send_message(request).await?;
         Ok(true)
     }
 
+    /// Asks how the backup started as job `job_id` is getting on
+    pub async fn send_backup_status(&mut self, job_id: u64) -> Result<bool, SocketError> {
+        let mut request = GenericRequest {
+            payload: BackupStatusReq {
+                job_id
+            }.encode_to_vec(),
+            ..Default::default()
+        };
+        request.set_req_type(ReqType::BackupStatus);
+        self.send_message(request).await?;
+        Ok(true)
+    }
+
+    /// Lists the backups in the server's catalog
+    pub async fn send_list_backups(&mut self) -> Result<bool, SocketError> {
+        let mut request = GenericRequest {
+            payload: ListBackupsReq::default().encode_to_vec(),
+            ..Default::default()
+        };
+        request.set_req_type(ReqType::ListBackups);
+        self.send_message(request).await?;
+        Ok(true)
+    }
+
+    pub async fn send_backup_info(&mut self, backup_id: &str) -> Result<bool, SocketError> {
+        let mut request = GenericRequest {
+            payload: BackupInfoReq {
+                backup_id: backup_id.to_string()
+            }.encode_to_vec(),
+            ..Default::default()
+        };
+        request.set_req_type(ReqType::BackupInfo);
+        self.send_message(request).await?;
+        Ok(true)
+    }
+
+    /// Deletes a backup in the server's catalog, file and all
+    pub async fn send_delete_backup(&mut self, backup_id: &str) -> Result<bool, SocketError> {
+        let mut request = GenericRequest {
+            payload: DeleteBackupReq {
+                backup_id: backup_id.to_string()
+            }.encode_to_vec(),
+            ..Default::default()
+        };
+        request.set_req_type(ReqType::DeleteBackup);
+        self.send_message(request).await?;
+        Ok(true)
+    }
+
+    /// Starts merging the backup `backup_id` and its chain into the full
+    /// backup `target_backup_id`
+    pub async fn send_compact_backup(&mut self, backup_id: &str, target_backup_id: &str)
+            -> Result<bool, SocketError> {
+        let mut request = GenericRequest {
+            payload: CompactBackupReq {
+                backup_id: backup_id.to_string(),
+                target_backup_id: target_backup_id.to_string()
+            }.encode_to_vec(),
+            ..Default::default()
+        };
+        request.set_req_type(ReqType::CompactBackup);
+        self.send_message(request).await?;
+        Ok(true)
+    }
+
+    /// Exports the store to `file_name` in the server's export directory
+    pub async fn send_export(&mut self, file_name: &str, format: ExportFormat)
+            -> Result<bool, SocketError> {
+        let mut request = GenericRequest::default();
+        let mut export_req = ExportReq {
+            file_name: file_name.to_string(),
+            store: self.target_store_.clone(),
+            ..Default::default()
+        };
+        export_req.set_format(format);
+        request.payload = export_req.encode_to_vec();
+        request.set_req_type(ReqType::Export);
+        self.send_message(request).await?;
+        Ok(true)
+    }
+
+    /// Imports `file_name` from the server's export directory into the store,
+    /// giving up without changing anything if more than `max_errors` lines
+    /// fail
+    pub async fn send_import(&mut self, file_name: &str, format: ExportFormat,
+            on_conflict: ImportConflict, max_errors: u64) -> Result<bool, SocketError> {
+        let mut request = GenericRequest::default();
+        let mut import_req = ImportReq {
+            file_name: file_name.to_string(),
+            store: self.target_store_.clone(),
+            max_errors,
+            ..Default::default()
+        };
+        import_req.set_format(format);
+        import_req.set_on_conflict(on_conflict);
+        request.payload = import_req.encode_to_vec();
+        request.set_req_type(ReqType::Import);
+        self.send_message(request).await?;
+        Ok(true)
+    }
+
+    pub async fn send_get_ttl(&mut self, key: &str) -> Result<bool, SocketError> {
+        let mut request = GenericRequest::default();
+        let get_ttl_req = GetTtlReq {
+            key: key.as_bytes().to_vec(),
+            store: self.target_store_.clone()
+        };
+        request.payload = get_ttl_req.encode_to_vec();
+        request.set_req_type(ReqType::GetTtl);
+        self.send_message(request).await?;
+        Ok(true)
+    }
+
+    pub async fn send_set_ttl(&mut self, key: &str, ttl_ms: u64) -> Result<bool, SocketError> {
+        let mut request = GenericRequest::default();
+        let set_ttl_req = SetTtlReq {
+            key: key.as_bytes().to_vec(),
+            ttl_ms,
+            store: self.target_store_.clone()
+        };
+        request.payload = set_ttl_req.encode_to_vec();
+        request.set_req_type(ReqType::SetTtl);
+        self.send_message(request).await?;
+        Ok(true)
+    }
+
+    pub async fn send_remove_ttl(&mut self, key: &str) -> Result<bool, SocketError> {
+        let mut request = GenericRequest::default();
+        let remove_ttl_req = RemoveTtlReq {
+            key: key.as_bytes().to_vec(),
+            store: self.target_store_.clone()
+        };
+        request.payload = remove_ttl_req.encode_to_vec();
+        request.set_req_type(ReqType::RemoveTtl);
+        self.send_message(request).await?;
+        Ok(true)
+    }
+
+    pub async fn send_stats(&mut self) -> Result<bool, SocketError> {
+        let mut request = GenericRequest {
+            payload: StatsReq {
+                store: self.target_store_.clone()
+            }.encode_to_vec(),
+            ..Default::default()
+        };
+        request.set_req_type(ReqType::Stats);
+        self.send_message(request).await?;
+        Ok(true)
+    }
+
+    /// Creates an empty store called `name`
+    pub async fn send_create_store(&mut self, name: &str) -> Result<bool, SocketError> {
+        let mut request = GenericRequest {
+            payload: CreateStoreReq {
+                name: name.to_string()
+            }.encode_to_vec(),
+            ..Default::default()
+        };
+        request.set_req_type(ReqType::CreateStore);
+        self.send_message(request).await?;
+        Ok(true)
+    }
+
+    /// Drops the store called `name` along with everything in it
+    pub async fn send_drop_store(&mut self, name: &str) -> Result<bool, SocketError> {
+        let mut request = GenericRequest {
+            payload: DropStoreReq {
+                name: name.to_string()
+            }.encode_to_vec(),
+            ..Default::default()
+        };
+        request.set_req_type(ReqType::DropStore);
+        self.send_message(request).await?;
+        Ok(true)
+    }
+
+    pub async fn send_list_stores(&mut self) -> Result<bool, SocketError> {
+        let mut request = GenericRequest {
+            payload: ListStoresReq::default().encode_to_vec(),
+            ..Default::default()
+        };
+        request.set_req_type(ReqType::ListStores);
+        self.send_message(request).await?;
+        Ok(true)
+    }
+
+    /// Makes `name` the store used by this connection's later requests that
+    /// do not name one
+    pub async fn send_select_store(&mut self, name: &str) -> Result<bool, SocketError> {
+        let mut request = GenericRequest {
+            payload: SelectStoreReq {
+                name: name.to_string()
+            }.encode_to_vec(),
+            ..Default::default()
+        };
+        request.set_req_type(ReqType::SelectStore);
+        self.send_message(request).await?;
+        Ok(true)
+    }
+
+    /// Receives the response to a read, returning the value exactly as it is
+    /// stored, or None if the key was not found.
+    pub async fn receive_read_bytes(&mut self) -> Result<Option<Vec<u8>>, SocketError> {
+        let payload = self.receive_payload(ReqType::Read).await?;
+        let resp = parse_read_response_message(&payload)?;
+        if !resp.success {
+            return Ok(None);
+        }
+        match resp.typed_pair {
+            Some(p) => Ok(Some(p.value)),
+            // Servers that predate typed values only send the text pair
+            None => Ok(resp.pair.map(|p| p.value.into_bytes()))
+        }
+    }
+
+    /// Receives the response to a read, returning the pair along with its
+    /// version, or None if the key was not found.
+    pub async fn receive_read_versioned(&mut self)
+            -> Result<Option<(GenericKeyValuePair, u64)>, SocketError> {
+        let payload = self.receive_payload(ReqType::Read).await?;
+        let resp = parse_read_response_message(&payload)?;
+        if !resp.success {
+            return Ok(None);
+        }
+        match resp.typed_pair {
+            Some(p) => Ok(Some((p, resp.version))),
+            None => Err(SocketError {
+                kind_: ErrorKind::ParseError,
+                context_: "No typed pair in response".to_string()
+            })
+        }
+    }
+
+    /// Receives the response to an MGET, holding each key's pair along with
+    /// its version, or None if it was not found, in the order requested
+    pub async fn receive_mget(&mut self)
+            -> Result<Vec<Option<(GenericKeyValuePair, u64)>>, SocketError> {
+        let payload = self.receive_payload(ReqType::Mget).await?;
+        let resp = parse_mget_response_message(&payload)?;
+        if !resp.error.is_empty() {
+            return Err(SocketError {
+                kind_: ErrorKind::RequestFailedError,
+                context_: resp.error
+            });
+        }
+        Ok(resp.results.into_iter().map(|r| r.pair.map(|p| (p, r.version))).collect())
+    }
+
+    /// Receives the response to an MSET, holding each pair's new version, or
+    /// None if it was not written, in the order sent
+    pub async fn receive_mset(&mut self) -> Result<Vec<Option<u64>>, SocketError> {
+        let payload = self.receive_payload(ReqType::Mset).await?;
+        let resp = parse_mset_response_message(&payload)?;
+        if !resp.error.is_empty() {
+            return Err(SocketError {
+                kind_: ErrorKind::RequestFailedError,
+                context_: resp.error
+            });
+        }
+        Ok(resp.results.into_iter().map(|r| r.success.then_some(r.version)).collect())
+    }
+
+    /// Receives the response to an MDEL, holding whether each key was
+    /// deleted, in the order sent. A deletion has nothing else to report.
+    pub async fn receive_mdel(&mut self) -> Result<Vec<bool>, SocketError> {
+        let payload = self.receive_payload(ReqType::Mdel).await?;
+        let resp = parse_mdel_response_message(&payload)?;
+        if !resp.error.is_empty() {
+            return Err(SocketError {
+                kind_: ErrorKind::RequestFailedError,
+                context_: resp.error
+            });
+        }
+        Ok(resp.results.into_iter().map(|r| r.success).collect())
+    }
+
+    /// Receives the response to a compare-and-swap. On a conflict, the
+    /// response holds the key's current pair and version.
+    pub async fn receive_cas(&mut self) -> Result<CasResp, SocketError> {
+        let payload = self.receive_payload(ReqType::Cas).await?;
+        parse_cas_response_message(&payload)
+    }
+
+    /// Receives the response to an increment. On success, the response holds
+    /// the new value; otherwise `error_kind` says whether the key's value
+    /// was to blame.
+    pub async fn receive_incr(&mut self) -> Result<IncrResp, SocketError> {
+        let payload = self.receive_payload(ReqType::Incr).await?;
+        parse_incr_response_message(&payload)
+    }
+
+    /// Receives the response to a transaction, holding a result per
+    /// operation
+    pub async fn receive_transaction(&mut self) -> Result<TransactionResp, SocketError> {
+        let payload = self.receive_payload(ReqType::Transaction).await?;
+        parse_transaction_response_message(&payload)
+    }
+
+    pub async fn receive_watch(&mut self) -> Result<WatchResp, SocketError> {
+        let payload = self.receive_payload(ReqType::Watch).await?;
+        parse_watch_response_message(&payload)
+    }
+
+    pub async fn receive_unwatch(&mut self) -> Result<UnwatchResp, SocketError> {
+        let payload = self.receive_payload(ReqType::Unwatch).await?;
+        parse_unwatch_response_message(&payload)
+    }
+
+    /// Receives the response to an EXEC, whose `conflict` is set if a
+    /// watched key changed
+    pub async fn receive_exec(&mut self) -> Result<TransactionResp, SocketError> {
+        let payload = self.receive_payload(ReqType::Exec).await?;
+        parse_transaction_response_message(&payload)
+    }
+
+    /// Receives the response to a history request, listing the key's
+    /// revisions newest first
+    pub async fn receive_history(&mut self) -> Result<HistoryResp, SocketError> {
+        let payload = self.receive_payload(ReqType::History).await?;
+        parse_history_response_message(&payload)
+    }
+
+    /// Streams the pairs with keys between `start` and `end`, in byte order
+    /// or in reverse if `reverse` is set, stopping after `limit` pairs if
+    /// given. Pairs are fetched from the server a page at a time as the
+    /// stream is consumed. The stream ends after the first error.
+    pub fn scan_range(
+            &mut self, start: Bound<Vec<u8>>, end: Bound<Vec<u8>>, limit: Option<usize>,
+            reverse: bool) -> BoxStream<'_, Result<GenericKeyValuePair, SocketError>> {
+        let state = ScanState {
+            client: self,
+            start,
+            end,
+            remaining: limit,
+            reverse,
+            page: VecDeque::new(),
+            done: false
+        };
+        stream::unfold(state, |mut state| async move {
+            loop {
+                if state.remaining == Some(0) {
+                    return None;
+                }
+                if let Some(pair) = state.page.pop_front() {
+                    state.remaining = state.remaining.map(|r| r - 1);
+                    return Some((Ok(pair), state));
+                }
+                if state.done {
+                    return None;
+                }
+                if let Err(e) = state.fetch_page().await {
+                    state.done = true;
+                    return Some((Err(e), state));
+                }
+            }
+        }).boxed()
+    }
+
+    /// Fetches one page of keys matching `pattern`, or every key if None.
+    /// Start with an empty cursor and pass in the cursor of each response to
+    /// get the next page, until it comes back empty.
+    pub async fn scan_page(
+            &mut self, pattern: Option<scan_req::Pattern>, cursor: &[u8], count: u32,
+            with_values: bool) -> Result<ScanResp, SocketError> {
+        let mut request = GenericRequest::default();
+        let scan_req = ScanReq {
+            pattern,
+            cursor: cursor.to_vec(),
+            count,
+            with_values,
+            store: self.target_store_.clone()
+        };
+        request.payload = scan_req.encode_to_vec();
+        request.set_req_type(ReqType::Scan);
+        self.send_message(request).await?;
+        let payload = self.receive_payload(ReqType::Scan).await?;
+        let resp = parse_scan_response_message(&payload)?;
+        if !resp.success {
+            return Err(SocketError {
+                kind_: ErrorKind::RequestFailedError,
+                context_: resp.error
+            });
+        }
+        Ok(resp)
+    }
+
+    /// Sends a range scan and waits for its response
+    async fn send_scan_range(&mut self, req: ScanRangeReq) -> Result<ScanRangeResp, SocketError> {
+        let mut request = GenericRequest {
+            payload: req.encode_to_vec(),
+            ..Default::default()
+        };
+        request.set_req_type(ReqType::ScanRange);
+        self.send_message(request).await?;
+        let payload = self.receive_payload(ReqType::ScanRange).await?;
+        parse_scan_range_response_message(&payload)
+    }
+
+    /// Receives the next response, which must be to a request of
+    /// `req_type`, and returns its payload.
+    async fn receive_payload(&mut self, req_type: ReqType) -> Result<Vec<u8>, SocketError> {
+        let bytes = match self._framed.next().await {
+            Some(Ok(b)) => b,
+            _ => return Err(SocketError {
+                kind_: ErrorKind::ConnectError,
+                context_: "Connection closed!".to_string()
+            })
+        };
+        let generic_resp = parse_generic_response_message(&bytes.freeze())?;
+        if generic_resp.req_type() != req_type {
+            return Err(SocketError {
+                kind_: ErrorKind::ParseError,
+                context_: format!("Expected a {:?} response, got {:?}",
+                    req_type, generic_resp.req_type())
+            });
+        }
+        Ok(generic_resp.payload)
+    }
+
     pub async fn receive_resp(&mut self) -> Result<String, SocketError> {
         if let Some(Ok(bytes)) = self._framed.next().await {
             match parse_generic_response(&bytes.freeze()) {
@@ -124,4 +881,52 @@ impl ConstructCacheClient {
         }
         Ok("".to_string())
     }
-}
\ No newline at end of file
+}
+
+fn bound_key(bound: &Bound<Vec<u8>>) -> (Option<Vec<u8>>, bool) {
+    match bound {
+        Bound::Unbounded => (None, false),
+        Bound::Included(k) => (Some(k.clone()), false),
+        Bound::Excluded(k) => (Some(k.clone()), true)
+    }
+}
+
+impl ScanState<'_> {
+    /// Fetches the next page of the scan and narrows the range to exclude
+    /// everything up to the end of that page.
+    async fn fetch_page(&mut self) -> Result<(), SocketError> {
+        let (start, start_exclusive) = bound_key(&self.start);
+        let (end, end_exclusive) = bound_key(&self.end);
+        let limit = self.remaining.unwrap_or(usize::MAX).min(u32::MAX as usize) as u32;
+        let resp = self.client.send_scan_range(ScanRangeReq {
+            start,
+            start_exclusive,
+            end,
+            end_exclusive,
+            limit,
+            reverse: self.reverse,
+            store: self.client.target_store_.clone()
+        }).await?;
+        if !resp.success {
+            return Err(SocketError {
+                kind_: ErrorKind::RequestFailedError,
+                context_: if resp.error.is_empty() {
+                    "Cannot scan range".to_string()
+                } else {
+                    resp.error
+                }
+            });
+        }
+        if let Some(last) = resp.pairs.last() {
+            let past_last = Bound::Excluded(last.key.clone());
+            if self.reverse {
+                self.end = past_last;
+            } else {
+                self.start = past_last;
+            }
+        }
+        self.done = !resp.more || resp.pairs.is_empty();
+        self.page = resp.pairs.into();
+        Ok(())
+    }
+}
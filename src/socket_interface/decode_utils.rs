@@ -55,6 +55,96 @@ pub fn parse_update_request(request: &[u8]) -> Result <UpdateKvPairReq, SocketEr
     }
 }
 
+pub fn parse_cas_request(request: &[u8]) -> Result<CasReq, SocketError> {
+    match CasReq::decode(request) {
+        Ok(res) => Ok(res),
+        Err(e) => Err(SocketError {
+            kind_: ErrorKind::ParseError,
+            context_: e.to_string()
+        })
+    }
+}
+
+pub fn parse_history_request(request: &[u8]) -> Result<HistoryReq, SocketError> {
+    match HistoryReq::decode(request) {
+        Ok(res) => Ok(res),
+        Err(e) => Err(SocketError {
+            kind_: ErrorKind::ParseError,
+            context_: e.to_string()
+        })
+    }
+}
+
+pub fn parse_incr_request(request: &[u8]) -> Result<IncrReq, SocketError> {
+    match IncrReq::decode(request) {
+        Ok(res) => Ok(res),
+        Err(e) => Err(SocketError {
+            kind_: ErrorKind::ParseError,
+            context_: e.to_string()
+        })
+    }
+}
+
+pub fn parse_transaction_request(request: &[u8]) -> Result<TransactionReq, SocketError> {
+    match TransactionReq::decode(request) {
+        Ok(res) => Ok(res),
+        Err(e) => Err(SocketError {
+            kind_: ErrorKind::ParseError,
+            context_: e.to_string()
+        })
+    }
+}
+
+pub fn parse_watch_request(request: &[u8]) -> Result<WatchReq, SocketError> {
+    match WatchReq::decode(request) {
+        Ok(res) => Ok(res),
+        Err(e) => Err(SocketError {
+            kind_: ErrorKind::ParseError,
+            context_: e.to_string()
+        })
+    }
+}
+
+pub fn parse_unwatch_request(request: &[u8]) -> Result<UnwatchReq, SocketError> {
+    match UnwatchReq::decode(request) {
+        Ok(res) => Ok(res),
+        Err(e) => Err(SocketError {
+            kind_: ErrorKind::ParseError,
+            context_: e.to_string()
+        })
+    }
+}
+
+pub fn parse_mget_request(request: &[u8]) -> Result<MGetReq, SocketError> {
+    match MGetReq::decode(request) {
+        Ok(res) => Ok(res),
+        Err(e) => Err(SocketError {
+            kind_: ErrorKind::ParseError,
+            context_: e.to_string()
+        })
+    }
+}
+
+pub fn parse_mset_request(request: &[u8]) -> Result<MSetReq, SocketError> {
+    match MSetReq::decode(request) {
+        Ok(res) => Ok(res),
+        Err(e) => Err(SocketError {
+            kind_: ErrorKind::ParseError,
+            context_: e.to_string()
+        })
+    }
+}
+
+pub fn parse_mdel_request(request: &[u8]) -> Result<MDelReq, SocketError> {
+    match MDelReq::decode(request) {
+        Ok(res) => Ok(res),
+        Err(e) => Err(SocketError {
+            kind_: ErrorKind::ParseError,
+            context_: e.to_string()
+        })
+    }
+}
+
 pub fn parse_delete_request(request: &[u8]) -> Result <DeleteKvPairReq, SocketError> {
     match DeleteKvPairReq::decode(request) {
         Ok(res) => Ok(res),
@@ -65,8 +155,688 @@ pub fn parse_delete_request(request: &[u8]) -> Result <DeleteKvPairReq, SocketEr
     }
 }
 
-pub fn parse_backup_request(request: &[u8]) -> Result<BackupReq, SocketError> {
-    match BackupReq::decode(request) {
+pub fn parse_backup_request(request: &[u8]) -> Result<BackupReq, SocketError> {
+    match BackupReq::decode(request) {
+        Ok(res) => Ok(res),
+        Err(e) => Err(SocketError {
+            kind_: ErrorKind::ParseError,
+            context_: e.to_string()
+        })
+    }
+}
+
+pub fn parse_restore_request(request: &[u8]) -> Result<RestoreReq, SocketError> {
+    match RestoreReq::decode(request) {
+        Ok(res) => Ok(res),
+        Err(e) => Err(SocketError {
+            kind_: ErrorKind::ParseError,
+            context_: e.to_string()
+        })
+    }
+}
+
+pub fn parse_get_ttl_request(request: &[u8]) -> Result<GetTtlReq, SocketError> {
+    match GetTtlReq::decode(request) {
+        Ok(res) => Ok(res),
+        Err(e) => Err(SocketError {
+            kind_: ErrorKind::ParseError,
+            context_: e.to_string()
+        })
+    }
+}
+
+pub fn parse_set_ttl_request(request: &[u8]) -> Result<SetTtlReq, SocketError> {
+    match SetTtlReq::decode(request) {
+        Ok(res) => Ok(res),
+        Err(e) => Err(SocketError {
+            kind_: ErrorKind::ParseError,
+            context_: e.to_string()
+        })
+    }
+}
+
+pub fn parse_remove_ttl_request(request: &[u8]) -> Result<RemoveTtlReq, SocketError> {
+    match RemoveTtlReq::decode(request) {
+        Ok(res) => Ok(res),
+        Err(e) => Err(SocketError {
+            kind_: ErrorKind::ParseError,
+            context_: e.to_string()
+        })
+    }
+}
+
+pub fn parse_stats_request(request: &[u8]) -> Result<StatsReq, SocketError> {
+    match StatsReq::decode(request) {
+        Ok(res) => Ok(res),
+        Err(e) => Err(SocketError {
+            kind_: ErrorKind::ParseError,
+            context_: e.to_string()
+        })
+    }
+}
+
+pub fn parse_scan_range_request(request: &[u8]) -> Result<ScanRangeReq, SocketError> {
+    match ScanRangeReq::decode(request) {
+        Ok(res) => Ok(res),
+        Err(e) => Err(SocketError {
+            kind_: ErrorKind::ParseError,
+            context_: e.to_string()
+        })
+    }
+}
+
+pub fn parse_scan_request(request: &[u8]) -> Result<ScanReq, SocketError> {
+    match ScanReq::decode(request) {
+        Ok(res) => Ok(res),
+        Err(e) => Err(SocketError {
+            kind_: ErrorKind::ParseError,
+            context_: e.to_string()
+        })
+    }
+}
+
+pub fn parse_create_store_request(request: &[u8]) -> Result<CreateStoreReq, SocketError> {
+    match CreateStoreReq::decode(request) {
+        Ok(res) => Ok(res),
+        Err(e) => Err(SocketError {
+            kind_: ErrorKind::ParseError,
+            context_: e.to_string()
+        })
+    }
+}
+
+pub fn parse_drop_store_request(request: &[u8]) -> Result<DropStoreReq, SocketError> {
+    match DropStoreReq::decode(request) {
+        Ok(res) => Ok(res),
+        Err(e) => Err(SocketError {
+            kind_: ErrorKind::ParseError,
+            context_: e.to_string()
+        })
+    }
+}
+
+pub fn parse_list_stores_request(request: &[u8]) -> Result<ListStoresReq, SocketError> {
+    match ListStoresReq::decode(request) {
+        Ok(res) => Ok(res),
+        Err(e) => Err(SocketError {
+            kind_: ErrorKind::ParseError,
+            context_: e.to_string()
+        })
+    }
+}
+
+pub fn parse_select_store_request(request: &[u8]) -> Result<SelectStoreReq, SocketError> {
+    match SelectStoreReq::decode(request) {
+        Ok(res) => Ok(res),
+        Err(e) => Err(SocketError {
+            kind_: ErrorKind::ParseError,
+            context_: e.to_string()
+        })
+    }
+}
+
+pub fn parse_backup_status_request(request: &[u8]) -> Result<BackupStatusReq, SocketError> {
+    match BackupStatusReq::decode(request) {
+        Ok(res) => Ok(res),
+        Err(e) => Err(SocketError {
+            kind_: ErrorKind::ParseError,
+            context_: e.to_string()
+        })
+    }
+}
+
+pub fn parse_list_backups_request(request: &[u8]) -> Result<ListBackupsReq, SocketError> {
+    match ListBackupsReq::decode(request) {
+        Ok(res) => Ok(res),
+        Err(e) => Err(SocketError {
+            kind_: ErrorKind::ParseError,
+            context_: e.to_string()
+        })
+    }
+}
+
+pub fn parse_backup_info_request(request: &[u8]) -> Result<BackupInfoReq, SocketError> {
+    match BackupInfoReq::decode(request) {
+        Ok(res) => Ok(res),
+        Err(e) => Err(SocketError {
+            kind_: ErrorKind::ParseError,
+            context_: e.to_string()
+        })
+    }
+}
+
+pub fn parse_delete_backup_request(request: &[u8]) -> Result<DeleteBackupReq, SocketError> {
+    match DeleteBackupReq::decode(request) {
+        Ok(res) => Ok(res),
+        Err(e) => Err(SocketError {
+            kind_: ErrorKind::ParseError,
+            context_: e.to_string()
+        })
+    }
+}
+
+pub fn parse_compact_backup_request(request: &[u8]) -> Result<CompactBackupReq, SocketError> {
+    match CompactBackupReq::decode(request) {
+        Ok(res) => Ok(res),
+        Err(e) => Err(SocketError {
+            kind_: ErrorKind::ParseError,
+            context_: e.to_string()
+        })
+    }
+}
+
+pub fn parse_export_request(request: &[u8]) -> Result<ExportReq, SocketError> {
+    match ExportReq::decode(request) {
+        Ok(res) => Ok(res),
+        Err(e) => Err(SocketError {
+            kind_: ErrorKind::ParseError,
+            context_: e.to_string()
+        })
+    }
+}
+
+pub fn parse_import_request(request: &[u8]) -> Result<ImportReq, SocketError> {
+    match ImportReq::decode(request) {
+        Ok(res) => Ok(res),
+        Err(e) => Err(SocketError {
+            kind_: ErrorKind::ParseError,
+            context_: e.to_string()
+        })
+    }
+}
+
+fn parse_ping_response(payload: &[u8]) -> Result<String, SocketError> {
+    match PingResponse::decode(payload) {
+        Ok(v) => {
+            Ok(v.ping_resp_message.to_string())
+        },
+        Err(e) => {
+            Err(SocketError {
+                kind_: ErrorKind::ParseError,
+                context_: e.to_string()
+            })
+        }
+    }
+}
+
+fn parse_create_response(payload: &[u8]) -> Result<String, SocketError> {
+    match CreateKvPairResp::decode(payload) {
+        Ok(v) => {
+            if v.success {
+                Ok("Successfully created pair!".to_string())
+            } else if !v.error.is_empty() {
+                Ok(format!("Could not create pair: {}", v.error))
+            } else {
+                Ok("Key already exists!".to_string())
+            }
+        },
+        Err(e) => {
+            Err(SocketError {
+                kind_: ErrorKind::ParseError,
+                context_: e.to_string()
+            })
+        }
+    }
+    
+}
+
+fn parse_read_response(payload: &[u8]) -> Result<String, SocketError> {
+    match ReadKvPairResp::decode(payload) {
+        Ok(v) => {
+            if v.success {
+                match v.pair {
+                    Some(p) => match v.typed_pair {
+                        Some(t) if t.data_type() != DataType::String => {
+                            Ok(format!("{} ({}, version {})", p.value,
+                                t.data_type().as_str_name(), v.version))
+                        },
+                        _ => Ok(format!("{} (version {})", p.value, v.version))
+                    },
+                    None => Err(SocketError {
+                        kind_: ErrorKind::ParseError,
+                        context_: "No pair in response".to_string()
+                     })
+                }
+            } else if !v.error.is_empty() {
+                Ok(format!("Cannot read key: {}", v.error))
+            } else {
+                Ok("Cannot find key!".to_string())
+            }
+        },
+        Err(e) => {
+            Err(SocketError {
+                kind_: ErrorKind::ParseError,
+                context_: e.to_string()
+            })
+        }
+    }
+}
+
+fn parse_update_response(payload: &[u8]) -> Result<String, SocketError> {
+    match UpdateKvPairResp::decode(payload) {
+         Ok(v) => {
+            if v.success {
+                Ok("Successfully updated pair!".to_string())
+            } else if !v.error.is_empty() {
+                Ok(format!("Could not update pair: {}", v.error))
+            } else {
+                Ok("Key does not exist!".to_string())
+            }
+        },
+        Err(e) => {
+            Err(SocketError {
+                kind_: ErrorKind::ParseError,
+                context_: e.to_string()
+            })
+        }
+    }
+}
+
+fn parse_cas_response(payload: &[u8]) -> Result<String, SocketError> {
+    let v = parse_cas_response_message(payload)?;
+    if v.success {
+        Ok(format!("Swapped! New version: {}", v.version.unwrap_or_default()))
+    } else if !v.error.is_empty() {
+        Ok(format!("Could not swap: {}", v.error))
+    } else {
+        match v.current {
+            Some(c) => Ok(format!("Conflict: key holds {} ({}, version {})",
+                key_value_pair::value_to_text(c.data_type(), &c.value),
+                c.data_type().as_str_name(), v.version.unwrap_or_default())),
+            None => Ok("Key does not exist!".to_string())
+        }
+    }
+}
+
+fn parse_incr_response(payload: &[u8]) -> Result<String, SocketError> {
+    let v = parse_incr_response_message(payload)?;
+    if v.success {
+        Ok(format!("{} (version {})", v.value, v.version))
+    } else {
+        Ok(format!("Could not increment: {}", v.error))
+    }
+}
+
+pub fn parse_incr_response_message(payload: &[u8]) -> Result<IncrResp, SocketError> {
+    match IncrResp::decode(payload) {
+        Ok(res) => Ok(res),
+        Err(e) => Err(SocketError {
+            kind_: ErrorKind::ParseError,
+            context_: e.to_string()
+        })
+    }
+}
+
+/// Says whether the transaction was applied, then how each operation went,
+/// one per line
+fn parse_transaction_response(payload: &[u8]) -> Result<String, SocketError> {
+    let v = parse_transaction_response_message(payload)?;
+    if !v.error.is_empty() {
+        return Ok(format!("Could not run transaction: {}", v.error));
+    }
+    let mut lines = vec![if v.success {
+        "Transaction applied!".to_string()
+    } else if v.conflict {
+        match &v.conflicting_key {
+            Some(k) => format!("Watched key {} changed, nothing was applied",
+                String::from_utf8_lossy(k)),
+            None => "Watched store was dropped, nothing was applied".to_string()
+        }
+    } else {
+        "Transaction failed, nothing was applied".to_string()
+    }];
+    for (i, r) in v.results.iter().enumerate() {
+        let outcome = match r.status() {
+            TxnOpStatus::Ok => match r.version {
+                Some(version) => format!("ok (version {})", version),
+                None => "ok".to_string()
+            },
+            TxnOpStatus::Failed => format!("failed: {}", r.error),
+            TxnOpStatus::NotRun => "not run".to_string()
+        };
+        lines.push(format!("{}: {}", i + 1, outcome));
+    }
+    Ok(lines.join("\n"))
+}
+
+pub fn parse_transaction_response_message(payload: &[u8])
+        -> Result<TransactionResp, SocketError> {
+    match TransactionResp::decode(payload) {
+        Ok(res) => Ok(res),
+        Err(e) => Err(SocketError {
+            kind_: ErrorKind::ParseError,
+            context_: e.to_string()
+        })
+    }
+}
+
+fn parse_watch_response(payload: &[u8]) -> Result<String, SocketError> {
+    let v = parse_watch_response_message(payload)?;
+    if !v.success {
+        return Ok(format!("Cannot watch keys: {}", v.error));
+    }
+    Ok(format!("Watching {} keys", v.watched))
+}
+
+pub fn parse_watch_response_message(payload: &[u8]) -> Result<WatchResp, SocketError> {
+    match WatchResp::decode(payload) {
+        Ok(res) => Ok(res),
+        Err(e) => Err(SocketError {
+            kind_: ErrorKind::ParseError,
+            context_: e.to_string()
+        })
+    }
+}
+
+fn parse_unwatch_response(payload: &[u8]) -> Result<String, SocketError> {
+    let v = parse_unwatch_response_message(payload)?;
+    Ok(format!("Stopped watching {} keys", v.unwatched))
+}
+
+pub fn parse_unwatch_response_message(payload: &[u8]) -> Result<UnwatchResp, SocketError> {
+    match UnwatchResp::decode(payload) {
+        Ok(res) => Ok(res),
+        Err(e) => Err(SocketError {
+            kind_: ErrorKind::ParseError,
+            context_: e.to_string()
+        })
+    }
+}
+
+/// Lists one line per key, e.g. "1: hello (version 7)"
+fn parse_mget_response(payload: &[u8]) -> Result<String, SocketError> {
+    let v = parse_mget_response_message(payload)?;
+    if !v.error.is_empty() {
+        return Ok(format!("Cannot read keys: {}", v.error));
+    }
+    Ok(v.results.iter().enumerate()
+        .map(|(i, r)| match &r.pair {
+            None => format!("{}: not found", i + 1),
+            Some(p) if p.data_type() == DataType::String => format!("{}: {} (version {})", i + 1,
+                key_value_pair::value_to_text(p.data_type(), &p.value), r.version),
+            Some(p) => format!("{}: {} ({}, version {})", i + 1,
+                key_value_pair::value_to_text(p.data_type(), &p.value),
+                p.data_type().as_str_name(), r.version)
+        })
+        .collect::<Vec<String>>()
+        .join("\n"))
+}
+
+pub fn parse_mget_response_message(payload: &[u8]) -> Result<MGetResp, SocketError> {
+    match MGetResp::decode(payload) {
+        Ok(res) => Ok(res),
+        Err(e) => Err(SocketError {
+            kind_: ErrorKind::ParseError,
+            context_: e.to_string()
+        })
+    }
+}
+
+/// Lists one line per pair, e.g. "1: written (version 7)"
+fn parse_mset_response(payload: &[u8]) -> Result<String, SocketError> {
+    let v = parse_mset_response_message(payload)?;
+    if !v.error.is_empty() {
+        return Ok(format!("Cannot write pairs: {}", v.error));
+    }
+    Ok(v.results.iter().enumerate()
+        .map(|(i, r)| if r.success {
+            format!("{}: written (version {})", i + 1, r.version)
+        } else {
+            format!("{}: failed: {}", i + 1, r.error)
+        })
+        .collect::<Vec<String>>()
+        .join("\n"))
+}
+
+pub fn parse_mset_response_message(payload: &[u8]) -> Result<MSetResp, SocketError> {
+    match MSetResp::decode(payload) {
+        Ok(res) => Ok(res),
+        Err(e) => Err(SocketError {
+            kind_: ErrorKind::ParseError,
+            context_: e.to_string()
+        })
+    }
+}
+
+/// Lists one line per key, e.g. "1: deleted"
+fn parse_mdel_response(payload: &[u8]) -> Result<String, SocketError> {
+    let v = parse_mdel_response_message(payload)?;
+    if !v.error.is_empty() {
+        return Ok(format!("Cannot delete keys: {}", v.error));
+    }
+    Ok(v.results.iter().enumerate()
+        .map(|(i, r)| match (r.success, r.error.is_empty()) {
+            (true, _) => format!("{}: deleted", i + 1),
+            (false, true) => format!("{}: not found", i + 1),
+            (false, false) => format!("{}: failed: {}", i + 1, r.error)
+        })
+        .collect::<Vec<String>>()
+        .join("\n"))
+}
+
+pub fn parse_mdel_response_message(payload: &[u8]) -> Result<MDelResp, SocketError> {
+    match MDelResp::decode(payload) {
+        Ok(res) => Ok(res),
+        Err(e) => Err(SocketError {
+            kind_: ErrorKind::ParseError,
+            context_: e.to_string()
+        })
+    }
+}
+
+/// Lists revisions one per line, e.g. "version 7 at 1700000000000: hello"
+fn parse_history_response(payload: &[u8]) -> Result<String, SocketError> {
+    let v = parse_history_response_message(payload)?;
+    if !v.error.is_empty() {
+        return Ok(format!("Cannot read history: {}", v.error));
+    }
+    if v.revisions.is_empty() {
+        return Ok("Key has no history!".to_string());
+    }
+    Ok(v.revisions.iter()
+        .map(|r| {
+            let when = match r.written_at_ms {
+                Some(t) => format!("at {}", t),
+                None => "before history began".to_string()
+            };
+            let what = match &r.pair {
+                None => "deleted".to_string(),
+                Some(p) if p.data_type() == DataType::String =>
+                    key_value_pair::value_to_text(p.data_type(), &p.value),
+                Some(p) => format!("{} ({})", key_value_pair::value_to_text(p.data_type(), &p.value),
+                    p.data_type().as_str_name())
+            };
+            format!("version {} {}: {}", r.version, when, what)
+        })
+        .collect::<Vec<String>>()
+        .join("\n"))
+}
+
+pub fn parse_history_response_message(payload: &[u8]) -> Result<HistoryResp, SocketError> {
+    match HistoryResp::decode(payload) {
+        Ok(res) => Ok(res),
+        Err(e) => Err(SocketError {
+            kind_: ErrorKind::ParseError,
+            context_: e.to_string()
+        })
+    }
+}
+
+fn parse_delete_response(payload: &[u8]) -> Result<String, SocketError> {
+    match DeleteKvPairResp::decode(payload) {
+        Ok(v) => {
+            if v.success {
+                Ok("Successfully deleted entry!".to_string())
+            } else if !v.error.is_empty() {
+                Ok(format!("Could not delete entry: {}", v.error))
+            } else {
+                Ok("Key does not exist!".to_string())
+            }
+        },
+        Err(e) => {
+            Err(SocketError {
+                kind_: ErrorKind::ParseError,
+                context_: e.to_string()
+            })
+        }
+    }
+}
+
+fn parse_backup_response(payload: &[u8]) -> Result<String, SocketError> {
+    match BackupResp::decode(payload) {
+        Ok(v) => {
+            if v.success {
+                Ok(format!("Started backup as job {}", v.job_id))
+            } else if v.error.is_empty() {
+                Ok("Could not complete backup!".to_string())
+            } else {
+                Ok(format!("Could not complete backup: {}", v.error))
+            }
+        },
+        Err(e) => {
+            Err(SocketError {
+                kind_: ErrorKind::ParseError,
+                context_: e.to_string()
+            })
+        }
+    }
+}
+
+fn parse_restore_response(payload: &[u8]) -> Result<String, SocketError> {
+    match RestoreResp::decode(payload) {
+        Ok(v) => {
+            if v.success && v.dry_run {
+                Ok(format!("Restoring would add {} keys, overwrite {} and remove {}",
+                    v.added, v.overwritten, v.removed))
+            } else if v.success {
+                Ok(format!("Successfully restored from backup! Added {} keys, overwrote {} \
+                    and removed {}", v.added, v.overwritten, v.removed))
+            } else if !v.error.is_empty() {
+                Ok(format!("Could not restore from backup: {}", v.error))
+            } else {
+                Ok("Could not restore from backup!".to_string())
+            }
+        },
+        Err(e) => {
+            Err(SocketError {
+                kind_: ErrorKind::ParseError,
+                context_: e.to_string()
+            })
+        }
+    }
+}
+
+fn parse_get_ttl_response(payload: &[u8]) -> Result<String, SocketError> {
+    match GetTtlResp::decode(payload) {
+        Ok(v) => {
+            if !v.error.is_empty() {
+                Ok(format!("Cannot get TTL: {}", v.error))
+            } else if !v.success {
+                Ok("Cannot find key!".to_string())
+            } else {
+                match v.ttl_ms {
+                    Some(t) => Ok(format!("Expires in {} ms", t)),
+                    None => Ok("Key does not expire".to_string())
+                }
+            }
+        },
+        Err(e) => {
+            Err(SocketError {
+                kind_: ErrorKind::ParseError,
+                context_: e.to_string()
+            })
+        }
+    }
+}
+
+fn parse_set_ttl_response(payload: &[u8]) -> Result<String, SocketError> {
+    match SetTtlResp::decode(payload) {
+        Ok(v) => {
+            if v.success {
+                Ok("Successfully set TTL!".to_string())
+            } else if !v.error.is_empty() {
+                Ok(format!("Could not set TTL: {}", v.error))
+            } else {
+                Ok("Key does not exist!".to_string())
+            }
+        },
+        Err(e) => {
+            Err(SocketError {
+                kind_: ErrorKind::ParseError,
+                context_: e.to_string()
+            })
+        }
+    }
+}
+
+fn parse_remove_ttl_response(payload: &[u8]) -> Result<String, SocketError> {
+    match RemoveTtlResp::decode(payload) {
+        Ok(v) => {
+            if v.success {
+                Ok("Successfully removed TTL!".to_string())
+            } else if !v.error.is_empty() {
+                Ok(format!("Could not remove TTL: {}", v.error))
+            } else {
+                Ok("Key does not exist!".to_string())
+            }
+        },
+        Err(e) => {
+            Err(SocketError {
+                kind_: ErrorKind::ParseError,
+                context_: e.to_string()
+            })
+        }
+    }
+}
+
+fn parse_stats_response(payload: &[u8]) -> Result<String, SocketError> {
+    match StatsResp::decode(payload) {
+        Ok(v) => {
+            if !v.error.is_empty() {
+                return Ok(format!("Cannot get stats: {}", v.error));
+            }
+            let limit = match v.max_memory_bytes {
+                Some(m) => format!("{} bytes ({})", m, v.eviction_policy),
+                None => "none".to_string()
+            };
+            Ok(format!(
+                "keys: {}, memory: {} bytes, limit: {}, evicted: {}, expired: {}",
+                v.key_count, v.memory_bytes, limit, v.evicted_keys, v.expired_keys))
+        },
+        Err(e) => {
+            Err(SocketError {
+                kind_: ErrorKind::ParseError,
+                context_: e.to_string()
+            })
+        }
+    }
+}
+
+fn parse_scan_range_response(payload: &[u8]) -> Result<String, SocketError> {
+    let v = parse_scan_range_response_message(payload)?;
+    if !v.success {
+        if !v.error.is_empty() {
+            return Ok(format!("Cannot scan range: {}", v.error));
+        }
+        return Ok("Cannot scan range!".to_string());
+    }
+    if v.pairs.is_empty() {
+        return Ok("No keys in range".to_string());
+    }
+    let mut lines: Vec<String> = v.pairs.iter()
+        .map(|p| match generic_kvp_to_kvp_rust(p.clone()) {
+            Ok(kvp) => format!("{} = {}", kvp.key_text(), kvp.value()),
+            Err(e) => format!("{} = <{}>", String::from_utf8_lossy(&p.key), e)
+        })
+        .collect();
+    if v.more {
+        lines.push("...".to_string());
+    }
+    Ok(lines.join("\n"))
+}
+
+pub fn parse_scan_range_response_message(payload: &[u8]) -> Result<ScanRangeResp, SocketError> {
+    match ScanRangeResp::decode(payload) {
         Ok(res) => Ok(res),
         Err(e) => Err(SocketError {
             kind_: ErrorKind::ParseError,
@@ -75,8 +845,32 @@ pub fn parse_backup_request(request: &[u8]) -> Result<BackupReq, SocketError> {
     }
 }
 
-pub fn parse_restore_request(request: &[u8]) -> Result<RestoreReq, SocketError> {
-    match RestoreReq::decode(request) {
+fn parse_scan_response(payload: &[u8]) -> Result<String, SocketError> {
+    let v = parse_scan_response_message(payload)?;
+    if !v.success {
+        return Ok(format!("Cannot scan: {}", v.error));
+    }
+    let mut lines: Vec<String> = if v.pairs.is_empty() {
+        v.keys.iter().map(|k| String::from_utf8_lossy(k).into_owned()).collect()
+    } else {
+        v.pairs.iter()
+            .map(|p| match generic_kvp_to_kvp_rust(p.clone()) {
+                Ok(kvp) => format!("{} = {}", kvp.key_text(), kvp.value()),
+                Err(e) => format!("{} = <{}>", String::from_utf8_lossy(&p.key), e)
+            })
+            .collect()
+    };
+    if v.cursor.is_empty() {
+        lines.push("(end of scan)".to_string());
+    } else {
+        lines.push(format!("(cursor {})",
+            v.cursor.iter().map(|b| format!("{:02x}", b)).collect::<String>()));
+    }
+    Ok(lines.join("\n"))
+}
+
+pub fn parse_scan_response_message(payload: &[u8]) -> Result<ScanResp, SocketError> {
+    match ScanResp::decode(payload) {
         Ok(res) => Ok(res),
         Err(e) => Err(SocketError {
             kind_: ErrorKind::ParseError,
@@ -85,10 +879,14 @@ pub fn parse_restore_request(request: &[u8]) -> Result<RestoreReq, SocketError>
     }
 }
 
-fn parse_ping_response(payload: &[u8]) -> Result<String, SocketError> {
-    match PingResponse::decode(payload) {
+fn parse_create_store_response(payload: &[u8]) -> Result<String, SocketError> {
+    match CreateStoreResp::decode(payload) {
         Ok(v) => {
-            Ok(v.ping_resp_message.to_string())
+            if v.success {
+                Ok("Successfully created store!".to_string())
+            } else {
+                Ok(format!("Could not create store: {}", v.error))
+            }
         },
         Err(e) => {
             Err(SocketError {
@@ -99,13 +897,13 @@ fn parse_ping_response(payload: &[u8]) -> Result<String, SocketError> {
     }
 }
 
-fn parse_create_response(payload: &[u8]) -> Result<String, SocketError> {
-    match CreateKvPairResp::decode(payload) {
+fn parse_drop_store_response(payload: &[u8]) -> Result<String, SocketError> {
+    match DropStoreResp::decode(payload) {
         Ok(v) => {
             if v.success {
-                Ok("Successfully created pair!".to_string())
+                Ok("Successfully dropped store!".to_string())
             } else {
-                Ok("Key already exists!".to_string())
+                Ok(format!("Could not drop store: {}", v.error))
             }
         },
         Err(e) => {
@@ -115,22 +913,37 @@ fn parse_create_response(payload: &[u8]) -> Result<String, SocketError> {
             })
         }
     }
-    
 }
 
-fn parse_read_response(payload: &[u8]) -> Result<String, SocketError> {
-    match ReadKvPairResp::decode(payload) {
+fn parse_list_stores_response(payload: &[u8]) -> Result<String, SocketError> {
+    let v = parse_list_stores_response_message(payload)?;
+    Ok(v.names.iter()
+        .map(|n| if *n == v.default_store {
+            format!("{} (default)", n)
+        } else {
+            n.clone()
+        })
+        .collect::<Vec<String>>()
+        .join("\n"))
+}
+
+pub fn parse_list_stores_response_message(payload: &[u8]) -> Result<ListStoresResp, SocketError> {
+    match ListStoresResp::decode(payload) {
+        Ok(res) => Ok(res),
+        Err(e) => Err(SocketError {
+            kind_: ErrorKind::ParseError,
+            context_: e.to_string()
+        })
+    }
+}
+
+fn parse_select_store_response(payload: &[u8]) -> Result<String, SocketError> {
+    match SelectStoreResp::decode(payload) {
         Ok(v) => {
             if v.success {
-                match v.pair {
-                    Some(p) => Ok(p.value),
-                    None => Err(SocketError {
-                        kind_: ErrorKind::ParseError,
-                        context_: "No pair in response".to_string()
-                     })
-                }
+                Ok("Selected store!".to_string())
             } else {
-                Ok("Cannot find key!".to_string())
+                Ok(format!("Could not select store: {}", v.error))
             }
         },
         Err(e) => {
@@ -142,31 +955,91 @@ fn parse_read_response(payload: &[u8]) -> Result<String, SocketError> {
     }
 }
 
-fn parse_update_response(payload: &[u8]) -> Result<String, SocketError> {
-    match UpdateKvPairResp::decode(payload) {
-         Ok(v) => {
-            if v.success {
-                Ok("Successfully updated pair!".to_string())
-            } else {
-                Ok("Key does not exist!".to_string())
-            }
-        },
-        Err(e) => {
-            Err(SocketError {
-                kind_: ErrorKind::ParseError,
-                context_: e.to_string()
-            })
-        }
+fn parse_backup_status_response(payload: &[u8]) -> Result<String, SocketError> {
+    let v = parse_backup_status_response_message(payload)?;
+    if !v.success {
+        return Ok(format!("Could not get backup status: {}", v.error));
     }
+    let progress = format!("{} of {} records, {} bytes written",
+        v.records_written, v.record_count, v.bytes_written);
+    Ok(match v.state() {
+        BackupState::Running => format!("Backing up {} to {}: {}", v.store, v.backup_id, progress),
+        BackupState::Succeeded => format!("Backed up {} to {}: {}", v.store, v.backup_id, progress),
+        BackupState::Failed => format!("Backup of {} to {} failed after {}: {}",
+            v.store, v.backup_id, progress, v.failure)
+    })
 }
 
-fn parse_delete_response(payload: &[u8]) -> Result<String, SocketError> {
-    match DeleteKvPairResp::decode(payload) {
+pub fn parse_backup_status_response_message(payload: &[u8])
+        -> Result<BackupStatusResp, SocketError> {
+    match BackupStatusResp::decode(payload) {
+        Ok(res) => Ok(res),
+        Err(e) => Err(SocketError {
+            kind_: ErrorKind::ParseError,
+            context_: e.to_string()
+        })
+    }
+}
+
+/// One line describing a backup in the catalog
+fn format_backup_info(b: &BackupInfo) -> String {
+    let base = if b.base_backup_id.is_empty() {
+        String::new()
+    } else {
+        format!(", based on {}", b.base_backup_id)
+    };
+    format!("{}: store {}{}, {} records, {} bytes, checksum {:#010x}, created at {} ms",
+        b.backup_id, b.store, base, b.record_count, b.size_bytes, b.checksum, b.created_at_ms)
+}
+
+fn parse_list_backups_response(payload: &[u8]) -> Result<String, SocketError> {
+    let v = parse_list_backups_response_message(payload)?;
+    if v.backups.is_empty() {
+        return Ok(String::from("No backups"));
+    }
+    Ok(v.backups.iter()
+        .map(format_backup_info)
+        .collect::<Vec<String>>()
+        .join("\n"))
+}
+
+pub fn parse_list_backups_response_message(payload: &[u8])
+        -> Result<ListBackupsResp, SocketError> {
+    match ListBackupsResp::decode(payload) {
+        Ok(res) => Ok(res),
+        Err(e) => Err(SocketError {
+            kind_: ErrorKind::ParseError,
+            context_: e.to_string()
+        })
+    }
+}
+
+fn parse_backup_info_response(payload: &[u8]) -> Result<String, SocketError> {
+    let v = parse_backup_info_response_message(payload)?;
+    if !v.success {
+        return Ok(format!("Could not get backup info: {}", v.error));
+    }
+    Ok(v.info.as_ref().map(format_backup_info).unwrap_or_default())
+}
+
+pub fn parse_backup_info_response_message(payload: &[u8])
+        -> Result<BackupInfoResp, SocketError> {
+    match BackupInfoResp::decode(payload) {
+        Ok(res) => Ok(res),
+        Err(e) => Err(SocketError {
+            kind_: ErrorKind::ParseError,
+            context_: e.to_string()
+        })
+    }
+}
+
+fn parse_delete_backup_response(payload: &[u8]) -> Result<String, SocketError> {
+    match DeleteBackupResp::decode(payload) {
         Ok(v) => {
             if v.success {
-                Ok("Successfully deleted entry!".to_string())
+                Ok("Deleted backup!".to_string())
             } else {
-                Ok("Key does not exist!".to_string())
+                Ok(format!("Could not delete backup: {}", v.error))
             }
         },
         Err(e) => {
@@ -178,13 +1051,13 @@ fn parse_delete_response(payload: &[u8]) -> Result<String, SocketError> {
     }
 }
 
-fn parse_backup_response(payload: &[u8]) -> Result<String, SocketError> {
-    match BackupResp::decode(payload) {
+fn parse_compact_backup_response(payload: &[u8]) -> Result<String, SocketError> {
+    match CompactBackupResp::decode(payload) {
         Ok(v) => {
             if v.success {
-                Ok("Successfully created backup!".to_string())
+                Ok(format!("Started compaction as job {}", v.job_id))
             } else {
-                Ok("Could not complete backup!".to_string())
+                Ok(format!("Could not compact backup: {}", v.error))
             }
         },
         Err(e) => {
@@ -196,13 +1069,13 @@ fn parse_backup_response(payload: &[u8]) -> Result<String, SocketError> {
     }
 }
 
-fn parse_restore_response(payload: &[u8]) -> Result<String, SocketError> {
-    match RestoreResp::decode(payload) {
+fn parse_export_response(payload: &[u8]) -> Result<String, SocketError> {
+    match ExportResp::decode(payload) {
         Ok(v) => {
             if v.success {
-                Ok("Successfully restored from backup!".to_string())
+                Ok(format!("Exported {} pairs!", v.record_count))
             } else {
-                Ok("Could not restore from backup!".to_string())
+                Ok(format!("Could not export: {}", v.error))
             }
         },
         Err(e) => {
@@ -214,17 +1087,64 @@ fn parse_restore_response(payload: &[u8]) -> Result<String, SocketError> {
     }
 }
 
+fn parse_import_response(payload: &[u8]) -> Result<String, SocketError> {
+    let v = parse_import_response_message(payload)?;
+    let mut lines = vec![if v.success {
+        format!("Imported! Added {} keys, overwrote {}, skipped {} live and {} expired",
+            v.added, v.overwritten, v.skipped, v.expired)
+    } else {
+        format!("Could not import: {}", v.error)
+    }];
+    lines.extend(v.line_errors.iter().map(|e| format!("Line {}: {}", e.line, e.message)));
+    let unlisted = v.error_count.saturating_sub(v.line_errors.len() as u64);
+    if unlisted > 0 {
+        lines.push(format!("...and {} more failed lines", unlisted));
+    }
+    Ok(lines.join("\n"))
+}
 
+pub fn parse_import_response_message(payload: &[u8]) -> Result<ImportResp, SocketError> {
+    match ImportResp::decode(payload) {
+        Ok(res) => Ok(res),
+        Err(e) => Err(SocketError {
+            kind_: ErrorKind::ParseError,
+            context_: e.to_string()
+        })
+    }
+}
 
-pub fn parse_generic_response(response: &[u8]) -> Result<String, SocketError> {
-    let parsed_response: GenericResponse;
+pub fn parse_generic_response_message(response: &[u8]) -> Result<GenericResponse, SocketError> {
     match GenericResponse::decode(response) {
-        Ok(res) => parsed_response = res,
-        Err(e) => return Err(SocketError {
+        Ok(res) => Ok(res),
+        Err(e) => Err(SocketError {
+            kind_: ErrorKind::ParseError,
+            context_: e.to_string()
+        })
+    }
+}
+
+pub fn parse_read_response_message(payload: &[u8]) -> Result<ReadKvPairResp, SocketError> {
+    match ReadKvPairResp::decode(payload) {
+        Ok(res) => Ok(res),
+        Err(e) => Err(SocketError {
+            kind_: ErrorKind::ParseError,
+            context_: e.to_string()
+        })
+    }
+}
+
+pub fn parse_cas_response_message(payload: &[u8]) -> Result<CasResp, SocketError> {
+    match CasResp::decode(payload) {
+        Ok(res) => Ok(res),
+        Err(e) => Err(SocketError {
             kind_: ErrorKind::ParseError,
             context_: e.to_string()
         })
     }
+}
+
+pub fn parse_generic_response(response: &[u8]) -> Result<String, SocketError> {
+    let parsed_response = parse_generic_response_message(response)?;
     let returnable: String;
     let req_type = parsed_response.req_type();
     let payload = parsed_response.payload;
@@ -270,12 +1190,162 @@ pub fn parse_generic_response(response: &[u8]) -> Result<String, SocketError> {
                 Ok(v) => returnable = v,
                 Err(e) => return Err(e)
             }
-        }
-        _ => {
-            return Err(SocketError {
-                kind_: ErrorKind::ParseError,
-                context_: String::from("Unexpected request type")
-            });
+        },
+        ReqType::GetTtl => {
+            match parse_get_ttl_response(&payload) {
+                Ok(v) => returnable = v,
+                Err(e) => return Err(e)
+            }
+        },
+        ReqType::SetTtl => {
+            match parse_set_ttl_response(&payload) {
+                Ok(v) => returnable = v,
+                Err(e) => return Err(e)
+            }
+        },
+        ReqType::RemoveTtl => {
+            match parse_remove_ttl_response(&payload) {
+                Ok(v) => returnable = v,
+                Err(e) => return Err(e)
+            }
+        },
+        ReqType::Stats => {
+            match parse_stats_response(&payload) {
+                Ok(v) => returnable = v,
+                Err(e) => return Err(e)
+            }
+        },
+        ReqType::ScanRange => {
+            match parse_scan_range_response(&payload) {
+                Ok(v) => returnable = v,
+                Err(e) => return Err(e)
+            }
+        },
+        ReqType::Scan => {
+            match parse_scan_response(&payload) {
+                Ok(v) => returnable = v,
+                Err(e) => return Err(e)
+            }
+        },
+        ReqType::CreateStore => {
+            match parse_create_store_response(&payload) {
+                Ok(v) => returnable = v,
+                Err(e) => return Err(e)
+            }
+        },
+        ReqType::DropStore => {
+            match parse_drop_store_response(&payload) {
+                Ok(v) => returnable = v,
+                Err(e) => return Err(e)
+            }
+        },
+        ReqType::ListStores => {
+            match parse_list_stores_response(&payload) {
+                Ok(v) => returnable = v,
+                Err(e) => return Err(e)
+            }
+        },
+        ReqType::SelectStore => {
+            match parse_select_store_response(&payload) {
+                Ok(v) => returnable = v,
+                Err(e) => return Err(e)
+            }
+        },
+        ReqType::BackupStatus => {
+            match parse_backup_status_response(&payload) {
+                Ok(v) => returnable = v,
+                Err(e) => return Err(e)
+            }
+        },
+        ReqType::ListBackups => {
+            match parse_list_backups_response(&payload) {
+                Ok(v) => returnable = v,
+                Err(e) => return Err(e)
+            }
+        },
+        ReqType::BackupInfo => {
+            match parse_backup_info_response(&payload) {
+                Ok(v) => returnable = v,
+                Err(e) => return Err(e)
+            }
+        },
+        ReqType::DeleteBackup => {
+            match parse_delete_backup_response(&payload) {
+                Ok(v) => returnable = v,
+                Err(e) => return Err(e)
+            }
+        },
+        ReqType::CompactBackup => {
+            match parse_compact_backup_response(&payload) {
+                Ok(v) => returnable = v,
+                Err(e) => return Err(e)
+            }
+        },
+        ReqType::Export => {
+            match parse_export_response(&payload) {
+                Ok(v) => returnable = v,
+                Err(e) => return Err(e)
+            }
+        },
+        ReqType::Import => {
+            match parse_import_response(&payload) {
+                Ok(v) => returnable = v,
+                Err(e) => return Err(e)
+            }
+        },
+        ReqType::Cas => {
+            match parse_cas_response(&payload) {
+                Ok(v) => returnable = v,
+                Err(e) => return Err(e)
+            }
+        },
+        ReqType::History => {
+            match parse_history_response(&payload) {
+                Ok(v) => returnable = v,
+                Err(e) => return Err(e)
+            }
+        },
+        ReqType::Incr => {
+            match parse_incr_response(&payload) {
+                Ok(v) => returnable = v,
+                Err(e) => return Err(e)
+            }
+        },
+        ReqType::Transaction | ReqType::Exec => {
+            match parse_transaction_response(&payload) {
+                Ok(v) => returnable = v,
+                Err(e) => return Err(e)
+            }
+        },
+        ReqType::Watch => {
+            match parse_watch_response(&payload) {
+                Ok(v) => returnable = v,
+                Err(e) => return Err(e)
+            }
+        },
+        ReqType::Unwatch => {
+            match parse_unwatch_response(&payload) {
+                Ok(v) => returnable = v,
+                Err(e) => return Err(e)
+            }
+        },
+        ReqType::Mget => {
+            match parse_mget_response(&payload) {
+                Ok(v) => returnable = v,
+                Err(e) => return Err(e)
+            }
+        },
+        ReqType::Mset => {
+            match parse_mset_response(&payload) {
+                Ok(v) => returnable = v,
+                Err(e) => return Err(e)
+            }
+        },
+        ReqType::Mdel => {
+            match parse_mdel_response(&payload) {
+                Ok(v) => returnable = v,
+                Err(e) => return Err(e)
+            }
         }
     }
     Ok(returnable)
@@ -288,3 +1358,30 @@ pub fn kvp_proto_to_kvp_rust(inp: KeyValuePair) -> key_value_pair::KeyValuePair
         &inp.value
     )
 }
+
+/// Fails if the value is not a valid encoding of its data type.
+pub fn generic_kvp_to_kvp_rust(inp: GenericKeyValuePair)
+        -> Result<key_value_pair::KeyValuePair, String> {
+    let data_type = inp.data_type();
+    key_value_pair::KeyValuePair::new_typed(&inp.key, data_type, inp.value)
+}
+
+pub fn kvp_rust_to_generic_kvp(inp: &key_value_pair::KeyValuePair) -> GenericKeyValuePair {
+    GenericKeyValuePair {
+        key: inp.key().to_vec(),
+        data_type: inp.data_type().into(),
+        value: inp.value_bytes().to_vec()
+    }
+}
+
+/// Picks the pair out of a create or update request, preferring the typed
+/// pair if both are set.
+pub fn request_pair_to_kvp_rust(pair: Option<KeyValuePair>,
+        typed_pair: Option<GenericKeyValuePair>)
+        -> Result<key_value_pair::KeyValuePair, String> {
+    match (pair, typed_pair) {
+        (_, Some(t)) => generic_kvp_to_kvp_rust(t),
+        (Some(p), None) => Ok(kvp_proto_to_kvp_rust(p)),
+        (None, None) => Err(String::from("No pair in request"))
+    }
+}
@@ -0,0 +1,1587 @@
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::ops::Bound;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, RwLockWriteGuard};
+use crate::key_value_store::errors::{ErrorKind, RWError};
+use crate::key_value_store::eviction::{EvictionPolicy, Evictor};
+use crate::key_value_store::filestore;
+use crate::key_value_store::history::{History, PointInTime, Retention, Revision};
+use crate::key_value_store::key_pattern::KeyPattern;
+use crate::key_value_store::key_value_pair;
+use crate::key_value_store::key_value_store::{entry_size, now_ms, KeyValueStore, ScanPage,
+    StoredEntry};
+use crate::key_value_store::restore::{plan_restore, RestoreCounts, RestoreMode, RestoreOptions,
+    RestorePlan};
+use crate::key_value_store::sharded_store::{FrozenShards, ShardedStore, DEFAULT_SHARD_COUNT};
+use crate::key_value_store::storage_engine::{EngineKind, StorageEngine};
+use crate::key_value_store::text_format::{ImportOptions, ImportReport, OnConflict, ParsedImport};
+use crate::key_value_store::write_ahead_log::WriteAheadLog;
+use crate::proto::*;
+use log::{trace, warn, info, error};
+
+use super::server_impl::MemoryLimit;
+
+/// The most expired keys removed while holding the store lock at once
+pub(super) const EXPIRY_BATCH_SIZE: usize = 1000;
+/// Returned to clients when a write would exceed the memory budget and the
+/// eviction policy cannot make room for it
+const OUT_OF_MEMORY: &str = "Out of memory";
+/// Returned to clients when the storage engine fails, the details of which
+/// are only logged
+const STORAGE_ERROR: &str = "Storage error";
+/// Returned to clients when a write needs a key to be live and it is not
+const KEY_DOES_NOT_EXIST: &str = "Key does not exist";
+/// Returned to clients that ask for past versions of a store that does not
+/// keep them
+const NO_HISTORY: &str = "The store does not keep history";
+/// The most revisions of a key removed from each shard's history at once
+const HISTORY_TRIM_BATCH_SIZE: usize = 1000;
+/// The write-ahead log is not compacted until it is at least this big
+const WAL_COMPACTION_MIN_BYTES: u64 = 4 * 1024 * 1024;
+/// The write-ahead log is compacted once it is this many times the size of
+/// the store
+const WAL_COMPACTION_RATIO: u64 = 2;
+
+fn storage_error(e: RWError) -> String {
+    error!("Storage engine error: {:?}", e.to_string());
+    String::from(STORAGE_ERROR)
+}
+
+/// Plans restoring each of `parts` into its shard, see `plan_restore`,
+/// returning the plans along with their total counts
+fn plan_shards<'a, 'b>(shards: impl Iterator<Item = &'b dyn StorageEngine>,
+        parts: &'a [KeyValueStore], options: &RestoreOptions)
+        -> Result<(Vec<RestorePlan<'a>>, RestoreCounts), String> {
+    let mut plans = Vec::with_capacity(parts.len());
+    let mut counts = RestoreCounts::default();
+    for (shard, part) in shards.zip(parts) {
+        let plan = plan_restore(shard, part, options).map_err(storage_error)?;
+        counts.add(&plan.counts);
+        plans.push(plan);
+    }
+    Ok((plans, counts))
+}
+
+/// How many bytes applying `plans` to `shards`, a plan per shard, would add
+/// to the store, or take away if negative
+fn restore_growth(shards: &[RwLockWriteGuard<'_, Box<dyn StorageEngine>>], plans: &[RestorePlan])
+        -> Result<i64, String> {
+    let mut growth = 0;
+    for (shard, plan) in shards.iter().zip(plans) {
+        let size = |key: &[u8]| -> Result<i64, String> {
+            Ok(shard.get(key).map_err(storage_error)?
+                .map_or(0, |p| entry_size(p.key(), p.value_bytes()) as i64))
+        };
+        for entry in &plan.puts {
+            growth += entry_size(entry.key, entry.value) as i64 - size(entry.key)?;
+        }
+        for key in &plan.deletes {
+            growth -= size(key)?;
+        }
+    }
+    Ok(growth)
+}
+
+/// The pair held by an entry read from a backup, whose value was validated
+/// when the backup was read
+fn entry_pair(entry: &StoredEntry) -> Result<key_value_pair::KeyValuePair, String> {
+    key_value_pair::KeyValuePair::new_typed(entry.key, entry.data_type, entry.value.to_vec())
+        .map_err(|e| storage_error(RWError {
+            kind_: ErrorKind::DataDecodeError,
+            context_: e
+        }))
+}
+
+/// The write-ahead log record of a create or update leaving `pair` in the
+/// given state
+fn mutation_record(op: WalOp, pair: &key_value_pair::KeyValuePair, expires_at: Option<u64>,
+        version: u64) -> WalRecord {
+    WalRecord {
+        op: op.into(),
+        key: pair.key().to_vec(),
+        value: pair.value_bytes().to_vec(),
+        expires_at_ms: expires_at,
+        data_type: match pair.data_type() {
+            DataType::String => None,
+            t => Some(t.into())
+        },
+        version,
+        batch: Vec::new()
+    }
+}
+
+/// The write-ahead log record of the deletion of `key`
+fn delete_record(key: &[u8]) -> WalRecord {
+    WalRecord {
+        op: WalOp::Delete.into(),
+        key: key.to_vec(),
+        ..Default::default()
+    }
+}
+
+/// Where a keyspace keeps its pairs
+pub(super) enum Location<'a> {
+    /// In memory, made durable by the write-ahead log at this path if given
+    Memory { wal_file: Option<&'a str> },
+    /// On disk in this directory in the given engine, see
+    /// `ShardedStore::open_on_disk`
+    Disk { dir: &'a str, engine: EngineKind },
+}
+
+/// What a compare-and-swap expects a key to hold, see
+/// `Keyspace::compare_and_swap`
+pub(super) enum Expected {
+    /// The version the pair was read at
+    Version(u64),
+    /// The value, which must have the same type too
+    Value(DataType, Vec<u8>),
+}
+
+impl Expected {
+    fn matches(&self, current: &key_value_pair::KeyValuePair, version: u64) -> bool {
+        match self {
+            Expected::Version(v) => *v == version,
+            Expected::Value(data_type, value) => *data_type == current.data_type()
+                && value.as_slice() == current.value_bytes(),
+        }
+    }
+}
+
+/// How a compare-and-swap went
+pub(super) enum CasOutcome {
+    /// The pair was written and given this version
+    Swapped(u64),
+    /// Nothing was written, since the key held this pair at this version
+    /// instead, or was not live
+    Conflict(Option<(key_value_pair::KeyValuePair, u64)>),
+}
+
+/// How adding to an integer went, see `Keyspace::increment`
+pub(super) enum IncrOutcome {
+    /// The pair now holds this value, written at this version
+    Incremented(i64, u64),
+    /// Nothing was written, since the key holds a value of this type that
+    /// is not an integer
+    NotAnInteger(DataType),
+    /// Nothing was written, since adding to this value overflows
+    Overflow(i64),
+}
+
+/// What a transaction checks a key for, see `TxnOperation::Check`
+pub(super) enum Condition {
+    /// The key is live and holds what this says, like a compare-and-swap
+    /// expects
+    Holds(Expected),
+    /// The key is live if true, and not live if false
+    Exists(bool),
+    /// The key has not changed since it was watched, see `Keyspace::watch`
+    Unchanged(WatchStamp),
+}
+
+/// A watched key as it was when the watch started, see `Keyspace::watch`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(super) struct WatchStamp {
+    // The key's version, or None if it was not live
+    version: Option<u64>,
+    // How many times the key had changed while anyone watched it
+    changes: u64,
+}
+
+/// A key someone watches, see `Keyspace::watch`
+#[derive(Default)]
+struct WatchedKey {
+    watchers: usize,
+    // How many times the key changed since it was first watched
+    changes: u64,
+}
+
+/// One operation of a transaction, see `Keyspace::transaction`
+pub(super) enum TxnOperation {
+    /// Creates a pair like `add_value`, expiring after the TTL if given
+    Create(key_value_pair::KeyValuePair, Option<u64>),
+    /// Updates a live pair like `update_value`
+    Update(key_value_pair::KeyValuePair, Option<u64>),
+    /// Deletes a live key
+    Delete(Vec<u8>),
+    /// Changes nothing, but fails unless the key meets the condition
+    Check(Vec<u8>, Condition),
+}
+
+impl TxnOperation {
+    fn key(&self) -> &[u8] {
+        match self {
+            TxnOperation::Create(pair, _) | TxnOperation::Update(pair, _) => pair.key(),
+            TxnOperation::Delete(key) | TxnOperation::Check(key, _) => key,
+        }
+    }
+}
+
+/// How one operation of a transaction went
+#[derive(Debug, PartialEq)]
+pub(super) enum TxnOutcome {
+    /// It succeeded, if the transaction as a whole did. Holds the version a
+    /// write gives its pair, or the version a checked key is at if it is
+    /// live.
+    Ok(Option<u64>),
+    /// It failed for this reason, so nothing was applied
+    Failed(String),
+    /// An earlier operation failed, so it was not tried
+    NotRun,
+}
+
+/// A key as a transaction has left it so far: its pair, version and expiry,
+/// or None if it is not live
+type TxnState = Option<(key_value_pair::KeyValuePair, u64, Option<u64>)>;
+
+/// A change a transaction makes, applied once every operation succeeded
+struct TxnWrite {
+    // Where the key's shard is among the locked ones
+    slot: usize,
+    op: WalOp,
+    key: Vec<u8>,
+    // None for a deletion
+    pair: Option<key_value_pair::KeyValuePair>,
+    // The pair's version, or for a deletion the version of the next write
+    version: u64,
+    expires_at: Option<u64>,
+}
+
+/// The memory limit of a keyspace, shared by all of its shards. How much of it
+/// is in use is counted as shards change, so that a write can be checked
+/// against the whole store's usage without locking every shard.
+struct MemoryBudget {
+    max_bytes: u64,
+    policy: EvictionPolicy,
+    // What every shard uses, along with any room reserved for writes in
+    // progress
+    used: AtomicU64,
+    // What each shard counts for in `used`. Only changed while holding the
+    // write lock on the shard.
+    shard_used: Vec<AtomicU64>,
+}
+
+impl MemoryBudget {
+    fn new(limit: &MemoryLimit, shard_count: usize) -> MemoryBudget {
+        MemoryBudget {
+            max_bytes: limit.max_bytes,
+            policy: limit.policy,
+            used: AtomicU64::new(0),
+            shard_used: (0..shard_count).map(|_| AtomicU64::new(0)).collect()
+        }
+    }
+
+    /// Counts what the shard at index `shard`, locked for writing (or not
+    /// shared yet), uses now in place of what it used to, dropping any room
+    /// reserved for it.
+    fn count(&self, shard: usize, store: &dyn StorageEngine) {
+        let now = store.memory_usage();
+        let before = self.shard_used[shard].swap(now, Ordering::AcqRel);
+        // Wraps around to a subtraction when the shard shrank
+        self.used.fetch_add(now.wrapping_sub(before), Ordering::AcqRel);
+    }
+
+    /// Reserves `bytes` for a write to the shard at index `shard`, locked for
+    /// writing, if that keeps the store within the limit. The reservation is
+    /// dropped by the next `count` of the shard.
+    fn try_reserve(&self, shard: usize, bytes: u64) -> bool {
+        let reserved = self.used
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |used| {
+                (used + bytes <= self.max_bytes).then_some(used + bytes)
+            })
+            .is_ok();
+        if reserved {
+            self.shard_used[shard].fetch_add(bytes, Ordering::AcqRel);
+        }
+        reserved
+    }
+}
+
+/// A single named store hosted by a server, along with its own write-ahead
+/// log and eviction bookkeeping. The store is sharded, see `ShardedStore`.
+pub struct Keyspace {
+    store_: ShardedStore,
+    // Set if the store is kept on disk, in which case there is no log
+    data_dir_: Option<String>,
+    // Mutations are recorded here before they are acknowledged, if enabled.
+    // Always locked while holding the write lock on the shard being changed.
+    wal_: Option<Mutex<WriteAheadLog>>,
+    // Set if there is a memory limit, which is enforced across all shards
+    budget_: Option<MemoryBudget>,
+    // One per shard when there is a memory limit, tracking the keys in it.
+    // Like the log, locked while holding a lock on its shard.
+    evictors_: Vec<Mutex<Evictor>>,
+    // One per shard if the store keeps the past versions of its pairs.
+    // Locked while holding a lock on its shard, and the evictor if needed.
+    histories_: Vec<Mutex<History>>,
+    // One per shard, holding the keys being watched in it. Locked while
+    // holding a lock on its shard, and the evictor and history if needed.
+    watched_: Vec<Mutex<HashMap<Vec<u8>, WatchedKey>>>,
+    expired_keys_: AtomicU64
+}
+
+impl Keyspace {
+    /// Creates an empty keyspace with no log and no memory limit
+    pub fn new(name: &str) -> Keyspace {
+        Keyspace {
+            store_: ShardedStore::new(name, DEFAULT_SHARD_COUNT),
+            data_dir_: None,
+            wal_: None,
+            budget_: None,
+            evictors_: Vec::new(),
+            histories_: Vec::new(),
+            watched_: (0..DEFAULT_SHARD_COUNT).map(|_| Mutex::new(HashMap::new())).collect(),
+            expired_keys_: AtomicU64::new(0)
+        }
+    }
+
+    /// Creates the keyspace `name` split into `shard_count` shards, kept at
+    /// `location`. Whatever is already there, be it records in the log or a
+    /// store on disk, is brought back. Past versions are kept as `history`
+    /// says, starting from what was brought back.
+    pub(super) fn open(name: &str, location: Location, memory_limit: Option<&MemoryLimit>,
+            history: Option<&Retention>, shard_count: usize) -> Result<Keyspace, RWError> {
+        let mut wal = None;
+        let mut data_dir = None;
+        let sharded = match location {
+            Location::Memory { wal_file } => {
+                let mut store = KeyValueStore::new(name);
+                if let Some(wal_file) = wal_file {
+                    let mut w = WriteAheadLog::open(wal_file)?;
+                    // The keyspace exists for as long as its log does, so the
+                    // log's creation has to survive a crash
+                    filestore::sync_parent_dir(wal_file)?;
+                    let replayed = w.replay(&mut store)?;
+                    info!("Replayed {:?} records from {:?}", replayed, w.path());
+                    wal = Some(Mutex::new(w));
+                }
+                ShardedStore::from_store(&store, shard_count)
+            },
+            Location::Disk { dir, engine } => {
+                data_dir = Some(dir.to_string());
+                ShardedStore::open_on_disk(name, dir, engine, shard_count)?
+            }
+        };
+        let mut budget = None;
+        let mut evictors = Vec::new();
+        if let Some(limit) = memory_limit {
+            let b = MemoryBudget::new(limit, sharded.shard_count());
+            for i in 0..sharded.shard_count() {
+                let shard = sharded.shard(i).read().unwrap();
+                b.count(i, shard.as_ref());
+                let mut e = Evictor::new(limit.policy);
+                e.reset(shard.as_ref())?;
+                evictors.push(Mutex::new(e));
+            }
+            budget = Some(b);
+        }
+        let histories = match history {
+            Some(r) if r.keeps_history() => (0..sharded.shard_count())
+                .map(|_| Mutex::new(History::new(*r)))
+                .collect(),
+            _ => Vec::new()
+        };
+        let watched = (0..sharded.shard_count()).map(|_| Mutex::new(HashMap::new())).collect();
+        Ok(Keyspace {
+            store_: sharded,
+            data_dir_: data_dir,
+            wal_: wal,
+            budget_: budget,
+            evictors_: evictors,
+            histories_: histories,
+            watched_: watched,
+            expired_keys_: AtomicU64::new(0)
+        })
+    }
+
+    pub(super) fn name(&self) -> &str {
+        self.store_.name()
+    }
+
+    pub(super) fn scan_range(&self, start: Bound<&[u8]>, end: Bound<&[u8]>, limit: usize,
+            reverse: bool) -> Result<Vec<key_value_pair::KeyValuePair>, String> {
+        self.store_.scan_range(start, end, limit, reverse).map_err(storage_error)
+    }
+
+    pub(super) fn scan(&self, pattern: &KeyPattern, resume_after: Option<&[u8]>, count: usize,
+            max_examined: usize) -> Result<ScanPage, String> {
+        self.store_.scan(pattern, resume_after, count, max_examined).map_err(storage_error)
+    }
+
+    pub(super) fn stats(&self) -> StatsResp {
+        let mut resp = StatsResp {
+            key_count: self.store_.len() as u64,
+            memory_bytes: self.store_.memory_usage(),
+            expired_keys: self.expired_keys_.load(Ordering::Relaxed),
+            ..Default::default()
+        };
+        if let Some(budget) = &self.budget_ {
+            resp.max_memory_bytes = Some(budget.max_bytes);
+            resp.eviction_policy = budget.policy.to_string();
+        }
+        for evictor_lock in &self.evictors_ {
+            resp.evicted_keys += evictor_lock.lock().unwrap().evicted();
+        }
+        resp
+    }
+
+    /// Deletes the keyspace's write-ahead log, or its files on disk, once it
+    /// has been dropped from the server. Waits for any mutation still in
+    /// flight to finish first.
+    pub(super) fn destroy(&self) -> Result<(), RWError> {
+        self.store_.destroy()?;
+        if let Some(dir) = &self.data_dir_ {
+            if let Err(e) = std::fs::remove_dir(dir) {
+                return Err(RWError {
+                    kind_: ErrorKind::FileWriteError,
+                    context_: e.to_string()
+                });
+            }
+            filestore::sync_parent_dir(dir)?;
+        }
+        match &self.wal_ {
+            None => Ok(()),
+            Some(wal_lock) => wal_lock.lock().unwrap().remove()
+        }
+    }
+
+    /// Records a mutation in the write-ahead log, if there is one. Returns an
+    /// error if the record could not be made durable, in which case the
+    /// mutation must not be applied.
+    fn log_mutation(&self, op: WalOp, pair: &key_value_pair::KeyValuePair,
+            expires_at: Option<u64>, version: u64) -> Result<(), String> {
+        self.append_to_wal(mutation_record(op, pair, expires_at, version))
+    }
+
+    /// Records the deletion of a key, see `log_mutation`.
+    fn log_delete(&self, key: &[u8]) -> Result<(), String> {
+        self.append_to_wal(delete_record(key))
+    }
+
+    /// Records several mutations as a single record, so that replaying the
+    /// log applies either all of them or none. See `log_mutation`.
+    fn log_batch(&self, records: Vec<WalRecord>) -> Result<(), String> {
+        if records.is_empty() {
+            return Ok(());
+        }
+        self.append_to_wal(WalRecord {
+            op: WalOp::Batch.into(),
+            batch: records,
+            ..Default::default()
+        })
+    }
+
+    fn append_to_wal(&self, record: WalRecord) -> Result<(), String> {
+        let wal_lock = match &self.wal_ {
+            None => return Ok(()),
+            Some(w) => w
+        };
+        match wal_lock.lock().unwrap().append(&record) {
+            Ok(_) => Ok(()),
+            Err(e) => {
+                error!("Cannot write to write-ahead log: {:?}", e.to_string());
+                Err(String::from("Cannot write to write-ahead log"))
+            }
+        }
+    }
+
+    fn record_access(&self, shard: usize, key: &[u8]) {
+        if let Some(evictor_lock) = self.evictors_.get(shard) {
+            evictor_lock.lock().unwrap().record_access(key);
+        }
+    }
+
+    fn record_remove(&self, shard: usize, key: &[u8]) {
+        if let Some(evictor_lock) = self.evictors_.get(shard) {
+            evictor_lock.lock().unwrap().record_remove(key);
+        }
+    }
+
+    /// Records a write of `pair`, or the deletion of `key` if None, in the
+    /// history of the shard at index `shard`, if the store keeps history.
+    /// Called just before `store` is changed, so that a key with no history
+    /// yet can start from the pair it holds. Also counts the change for
+    /// anyone watching the key.
+    fn record_history(&self, shard: usize, store: &dyn StorageEngine, key: &[u8],
+            pair: Option<&key_value_pair::KeyValuePair>, version: u64, expires_at: Option<u64>)
+            -> Result<(), String> {
+        self.record_change(shard, key);
+        let history_lock = match self.histories_.get(shard) {
+            None => return Ok(()),
+            Some(h) => h
+        };
+        let mut history = history_lock.lock().unwrap();
+        if !history.is_tracked(key) {
+            if let Some((current, v)) = store.get_versioned(key).map_err(storage_error)? {
+                history.seed(key, current, v, store.expires_at(key));
+            }
+        }
+        let now = now_ms();
+        history.record(key, Revision {
+            version,
+            written_at: Some(now),
+            expires_at,
+            pair: pair.cloned()
+        }, now);
+        Ok(())
+    }
+
+    /// Counts what the shard at index `shard`, locked for writing, uses now
+    /// against the memory limit, if there is one. Called after every change
+    /// to a shard.
+    fn count_usage(&self, shard: usize, store: &dyn StorageEngine) {
+        if let Some(budget) = &self.budget_ {
+            budget.count(shard, store);
+        }
+    }
+
+    /// Makes sure writing `keys` can grow the store by `needed` bytes without
+    /// going over the memory limit, evicting other keys if the policy allows
+    /// it. `locked` holds the shards the caller has locked for writing along
+    /// with their indices, the first of which the room is reserved in until
+    /// it is next counted. Keys are evicted from the shard using the most
+    /// memory first, skipping shards locked by anyone else rather than
+    /// waiting for them. Returns an error if there is no way to make enough
+    /// room.
+    fn make_room(&self, locked: &mut [(usize, &mut dyn StorageEngine)], keys: &[&[u8]],
+            needed: u64) -> Result<(), String> {
+        let budget = match &self.budget_ {
+            None => return Ok(()),
+            Some(b) => b
+        };
+        for (shard, store) in locked.iter() {
+            budget.count(*shard, *store);
+        }
+        let reserve_in = locked[0].0;
+        if budget.try_reserve(reserve_in, needed) {
+            return Ok(());
+        }
+        if needed > budget.max_bytes {
+            return Err(OUT_OF_MEMORY.to_string());
+        }
+        // Expired keys are the cheapest thing to drop
+        for (shard, store) in locked.iter_mut() {
+            let expired = store.remove_expired(now_ms(), usize::MAX).map_err(storage_error)?;
+            self.expired_keys_.fetch_add(expired.len() as u64, Ordering::Relaxed);
+            for k in expired {
+                self.record_remove(*shard, &k);
+                self.record_change(*shard, &k);
+            }
+            budget.count(*shard, *store);
+        }
+        let mut exhausted = vec![false; self.store_.shard_count()];
+        while !budget.try_reserve(reserve_in, needed) {
+            let largest = (0..exhausted.len())
+                .filter(|i| !exhausted[*i])
+                .max_by_key(|i| (budget.shard_used[*i].load(Ordering::Acquire), Reverse(*i)));
+            let shard = match largest {
+                None => {
+                    let keys: Vec<_> = keys.iter().map(|k| String::from_utf8_lossy(k)).collect();
+                    warn!("Cannot make room for {:?} under {}", keys, budget.policy);
+                    return Err(OUT_OF_MEMORY.to_string());
+                },
+                Some(i) => i
+            };
+            let evicted = match locked.iter_mut().find(|(i, _)| *i == shard) {
+                Some((_, store)) => self.evict_one(shard, &mut **store, keys)?,
+                // Never waited for, so the lock order does not matter
+                None => match self.store_.shard(shard).try_write() {
+                    Ok(mut store) => self.evict_one(shard, store.as_mut(), keys)?,
+                    Err(_) => false
+                }
+            };
+            exhausted[shard] = !evicted;
+        }
+        Ok(())
+    }
+
+    /// Evicts a key from `store`, the shard at index `shard`, under the
+    /// policy, never picking any of `keys`. Returns false if there is
+    /// nothing the policy allows evicting.
+    fn evict_one(&self, shard: usize, store: &mut dyn StorageEngine, keys: &[&[u8]])
+            -> Result<bool, String> {
+        let evictor_lock = &self.evictors_[shard];
+        let victim = match evictor_lock.lock().unwrap()
+                .pick_victim_excluding(store, &|k| keys.contains(&k)) {
+            None => return Ok(false),
+            Some(v) => v
+        };
+        self.log_delete(&victim)?;
+        self.record_history(shard, store, &victim, None, store.next_version(), None)?;
+        store.delete(&victim).map_err(storage_error)?;
+        evictor_lock.lock().unwrap().record_eviction(&victim);
+        self.count_usage(shard, store);
+        trace!("Evicted {:?}", String::from_utf8_lossy(&victim));
+        Ok(true)
+    }
+
+    /// Writes `pair` to `store`, the shard at index `shard`, once there is
+    /// room for it and it has been logged as `op`. `old_size` is the size of
+    /// the pair it replaces, if any. Returns the version it was given.
+    fn write_pair(&self, shard: usize, store: &mut dyn StorageEngine, op: WalOp,
+            pair: key_value_pair::KeyValuePair, expires_at: Option<u64>, old_size: u64)
+            -> Result<u64, String> {
+        let new_size = entry_size(pair.key(), pair.value_bytes());
+        self.make_room(&mut [(shard, &mut *store)], &[pair.key()],
+            new_size.saturating_sub(old_size))?;
+        let version = store.next_version();
+        self.log_mutation(op, &pair, expires_at, version)?;
+        self.record_history(shard, store, pair.key(), Some(&pair), version, expires_at)?;
+        let key = pair.key().to_vec();
+        store.put_versioned(pair, expires_at, version).map_err(storage_error)?;
+        self.count_usage(shard, store);
+        self.record_access(shard, &key);
+        Ok(version)
+    }
+
+    /// Returns Ok(false) if the key is already in the store.
+    pub(super) fn add_value(&self, pair: key_value_pair::KeyValuePair, ttl_ms: Option<u64>)
+            -> Result<bool, String> {
+        let expires_at = ttl_ms.map(|t| now_ms() + t);
+        let shard = self.store_.shard_index(pair.key());
+        let mut store = self.store_.shard(shard).write().unwrap();
+        if store.get(pair.key()).map_err(storage_error)?.is_some() {
+            info!("Did not add pair!");
+            return Ok(false);
+        }
+        self.write_pair(shard, store.as_mut(), WalOp::Create, pair, expires_at, 0)?;
+        info!("Successfully added pair!");
+        Ok(true)
+    }
+
+    /// Returns the pair stored under `key` along with its version, or None if
+    /// the key is not in the store.
+    pub(super) fn get_value(&self, key: &[u8])
+            -> Result<Option<(key_value_pair::KeyValuePair, u64)>, String> {
+        let shard = self.store_.shard_index(key);
+        let store = self.store_.shard(shard).read().unwrap();
+        let val = store.get_versioned(key).map_err(storage_error)?;
+        if val.is_some() {
+            self.record_access(shard, key);
+        }
+        Ok(val)
+    }
+
+    /// Starts watching `key`, returning what it is like now. From then on,
+    /// every write, deletion, expiry change and expiry of the key counts as
+    /// a change, which a transaction can check for with
+    /// `Condition::Unchanged`. Every watch has to be ended with `unwatch`.
+    pub(super) fn watch(&self, key: &[u8]) -> Result<WatchStamp, String> {
+        let shard = self.store_.shard_index(key);
+        // Held so that no change gets in between reading and counting
+        let store = self.store_.shard(shard).read().unwrap();
+        let version = store.get_versioned(key).map_err(storage_error)?.map(|(_, v)| v);
+        let mut watched = self.watched_[shard].lock().unwrap();
+        let w = watched.entry(key.to_vec()).or_default();
+        w.watchers += 1;
+        Ok(WatchStamp { version, changes: w.changes })
+    }
+
+    /// Ends a watch started with `watch`
+    pub(super) fn unwatch(&self, key: &[u8]) {
+        let mut watched = self.watched_[self.store_.shard_index(key)].lock().unwrap();
+        if let Some(w) = watched.get_mut(key) {
+            w.watchers -= 1;
+            if w.watchers == 0 {
+                watched.remove(key);
+            }
+        }
+    }
+
+    /// Counts a change to `key` in the shard at index `shard`, if it is
+    /// watched. Called with the shard locked for writing.
+    fn record_change(&self, shard: usize, key: &[u8]) {
+        if let Some(w) = self.watched_[shard].lock().unwrap().get_mut(key) {
+            w.changes += 1;
+        }
+    }
+
+    /// Counts a change to every watched key in the shard at index `shard`,
+    /// e.g. when it is replaced whole
+    fn record_change_all(&self, shard: usize) {
+        for w in self.watched_[shard].lock().unwrap().values_mut() {
+            w.changes += 1;
+        }
+    }
+
+    /// Whether `key` is at the version it was watched at and has not
+    /// changed since
+    fn is_unchanged(&self, shard: usize, key: &[u8], stamp: &WatchStamp, version: Option<u64>)
+            -> bool {
+        let changes = self.watched_[shard].lock().unwrap().get(key).map(|w| w.changes);
+        stamp.version == version && changes == Some(stamp.changes)
+    }
+
+    /// Returns Ok(false) if the key is not in the store.
+    pub(super) fn update_value(&self, pair: key_value_pair::KeyValuePair, ttl_ms: Option<u64>)
+            -> Result<bool, String> {
+        let shard = self.store_.shard_index(pair.key());
+        let mut store = self.store_.shard(shard).write().unwrap();
+        let old_size = match store.get(pair.key()).map_err(storage_error)? {
+            None => return Ok(false),
+            Some(kvp) => entry_size(kvp.key(), kvp.value_bytes())
+        };
+        let expires_at = match ttl_ms {
+            Some(t) => Some(now_ms() + t),
+            None => store.expires_at(pair.key())
+        };
+        self.write_pair(shard, store.as_mut(), WalOp::Update, pair, expires_at, old_size)?;
+        Ok(true)
+    }
+
+    /// Creates the pair like `add_value` if the key is not live, and updates
+    /// it like `update_value` if it is. Returns the pair's new version.
+    pub(super) fn set_value(&self, pair: key_value_pair::KeyValuePair, ttl_ms: Option<u64>)
+            -> Result<u64, String> {
+        let shard = self.store_.shard_index(pair.key());
+        let mut store = self.store_.shard(shard).write().unwrap();
+        let (op, old_size, expires_at) = match store.get(pair.key()).map_err(storage_error)? {
+            None => (WalOp::Create, 0, ttl_ms.map(|t| now_ms() + t)),
+            Some(kvp) => {
+                let expires_at = match ttl_ms {
+                    Some(t) => Some(now_ms() + t),
+                    None => store.expires_at(pair.key())
+                };
+                (WalOp::Update, entry_size(kvp.key(), kvp.value_bytes()), expires_at)
+            }
+        };
+        self.write_pair(shard, store.as_mut(), op, pair, expires_at, old_size)
+    }
+
+    /// Updates a live key like `update_value`, but only if it still holds
+    /// what `expected` says, checked under the same lock as the write.
+    pub(super) fn compare_and_swap(&self, pair: key_value_pair::KeyValuePair,
+            expected: &Expected, ttl_ms: Option<u64>) -> Result<CasOutcome, String> {
+        let shard = self.store_.shard_index(pair.key());
+        let mut store = self.store_.shard(shard).write().unwrap();
+        let (current, version) = match store.get_versioned(pair.key()).map_err(storage_error)? {
+            None => return Ok(CasOutcome::Conflict(None)),
+            Some(c) => c
+        };
+        if !expected.matches(&current, version) {
+            return Ok(CasOutcome::Conflict(Some((current, version))));
+        }
+        let expires_at = match ttl_ms {
+            Some(t) => Some(now_ms() + t),
+            None => store.expires_at(pair.key())
+        };
+        let old_size = entry_size(current.key(), current.value_bytes());
+        let version = self.write_pair(shard, store.as_mut(), WalOp::Update, pair, expires_at,
+            old_size)?;
+        Ok(CasOutcome::Swapped(version))
+    }
+
+    /// Adds `delta` to the integer `key` holds, see `integer_value`, under
+    /// the same lock as the read. A key that is not live is created holding
+    /// `delta` and expiring after `ttl_ms`, if given; otherwise its expiry
+    /// is kept.
+    pub(super) fn increment(&self, key: &[u8], delta: i64, ttl_ms: Option<u64>)
+            -> Result<IncrOutcome, String> {
+        let shard = self.store_.shard_index(key);
+        let mut store = self.store_.shard(shard).write().unwrap();
+        let (pair, value, op, expires_at, old_size) = match store.get(key).map_err(storage_error)? {
+            None => {
+                // Eight bytes always make a valid SINT64
+                let pair = key_value_pair::KeyValuePair::new_typed(key, DataType::Sint64,
+                    delta.to_le_bytes().to_vec()).unwrap();
+                (pair, delta, WalOp::Create, ttl_ms.map(|t| now_ms() + t), 0)
+            },
+            Some(current) => {
+                let value = match current.integer_value() {
+                    None => return Ok(IncrOutcome::NotAnInteger(current.data_type())),
+                    Some(v) => v
+                };
+                let sum = match value.checked_add(delta) {
+                    None => return Ok(IncrOutcome::Overflow(value)),
+                    Some(s) => s
+                };
+                let old_size = entry_size(current.key(), current.value_bytes());
+                (current.with_integer_value(sum), sum, WalOp::Update, store.expires_at(key),
+                    old_size)
+            }
+        };
+        let version = self.write_pair(shard, store.as_mut(), op, pair, expires_at, old_size)?;
+        Ok(IncrOutcome::Incremented(value, version))
+    }
+
+    /// Runs `ops` in order, each seeing what the ones before it did, and
+    /// applies all of them if they all succeed, or none of them otherwise.
+    /// Every shard involved stays locked throughout, so no other request
+    /// sees some of the changes without the others, and the keys involved
+    /// are never evicted to make room for the changes. With a write-ahead
+    /// log, the changes are logged as a single record. Returns how each
+    /// operation went, or an error if the changes could not be made at all.
+    pub(super) fn transaction(&self, ops: Vec<TxnOperation>) -> Result<Vec<TxnOutcome>, String> {
+        let mut indices: Vec<usize> = ops.iter()
+            .map(|op| self.store_.shard_index(op.key()))
+            .collect();
+        indices.sort_unstable();
+        indices.dedup();
+        let mut shards = self.store_.write_shards(&indices);
+        let slot = |key: &[u8]| indices.binary_search(&self.store_.shard_index(key)).unwrap();
+        let mut next_versions: Vec<u64> = shards.iter().map(|s| s.next_version()).collect();
+        // The size of every key involved before the transaction, and the
+        // state of every key written as the transaction leaves it so far
+        let mut old_sizes: HashMap<Vec<u8>, u64> = HashMap::new();
+        let mut states: HashMap<Vec<u8>, TxnState> = HashMap::new();
+        let mut writes: Vec<TxnWrite> = Vec::new();
+        let mut results = Vec::with_capacity(ops.len());
+        let mut failed = false;
+        let now = now_ms();
+        for op in ops {
+            if failed {
+                results.push(TxnOutcome::NotRun);
+                continue;
+            }
+            let key = op.key().to_vec();
+            let i = slot(&key);
+            let current = match states.get(&key) {
+                Some(state) => state.clone(),
+                None => {
+                    let store = shards[i].as_ref();
+                    let found = store.get_versioned(&key).map_err(storage_error)?;
+                    old_sizes.insert(key.clone(),
+                        found.as_ref().map_or(0, |(p, _)| entry_size(p.key(), p.value_bytes())));
+                    found.map(|(p, v)| (p, v, store.expires_at(&key)))
+                }
+            };
+            let (op, pair, expires_at) = match (op, &current) {
+                (TxnOperation::Check(_, condition), _) => {
+                    let version = current.as_ref().map(|(_, v, _)| *v);
+                    let result = match (condition, &current) {
+                        (Condition::Exists(false), None) | (Condition::Exists(true), Some(_)) =>
+                            TxnOutcome::Ok(version),
+                        (Condition::Holds(expected), Some((p, v, _))) if expected.matches(p, *v) =>
+                            TxnOutcome::Ok(version),
+                        (Condition::Unchanged(stamp), _) =>
+                            if self.is_unchanged(indices[i], &key, &stamp, version) {
+                                TxnOutcome::Ok(version)
+                            } else {
+                                TxnOutcome::Failed(String::from("Key changed since it was watched"))
+                            },
+                        (Condition::Exists(false), Some(_)) =>
+                            TxnOutcome::Failed(String::from("Key exists")),
+                        (_, None) => TxnOutcome::Failed(KEY_DOES_NOT_EXIST.to_string()),
+                        (_, Some((_, v, _))) => TxnOutcome::Failed(format!(
+                            "Key does not hold what was expected, and is at version {}", v))
+                    };
+                    failed = matches!(result, TxnOutcome::Failed(_));
+                    results.push(result);
+                    continue;
+                },
+                (TxnOperation::Create(pair, ttl_ms), None) =>
+                    (WalOp::Create, Some(pair), ttl_ms.map(|t| now + t)),
+                (TxnOperation::Update(pair, ttl_ms), Some((_, _, expires_at))) =>
+                    (WalOp::Update, Some(pair), ttl_ms.map(|t| now + t).or(*expires_at)),
+                (TxnOperation::Delete(_), Some(_)) => (WalOp::Delete, None, None),
+                (TxnOperation::Create(..), Some(_)) => {
+                    failed = true;
+                    results.push(TxnOutcome::Failed(String::from("Key already exists")));
+                    continue;
+                },
+                (TxnOperation::Update(..) | TxnOperation::Delete(_), None) => {
+                    failed = true;
+                    results.push(TxnOutcome::Failed(KEY_DOES_NOT_EXIST.to_string()));
+                    continue;
+                }
+            };
+            let version = next_versions[i];
+            if pair.is_some() {
+                next_versions[i] += 1;
+                results.push(TxnOutcome::Ok(Some(version)));
+            } else {
+                results.push(TxnOutcome::Ok(None));
+            }
+            states.insert(key.clone(), pair.clone().map(|p| (p, version, expires_at)));
+            writes.push(TxnWrite { slot: i, op, key, pair, version, expires_at });
+        }
+        if failed {
+            return Ok(results);
+        }
+
+        if self.budget_.is_some() {
+            // Only the store as a whole has to fit, so shrinking one shard
+            // makes room in another
+            let growth: i64 = states.iter().map(|(key, state)| {
+                let new_size = state.as_ref()
+                    .map_or(0, |(p, _, _)| entry_size(p.key(), p.value_bytes()));
+                new_size as i64 - old_sizes[key] as i64
+            }).sum();
+            let keys: Vec<&[u8]> = old_sizes.keys().map(|k| k.as_slice()).collect();
+            let mut locked: Vec<(usize, &mut dyn StorageEngine)> = indices.iter().copied()
+                .zip(shards.iter_mut().map(|s| s.as_mut() as &mut dyn StorageEngine))
+                .collect();
+            self.make_room(&mut locked, &keys, growth.max(0) as u64)?;
+        }
+        self.log_batch(writes.iter().map(|w| match &w.pair {
+            Some(p) => mutation_record(w.op, p, w.expires_at, w.version),
+            None => delete_record(&w.key)
+        }).collect())?;
+        for w in writes {
+            let shard = indices[w.slot];
+            let store = shards[w.slot].as_mut();
+            self.record_history(shard, store, &w.key, w.pair.as_ref(), w.version, w.expires_at)?;
+            match w.pair {
+                Some(pair) => {
+                    store.put_versioned(pair, w.expires_at, w.version).map_err(storage_error)?;
+                    self.record_access(shard, &w.key);
+                },
+                None => {
+                    self.record_remove(shard, &w.key);
+                    store.delete(&w.key).map_err(storage_error)?;
+                }
+            }
+        }
+        for (shard, store) in indices.iter().zip(&shards) {
+            self.count_usage(*shard, store.as_ref());
+        }
+        Ok(results)
+    }
+
+    /// Returns Ok(false) if the key is not in the store.
+    pub(super) fn delete_value(&self, key: &[u8]) -> Result<bool, String> {
+        let shard = self.store_.shard_index(key);
+        let mut store = self.store_.shard(shard).write().unwrap();
+        if store.get(key).map_err(storage_error)?.is_none() {
+            return Ok(false);
+        }
+        self.log_delete(key)?;
+        self.record_history(shard, store.as_ref(), key, None, store.next_version(), None)?;
+        self.record_remove(shard, key);
+        let deleted = store.delete(key).map_err(storage_error)?;
+        self.count_usage(shard, store.as_ref());
+        Ok(deleted)
+    }
+
+    /// Returns None if the key is not in the store, and Some(None) if it is
+    /// but never expires.
+    pub(super) fn get_ttl(&self, key: &[u8]) -> Result<Option<Option<u64>>, String> {
+        let store = self.store_.shard_for(key).read().unwrap();
+        if store.get(key).map_err(storage_error)?.is_none() {
+            return Ok(None);
+        }
+        let now = now_ms();
+        Ok(Some(store.expires_at(key).map(|t| t.saturating_sub(now))))
+    }
+
+    /// Sets the expiry of a key to `ttl_ms` from now, or removes it if None.
+    /// Returns Ok(false) if the key is not in the store.
+    pub(super) fn set_ttl(&self, key: &[u8], ttl_ms: Option<u64>) -> Result<bool, String> {
+        let expires_at = ttl_ms.map(|t| now_ms() + t);
+        let shard = self.store_.shard_index(key);
+        let mut store = self.store_.shard(shard).write().unwrap();
+        let (current, version) = match store.get_versioned(key).map_err(storage_error)? {
+            None => return Ok(false),
+            Some(c) => c
+        };
+        // Logged as an update carrying the pair's full new state, which keeps
+        // its version
+        self.log_mutation(WalOp::Update, &current, expires_at, version)?;
+        if let Some(history_lock) = self.histories_.get(shard) {
+            history_lock.lock().unwrap().set_expiry(key, version, expires_at);
+        }
+        self.record_change(shard, key);
+        store.set_expires_at(key, expires_at).map_err(storage_error)
+    }
+
+    /// Returns the pair `key` held at `point` along with the version it was
+    /// written at, or None if it held none then, see `History::as_of`.
+    pub(super) fn get_value_as_of(&self, key: &[u8], point: PointInTime)
+            -> Result<Option<(key_value_pair::KeyValuePair, u64)>, String> {
+        let shard = self.store_.shard_index(key);
+        let history_lock = self.histories_.get(shard).ok_or(NO_HISTORY)?;
+        let store = self.store_.shard(shard).read().unwrap();
+        if let Some(found) = history_lock.lock().unwrap().as_of(key, point) {
+            return Ok(found);
+        }
+        // Not written since the history began, so it has held the same pair
+        // all along
+        let current = store.get_versioned(key).map_err(storage_error)?;
+        Ok(current.filter(|(_, version)| match point {
+            PointInTime::Version(v) => *version <= v,
+            PointInTime::Timestamp(_) => true
+        }))
+    }
+
+    /// Returns at most `limit` revisions of `key`, newest first. A live key
+    /// not written since the history began has the one it holds.
+    pub(super) fn history(&self, key: &[u8], limit: usize) -> Result<Vec<Revision>, String> {
+        let shard = self.store_.shard_index(key);
+        let history_lock = self.histories_.get(shard).ok_or(NO_HISTORY)?;
+        let store = self.store_.shard(shard).read().unwrap();
+        let history = history_lock.lock().unwrap();
+        if history.is_tracked(key) {
+            return Ok(history.revisions(key).rev().take(limit).cloned().collect());
+        }
+        let current = store.get_versioned(key).map_err(storage_error)?;
+        Ok(current.into_iter().take(limit).map(|(pair, version)| Revision {
+            version,
+            written_at: None,
+            expires_at: store.expires_at(key),
+            pair: Some(pair)
+        }).collect())
+    }
+
+    /// Removes a batch of expired keys from each shard. Expired keys are
+    /// already invisible to clients; this only reclaims their memory, so
+    /// nothing is written to the write-ahead log. Returns true if any shard
+    /// filled its batch, meaning there may be more waiting.
+    pub(super) fn remove_expired_keys(&self) -> bool {
+        let mut more = false;
+        for shard in 0..self.store_.shard_count() {
+            let mut store = self.store_.shard(shard).write().unwrap();
+            let removed = match store.remove_expired(now_ms(), EXPIRY_BATCH_SIZE) {
+                Ok(r) => r,
+                Err(e) => {
+                    error!("Cannot remove expired keys: {:?}", e.to_string());
+                    continue;
+                }
+            };
+            if !removed.is_empty() {
+                trace!("Removed {:?} expired keys", removed.len());
+                self.expired_keys_.fetch_add(removed.len() as u64, Ordering::Relaxed);
+            }
+            for k in &removed {
+                self.record_remove(shard, k);
+                self.record_change(shard, k);
+            }
+            self.count_usage(shard, store.as_ref());
+            more |= removed.len() == EXPIRY_BATCH_SIZE;
+        }
+        more
+    }
+
+    /// Drops a batch of past versions from each shard's history once they
+    /// are older than the retention allows. Returns true if any shard
+    /// filled its batch, like `remove_expired_keys`.
+    pub(super) fn trim_history(&self) -> bool {
+        let mut more = false;
+        for history_lock in &self.histories_ {
+            let dropped = history_lock.lock().unwrap().trim(now_ms(), HISTORY_TRIM_BATCH_SIZE);
+            if dropped > 0 {
+                trace!("Dropped {:?} past versions", dropped);
+            }
+            more |= dropped == HISTORY_TRIM_BATCH_SIZE;
+        }
+        more
+    }
+
+    /// Rewrites the write-ahead log with a single record per live pair once
+    /// it has grown well past the size of the store, like `DiskStore`
+    /// compacts its log, so that it does not keep every mutation ever made.
+    /// Writes to the store wait while the log is rewritten. A failure leaves
+    /// the log as it was, so it is only logged.
+    pub(super) fn maybe_compact_wal(&self) {
+        let wal_lock = match &self.wal_ {
+            None => return,
+            Some(w) => w
+        };
+        // The log is locked after the shards, so it is only looked at here
+        let len = wal_lock.lock().unwrap().len();
+        if len < WAL_COMPACTION_MIN_BYTES
+                || len < WAL_COMPACTION_RATIO * self.store_.memory_usage() {
+            return;
+        }
+        // The rewritten log has to hold exactly what the shards do, so
+        // writes wait until it is done
+        let shards = self.store_.read_all();
+        let mut wal = wal_lock.lock().unwrap();
+        // Deleted keys are dropped along with their versions, which is fine
+        // since a store opened later starts its versions past them anyway
+        let records = shards.iter().flat_map(|shard| shard.keys().filter_map(|key| {
+            let key = match key {
+                Ok(k) => k,
+                Err(e) => return Some(Err(e))
+            };
+            match shard.get_versioned(&key) {
+                Ok(None) => None,
+                Ok(Some((pair, version))) => Some(Ok(mutation_record(WalOp::Create, &pair,
+                    shard.expires_at(&key), version))),
+                Err(e) => Some(Err(e))
+            }
+        }));
+        match wal.rewrite(records) {
+            Ok(_) => info!("Compacted {:?} from {:?} to {:?} bytes", wal.path(), len, wal.len()),
+            Err(e) => error!("Cannot compact write-ahead log: {:?}", e.to_string())
+        }
+    }
+
+    /// Freezes the store as it is now for a backup, see
+    /// `ShardedStore::freeze`
+    pub(super) fn freeze(&self) -> Result<FrozenShards, RWError> {
+        self.store_.freeze().inspect_err(|e| {
+            error!("Inner error in backup: {:?}", e.to_string());
+        })
+    }
+
+    /// Restores the backup in `backup_file` as `options` say, returning how
+    /// many keys it added, overwrote and removed. An incremental backup is
+    /// replayed on top of its chain, finding base backups with `resolve`.
+    /// With `dry_run`, the keys are only counted and nothing changes. Under a
+    /// memory limit, a restore that would go over it fails without changing
+    /// anything if the policy is noeviction, and otherwise evicts keys once
+    /// restored like any other write.
+    pub(super) fn restore(&self, backup_file: &str,
+            resolve: &dyn Fn(&str) -> Result<String, RWError>, options: &RestoreOptions,
+            dry_run: bool) -> Result<RestoreCounts, String> {
+        // Read and split before locking anything; the backup may be large
+        let restored = match filestore::read_backup_chain(backup_file, resolve) {
+            Ok(s) => s,
+            Err(e) => {
+                error!("Inner error in restore: {:?}", e.to_string());
+                return Err(format!("Cannot read backup: {}", e));
+            }
+        };
+        self.restore_store(&restored, options, dry_run)
+    }
+
+    /// Imports the pairs read from a text file as `options` say, see
+    /// `filestore::import_from_file`
+    pub(super) fn import(&self, mut parsed: ParsedImport, options: &ImportOptions)
+            -> Result<ImportReport, String> {
+        if options.on_conflict == OnConflict::Fail {
+            parsed.fail_live_keys(|key| Ok(self.store_.get(key)?.is_some()))
+                .map_err(storage_error)?;
+        }
+        if parsed.too_many_errors(options) {
+            return Ok(parsed.abandon());
+        }
+        let counts = self.restore_store(&parsed.store, &options.restore_options(), false)?;
+        Ok(parsed.finish(&counts))
+    }
+
+    /// Restores `restored`, already read from a backup, see `restore`
+    fn restore_store(&self, restored: &KeyValueStore, options: &RestoreOptions, dry_run: bool)
+            -> Result<RestoreCounts, String> {
+        let parts = self.store_.split(restored);
+        if dry_run {
+            let shards = self.store_.read_all();
+            let (_, counts) = plan_shards(shards.iter().map(|s| s.as_ref()), &parts, options)?;
+            return Ok(counts);
+        }
+        let mut shards = self.store_.write_all();
+        let (plans, counts) = plan_shards(shards.iter().map(|s| s.as_ref()), &parts, options)?;
+        let replace = options.mode == RestoreMode::Replace && options.prefix.is_none();
+        if let Some(budget) = &self.budget_ {
+            for (i, shard) in shards.iter().enumerate() {
+                budget.count(i, shard.as_ref());
+            }
+            if budget.policy == EvictionPolicy::NoEviction {
+                let growth = if replace {
+                    parts.iter().map(|p| p.memory_usage() as i64).sum::<i64>()
+                        - shards.iter().map(|s| s.memory_usage() as i64).sum::<i64>()
+                } else {
+                    restore_growth(&shards, &plans)?
+                };
+                if budget.used.load(Ordering::Acquire) as i64 + growth > budget.max_bytes as i64 {
+                    warn!("Cannot restore {:?} keys under {}", restored.len(), budget.policy);
+                    return Err(OUT_OF_MEMORY.to_string());
+                }
+            }
+        }
+        if replace {
+            self.replace_all(&mut shards, &parts)?;
+        } else {
+            self.apply_restore(&mut shards, plans)?;
+        }
+        if self.budget_.is_some() {
+            // The restored keys count as just written, so within a shard the
+            // keys that were already there go first under LRU
+            let mut locked: Vec<(usize, &mut dyn StorageEngine)> = shards.iter_mut()
+                .map(|s| s.as_mut() as &mut dyn StorageEngine)
+                .enumerate()
+                .collect();
+            if let Err(e) = self.make_room(&mut locked, &[], 0) {
+                // Too late to take the restore back
+                warn!("Restored store is over its memory limit");
+                return Err(e);
+            }
+        }
+        Ok(counts)
+    }
+
+    /// Replaces every shard with its part of the restored store, which is
+    /// quicker than applying a plan that changes every key
+    fn replace_all(&self, shards: &mut [RwLockWriteGuard<'_, Box<dyn StorageEngine>>],
+            parts: &[KeyValueStore]) -> Result<(), String> {
+        // Restored pairs are new writes as far as versions go
+        let parts: Vec<KeyValueStore> = shards.iter().zip(parts)
+            .map(|(shard, part)| part.restamped(shard.next_version()))
+            .collect();
+        if !self.histories_.is_empty() {
+            self.record_replace(shards, &parts)?;
+        }
+        for shard in 0..shards.len() {
+            self.record_change_all(shard);
+        }
+        let replaced = self.store_.replace_with(shards, &parts);
+        // Even a failed replace may have changed some of the shards
+        for (evictor_lock, shard) in self.evictors_.iter().zip(shards.iter()) {
+            if let Err(e) = evictor_lock.lock().unwrap().reset(shard.as_ref()) {
+                error!("Cannot track the restored keys for eviction: {:?}", e.to_string());
+            }
+        }
+        for (i, shard) in shards.iter().enumerate() {
+            self.count_usage(i, shard.as_ref());
+        }
+        if let Err(e) = replaced {
+            error!("Cannot replace the store's contents: {:?}", e.to_string());
+            return Err(String::from(STORAGE_ERROR));
+        }
+        // The log describes the store as it was before the restore, so it
+        // has to be rewritten to match the restored contents.
+        if let Some(wal_lock) = &self.wal_ {
+            if let Err(e) = wal_lock.lock().unwrap().reset(&parts) {
+                error!("Cannot reset write-ahead log after restore: {:?}",
+                    e.to_string());
+                return Err(String::from("Cannot reset write-ahead log"));
+            }
+        }
+        Ok(())
+    }
+
+    /// Records replacing every shard with its part of a restored store in
+    /// the history, see `replace_all`
+    fn record_replace(&self, shards: &[RwLockWriteGuard<'_, Box<dyn StorageEngine>>],
+            parts: &[KeyValueStore]) -> Result<(), String> {
+        for (i, (shard, part)) in shards.iter().zip(parts).enumerate() {
+            let shard = shard.as_ref();
+            // Deleted before any of the restored pairs were written
+            let first = shard.next_version();
+            for key in shard.keys() {
+                let key = key.map_err(storage_error)?;
+                if part.get(&key).is_none() {
+                    self.record_history(i, shard, &key, None, first, None)?;
+                }
+            }
+            for entry in part.iter() {
+                let pair = entry_pair(&entry)?;
+                self.record_history(i, shard, entry.key, Some(&pair), entry.version,
+                    entry.expires_at)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Applies the changes planned for each shard, logging all of them as a
+    /// single record before changing anything, so that replaying the log
+    /// applies either the whole restore or none of it
+    fn apply_restore(&self, shards: &mut [RwLockWriteGuard<'_, Box<dyn StorageEngine>>],
+            plans: Vec<RestorePlan>) -> Result<(), String> {
+        // Each shard's restored pairs get the versions following its own
+        let first_versions: Vec<u64> = shards.iter().map(|s| s.next_version()).collect();
+        let mut records = Vec::new();
+        for (plan, first) in plans.iter().zip(&first_versions) {
+            records.extend(plan.deletes.iter().map(|key| WalRecord {
+                op: WalOp::Delete.into(),
+                key: key.clone(),
+                ..Default::default()
+            }));
+            // Replayed the same way whether or not the key was there before
+            records.extend(plan.puts.iter().zip(*first..).map(|(entry, version)| WalRecord {
+                op: WalOp::Update.into(),
+                key: entry.key.to_vec(),
+                value: entry.value.to_vec(),
+                expires_at_ms: entry.expires_at,
+                data_type: match entry.data_type {
+                    DataType::String => None,
+                    t => Some(t.into())
+                },
+                version,
+                batch: Vec::new()
+            }));
+        }
+        self.log_batch(records)?;
+        for (i, ((shard, plan), first)) in shards.iter_mut().zip(plans).zip(first_versions)
+                .enumerate() {
+            for key in &plan.deletes {
+                // Deleted before any of the restored pairs were written
+                self.record_history(i, shard.as_ref(), key, None, first, None)?;
+                self.record_remove(i, key);
+                shard.delete(key).map_err(storage_error)?;
+            }
+            for (entry, version) in plan.puts.into_iter().zip(first..) {
+                let pair = entry_pair(&entry)?;
+                self.record_history(i, shard.as_ref(), entry.key, Some(&pair), version,
+                    entry.expires_at)?;
+                shard.put_versioned(pair, entry.expires_at, version).map_err(storage_error)?;
+                self.record_access(i, entry.key);
+            }
+            self.count_usage(i, shard.as_ref());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::key_value_store::key_value_pair::KeyValuePair;
+
+    const SHARDS: usize = 4;
+
+    fn limited(max_bytes: u64, policy: EvictionPolicy) -> Keyspace {
+        let limit = MemoryLimit { max_bytes, policy };
+        Keyspace::open("test", Location::Memory { wal_file: None }, Some(&limit), None, SHARDS)
+            .unwrap()
+    }
+
+    /// The first `count` keys, in order, that fall in the shard at `shard`
+    fn keys_in_shard(keyspace: &Keyspace, shard: usize, count: usize) -> Vec<String> {
+        (0..).map(|i| format!("key{}", i))
+            .filter(|k| keyspace.store_.shard_index(k.as_bytes()) == shard)
+            .take(count)
+            .collect()
+    }
+
+    fn size(key: &str, value: &str) -> u64 {
+        entry_size(key.as_bytes(), value.as_bytes())
+    }
+
+    #[test]
+    fn test_memory_limit_is_shared_by_shards() {
+        let big = "v".repeat(600);
+        let small = "v".repeat(300);
+        let max_bytes = size("big", &big) + size("small", &small);
+        let keyspace = limited(max_bytes, EvictionPolicy::NoEviction);
+        // Far more than an equal share of the limit
+        assert!(keyspace.add_value(KeyValuePair::new("big", &big), None).unwrap());
+        assert!(keyspace.add_value(KeyValuePair::new("small", &small), None).unwrap());
+        assert_eq!(keyspace.stats().memory_bytes, max_bytes);
+        assert_eq!(keyspace.add_value(KeyValuePair::new("more", "v"), None),
+            Err(OUT_OF_MEMORY.to_string()));
+        // Deleting anywhere makes room everywhere
+        assert!(keyspace.delete_value(b"small").unwrap());
+        assert!(keyspace.add_value(KeyValuePair::new("more", "v"), None).unwrap());
+        let stats = keyspace.stats();
+        assert_eq!(stats.max_memory_bytes, Some(max_bytes));
+        assert_eq!(stats.eviction_policy, "noeviction");
+    }
+
+    #[test]
+    fn test_eviction_from_largest_shard() {
+        let keyspace = limited(u64::MAX, EvictionPolicy::Lru);
+        let large = keys_in_shard(&keyspace, 0, 3);
+        let small = keys_in_shard(&keyspace, 1, 2);
+        let value = "v".repeat(100);
+        // Full to the byte once the first small key is in
+        let max_bytes = large.iter().map(|k| size(k, &value)).sum::<u64>() + size(&small[0], "v");
+        let keyspace = limited(max_bytes, EvictionPolicy::Lru);
+        for key in &large {
+            assert!(keyspace.add_value(KeyValuePair::new(key, &value), None).unwrap());
+        }
+        assert!(keyspace.add_value(KeyValuePair::new(&small[0], "v"), None).unwrap());
+        assert!(keyspace.add_value(KeyValuePair::new(&small[1], "v"), None).unwrap());
+        // The least recently used key of the largest shard made room
+        assert!(keyspace.get_value(large[0].as_bytes()).unwrap().is_none());
+        for key in large.iter().skip(1).chain(&small) {
+            assert!(keyspace.get_value(key.as_bytes()).unwrap().is_some(), "{}", key);
+        }
+        let stats = keyspace.stats();
+        assert_eq!(stats.evicted_keys, 1);
+        assert!(stats.memory_bytes <= max_bytes);
+    }
+
+    #[test]
+    fn test_transaction_moves_memory_between_shards() {
+        let keyspace = limited(u64::MAX, EvictionPolicy::NoEviction);
+        let first = keys_in_shard(&keyspace, 0, 1).remove(0);
+        let second = keys_in_shard(&keyspace, 1, 1).remove(0);
+        let max_bytes = size(&first, "value");
+        let keyspace = limited(max_bytes, EvictionPolicy::NoEviction);
+        assert!(keyspace.add_value(KeyValuePair::new(&first, "value"), None).unwrap());
+        // Grows one shard by what it frees in another
+        let outcomes = keyspace.transaction(vec![
+            TxnOperation::Delete(first.as_bytes().to_vec()),
+            TxnOperation::Create(KeyValuePair::new(&second, "value"), None)
+        ]).unwrap();
+        assert!(outcomes.iter().all(|o| matches!(o, TxnOutcome::Ok(_))), "{:?}", outcomes);
+        assert_eq!(keyspace.stats().memory_bytes, max_bytes);
+        assert_eq!(keyspace.add_value(KeyValuePair::new(&first, "v"), None),
+            Err(OUT_OF_MEMORY.to_string()));
+    }
+
+    fn backup(pairs: &[(&str, &str)]) -> KeyValueStore {
+        let mut store = KeyValueStore::new("backup");
+        for (k, v) in pairs {
+            store.add(KeyValuePair::new(k, v));
+        }
+        store
+    }
+
+    fn merge() -> RestoreOptions {
+        RestoreOptions { mode: RestoreMode::MergeBackupWins, prefix: None }
+    }
+
+    #[test]
+    fn test_restore_over_limit_fails_under_noeviction() {
+        let max_bytes = size("a", "live") + size("b", "live");
+        let keyspace = limited(max_bytes, EvictionPolicy::NoEviction);
+        assert!(keyspace.add_value(KeyValuePair::new("a", "live"), None).unwrap());
+        let restored = backup(&[("b", "restored"), ("c", "restored")]);
+        for options in [merge(), RestoreOptions::default()] {
+            assert_eq!(keyspace.restore_store(&restored, &options, false),
+                Err(OUT_OF_MEMORY.to_string()));
+            assert_eq!(keyspace.get_value(b"a").unwrap().unwrap().0.value(), "live");
+            assert!(keyspace.get_value(b"b").unwrap().is_none());
+        }
+        // Fits once it replaces the live key
+        let restored = backup(&[("b", "live")]);
+        let counts = keyspace.restore_store(&restored, &RestoreOptions::default(), false).unwrap();
+        assert_eq!((counts.added, counts.removed), (1, 1));
+    }
+
+    #[test]
+    fn test_restore_over_limit_evicts() {
+        let max_bytes = size("a", "live") + size("b", "live") + size("c", "restored");
+        let keyspace = limited(max_bytes, EvictionPolicy::Lru);
+        assert!(keyspace.add_value(KeyValuePair::new("a", "live"), None).unwrap());
+        assert!(keyspace.add_value(KeyValuePair::new("b", "live"), None).unwrap());
+        let restored = backup(&[("c", "restored"), ("d", "restored")]);
+        let counts = keyspace.restore_store(&restored, &merge(), false).unwrap();
+        assert_eq!(counts.added, 2);
+        let stats = keyspace.stats();
+        assert!(stats.memory_bytes <= max_bytes);
+        assert!(stats.evicted_keys > 0);
+        assert_eq!(stats.key_count, 4 - stats.evicted_keys);
+    }
+
+    #[test]
+    fn test_restore_is_logged_as_one_record() {
+        let wal_file = "/tmp/test_keyspace_restore.wal";
+        let _ = std::fs::remove_file(wal_file);
+        let open = || Keyspace::open("test", Location::Memory { wal_file: Some(wal_file) }, None,
+            None, SHARDS).unwrap();
+        let keyspace = open();
+        assert!(keyspace.add_value(KeyValuePair::new("a", "live"), None).unwrap());
+        assert!(keyspace.add_value(KeyValuePair::new("b", "live"), None).unwrap());
+        let restored = backup(&[("b", "restored"), ("c", "restored")]);
+        let options = RestoreOptions { mode: RestoreMode::Replace, prefix: Some(b"".to_vec()) };
+        keyspace.restore_store(&restored, &options, false).unwrap();
+        drop(keyspace);
+
+        let mut records = Vec::new();
+        WriteAheadLog::open(wal_file).unwrap()
+            .replay_records(|r| { records.push(r); Ok(()) })
+            .unwrap();
+        assert_eq!(records.len(), 3);
+        let restore = &records[2];
+        assert_eq!(restore.op(), WalOp::Batch);
+        let mut ops: Vec<(WalOp, String)> = restore.batch.iter()
+            .map(|r| (r.op(), String::from_utf8_lossy(&r.key).into_owned()))
+            .collect();
+        ops.sort_by(|a, b| a.1.cmp(&b.1));
+        assert_eq!(ops, vec![(WalOp::Delete, String::from("a")),
+            (WalOp::Update, String::from("b")), (WalOp::Update, String::from("c"))]);
+
+        let reopened = open();
+        assert!(reopened.get_value(b"a").unwrap().is_none());
+        for key in [b"b", b"c"] {
+            assert_eq!(reopened.get_value(key).unwrap().unwrap().0.value(), "restored");
+        }
+        std::fs::remove_file(wal_file).unwrap();
+    }
+
+    fn unlimited() -> Keyspace {
+        Keyspace::open("test", Location::Memory { wal_file: None }, None, None, SHARDS).unwrap()
+    }
+
+    fn value(keyspace: &Keyspace, key: &str) -> Option<String> {
+        keyspace.get_value(key.as_bytes()).unwrap().map(|(p, _)| p.value())
+    }
+
+    #[test]
+    fn test_transaction_outcomes() {
+        let keyspace = unlimited();
+        assert!(keyspace.add_value(KeyValuePair::new("a", "1"), None).unwrap());
+        let (_, a_version) = keyspace.get_value(b"a").unwrap().unwrap();
+        let outcomes = keyspace.transaction(vec![
+            TxnOperation::Check(b"a".to_vec(), Condition::Holds(Expected::Version(a_version))),
+            TxnOperation::Update(KeyValuePair::new("a", "2"), None),
+            TxnOperation::Create(KeyValuePair::new("b", "1"), None),
+            TxnOperation::Check(b"b".to_vec(), Condition::Exists(true)),
+            TxnOperation::Delete(b"b".to_vec()),
+            TxnOperation::Check(b"b".to_vec(), Condition::Exists(false)),
+        ]).unwrap();
+        let (_, new_version) = keyspace.get_value(b"a").unwrap().unwrap();
+        assert_eq!(outcomes[0], TxnOutcome::Ok(Some(a_version)));
+        assert_eq!(outcomes[1], TxnOutcome::Ok(Some(new_version)));
+        assert!(new_version > a_version);
+        // Later operations see what earlier ones did
+        let b_version = match outcomes[2] {
+            TxnOutcome::Ok(Some(v)) => v,
+            ref o => panic!("Unexpected outcome {:?}", o)
+        };
+        assert_eq!(outcomes[3], TxnOutcome::Ok(Some(b_version)));
+        assert_eq!(outcomes[4..], [TxnOutcome::Ok(None), TxnOutcome::Ok(None)]);
+        assert_eq!(value(&keyspace, "a").as_deref(), Some("2"));
+        assert_eq!(value(&keyspace, "b"), None);
+    }
+
+    #[test]
+    fn test_failed_condition_rolls_back_transaction() {
+        let keyspace = unlimited();
+        assert!(keyspace.add_value(KeyValuePair::new("a", "1"), None).unwrap());
+        assert!(keyspace.add_value(KeyValuePair::new("c", "1"), None).unwrap());
+        let (_, a_version) = keyspace.get_value(b"a").unwrap().unwrap();
+        let outcomes = keyspace.transaction(vec![
+            TxnOperation::Update(KeyValuePair::new("a", "2"), None),
+            TxnOperation::Create(KeyValuePair::new("b", "1"), None),
+            TxnOperation::Delete(b"c".to_vec()),
+            TxnOperation::Check(b"a".to_vec(), Condition::Holds(Expected::Version(a_version))),
+            TxnOperation::Create(KeyValuePair::new("d", "1"), None),
+        ]).unwrap();
+        assert!(matches!(outcomes[..3], [TxnOutcome::Ok(_), TxnOutcome::Ok(_), TxnOutcome::Ok(_)]),
+            "{:?}", outcomes);
+        // The update made earlier in the transaction moved the version on
+        assert!(matches!(&outcomes[3], TxnOutcome::Failed(e) if e.contains("at version")),
+            "{:?}", outcomes);
+        assert_eq!(outcomes[4], TxnOutcome::NotRun);
+        assert_eq!(value(&keyspace, "a").as_deref(), Some("1"));
+        assert_eq!(keyspace.get_value(b"a").unwrap().unwrap().1, a_version);
+        assert_eq!(value(&keyspace, "b"), None);
+        assert_eq!(value(&keyspace, "c").as_deref(), Some("1"));
+        assert_eq!(value(&keyspace, "d"), None);
+        assert_eq!(keyspace.stats().key_count, 2);
+    }
+
+    #[test]
+    fn test_transaction_across_shards_replays_as_one() {
+        let wal_file = "/tmp/test_keyspace_transaction.wal";
+        let _ = std::fs::remove_file(wal_file);
+        let open = || Keyspace::open("test", Location::Memory { wal_file: Some(wal_file) }, None,
+            None, SHARDS).unwrap();
+        let keyspace = open();
+        let keys: Vec<String> = (0..SHARDS)
+            .map(|shard| keys_in_shard(&keyspace, shard, 1).remove(0))
+            .collect();
+        assert!(keyspace.add_value(KeyValuePair::new(&keys[0], "old"), None).unwrap());
+        let full_len = std::fs::metadata(wal_file).unwrap().len();
+        let mut ops = vec![TxnOperation::Delete(keys[0].as_bytes().to_vec())];
+        ops.extend(keys[1..].iter()
+            .map(|k| TxnOperation::Create(KeyValuePair::new(k, "new"), None)));
+        let outcomes = keyspace.transaction(ops).unwrap();
+        assert!(outcomes.iter().all(|o| matches!(o, TxnOutcome::Ok(_))), "{:?}", outcomes);
+        let versions: Vec<_> = keys[1..].iter()
+            .map(|k| keyspace.get_value(k.as_bytes()).unwrap().unwrap().1)
+            .collect();
+        drop(keyspace);
+
+        let reopened = open();
+        assert_eq!(value(&reopened, &keys[0]), None);
+        for (key, version) in keys[1..].iter().zip(&versions) {
+            assert_eq!(reopened.get_value(key.as_bytes()).unwrap(),
+                Some((KeyValuePair::new(key, "new"), *version)));
+        }
+        drop(reopened);
+
+        // Cut short, none of it is replayed
+        let torn_len = std::fs::metadata(wal_file).unwrap().len() - 2;
+        assert!(torn_len > full_len);
+        std::fs::OpenOptions::new().write(true).open(wal_file).unwrap().set_len(torn_len).unwrap();
+        let reopened = open();
+        assert_eq!(value(&reopened, &keys[0]).as_deref(), Some("old"));
+        assert_eq!(reopened.stats().key_count, 1);
+        std::fs::remove_file(wal_file).unwrap();
+    }
+}
@@ -1,4 +1,7 @@
 pub mod server_impl;
 pub mod client_impl;
+mod backup_catalog;
+mod backup_jobs;
 mod decode_utils;
+mod keyspace;
 pub mod socket_errors;
\ No newline at end of file
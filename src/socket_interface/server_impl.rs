@@ -1,255 +1,1992 @@
+use std::collections::{HashMap, HashSet};
+use std::ops::Bound;
+use std::path::Path;
 use std::str::FromStr;
 use std::sync::{Arc, RwLock};
 use prost::Message;
 use tokio::net::TcpListener;
 use tokio_util::codec::{Framed, LengthDelimitedCodec};
-use crate::key_value_store::key_value_store::KeyValueStore;
+use crate::key_value_store::errors::{ErrorKind, RWError};
+use crate::key_value_store::eviction::EvictionPolicy;
+use crate::key_value_store::filestore;
+use crate::key_value_store::history::{PointInTime, Retention};
+use crate::key_value_store::key_pattern::KeyPattern;
+use crate::key_value_store::restore::{self, RestoreOptions};
+use crate::key_value_store::sharded_store::DEFAULT_SHARD_COUNT;
+use crate::key_value_store::storage_engine::{EngineKind, StorageEngine};
+use crate::key_value_store::text_format::{ImportOptions, OnConflict, TextFormat};
 
 use futures::{SinkExt, StreamExt};
 
+use super::backup_catalog::BackupCatalog;
+use super::backup_jobs::{BackupJobs, WriteBackup};
 use super::decode_utils::*;
+use super::keyspace::{CasOutcome, Condition, Expected, IncrOutcome, Keyspace, Location,
+    TxnOperation, TxnOutcome, WatchStamp};
 use crate::proto::*;
 use log::{trace, warn, info, error};
+use std::time::Duration;
 
+/// How often the server looks for expired keys to remove
+const EXPIRY_INTERVAL: Duration = Duration::from_millis(100);
+/// The most pairs returned by a single range scan, whatever limit the
+/// client asks for
+const MAX_SCAN_PAGE: usize = 1000;
+/// Keys returned by a SCAN page when the client does not ask for a count
+const DEFAULT_SCAN_COUNT: usize = 10;
+/// The most keys a single SCAN page looks at, matching or not, so that a
+/// pattern matching few keys does not hold the store lock for long
+const MAX_SCAN_EXAMINED: usize = 10_000;
+/// Leads every SCAN cursor so that cursors from elsewhere are rejected
+const SCAN_CURSOR_VERSION: u8 = 1;
+/// The most revisions returned by a single HISTORY request, whatever limit
+/// the client asks for
+const MAX_HISTORY_PAGE: usize = 1000;
+/// The most operations a single transaction may hold
+const MAX_TXN_OPS: usize = 1000;
+/// The most keys a connection may watch at once
+const MAX_WATCHED_KEYS: usize = 1000;
+/// The most keys a single MGET, MSET or MDEL may act on
+const MAX_BATCH_KEYS: usize = 1000;
+/// Longest allowed store name
+const MAX_STORE_NAME_LEN: usize = 64;
+/// Longest allowed backup ID or export file name
+const MAX_FILE_NAME_LEN: usize = 128;
+/// The most failed lines an import response lists
+const MAX_REPORTED_LINE_ERRORS: usize = 100;
+/// Separates the default store's log file name from another store's name in
+/// that store's log file name
+const STORE_WAL_INFIX: &str = ".store-";
+/// Returned to clients when a request names a store the server does not have
+const NO_SUCH_STORE: &str = "No such store";
+/// Returned to clients when a request names a backup not in the catalog
+const NO_SUCH_BACKUP: &str = "No such backup";
+/// Leads the error returned to clients when a backup ID is not a plain name,
+/// e.g. when it tries to reach outside the backup directory
+pub const INVALID_BACKUP_ID: &str = "Invalid backup ID";
+/// Leads the error returned to clients when an export file name is not a
+/// plain name
+pub const INVALID_EXPORT_NAME: &str = "Invalid export file name";
+/// Where the disk engine keeps its stores unless told otherwise
+pub const DEFAULT_DATA_DIR: &str = "construct_cache_data";
+/// Where backups are written unless told otherwise
+pub const DEFAULT_BACKUP_DIR: &str = "construct_cache_backups";
+/// Where stores are exported to and imported from unless told otherwise
+pub const DEFAULT_EXPORT_DIR: &str = "construct_cache_exports";
+
+/// Turns one end of a requested scan range into a bound, an unset key
+/// leaving that end open
+fn scan_bound(key: &Option<Vec<u8>>, exclusive: bool) -> Bound<&[u8]> {
+    match key {
+        None => Bound::Unbounded,
+        Some(k) if exclusive => Bound::Excluded(k.as_slice()),
+        Some(k) => Bound::Included(k.as_slice())
+    }
+}
+
+/// Caps the estimated memory used by each store
+pub struct MemoryLimit {
+    pub max_bytes: u64,
+    pub policy: EvictionPolicy
+}
+
+/// Optional features of a server, all disabled by default
+#[derive(Default)]
+pub struct ServerOptions {
+    /// Records mutations to the default store in a write-ahead log at this
+    /// path, replayed on startup. Other stores get their own log next to it.
+    pub wal_file: Option<String>,
+    /// Evicts keys, or refuses writes, once a store grows past this
+    pub memory_limit: Option<MemoryLimit>,
+    /// The number of shards each store is split into, DEFAULT_SHARD_COUNT if
+    /// unset. See `ShardedStore`.
+    pub shard_count: Option<usize>,
+    /// The engine stores are kept in. With the disk engine, the write-ahead
+    /// log is not used.
+    pub engine: EngineKind,
+    /// Where the disk engine keeps each store, in a directory named after
+    /// it. DEFAULT_DATA_DIR if unset.
+    pub data_dir: Option<String>,
+    /// Keeps the list of backups the server has written in this file, read
+    /// on startup. Without it, backups are forgotten when the server stops.
+    pub backup_catalog: Option<String>,
+    /// Where backups are written and restored from, each in a file named
+    /// after its ID. DEFAULT_BACKUP_DIR if unset.
+    pub backup_dir: Option<String>,
+    /// Where stores are exported to and imported from as text.
+    /// DEFAULT_EXPORT_DIR if unset.
+    pub export_dir: Option<String>,
+    /// Keeps past versions of the pairs in every store, within these limits,
+    /// so that they can be read. History is only kept in memory.
+    pub history: Option<Retention>,
+    /// Overrides `history` for the stores named
+    pub store_history: HashMap<String, Retention>
+}
+
+impl ServerOptions {
+    fn data_dir(&self) -> &str {
+        self.data_dir.as_deref().unwrap_or(DEFAULT_DATA_DIR)
+    }
+
+    fn backup_dir(&self) -> &str {
+        self.backup_dir.as_deref().unwrap_or(DEFAULT_BACKUP_DIR)
+    }
+
+    fn export_dir(&self) -> &str {
+        self.export_dir.as_deref().unwrap_or(DEFAULT_EXPORT_DIR)
+    }
+
+    /// How long the store `name` keeps past versions, if at all
+    fn history(&self, name: &str) -> Option<&Retention> {
+        self.store_history.get(name).or(self.history.as_ref())
+    }
+
+    /// The log file or directory the store `name` is kept in, if any
+    fn store_path(&self, name: &str, is_default: bool) -> Option<String> {
+        match self.engine {
+            EngineKind::Memory => match &self.wal_file {
+                None => None,
+                Some(w) if is_default => Some(w.clone()),
+                Some(w) => Some(store_wal_path(w, name))
+            },
+            EngineKind::Disk | EngineKind::Lsm => Some(format!("{}/{}", self.data_dir(), name))
+        }
+    }
+
+    /// Turns a path from `store_path` into a `Location`
+    fn location<'a>(&self, path: &'a Option<String>) -> Location<'a> {
+        match &self.engine {
+            EngineKind::Memory => Location::Memory { wal_file: path.as_deref() },
+            // `store_path` always gives the on-disk engines a path
+            engine => Location::Disk { dir: path.as_deref().unwrap(), engine: engine.clone() }
+        }
+    }
+
+    /// Finds the names of the stores kept by a previous run, other than the
+    /// default one
+    fn find_stores(&self) -> Result<Vec<String>, RWError> {
+        match self.engine {
+            EngineKind::Memory => match &self.wal_file {
+                None => Ok(Vec::new()),
+                Some(w) => find_store_wals(w)
+            },
+            EngineKind::Disk | EngineKind::Lsm => find_store_dirs(self.data_dir())
+        }
+    }
+}
+
+/// Checks that `name` can be used as a store name: 1 to 64 ASCII letters,
+/// digits, '-' or '_'. Names end up in file names, so nothing else is allowed.
+pub fn validate_store_name(name: &str) -> Result<(), String> {
+    if name.is_empty() || name.len() > MAX_STORE_NAME_LEN {
+        return Err(format!("Store names must be 1 to {} characters long",
+            MAX_STORE_NAME_LEN));
+    }
+    if !name.bytes().all(|c| c.is_ascii_alphanumeric() || c == b'-' || c == b'_') {
+        return Err(String::from(
+            "Store names may only hold ASCII letters, digits, '-' and '_'"));
+    }
+    Ok(())
+}
+
+/// Checks that `name` can name a file in one of the server's directories: 1
+/// to 128 ASCII letters, digits, '-', '_' or '.', not starting with '.' and
+/// not ending in ".tmp". Anything that could name a file elsewhere, or the
+/// temporary file of another file being written, is refused. Returns what is
+/// wrong with the name otherwise.
+fn validate_file_name(name: &str) -> Result<(), String> {
+    if name.is_empty() || name.len() > MAX_FILE_NAME_LEN {
+        return Err(format!("must be 1 to {} characters long", MAX_FILE_NAME_LEN));
+    }
+    if !name.bytes().all(|c| c.is_ascii_alphanumeric() || c == b'-' || c == b'_' || c == b'.') {
+        return Err(String::from("may only hold ASCII letters, digits, '-', '_' and '.'"));
+    }
+    // Rules out "." and ".." along with hidden files
+    if name.starts_with('.') {
+        return Err(String::from("may not start with '.'"));
+    }
+    if name.ends_with(".tmp") {
+        return Err(String::from("may not end in \".tmp\""));
+    }
+    Ok(())
+}
+
+/// Checks that `backup_id` can be used as a backup ID, see
+/// `validate_file_name`. IDs are file names in the backup directory.
+pub fn validate_backup_id(backup_id: &str) -> Result<(), String> {
+    validate_file_name(backup_id)
+        .map_err(|e| format!("{}: backup IDs {}", INVALID_BACKUP_ID, e))
+}
+
+/// Validates the backup ID in a request, logging any that is refused since
+/// it may be an attempt to reach files outside the backup directory
+fn check_requested_backup_id(backup_id: &str) -> Result<(), String> {
+    validate_backup_id(backup_id).inspect_err(|_| {
+        warn!("Refused request for backup ID {:?}", backup_id);
+    })
+}
+
+/// Validates the file name in an export or import request, logging any that
+/// is refused like `check_requested_backup_id`
+fn check_requested_export_name(file_name: &str) -> Result<(), String> {
+    validate_file_name(file_name)
+        .map_err(|e| format!("{}: export file names {}", INVALID_EXPORT_NAME, e))
+        .inspect_err(|_| warn!("Refused request for export file {:?}", file_name))
+}
+
+/// The write-ahead log of the store `name`, kept next to the default store's
+fn store_wal_path(wal_file: &str, name: &str) -> String {
+    format!("{}{}{}", wal_file, STORE_WAL_INFIX, name)
+}
+
+/// Finds the names of the stores that have a log next to `wal_file`
+fn find_store_wals(wal_file: &str) -> Result<Vec<String>, RWError> {
+    let path = Path::new(wal_file);
+    let dir = match path.parent() {
+        Some(p) if !p.as_os_str().is_empty() => p,
+        _ => Path::new(".")
+    };
+    let prefix = match path.file_name() {
+        Some(f) => format!("{}{}", f.to_string_lossy(), STORE_WAL_INFIX),
+        None => return Ok(Vec::new())
+    };
+    let entries = match std::fs::read_dir(dir) {
+        Ok(e) => e,
+        Err(e) => return Err(RWError {
+            kind_: ErrorKind::FileReadError,
+            context_: e.to_string()
+        })
+    };
+    let mut names = Vec::new();
+    for entry in entries.flatten() {
+        let file_name = entry.file_name().to_string_lossy().into_owned();
+        // Leftovers such as temporary files from an interrupted rewrite do
+        // not make valid store names
+        if let Some(name) = file_name.strip_prefix(&prefix) {
+            if validate_store_name(name).is_ok() {
+                names.push(name.to_string());
+            }
+        }
+    }
+    Ok(names)
+}
+
+/// Finds the names of the stores that have a directory in `data_dir`
+fn find_store_dirs(data_dir: &str) -> Result<Vec<String>, RWError> {
+    let entries = match std::fs::read_dir(data_dir) {
+        Ok(e) => e,
+        // Nothing has been stored yet
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(RWError {
+            kind_: ErrorKind::FileReadError,
+            context_: e.to_string()
+        })
+    };
+    let mut names = Vec::new();
+    for entry in entries.flatten() {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if entry.path().is_dir() && validate_store_name(&name).is_ok() {
+            names.push(name);
+        }
+    }
+    Ok(names)
+}
+
+/// What the server keeps for a connection between its requests
+pub struct Session {
+    // The store used by requests that do not name one, see SELECT_STORE
+    store_: String,
+    // The keys watched for the next EXEC, if any
+    watched_: Option<Watched>,
+}
+
+/// Keys a connection watches, all in one store, along with what each was
+/// like when first watched. Stops watching them when dropped.
+struct Watched {
+    keyspace: Arc<Keyspace>,
+    keys: HashMap<Vec<u8>, WatchStamp>,
+}
+
+impl Drop for Watched {
+    fn drop(&mut self) {
+        for key in self.keys.keys() {
+            self.keyspace.unwatch(key);
+        }
+    }
+}
+
+impl Session {
+    /// Starts a connection's session, using `store` for requests that do not
+    /// name one until another is selected
+    pub fn new(store: &str) -> Session {
+        Session { store_: store.to_string(), watched_: None }
+    }
+}
 
 /// The main key value store server. Stores a listening address so that
 /// it may be able to selectively choose the interfaces it listens on
 pub struct ConstructCacheServer {
     listen_addr_: String,
-    kvs_access_: RwLock<KeyValueStore>
+    // Used by requests that neither name a store nor come from a connection
+    // that selected one. Cannot be dropped.
+    default_store_: String,
+    // Only held long enough to look up a store, or to create or drop one;
+    // never while waiting on a store's own lock, except when dropping it.
+    keyspaces_: RwLock<HashMap<String, Arc<Keyspace>>>,
+    backup_jobs_: BackupJobs,
+    // Shared with the backup jobs, which add to it as they finish
+    backup_catalog_: Arc<BackupCatalog>,
+    options_: ServerOptions
+}
+
+/// Turns an operation from a transaction request into a `TxnOperation`,
+/// failing if it is empty or holds an invalid pair
+fn txn_operation(op: TxnOp) -> Result<TxnOperation, String> {
+    match op.op {
+        Some(txn_op::Op::Create(w)) =>
+            Ok(TxnOperation::Create(request_pair_to_kvp_rust(None, w.pair)?, w.ttl_ms)),
+        Some(txn_op::Op::Update(w)) =>
+            Ok(TxnOperation::Update(request_pair_to_kvp_rust(None, w.pair)?, w.ttl_ms)),
+        Some(txn_op::Op::Delete(key)) => Ok(TxnOperation::Delete(key)),
+        Some(txn_op::Op::Check(c)) => {
+            let condition = match c.expected {
+                Some(txn_condition::Expected::ExpectedVersion(v)) =>
+                    Condition::Holds(Expected::Version(v)),
+                Some(txn_condition::Expected::ExpectedValue(v)) =>
+                    Condition::Holds(Expected::Value(v.data_type(), v.value)),
+                Some(txn_condition::Expected::Exists(e)) => Condition::Exists(e),
+                None => return Err(String::from("No condition in check"))
+            };
+            Ok(TxnOperation::Check(c.key, condition))
+        },
+        None => Err(String::from("Empty operation"))
+    }
+}
+
+/// Fails if a batch request acts on more keys than it may
+fn check_batch_size(keys: usize) -> Result<(), String> {
+    if keys > MAX_BATCH_KEYS {
+        return Err(format!("Batch requests may hold at most {} keys", MAX_BATCH_KEYS));
+    }
+    Ok(())
+}
+
+/// Builds the response to a transaction that ran, from how each of its
+/// operations went
+fn transaction_resp(outcomes: Vec<TxnOutcome>) -> TransactionResp {
+    TransactionResp {
+        success: outcomes.iter().all(|o| matches!(o, TxnOutcome::Ok(_))),
+        results: outcomes.into_iter().map(|o| {
+            let mut result = TxnOpResult::default();
+            match o {
+                TxnOutcome::Ok(version) => result.version = version,
+                TxnOutcome::Failed(e) => {
+                    result.set_status(TxnOpStatus::Failed);
+                    result.error = e;
+                },
+                TxnOutcome::NotRun => result.set_status(TxnOpStatus::NotRun)
+            }
+            result
+        }).collect(),
+        ..Default::default()
+    }
 }
 
 fn invalid_create_resp() -> CreateKvPairResp {
-    CreateKvPairResp { success: false }
+    CreateKvPairResp { success: false, error: String::new() }
 }
 
 
 impl ConstructCacheServer {
     pub fn new(listening_addr: &str, name: &str) -> Arc<ConstructCacheServer> {
+        let mut keyspaces = HashMap::new();
+        keyspaces.insert(name.to_string(), Arc::new(Keyspace::new(name)));
         Arc::new(ConstructCacheServer {
             listen_addr_: String::from_str(listening_addr).unwrap(),
-            kvs_access_: 
-                RwLock::new(
-                    KeyValueStore::new(name)
-                )
-            
+            default_store_: name.to_string(),
+            keyspaces_: RwLock::new(keyspaces),
+            backup_jobs_: BackupJobs::default(),
+            // Cannot fail without a file to read
+            backup_catalog_: Arc::new(BackupCatalog::open(None, DEFAULT_BACKUP_DIR).unwrap()),
+            options_: ServerOptions::default()
+        })
+    }
+
+    /// Creates a server whose mutations are recorded in the write-ahead log at
+    /// `wal_file`. Any records already in the log are replayed to rebuild the
+    /// store before the server is returned.
+    pub fn new_with_wal(listening_addr: &str, name: &str, wal_file: &str)
+            -> Result<Arc<ConstructCacheServer>, RWError> {
+        ConstructCacheServer::with_options(listening_addr, name, ServerOptions {
+            wal_file: Some(wal_file.to_string()),
+            ..Default::default()
         })
     }
 
-    pub fn handle_ping_request(&self, binary_req: &[u8]) -> Vec<u8> {
-        let ping_request: PingRequest;
-        match parse_ping_request(binary_req) {
-            Ok(v) => { ping_request = v; },
+    /// Creates a server whose default store is called `name`. With a
+    /// write-ahead log or the disk engine, every other store kept by a
+    /// previous run is brought back as well.
+    pub fn with_options(listening_addr: &str, name: &str, options: ServerOptions)
+            -> Result<Arc<ConstructCacheServer>, RWError> {
+        let mut keyspaces = HashMap::new();
+        let shard_count = options.shard_count.unwrap_or(DEFAULT_SHARD_COUNT);
+        let path = options.store_path(name, true);
+        let default = Keyspace::open(name, options.location(&path),
+            options.memory_limit.as_ref(), options.history(name), shard_count)?;
+        keyspaces.insert(name.to_string(), Arc::new(default));
+        for store_name in options.find_stores()? {
+            if store_name == name {
+                continue;
+            }
+            let path = options.store_path(&store_name, false);
+            let keyspace = Keyspace::open(&store_name, options.location(&path),
+                options.memory_limit.as_ref(), options.history(&store_name), shard_count)?;
+            info!("Opened store {:?}", store_name);
+            keyspaces.insert(store_name, Arc::new(keyspace));
+        }
+        let backup_catalog = BackupCatalog::open(options.backup_catalog.as_deref(),
+            options.backup_dir())?;
+        Ok(Arc::new(ConstructCacheServer {
+            listen_addr_: String::from_str(listening_addr).unwrap(),
+            default_store_: name.to_string(),
+            keyspaces_: RwLock::new(keyspaces),
+            backup_jobs_: BackupJobs::default(),
+            backup_catalog_: Arc::new(backup_catalog),
+            options_: options
+        }))
+    }
+
+    /// Looks up the store named in a request, falling back to the one
+    /// selected for the connection
+    fn keyspace(&self, name: &Option<String>, session_store: &str)
+            -> Result<Arc<Keyspace>, String> {
+        let name = name.as_deref().unwrap_or(session_store);
+        match self.keyspaces_.read().unwrap().get(name) {
+            Some(k) => Ok(k.clone()),
+            None => Err(format!("{}: {}", NO_SUCH_STORE, name))
+        }
+    }
+
+    fn create_store(&self, name: &str) -> Result<(), String> {
+        validate_store_name(name)?;
+        let mut keyspaces = self.keyspaces_.write().unwrap();
+        if keyspaces.contains_key(name) {
+            return Err(String::from("Store already exists"));
+        }
+        let path = self.options_.store_path(name, false);
+        match Keyspace::open(name, self.options_.location(&path),
+                self.options_.memory_limit.as_ref(), self.options_.history(name),
+                self.options_.shard_count.unwrap_or(DEFAULT_SHARD_COUNT)) {
+            Ok(k) => {
+                info!("Created store {:?}", name);
+                keyspaces.insert(name.to_string(), Arc::new(k));
+                Ok(())
+            },
+            Err(e) => {
+                error!("Cannot create store {:?}: {:?}", name, e.to_string());
+                Err(String::from("Cannot create store"))
+            }
+        }
+    }
+
+    fn drop_store(&self, name: &str) -> Result<(), String> {
+        if name == self.default_store_ {
+            return Err(String::from("Cannot drop the default store"));
+        }
+        // The map stays locked until the log or files are gone, so that a
+        // store created under the same name cannot pick them up.
+        let mut keyspaces = self.keyspaces_.write().unwrap();
+        let keyspace = match keyspaces.get(name) {
+            None => return Err(format!("{}: {}", NO_SUCH_STORE, name)),
+            Some(k) => k.clone()
+        };
+        if let Err(e) = keyspace.destroy() {
+            error!("Cannot drop store {:?}: {:?}", name, e.to_string());
+            return Err(String::from("Cannot remove the store's files"));
+        }
+        keyspaces.remove(name);
+        info!("Dropped store {:?}", name);
+        Ok(())
+    }
+
+    async fn expiry_loop(self: Arc<Self>) {
+        let mut interval = tokio::time::interval(EXPIRY_INTERVAL);
+        loop {
+            interval.tick().await;
+            let keyspaces: Vec<Arc<Keyspace>> =
+                self.keyspaces_.read().unwrap().values().cloned().collect();
+            for keyspace in keyspaces {
+                // A full batch means there may be more waiting; go again
+                // without waiting for the next tick. Past versions kept for
+                // too long are dropped along the way.
+                loop {
+                    let k = keyspace.clone();
+                    // `|` so that both always run
+                    let task = move || k.remove_expired_keys() | k.trim_history();
+                    match tokio::task::spawn_blocking(task).await {
+                        Ok(true) => continue,
+                        Ok(false) => break,
+                        Err(e) => {
+                            error!("Expiry task failed: {:?}", e);
+                            break;
+                        }
+                    }
+                }
+                // After the expired keys are gone, as the log can then drop
+                // their records too
+                let k = keyspace.clone();
+                if let Err(e) = tokio::task::spawn_blocking(move || k.maybe_compact_wal()).await {
+                    error!("Log compaction task failed: {:?}", e);
+                }
+            }
+        }
+    }
+
+    pub fn handle_ping_request(&self, binary_req: &[u8]) -> Vec<u8> {
+        let ping_request = match parse_ping_request(binary_req) {
+            Ok(v) => v,
+            Err(e) => {
+                warn!("Parse error: {:?}", e);
+                return vec![];
+            }
+        };
+        let message: String = ping_request.ping_message;
+        info!("Received ping: {:?}", message);
+        let resp = message.clone() + " acked by server";
+        let ping_resp = PingResponse {
+            ping_resp_message: resp
+        };
+        ping_resp.encode_to_vec()
+    }
+
+    pub fn handle_create_request(&self, binary_req: &[u8], session_store: &str) -> Vec<u8> {
+        let create_request = match parse_create_request(binary_req) {
+            Ok(v) => v,
+            Err(e) => {
+                warn!("Parse error: {:?}", e);
+                return invalid_create_resp().encode_to_vec();
+            }
+        };
+        if create_request.pair.is_none() && create_request.typed_pair.is_none() {
+            warn!("No pair to insert");
+            return invalid_create_resp().encode_to_vec();
+        }
+        let keyspace = match self.keyspace(&create_request.store, session_store) {
+            Ok(k) => k,
+            Err(e) => return CreateKvPairResp {
+                success: false,
+                error: e
+            }.encode_to_vec()
+        };
+        let insertable_pair = match request_pair_to_kvp_rust(create_request.pair,
+                create_request.typed_pair) {
+            Ok(x) => x,
+            Err(e) => {
+                warn!("Invalid pair: {:?}", e);
+                return CreateKvPairResp {
+                    success: false,
+                    error: e
+                }.encode_to_vec();
+            }
+        };
+        info!("Got key: {:?}", insertable_pair.key_text());
+        info!("Got value: {:?}", insertable_pair.value());
+
+        let resp = match keyspace.add_value(insertable_pair, create_request.ttl_ms) {
+            Ok(success) => CreateKvPairResp {
+                success,
+                error: String::new()
+            },
+            Err(e) => CreateKvPairResp {
+                success: false,
+                error: e
+            }
+        };
+        resp.encode_to_vec()
+    }
+
+
+    pub fn handle_read_request(&self, binary_req: &[u8], session_store: &str) -> Vec<u8> {
+        let read_request = match parse_read_request(binary_req) {
+            Ok(v) => v,
+            Err(e) => {
+                warn!("Parse error: {:?}", e);
+                return ReadKvPairResp::default().encode_to_vec();
+            }
+        };
+        let keyspace = match self.keyspace(&read_request.store, session_store) {
+            Ok(k) => k,
+            Err(e) => return ReadKvPairResp {
+                error: e,
+                ..Default::default()
+            }.encode_to_vec()
+        };
+        let key = read_request.key;
+        let found = match read_request.as_of {
+            None => keyspace.get_value(&key),
+            Some(read_kv_pair_req::AsOf::AsOfVersion(v)) =>
+                keyspace.get_value_as_of(&key, PointInTime::Version(v)),
+            Some(read_kv_pair_req::AsOf::AsOfMs(t)) =>
+                keyspace.get_value_as_of(&key, PointInTime::Timestamp(t))
+        };
+        match found {
+            Ok(None) => ReadKvPairResp::default().encode_to_vec(),
+            Err(e) => ReadKvPairResp {
+                error: e,
+                ..Default::default()
+            }.encode_to_vec(),
+            Ok(Some((x, version))) => ReadKvPairResp {
+                    success: true,
+                    pair: Some(KeyValuePair {
+                        key: x.key_text(),
+                        value: x.value()
+                    }),
+                    typed_pair: Some(kvp_rust_to_generic_kvp(&x)),
+                    error: String::new(),
+                    version
+                }.encode_to_vec()
+        }
+    }
+
+    pub fn handle_update_request(&self, binary_req: &[u8], session_store: &str) -> Vec<u8> {
+        let update_request = match parse_update_request(binary_req) {
+            Ok(v) => v,
+            Err(e) => {
+                warn!("Parse error: {:?}", e);
+                return UpdateKvPairResp {
+                    success: false,
+                    error: String::new()
+                }.encode_to_vec()
+            }
+        };
+        let keyspace = match self.keyspace(&update_request.store, session_store) {
+            Ok(k) => k,
+            Err(e) => return UpdateKvPairResp {
+                success: false,
+                error: e
+            }.encode_to_vec()
+        };
+        let mut resp = UpdateKvPairResp::default();
+        match request_pair_to_kvp_rust(update_request.pair, update_request.typed_pair) {
+            Ok(x) => {
+                match keyspace.update_value(x, update_request.ttl_ms) {
+                    Ok(success) => resp.success = success,
+                    Err(e) => {
+                        resp.success = false;
+                        resp.error = e;
+                    }
+                }
+            },
+            Err(e) => {
+                resp.success = false;
+                resp.error = e;
+            }
+        }
+        resp.encode_to_vec()
+    }
+
+    pub fn handle_cas_request(&self, binary_req: &[u8], session_store: &str) -> Vec<u8> {
+        let cas_request = match parse_cas_request(binary_req) {
+            Ok(v) => v,
+            Err(e) => {
+                warn!("Parse error: {:?}", e);
+                return CasResp {
+                    error: e.to_string(),
+                    ..Default::default()
+                }.encode_to_vec();
+            }
+        };
+        let expected = match cas_request.expected {
+            Some(cas_req::Expected::ExpectedVersion(v)) => Expected::Version(v),
+            Some(cas_req::Expected::ExpectedValue(v)) => {
+                let data_type = v.data_type();
+                Expected::Value(data_type, v.value)
+            },
+            None => return CasResp {
+                error: String::from("No expected version or value in request"),
+                ..Default::default()
+            }.encode_to_vec()
+        };
+        let keyspace = match self.keyspace(&cas_request.store, session_store) {
+            Ok(k) => k,
+            Err(e) => return CasResp {
+                error: e,
+                ..Default::default()
+            }.encode_to_vec()
+        };
+        let pair = match request_pair_to_kvp_rust(None, cas_request.pair) {
+            Ok(p) => p,
+            Err(e) => return CasResp {
+                error: e,
+                ..Default::default()
+            }.encode_to_vec()
+        };
+        match keyspace.compare_and_swap(pair, &expected, cas_request.ttl_ms) {
+            Ok(CasOutcome::Swapped(version)) => CasResp {
+                success: true,
+                version: Some(version),
+                ..Default::default()
+            },
+            Ok(CasOutcome::Conflict(current)) => CasResp {
+                version: current.as_ref().map(|(_, v)| *v),
+                current: current.as_ref().map(|(p, _)| kvp_rust_to_generic_kvp(p)),
+                ..Default::default()
+            },
+            Err(e) => CasResp {
+                error: e,
+                ..Default::default()
+            }
+        }.encode_to_vec()
+    }
+
+    pub fn handle_incr_request(&self, binary_req: &[u8], session_store: &str) -> Vec<u8> {
+        let incr_request = match parse_incr_request(binary_req) {
+            Ok(v) => v,
+            Err(e) => {
+                warn!("Parse error: {:?}", e);
+                return IncrResp {
+                    error: e.to_string(),
+                    ..Default::default()
+                }.encode_to_vec();
+            }
+        };
+        let keyspace = match self.keyspace(&incr_request.store, session_store) {
+            Ok(k) => k,
+            Err(e) => return IncrResp {
+                error: e,
+                ..Default::default()
+            }.encode_to_vec()
+        };
+        let mut resp = IncrResp::default();
+        match keyspace.increment(&incr_request.key, incr_request.delta, incr_request.ttl_ms) {
+            Ok(IncrOutcome::Incremented(value, version)) => {
+                resp.success = true;
+                resp.value = value;
+                resp.version = version;
+            },
+            Ok(IncrOutcome::NotAnInteger(data_type)) => {
+                resp.set_error_kind(IncrError::NotAnInteger);
+                resp.error = format!("Key holds a {} value that is not a 64-bit signed integer",
+                    data_type.as_str_name());
+            },
+            Ok(IncrOutcome::Overflow(value)) => {
+                resp.set_error_kind(IncrError::Overflow);
+                resp.value = value;
+                resp.error = format!("Adding {} to {} overflows a 64-bit signed integer",
+                    incr_request.delta, value);
+            },
+            Err(e) => resp.error = e
+        }
+        resp.encode_to_vec()
+    }
+
+    /// Parses a TRANSACTION or EXEC request, returning the keyspace it runs
+    /// on and its operations, or the response rejecting it
+    fn transaction_ops(&self, binary_req: &[u8], session_store: &str)
+            -> Result<(Arc<Keyspace>, Vec<TxnOperation>), TransactionResp> {
+        let txn_request = match parse_transaction_request(binary_req) {
+            Ok(v) => v,
+            Err(e) => {
+                warn!("Parse error: {:?}", e);
+                return Err(TransactionResp {
+                    error: e.to_string(),
+                    ..Default::default()
+                });
+            }
+        };
+        if txn_request.ops.len() > MAX_TXN_OPS {
+            return Err(TransactionResp {
+                error: format!("Transactions may hold at most {} operations", MAX_TXN_OPS),
+                ..Default::default()
+            });
+        }
+        let keyspace = match self.keyspace(&txn_request.store, session_store) {
+            Ok(k) => k,
+            Err(e) => return Err(TransactionResp {
+                error: e,
+                ..Default::default()
+            })
+        };
+        let mut ops = Vec::with_capacity(txn_request.ops.len());
+        for (i, op) in txn_request.ops.into_iter().enumerate() {
+            match txn_operation(op) {
+                Ok(o) => ops.push(o),
+                Err(e) => return Err(TransactionResp {
+                    error: format!("Operation {}: {}", i + 1, e),
+                    ..Default::default()
+                })
+            }
+        }
+        Ok((keyspace, ops))
+    }
+
+    pub fn handle_transaction_request(&self, binary_req: &[u8], session_store: &str) -> Vec<u8> {
+        let (keyspace, ops) = match self.transaction_ops(binary_req, session_store) {
+            Ok(v) => v,
+            Err(resp) => return resp.encode_to_vec()
+        };
+        match keyspace.transaction(ops) {
+            Ok(outcomes) => transaction_resp(outcomes),
+            Err(e) => TransactionResp {
+                error: e,
+                ..Default::default()
+            }
+        }.encode_to_vec()
+    }
+
+    /// Watches keys for the connection's next EXEC. Watching keys in another
+    /// store than the ones already watched fails.
+    pub fn handle_watch_request(&self, binary_req: &[u8], session: &mut Session) -> Vec<u8> {
+        let watch_request = match parse_watch_request(binary_req) {
+            Ok(v) => v,
+            Err(e) => {
+                warn!("Parse error: {:?}", e);
+                return WatchResp {
+                    error: e.to_string(),
+                    ..Default::default()
+                }.encode_to_vec();
+            }
+        };
+        let keyspace = match self.keyspace(&watch_request.store, &session.store_) {
+            Ok(k) => k,
+            Err(e) => return WatchResp {
+                error: e,
+                ..Default::default()
+            }.encode_to_vec()
+        };
+        let watched = session.watched_.get_or_insert_with(|| Watched {
+            keyspace: keyspace.clone(),
+            keys: HashMap::new()
+        });
+        if !Arc::ptr_eq(&watched.keyspace, &keyspace) {
+            return WatchResp {
+                error: format!("Already watching keys in store {:?}", watched.keyspace.name()),
+                watched: watched.keys.len() as u32,
+                ..Default::default()
+            }.encode_to_vec();
+        }
+        let new_keys = watch_request.keys.iter()
+            .filter(|k| !watched.keys.contains_key(k.as_slice()))
+            .collect::<HashSet<_>>();
+        if watched.keys.len() + new_keys.len() > MAX_WATCHED_KEYS {
+            return WatchResp {
+                error: format!("A connection may watch at most {} keys", MAX_WATCHED_KEYS),
+                watched: watched.keys.len() as u32,
+                ..Default::default()
+            }.encode_to_vec();
+        }
+        for key in new_keys {
+            match keyspace.watch(key) {
+                Ok(stamp) => {
+                    watched.keys.insert(key.clone(), stamp);
+                },
+                Err(e) => return WatchResp {
+                    error: e,
+                    watched: watched.keys.len() as u32,
+                    ..Default::default()
+                }.encode_to_vec()
+            }
+        }
+        WatchResp {
+            success: true,
+            error: String::new(),
+            watched: watched.keys.len() as u32
+        }.encode_to_vec()
+    }
+
+    pub fn handle_unwatch_request(&self, binary_req: &[u8], session: &mut Session) -> Vec<u8> {
+        if let Err(e) = parse_unwatch_request(binary_req) {
+            warn!("Parse error: {:?}", e);
+        }
+        let watched = session.watched_.take();
+        UnwatchResp {
+            unwatched: watched.map_or(0, |w| w.keys.len() as u32)
+        }.encode_to_vec()
+    }
+
+    /// Runs a transaction if none of the keys the connection watches
+    /// changed, checking them along with the transaction's own conditions,
+    /// and stops watching them
+    pub fn handle_exec_request(&self, binary_req: &[u8], session: &mut Session) -> Vec<u8> {
+        let watched = session.watched_.take();
+        let (keyspace, ops) = match self.transaction_ops(binary_req, &session.store_) {
+            Ok(v) => v,
+            Err(resp) => return resp.encode_to_vec()
+        };
+        let mut watched_keys = Vec::new();
+        let mut checks = Vec::new();
+        if let Some(w) = &watched {
+            if !Arc::ptr_eq(&w.keyspace, &keyspace) {
+                // The store was dropped and made again since the watch
+                // started, in which case every watched key changed
+                if w.keyspace.name() == keyspace.name() {
+                    let mut resp = transaction_resp(
+                        ops.iter().map(|_| TxnOutcome::NotRun).collect());
+                    resp.conflict = true;
+                    return resp.encode_to_vec();
+                }
+                return TransactionResp {
+                    error: format!("Watched keys are in store {:?}", w.keyspace.name()),
+                    ..Default::default()
+                }.encode_to_vec();
+            }
+            for (key, stamp) in &w.keys {
+                checks.push(TxnOperation::Check(key.clone(), Condition::Unchanged(*stamp)));
+                watched_keys.push(key.clone());
+            }
+        }
+        checks.extend(ops);
+        let mut outcomes = match keyspace.transaction(checks) {
+            Ok(o) => o,
+            Err(e) => return TransactionResp {
+                error: e,
+                ..Default::default()
+            }.encode_to_vec()
+        };
+        // Only stop watching once the checks are done
+        drop(watched);
+        let ops_outcomes = outcomes.split_off(watched_keys.len());
+        let changed = outcomes.iter().position(|o| matches!(o, TxnOutcome::Failed(_)));
+        let mut resp = transaction_resp(ops_outcomes);
+        if let Some(i) = changed {
+            resp.conflict = true;
+            resp.conflicting_key = Some(watched_keys.swap_remove(i));
+        }
+        resp.encode_to_vec()
+    }
+
+    pub fn handle_history_request(&self, binary_req: &[u8], session_store: &str) -> Vec<u8> {
+        let history_request = match parse_history_request(binary_req) {
+            Ok(v) => v,
+            Err(e) => {
+                warn!("Parse error: {:?}", e);
+                return HistoryResp {
+                    error: e.to_string(),
+                    ..Default::default()
+                }.encode_to_vec();
+            }
+        };
+        let keyspace = match self.keyspace(&history_request.store, session_store) {
+            Ok(k) => k,
+            Err(e) => return HistoryResp {
+                error: e,
+                ..Default::default()
+            }.encode_to_vec()
+        };
+        let limit = history_request.limit.map_or(MAX_HISTORY_PAGE, |l| l as usize)
+            .min(MAX_HISTORY_PAGE);
+        match keyspace.history(&history_request.key, limit) {
+            Ok(revisions) => HistoryResp {
+                revisions: revisions.iter().map(|r| KeyRevision {
+                    version: r.version,
+                    written_at_ms: r.written_at,
+                    expires_at_ms: r.expires_at,
+                    pair: r.pair.as_ref().map(kvp_rust_to_generic_kvp)
+                }).collect(),
+                error: String::new()
+            },
+            Err(e) => HistoryResp {
+                error: e,
+                ..Default::default()
+            }
+        }.encode_to_vec()
+    }
+
+    pub fn handle_delete_request(&self, binary_req: &[u8], session_store: &str) -> Vec<u8> {
+        let delete_request = match parse_delete_request(binary_req) {
+            Ok(v) => v,
+            Err(e) => {
+                warn!("Parse error: {:?}", e);
+                return DeleteKvPairResp::default().encode_to_vec();
+            }
+        };
+        let keyspace = match self.keyspace(&delete_request.store, session_store) {
+            Ok(k) => k,
+            Err(e) => return DeleteKvPairResp {
+                success: false,
+                error: e
+            }.encode_to_vec()
+        };
+        match keyspace.delete_value(&delete_request.key) {
+            Ok(success) => DeleteKvPairResp {
+                success,
+                error: String::new()
+            },
+            Err(e) => DeleteKvPairResp {
+                success: false,
+                error: e
+            }
+        }.encode_to_vec()
+    }
+
+    pub fn handle_mget_request(&self, binary_req: &[u8], session_store: &str) -> Vec<u8> {
+        let mget_request = match parse_mget_request(binary_req) {
+            Ok(v) => v,
+            Err(e) => {
+                warn!("Parse error: {:?}", e);
+                return MGetResp {
+                    error: e.to_string(),
+                    ..Default::default()
+                }.encode_to_vec();
+            }
+        };
+        if let Err(e) = check_batch_size(mget_request.keys.len()) {
+            return MGetResp { error: e, ..Default::default() }.encode_to_vec();
+        }
+        let keyspace = match self.keyspace(&mget_request.store, session_store) {
+            Ok(k) => k,
+            Err(e) => return MGetResp { error: e, ..Default::default() }.encode_to_vec()
+        };
+        let mut results = Vec::with_capacity(mget_request.keys.len());
+        for key in &mget_request.keys {
+            match keyspace.get_value(key) {
+                Ok(found) => results.push(match found {
+                    None => MGetResult::default(),
+                    Some((x, version)) => MGetResult {
+                        pair: Some(kvp_rust_to_generic_kvp(&x)),
+                        version
+                    }
+                }),
+                Err(e) => return MGetResp { error: e, ..Default::default() }.encode_to_vec()
+            }
+        }
+        MGetResp {
+            error: String::new(),
+            results
+        }.encode_to_vec()
+    }
+
+    pub fn handle_mset_request(&self, binary_req: &[u8], session_store: &str) -> Vec<u8> {
+        let mset_request = match parse_mset_request(binary_req) {
+            Ok(v) => v,
+            Err(e) => {
+                warn!("Parse error: {:?}", e);
+                return MSetResp {
+                    error: e.to_string(),
+                    ..Default::default()
+                }.encode_to_vec();
+            }
+        };
+        if let Err(e) = check_batch_size(mset_request.pairs.len()) {
+            return MSetResp { error: e, ..Default::default() }.encode_to_vec();
+        }
+        let keyspace = match self.keyspace(&mset_request.store, session_store) {
+            Ok(k) => k,
+            Err(e) => return MSetResp { error: e, ..Default::default() }.encode_to_vec()
+        };
+        let ttl_ms = mset_request.ttl_ms;
+        MSetResp {
+            error: String::new(),
+            results: mset_request.pairs.into_iter().map(|p| {
+                match generic_kvp_to_kvp_rust(p).and_then(|x| keyspace.set_value(x, ttl_ms)) {
+                    Ok(version) => MSetResult {
+                        success: true,
+                        error: String::new(),
+                        version
+                    },
+                    Err(e) => MSetResult {
+                        error: e,
+                        ..Default::default()
+                    }
+                }
+            }).collect()
+        }.encode_to_vec()
+    }
+
+    pub fn handle_mdel_request(&self, binary_req: &[u8], session_store: &str) -> Vec<u8> {
+        let mdel_request = match parse_mdel_request(binary_req) {
+            Ok(v) => v,
+            Err(e) => {
+                warn!("Parse error: {:?}", e);
+                return MDelResp {
+                    error: e.to_string(),
+                    ..Default::default()
+                }.encode_to_vec();
+            }
+        };
+        if let Err(e) = check_batch_size(mdel_request.keys.len()) {
+            return MDelResp { error: e, ..Default::default() }.encode_to_vec();
+        }
+        let keyspace = match self.keyspace(&mdel_request.store, session_store) {
+            Ok(k) => k,
+            Err(e) => return MDelResp { error: e, ..Default::default() }.encode_to_vec()
+        };
+        MDelResp {
+            error: String::new(),
+            results: mdel_request.keys.iter().map(|key| match keyspace.delete_value(key) {
+                Ok(success) => MDelResult {
+                    success,
+                    error: String::new()
+                },
+                Err(e) => MDelResult {
+                    success: false,
+                    error: e
+                }
+            }).collect()
+        }.encode_to_vec()
+    }
+
+    pub fn handle_backup_request(&self, binary_req: &[u8], session_store: &str) -> Vec<u8> {
+        let backup_request: BackupReq;
+        match parse_backup_request(binary_req) {
+            Ok(v) => {
+                backup_request = v;
+            },
+            Err(e) => {
+                warn!("Parse error: {:?}", e);
+                return BackupResp {
+                    success: false,
+                    error: e.to_string(),
+                    job_id: 0
+                }.encode_to_vec()
+            }
+        }
+        if let Err(e) = check_requested_backup_id(&backup_request.backup_id) {
+            return BackupResp {
+                success: false,
+                error: e,
+                job_id: 0
+            }.encode_to_vec();
+        }
+        let keyspace = match self.keyspace(&backup_request.store, session_store) {
+            Ok(k) => k,
+            Err(e) => return BackupResp {
+                success: false,
+                error: e,
+                job_id: 0
+            }.encode_to_vec()
+        };
+        if let Err(e) = self.check_backup_target(&backup_request.backup_id) {
+            return BackupResp {
+                success: false,
+                error: e,
+                job_id: 0
+            }.encode_to_vec();
+        }
+        let write = match self.backup_writer(&backup_request, &keyspace) {
+            Ok(w) => w,
+            Err(e) => return BackupResp {
+                success: false,
+                error: e,
+                job_id: 0
+            }.encode_to_vec()
+        };
+        let reads = match &backup_request.base_backup_id {
+            Some(base_id) => self.backup_catalog_.chain(base_id),
+            None => Vec::new()
+        };
+        match self.backup_jobs_.start(keyspace.name(), &backup_request.backup_id, reads, write,
+                self.backup_catalog_.clone()) {
+            Ok(job) => BackupResp {
+                success: true,
+                error: String::new(),
+                job_id: job.id()
+            }.encode_to_vec(),
+            Err(e) => BackupResp {
+                success: false,
+                error: e,
+                job_id: 0
+            }.encode_to_vec()
+        }
+    }
+
+    /// Refuses to write over a backup that others are based on, since they
+    /// would then hold the changes since something else, or that a running
+    /// backup reads
+    fn check_backup_target(&self, backup_id: &str) -> Result<(), String> {
+        self.check_not_read(backup_id)?;
+        let dependents = self.backup_catalog_.dependents(backup_id);
+        if !dependents.is_empty() {
+            return Err(format!("Backups are based on {}: {}", backup_id, dependents.join(", ")));
+        }
+        Ok(())
+    }
+
+    /// Refuses to change a backup that a running backup reads, e.g. as part
+    /// of the chain it is based on
+    fn check_not_read(&self, backup_id: &str) -> Result<(), String> {
+        if self.backup_jobs_.is_reading(backup_id) {
+            return Err(format!("A running backup reads {}", backup_id));
+        }
+        Ok(())
+    }
+
+    /// Freezes the store for the backup `request` asks for, and returns how
+    /// to write it: in full, or as the changes since its base backup
+    fn backup_writer(&self, request: &BackupReq, keyspace: &Keyspace)
+            -> Result<WriteBackup, String> {
+        let base_id = match &request.base_backup_id {
+            Some(b) => b.clone(),
+            None => {
+                let frozen = keyspace.freeze().map_err(|e| e.to_string())?;
+                return Ok(Box::new(move |path, progress| frozen.write_to_file(path, progress)));
+            }
+        };
+        check_requested_backup_id(&base_id)?;
+        if base_id == request.backup_id {
+            return Err(String::from("A backup cannot be based on itself"));
+        }
+        let base = match self.backup_catalog_.get(&base_id) {
+            Some(b) => b,
+            None => return Err(String::from("No such base backup"))
+        };
+        if base.store != keyspace.name() {
+            return Err(format!("The base backup is of store {}", base.store));
+        }
+        if self.backup_jobs_.is_writing(&base_id) {
+            return Err(String::from("The base backup is being written"));
+        }
+        let frozen = keyspace.freeze().map_err(|e| e.to_string())?;
+        let catalog = self.backup_catalog_.clone();
+        Ok(Box::new(move |path, progress| {
+            // Read in the background too, since the chain may be long
+            let base = filestore::read_backup_chain(&catalog.path(&base_id),
+                &|id| catalog.resolve(id))?;
+            frozen.write_incremental_to_file(path, &base_id, &base, progress)
+        }))
+    }
+
+    pub fn handle_backup_status_request(&self, binary_req: &[u8]) -> Vec<u8> {
+        let status_request = match parse_backup_status_request(binary_req) {
+            Ok(v) => v,
+            Err(e) => {
+                warn!("Parse error: {:?}", e);
+                return BackupStatusResp {
+                    success: false,
+                    error: e.to_string(),
+                    ..Default::default()
+                }.encode_to_vec()
+            }
+        };
+        match self.backup_jobs_.get(status_request.job_id) {
+            Some(job) => job.status().encode_to_vec(),
+            None => BackupStatusResp {
+                success: false,
+                error: String::from("No such backup"),
+                ..Default::default()
+            }.encode_to_vec()
+        }
+    }
+
+    pub fn handle_list_backups_request(&self, binary_req: &[u8]) -> Vec<u8> {
+        if let Err(e) = parse_list_backups_request(binary_req) {
+            warn!("Parse error: {:?}", e);
+            return ListBackupsResp::default().encode_to_vec();
+        }
+        ListBackupsResp {
+            backups: self.backup_catalog_.list()
+        }.encode_to_vec()
+    }
+
+    /// Describes a backup in the catalog from its file, which may have
+    /// changed since it was written
+    pub fn handle_backup_info_request(&self, binary_req: &[u8]) -> Vec<u8> {
+        let info_request = match parse_backup_info_request(binary_req) {
+            Ok(v) => v,
+            Err(e) => {
+                warn!("Parse error: {:?}", e);
+                return BackupInfoResp {
+                    success: false,
+                    error: e.to_string(),
+                    info: None
+                }.encode_to_vec()
+            }
+        };
+        if let Err(e) = check_requested_backup_id(&info_request.backup_id) {
+            return BackupInfoResp {
+                success: false,
+                error: e,
+                info: None
+            }.encode_to_vec();
+        }
+        let backup = match self.backup_catalog_.get(&info_request.backup_id) {
+            Some(b) => b,
+            None => return BackupInfoResp {
+                success: false,
+                error: String::from(NO_SUCH_BACKUP),
+                info: None
+            }.encode_to_vec()
+        };
+        match filestore::read_backup_info(&self.backup_catalog_.path(&backup.backup_id)) {
+            Ok(file) => BackupInfoResp {
+                success: true,
+                error: String::new(),
+                info: Some(BackupInfo {
+                    store: file.name,
+                    record_count: file.record_count,
+                    size_bytes: file.size_bytes,
+                    checksum: file.checksum,
+                    base_backup_id: file.base.unwrap_or_default(),
+                    ..backup
+                })
+            }.encode_to_vec(),
+            Err(e) => BackupInfoResp {
+                success: false,
+                error: format!("Cannot read backup: {}", e),
+                info: Some(backup)
+            }.encode_to_vec()
+        }
+    }
+
+    pub fn handle_delete_backup_request(&self, binary_req: &[u8]) -> Vec<u8> {
+        let delete_request = match parse_delete_backup_request(binary_req) {
+            Ok(v) => v,
+            Err(e) => {
+                warn!("Parse error: {:?}", e);
+                return DeleteBackupResp {
+                    success: false,
+                    error: e.to_string()
+                }.encode_to_vec()
+            }
+        };
+        if let Err(e) = check_requested_backup_id(&delete_request.backup_id) {
+            return DeleteBackupResp {
+                success: false,
+                error: e
+            }.encode_to_vec();
+        }
+        if self.backup_jobs_.is_writing(&delete_request.backup_id) {
+            return DeleteBackupResp {
+                success: false,
+                error: String::from("The backup is being written")
+            }.encode_to_vec();
+        }
+        if let Err(e) = self.check_backup_target(&delete_request.backup_id) {
+            return DeleteBackupResp {
+                success: false,
+                error: e
+            }.encode_to_vec();
+        }
+        match self.backup_catalog_.delete(&delete_request.backup_id) {
+            Ok(true) => {
+                info!("Deleted backup {:?}", delete_request.backup_id);
+                DeleteBackupResp {
+                    success: true,
+                    error: String::new()
+                }.encode_to_vec()
+            },
+            Ok(false) => DeleteBackupResp {
+                success: false,
+                error: String::from(NO_SUCH_BACKUP)
+            }.encode_to_vec(),
+            Err(e) => {
+                error!("Cannot delete backup {:?}: {:?}", delete_request.backup_id, e);
+                DeleteBackupResp {
+                    success: false,
+                    error: e.to_string()
+                }.encode_to_vec()
+            }
+        }
+    }
+
+    /// Merges a backup and its chain into a full backup in the background
+    pub fn handle_compact_backup_request(&self, binary_req: &[u8]) -> Vec<u8> {
+        let compact_request = match parse_compact_backup_request(binary_req) {
+            Ok(v) => v,
+            Err(e) => {
+                warn!("Parse error: {:?}", e);
+                return CompactBackupResp {
+                    success: false,
+                    error: e.to_string(),
+                    job_id: 0
+                }.encode_to_vec()
+            }
+        };
+        let source_id = compact_request.backup_id;
+        let target_id = compact_request.target_backup_id;
+        if let Err(e) = check_requested_backup_id(&source_id)
+                .and_then(|_| check_requested_backup_id(&target_id)) {
+            return CompactBackupResp {
+                success: false,
+                error: e,
+                job_id: 0
+            }.encode_to_vec();
+        }
+        let source = match self.backup_catalog_.get(&source_id) {
+            Some(b) => b,
+            None => return CompactBackupResp {
+                success: false,
+                error: String::from(NO_SUCH_BACKUP),
+                job_id: 0
+            }.encode_to_vec()
+        };
+        // Compacting a backup into itself leaves what it holds unchanged
+        let checked = if target_id == source_id {
+            self.check_not_read(&target_id)
+        } else {
+            self.check_backup_target(&target_id)
+        };
+        if let Err(e) = checked {
+            return CompactBackupResp {
+                success: false,
+                error: e,
+                job_id: 0
+            }.encode_to_vec();
+        }
+        if self.backup_jobs_.is_writing(&source_id) {
+            return CompactBackupResp {
+                success: false,
+                error: String::from("The backup is being written"),
+                job_id: 0
+            }.encode_to_vec();
+        }
+        let reads = self.backup_catalog_.chain(&source_id);
+        let catalog = self.backup_catalog_.clone();
+        let write: WriteBackup = Box::new(move |path, progress| {
+            let store = filestore::read_backup_chain(&catalog.path(&source_id),
+                &|id| catalog.resolve(id))?;
+            filestore::write_entries_to_file(path, store.name(), store.len() as u64,
+                store.snapshot(), progress)
+        });
+        match self.backup_jobs_.start(&source.store, &target_id, reads, write,
+                self.backup_catalog_.clone()) {
+            Ok(job) => CompactBackupResp {
+                success: true,
+                error: String::new(),
+                job_id: job.id()
+            }.encode_to_vec(),
+            Err(e) => CompactBackupResp {
+                success: false,
+                error: e,
+                job_id: 0
+            }.encode_to_vec()
+        }
+    }
+
+    /// Exports the store to a text file from a frozen copy, so that the
+    /// store stays usable while the file is written
+    pub fn handle_export_request(&self, binary_req: &[u8], session_store: &str) -> Vec<u8> {
+        let export_request = match parse_export_request(binary_req) {
+            Ok(v) => v,
             Err(e) => {
                 warn!("Parse error: {:?}", e);
-                return vec![];
+                return ExportResp {
+                    success: false,
+                    error: e.to_string(),
+                    record_count: 0
+                }.encode_to_vec()
             }
         };
-        let message: String = ping_request.ping_message;
-        info!("Received ping: {:?}", message);
-        let resp = message.clone() + " acked by server";
-        let ping_resp = PingResponse {
-            ping_resp_message: resp
+        if let Err(e) = check_requested_export_name(&export_request.file_name) {
+            return ExportResp {
+                success: false,
+                error: e,
+                record_count: 0
+            }.encode_to_vec();
+        }
+        let keyspace = match self.keyspace(&export_request.store, session_store) {
+            Ok(k) => k,
+            Err(e) => return ExportResp {
+                success: false,
+                error: e,
+                record_count: 0
+            }.encode_to_vec()
         };
-        return ping_resp.encode_to_vec(); 
-    }
-
-    fn add_value(&self, pair: KeyValuePair) -> bool {
-        let mut store = self.kvs_access_.write().unwrap();
-        let success = (*store).add(kvp_proto_to_kvp_rust(pair));
-        if success {
-            info!("Successfully added pair!");
-        } else {
-            info!("Did not add pair!");
+        let format = match export_request.format() {
+            ExportFormat::JsonLines => TextFormat::JsonLines,
+            ExportFormat::Csv => TextFormat::Csv
+        };
+        let dir = self.options_.export_dir();
+        let path = format!("{}/{}", dir, export_request.file_name);
+        let result = std::fs::create_dir_all(dir)
+            .map_err(|e| RWError {
+                kind_: ErrorKind::FileOpenError,
+                context_: e.to_string()
+            })
+            .and_then(|_| keyspace.freeze())
+            .and_then(|frozen| frozen.export_to_file(&path, format));
+        match result {
+            Ok(count) => {
+                info!("Exported {:?} records of {:?} to {:?}", count, keyspace.name(), path);
+                ExportResp {
+                    success: true,
+                    error: String::new(),
+                    record_count: count
+                }.encode_to_vec()
+            },
+            Err(e) => {
+                error!("Cannot export {:?} to {:?}: {:?}", keyspace.name(), path, e);
+                ExportResp {
+                    success: false,
+                    error: e.to_string(),
+                    record_count: 0
+                }.encode_to_vec()
+            }
         }
-        return success;
     }
 
-    fn get_value(&self, key: &str) -> Option<String> {
-        let store = self.kvs_access_.read().unwrap();
-        let val = (*store).get(key);
-        match val {
-            None => None,
-            Some(kvp) => Some(kvp.value().to_string())
+    /// Imports a text file into the store. The whole file is read before
+    /// the store is locked.
+    pub fn handle_import_request(&self, binary_req: &[u8], session_store: &str) -> Vec<u8> {
+        let import_request = match parse_import_request(binary_req) {
+            Ok(v) => v,
+            Err(e) => {
+                warn!("Parse error: {:?}", e);
+                return ImportResp {
+                    success: false,
+                    error: e.to_string(),
+                    ..Default::default()
+                }.encode_to_vec()
+            }
+        };
+        if let Err(e) = check_requested_export_name(&import_request.file_name) {
+            return ImportResp {
+                success: false,
+                error: e,
+                ..Default::default()
+            }.encode_to_vec();
         }
+        let keyspace = match self.keyspace(&import_request.store, session_store) {
+            Ok(k) => k,
+            Err(e) => return ImportResp {
+                success: false,
+                error: e,
+                ..Default::default()
+            }.encode_to_vec()
+        };
+        let options = ImportOptions {
+            format: match import_request.format() {
+                ExportFormat::JsonLines => TextFormat::JsonLines,
+                ExportFormat::Csv => TextFormat::Csv
+            },
+            on_conflict: match import_request.on_conflict() {
+                ImportConflict::Overwrite => OnConflict::Overwrite,
+                ImportConflict::Skip => OnConflict::Skip,
+                ImportConflict::Fail => OnConflict::Fail
+            },
+            max_errors: import_request.max_errors
+        };
+        let path = format!("{}/{}", self.options_.export_dir(), import_request.file_name);
+        let parsed = match filestore::read_text_file(&path, options.format) {
+            Ok(p) => p,
+            Err(e) => return ImportResp {
+                success: false,
+                error: format!("Cannot read import file: {}", e),
+                ..Default::default()
+            }.encode_to_vec()
+        };
+        let report = match keyspace.import(parsed, &options) {
+            Ok(r) => r,
+            Err(e) => return ImportResp {
+                success: false,
+                error: e,
+                ..Default::default()
+            }.encode_to_vec()
+        };
+        info!("Imported {:?} into {:?}: {:?} added, {:?} overwritten, {:?} lines failed{}",
+            path, keyspace.name(), report.added, report.overwritten, report.errors.len(),
+            if report.abandoned { ", abandoned" } else { "" });
+        ImportResp {
+            success: !report.abandoned,
+            error: if report.abandoned {
+                String::from("Too many lines failed, nothing was imported")
+            } else {
+                String::new()
+            },
+            added: report.added,
+            overwritten: report.overwritten,
+            skipped: report.skipped,
+            expired: report.expired,
+            error_count: report.errors.len() as u64,
+            line_errors: report.errors.into_iter()
+                .take(MAX_REPORTED_LINE_ERRORS)
+                .map(|e| ImportLineError {
+                    line: e.line,
+                    message: e.message
+                })
+                .collect()
+        }.encode_to_vec()
     }
 
-    fn update_value(&self, pair: KeyValuePair) -> bool {
-        let mut store = self.kvs_access_.write().unwrap();
-        let res = (*store).update(kvp_proto_to_kvp_rust(pair));
-        return res;
+    pub fn handle_restore_request(&self, binary_req: &[u8], session_store: &str) -> Vec<u8> {
+        let restore_request: RestoreReq;
+        match parse_restore_request(binary_req) {
+            Ok(v) => {
+                restore_request = v;
+            },
+            Err(e) => {
+                warn!("Parse error: {:?}", e);
+                return RestoreResp::default().encode_to_vec()
+            }
+        }
+        let keyspace = match self.keyspace(&restore_request.store, session_store) {
+            Ok(k) => k,
+            Err(e) => return RestoreResp {
+                success: false,
+                error: e,
+                ..Default::default()
+            }.encode_to_vec()
+        };
+        if let Err(e) = check_requested_backup_id(&restore_request.backup_id) {
+            return RestoreResp {
+                success: false,
+                error: e,
+                ..Default::default()
+            }.encode_to_vec();
+        }
+        let options = RestoreOptions {
+            mode: match restore_request.mode() {
+                RestoreMode::Replace => restore::RestoreMode::Replace,
+                RestoreMode::BackupWins => restore::RestoreMode::MergeBackupWins,
+                RestoreMode::LiveWins => restore::RestoreMode::MergeLiveWins
+            },
+            prefix: restore_request.prefix
+        };
+        let catalog = &self.backup_catalog_;
+        match keyspace.restore(&catalog.path(&restore_request.backup_id),
+                &|id| catalog.resolve(id), &options, restore_request.dry_run) {
+            Ok(counts) => RestoreResp {
+                success: true,
+                error: String::new(),
+                added: counts.added,
+                overwritten: counts.overwritten,
+                removed: counts.removed,
+                dry_run: restore_request.dry_run
+            }.encode_to_vec(),
+            Err(e) => RestoreResp {
+                success: false,
+                error: e,
+                ..Default::default()
+            }.encode_to_vec()
+        }
     }
 
-    fn delete_value(&self, key: &str) -> bool {
-        let mut store = self.kvs_access_.write().unwrap();
-        (*store).delete(key)
+    pub fn handle_get_ttl_request(&self, binary_req: &[u8], session_store: &str) -> Vec<u8> {
+        let get_ttl_request = match parse_get_ttl_request(binary_req) {
+            Ok(v) => v,
+            Err(e) => {
+                warn!("Parse error: {:?}", e);
+                return GetTtlResp::default().encode_to_vec();
+            }
+        };
+        let keyspace = match self.keyspace(&get_ttl_request.store, session_store) {
+            Ok(k) => k,
+            Err(e) => return GetTtlResp {
+                error: e,
+                ..Default::default()
+            }.encode_to_vec()
+        };
+        match keyspace.get_ttl(&get_ttl_request.key) {
+            Ok(None) => GetTtlResp::default().encode_to_vec(),
+            Err(e) => GetTtlResp {
+                error: e,
+                ..Default::default()
+            }.encode_to_vec(),
+            Ok(Some(ttl)) => GetTtlResp {
+                success: true,
+                ttl_ms: ttl,
+                error: String::new()
+            }.encode_to_vec()
+        }
     }
 
-    fn backup_key_value_store(&self, backup_id: &str) -> bool {
-        let store = self.kvs_access_.write().unwrap();
-        match store.write_to_file(backup_id) {
-            Ok(_) => return true,
+    pub fn handle_set_ttl_request(&self, binary_req: &[u8], session_store: &str) -> Vec<u8> {
+        let set_ttl_request = match parse_set_ttl_request(binary_req) {
+            Ok(v) => v,
             Err(e) => {
-                error!("Inner error in backup: {:?}", e.to_string());
-                return false;
+                warn!("Parse error: {:?}", e);
+                return SetTtlResp::default().encode_to_vec();
             }
         };
+        let keyspace = match self.keyspace(&set_ttl_request.store, session_store) {
+            Ok(k) => k,
+            Err(e) => return SetTtlResp {
+                success: false,
+                error: e
+            }.encode_to_vec()
+        };
+        match keyspace.set_ttl(&set_ttl_request.key, Some(set_ttl_request.ttl_ms)) {
+            Ok(success) => SetTtlResp {
+                success,
+                error: String::new()
+            },
+            Err(e) => SetTtlResp {
+                success: false,
+                error: e
+            }
+        }.encode_to_vec()
     }
 
-    fn restore_key_value_store(&self, backup_id: &str) -> bool {
-        let mut store = self.kvs_access_.write().unwrap();
-        match store.read_from_file(backup_id) {
-            Ok(_) => return true,
+    pub fn handle_remove_ttl_request(&self, binary_req: &[u8], session_store: &str) -> Vec<u8> {
+        let remove_ttl_request = match parse_remove_ttl_request(binary_req) {
+            Ok(v) => v,
             Err(e) => {
-                error!("Inner error in restore: {:?}", e.to_string());
-                return false;
+                warn!("Parse error: {:?}", e);
+                return RemoveTtlResp::default().encode_to_vec();
             }
-        }
+        };
+        let keyspace = match self.keyspace(&remove_ttl_request.store, session_store) {
+            Ok(k) => k,
+            Err(e) => return RemoveTtlResp {
+                success: false,
+                error: e
+            }.encode_to_vec()
+        };
+        match keyspace.set_ttl(&remove_ttl_request.key, None) {
+            Ok(success) => RemoveTtlResp {
+                success,
+                error: String::new()
+            },
+            Err(e) => RemoveTtlResp {
+                success: false,
+                error: e
+            }
+        }.encode_to_vec()
     }
 
-    pub fn handle_create_request(&self, binary_req: &[u8]) -> Vec<u8> {
-        let create_request: CreateKvPairReq;
-        match parse_create_request(binary_req) {
-            Ok(v) => { create_request = v; },
+    pub fn handle_stats_request(&self, binary_req: &[u8], session_store: &str) -> Vec<u8> {
+        let stats_request = match parse_stats_request(binary_req) {
+            Ok(v) => v,
             Err(e) => {
                 warn!("Parse error: {:?}", e);
-                return invalid_create_resp().encode_to_vec();
+                return StatsResp::default().encode_to_vec();
             }
-        }
-        if create_request.pair == None {
-            warn!("No pair to insert");
-            return invalid_create_resp().encode_to_vec();
-        }
-        let insertable_pair;
-        match create_request.pair {
-            None => return invalid_create_resp().encode_to_vec(),
-            Some(x) => { insertable_pair = x; }
-        }
-        info!("Got key: {:?}", insertable_pair.key.as_str());
-        info!("Got value: {:?}", insertable_pair.value.as_str());
-
-        let success = self.add_value(insertable_pair);
-        let resp = CreateKvPairResp {
-            success: success
         };
-        return resp.encode_to_vec();
+        match self.keyspace(&stats_request.store, session_store) {
+            Ok(k) => k.stats().encode_to_vec(),
+            Err(e) => StatsResp {
+                error: e,
+                ..Default::default()
+            }.encode_to_vec()
+        }
     }
 
-
-    pub fn handle_read_request(&self, binary_req: &[u8]) -> Vec<u8> {
-        let read_request: ReadKvPairReq;
-        match parse_read_request(binary_req) {
-            Ok(v) => { read_request = v; },
+    pub fn handle_scan_range_request(&self, binary_req: &[u8], session_store: &str) -> Vec<u8> {
+        let scan_request = match parse_scan_range_request(binary_req) {
+            Ok(v) => v,
             Err(e) => {
                 warn!("Parse error: {:?}", e);
-                return ReadKvPairResp {
-                    success: false,
-                    pair: None
-                }.encode_to_vec();
+                return ScanRangeResp::default().encode_to_vec();
             }
-        }
-        let key = read_request.key;
-        match self.get_value(&key) {
-            None => ReadKvPairResp {
-                    success: false,
-                    pair: None
-                }.encode_to_vec(),
-            Some(x) => ReadKvPairResp {
-                    success: true,
-                    pair: Some(KeyValuePair {
-                        key: key,
-                        value: x
-                    })
-                }.encode_to_vec()
-        }
+        };
+        let keyspace = match self.keyspace(&scan_request.store, session_store) {
+            Ok(k) => k,
+            Err(e) => return ScanRangeResp {
+                error: e,
+                ..Default::default()
+            }.encode_to_vec()
+        };
+        let start = scan_bound(&scan_request.start, scan_request.start_exclusive);
+        let end = scan_bound(&scan_request.end, scan_request.end_exclusive);
+        let limit = (scan_request.limit as usize).min(MAX_SCAN_PAGE);
+        // Fetch one extra pair to find out whether there are more
+        let mut pairs = match keyspace.scan_range(start, end, limit + 1, scan_request.reverse) {
+            Ok(p) => p,
+            Err(e) => return ScanRangeResp {
+                error: e,
+                ..Default::default()
+            }.encode_to_vec()
+        };
+        let more = pairs.len() > limit;
+        pairs.truncate(limit);
+        ScanRangeResp {
+            success: true,
+            pairs: pairs.iter().map(kvp_rust_to_generic_kvp).collect(),
+            more,
+            error: String::new()
+        }.encode_to_vec()
     }
 
-    pub fn handle_update_request(&self, binary_req: &[u8]) -> Vec<u8> {
-        let update_request: UpdateKvPairReq;
-        match parse_update_request(binary_req) {
-            Ok(v) => { update_request = v; },
+    pub fn handle_scan_request(&self, binary_req: &[u8], session_store: &str) -> Vec<u8> {
+        let scan_request = match parse_scan_request(binary_req) {
+            Ok(v) => v,
             Err(e) => {
                 warn!("Parse error: {:?}", e);
-                return UpdateKvPairResp {
-                    success: false
-                }.encode_to_vec()
+                return ScanResp::default().encode_to_vec();
             }
-        }
-        let mut resp = UpdateKvPairResp::default();
-        match update_request.pair {
-            Some(x) => {
-                let success = self.update_value(x);
-                resp.success = success;
+        };
+        let keyspace = match self.keyspace(&scan_request.store, session_store) {
+            Ok(k) => k,
+            Err(e) => return ScanResp {
+                success: false,
+                error: e,
+                ..Default::default()
+            }.encode_to_vec()
+        };
+        // The cursor is the last key the previous page looked at, behind a
+        // version byte. Clients are meant to treat it as opaque.
+        let resume_after = match scan_request.cursor.split_first() {
+            None => None,
+            Some((&SCAN_CURSOR_VERSION, key)) => Some(key),
+            Some(_) => return ScanResp {
+                success: false,
+                error: String::from("Invalid cursor"),
+                ..Default::default()
+            }.encode_to_vec()
+        };
+        let pattern = match scan_request.pattern {
+            None => KeyPattern::Prefix(Vec::new()),
+            Some(scan_req::Pattern::Prefix(p)) => KeyPattern::Prefix(p),
+            Some(scan_req::Pattern::Glob(g)) => KeyPattern::Glob(g)
+        };
+        let count = match scan_request.count as usize {
+            0 => DEFAULT_SCAN_COUNT,
+            c => c.min(MAX_SCAN_PAGE)
+        };
+        // Locks are only held for one page; the cursor is enough to carry on
+        // from wherever this page stopped.
+        let page = match keyspace.scan(&pattern, resume_after, count, MAX_SCAN_EXAMINED) {
+            Ok(p) => p,
+            Err(e) => return ScanResp {
+                success: false,
+                error: e,
+                ..Default::default()
+            }.encode_to_vec()
+        };
+        let cursor = match page.resume_after {
+            None => Vec::new(),
+            Some(k) => [&[SCAN_CURSOR_VERSION], k.as_slice()].concat()
+        };
+        ScanResp {
+            success: true,
+            cursor,
+            keys: page.pairs.iter().map(|p| p.key().to_vec()).collect(),
+            pairs: if scan_request.with_values {
+                page.pairs.iter().map(kvp_rust_to_generic_kvp).collect()
+            } else {
+                Vec::new()
             },
-            None => {
-                resp.success = false;
-            }
-        }
-        return resp.encode_to_vec();
+            error: String::new()
+        }.encode_to_vec()
     }
 
-    pub fn handle_delete_request(&self, binary_req: &[u8]) -> Vec<u8> {
-        let delete_request: DeleteKvPairReq;
-        match parse_delete_request(binary_req) {
-            Ok(v) => { 
-                delete_request = v;
-                let key = delete_request.key;
-                let success = self.delete_value(&key);
-                return DeleteKvPairResp {
-                    success: success
-                }.encode_to_vec()
-            },
+    pub fn handle_create_store_request(&self, binary_req: &[u8]) -> Vec<u8> {
+        let create_request = match parse_create_store_request(binary_req) {
+            Ok(v) => v,
             Err(e) => {
                 warn!("Parse error: {:?}", e);
-                return DeleteKvPairResp {
-                    success: false
-                }.encode_to_vec()
+                return CreateStoreResp {
+                    success: false,
+                    error: e.to_string()
+                }.encode_to_vec();
             }
+        };
+        match self.create_store(&create_request.name) {
+            Ok(_) => CreateStoreResp {
+                success: true,
+                error: String::new()
+            }.encode_to_vec(),
+            Err(e) => CreateStoreResp {
+                success: false,
+                error: e
+            }.encode_to_vec()
         }
     }
 
-    pub fn handle_backup_request(&self, binary_req: &[u8]) -> Vec<u8> {
-        let backup_request: BackupReq;
-        match parse_backup_request(binary_req) {
-            Ok(v) => {
-                backup_request = v;
-            },
+    pub fn handle_drop_store_request(&self, binary_req: &[u8]) -> Vec<u8> {
+        let drop_request = match parse_drop_store_request(binary_req) {
+            Ok(v) => v,
             Err(e) => {
                 warn!("Parse error: {:?}", e);
-                return BackupResp {
-                    success: false
-                }.encode_to_vec()
+                return DropStoreResp {
+                    success: false,
+                    error: e.to_string()
+                }.encode_to_vec();
             }
+        };
+        match self.drop_store(&drop_request.name) {
+            Ok(_) => DropStoreResp {
+                success: true,
+                error: String::new()
+            }.encode_to_vec(),
+            Err(e) => DropStoreResp {
+                success: false,
+                error: e
+            }.encode_to_vec()
+        }
+    }
+
+    pub fn handle_list_stores_request(&self, binary_req: &[u8]) -> Vec<u8> {
+        if let Err(e) = parse_list_stores_request(binary_req) {
+            warn!("Parse error: {:?}", e);
+            return ListStoresResp::default().encode_to_vec();
         }
-        let success = self.backup_key_value_store(&backup_request.backup_id);
-        BackupResp {
-            success: success
+        let mut names: Vec<String> =
+            self.keyspaces_.read().unwrap().keys().cloned().collect();
+        names.sort();
+        ListStoresResp {
+            names,
+            default_store: self.default_store_.clone()
         }.encode_to_vec()
     }
 
-    pub fn handle_restore_request(&self, binary_req: &[u8]) -> Vec<u8> {
-        let restore_request: RestoreReq;
-        match parse_restore_request(binary_req) {
-            Ok(v) => {
-                restore_request = v;
-            },
+    /// Makes the requested store the one used by the rest of the connection's
+    /// requests that do not name a store. Only checks that the store exists
+    /// now; if it is dropped later, those requests fail.
+    pub fn handle_select_store_request(&self, binary_req: &[u8],
+            session_store: &mut String) -> Vec<u8> {
+        let select_request = match parse_select_store_request(binary_req) {
+            Ok(v) => v,
             Err(e) => {
                 warn!("Parse error: {:?}", e);
-                return RestoreResp {
-                    success: false
-                }.encode_to_vec()
+                return SelectStoreResp {
+                    success: false,
+                    error: e.to_string()
+                }.encode_to_vec();
             }
+        };
+        if let Err(e) = self.keyspace(&Some(select_request.name.clone()), session_store) {
+            return SelectStoreResp {
+                success: false,
+                error: e
+            }.encode_to_vec();
         }
-        let success = self.restore_key_value_store(&restore_request.backup_id);
-        RestoreResp {
-            success: success
+        *session_store = select_request.name;
+        SelectStoreResp {
+            success: true,
+            error: String::new()
         }.encode_to_vec()
     }
 
+    /// Handles one request and returns the encoded response. `session` is
+    /// what the server keeps for the connection, which some requests, like
+    /// SELECT_STORE and WATCH, change.
+    pub fn handle_request(&self, req_type: ReqType, payload: &[u8],
+            session: &mut Session) -> Vec<u8> {
+        let session_store = &session.store_;
+        match req_type {
+            ReqType::Ping => self.handle_ping_request(payload),
+            ReqType::Create => self.handle_create_request(payload, session_store),
+            ReqType::Read => self.handle_read_request(payload, session_store),
+            ReqType::Update => self.handle_update_request(payload, session_store),
+            ReqType::Delete => self.handle_delete_request(payload, session_store),
+            ReqType::Backup => self.handle_backup_request(payload, session_store),
+            ReqType::Restore => self.handle_restore_request(payload, session_store),
+            ReqType::GetTtl => self.handle_get_ttl_request(payload, session_store),
+            ReqType::SetTtl => self.handle_set_ttl_request(payload, session_store),
+            ReqType::RemoveTtl => self.handle_remove_ttl_request(payload, session_store),
+            ReqType::Stats => self.handle_stats_request(payload, session_store),
+            ReqType::ScanRange => self.handle_scan_range_request(payload, session_store),
+            ReqType::Scan => self.handle_scan_request(payload, session_store),
+            ReqType::CreateStore => self.handle_create_store_request(payload),
+            ReqType::DropStore => self.handle_drop_store_request(payload),
+            ReqType::ListStores => self.handle_list_stores_request(payload),
+            ReqType::SelectStore => self.handle_select_store_request(payload, &mut session.store_),
+            ReqType::BackupStatus => self.handle_backup_status_request(payload),
+            ReqType::ListBackups => self.handle_list_backups_request(payload),
+            ReqType::BackupInfo => self.handle_backup_info_request(payload),
+            ReqType::DeleteBackup => self.handle_delete_backup_request(payload),
+            ReqType::CompactBackup => self.handle_compact_backup_request(payload),
+            ReqType::Export => self.handle_export_request(payload, session_store),
+            ReqType::Import => self.handle_import_request(payload, session_store),
+            ReqType::Cas => self.handle_cas_request(payload, session_store),
+            ReqType::History => self.handle_history_request(payload, session_store),
+            ReqType::Incr => self.handle_incr_request(payload, session_store),
+            ReqType::Transaction => self.handle_transaction_request(payload, session_store),
+            ReqType::Watch => self.handle_watch_request(payload, session),
+            ReqType::Unwatch => self.handle_unwatch_request(payload, session),
+            ReqType::Exec => self.handle_exec_request(payload, session),
+            ReqType::Mget => self.handle_mget_request(payload, session_store),
+            ReqType::Mset => self.handle_mset_request(payload, session_store),
+            ReqType::Mdel => self.handle_mdel_request(payload, session_store)
+        }
+    }
+
     // TODO: Given that Error is a trait, we should ideally create custom
     // errors that extend it and improve our error reporting system.
     pub async fn main_loop(self: Arc<Self>) -> Result<(), Box<dyn std::error::Error>> {
         let listener = TcpListener::bind(self.listen_addr_.as_str()).await?;
+        tokio::spawn(self.clone().expiry_loop());
         // Create an infinite loop that waits on a connection to the socket.
         // Once a connection is hit, spawn off a handler to this connection
         // that reads the data input to the socket, handles it, and exits
@@ -261,48 +1998,37 @@ impl ConstructCacheServer {
                 let mut framed = Framed::new(
                     socket, LengthDelimitedCodec::new());
                 trace!("Received connection from: {:?}", addr);
+                let mut session = Session::new(&self_arc.default_store_);
                 while let Some(Ok(bytes)) = framed.next().await {
-                    if bytes.len() == 0 {
+                    if bytes.is_empty() {
                         return;
                     }
-                    let req: GenericRequest;
-                    match parse_generic_request(&bytes.freeze()) {
-                        Ok(r) => req = r,
-                        Err(e) => { 
+                    let req = match parse_generic_request(&bytes.freeze()) {
+                        Ok(r) => r,
+                        Err(e) => {
                             warn!("Parse error: {:?}", e);
                             return;
                         }
-                    }
+                    };
                     let req_type = req.req_type();
                     let payload = req.payload;
-                    let resp: Vec<u8>;
-                    match req_type {
-                        ReqType::Ping => {
-                            resp = self_arc.handle_ping_request(&payload);
-                        },
-                        ReqType::Create => {
-                            resp = self_arc.handle_create_request(&payload);
-                        },
-                        ReqType::Read => {
-                            resp = self_arc.handle_read_request(&payload);
-                        },
-                        ReqType::Update => {
-                            resp = self_arc.handle_update_request(&payload);
-                        },
-                        ReqType::Delete => {
-                            resp = self_arc.handle_delete_request(&payload);
-                        },
-                        ReqType::Backup => {
-                            resp = self_arc.handle_backup_request(&payload);
+                    let server = self_arc.clone();
+                    // Handlers wait on store locks and on the disk, so they
+                    // run on the blocking pool to keep the tokio workers free
+                    let handled = tokio::task::spawn_blocking(move || {
+                        let resp = server.handle_request(req_type, &payload, &mut session);
+                        (resp, session)
+                    }).await;
+                    let resp = match handled {
+                        Ok((r, s)) => {
+                            session = s;
+                            r
                         },
-                        ReqType::Restore => {
-                            resp = self_arc.handle_restore_request(&payload);
-                        }
-                        _ => {
-                            warn!("Unrecognized request type from {:?}", addr);
-                            continue;
+                        Err(e) => {
+                            error!("Request handler failed: {:?}", e);
+                            return;
                         }
-                    }
+                    };
                     let mut generic_resp = GenericResponse::default();
                     generic_resp.set_req_type(req_type);
                     generic_resp.payload = resp;
@@ -317,4 +2043,518 @@ impl ConstructCacheServer {
             });
         }
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn server() -> (Arc<ConstructCacheServer>, Session) {
+        (ConstructCacheServer::new("127.0.0.1:0", "default"), Session::new("default"))
+    }
+
+    fn string_pair(key: &str, value: &str) -> GenericKeyValuePair {
+        GenericKeyValuePair {
+            key: key.as_bytes().to_vec(),
+            data_type: DataType::String as i32,
+            value: value.as_bytes().to_vec()
+        }
+    }
+
+    fn create_op(key: &str, value: &str) -> TxnOp {
+        TxnOp {
+            op: Some(txn_op::Op::Create(TxnWrite { pair: Some(string_pair(key, value)), ttl_ms: None }))
+        }
+    }
+
+    fn create(server: &ConstructCacheServer, session: &mut Session, key: &str, value: &str) {
+        let req = CreateKvPairReq {
+            pair: Some(KeyValuePair { key: key.to_string(), value: value.to_string() }),
+            ..Default::default()
+        };
+        let resp = server.handle_request(ReqType::Create, &req.encode_to_vec(), session);
+        assert!(CreateKvPairResp::decode(resp.as_slice()).unwrap().success);
+    }
+
+    fn delete(server: &ConstructCacheServer, session: &mut Session, key: &str) {
+        let req = DeleteKvPairReq { key: key.as_bytes().to_vec(), store: None };
+        let resp = server.handle_request(ReqType::Delete, &req.encode_to_vec(), session);
+        assert!(DeleteKvPairResp::decode(resp.as_slice()).unwrap().success);
+    }
+
+    fn watch(server: &ConstructCacheServer, session: &mut Session, keys: &[&str]) -> WatchResp {
+        let req = WatchReq { keys: keys.iter().map(|k| k.as_bytes().to_vec()).collect(), store: None };
+        let resp = server.handle_request(ReqType::Watch, &req.encode_to_vec(), session);
+        WatchResp::decode(resp.as_slice()).unwrap()
+    }
+
+    fn exec(server: &ConstructCacheServer, session: &mut Session, ops: Vec<TxnOp>)
+            -> TransactionResp {
+        let req = TransactionReq { ops, store: None };
+        let resp = server.handle_request(ReqType::Exec, &req.encode_to_vec(), session);
+        TransactionResp::decode(resp.as_slice()).unwrap()
+    }
+
+    fn mget(server: &ConstructCacheServer, session: &mut Session, keys: &[&str]) -> MGetResp {
+        let req = MGetReq { keys: keys.iter().map(|k| k.as_bytes().to_vec()).collect(), store: None };
+        let resp = server.handle_request(ReqType::Mget, &req.encode_to_vec(), session);
+        MGetResp::decode(resp.as_slice()).unwrap()
+    }
+
+    fn mset(server: &ConstructCacheServer, session: &mut Session, pairs: &[(&str, &str)])
+            -> MSetResp {
+        let req = MSetReq {
+            pairs: pairs.iter().map(|(k, v)| string_pair(k, v)).collect(),
+            ..Default::default()
+        };
+        let resp = server.handle_request(ReqType::Mset, &req.encode_to_vec(), session);
+        MSetResp::decode(resp.as_slice()).unwrap()
+    }
+
+    fn mdel(server: &ConstructCacheServer, session: &mut Session, keys: &[&str]) -> MDelResp {
+        let req = MDelReq { keys: keys.iter().map(|k| k.as_bytes().to_vec()).collect(), store: None };
+        let resp = server.handle_request(ReqType::Mdel, &req.encode_to_vec(), session);
+        MDelResp::decode(resp.as_slice()).unwrap()
+    }
+
+    #[test]
+    fn test_batches_of_present_and_missing_keys() {
+        let (server, mut session) = server();
+        let resp = mset(&server, &mut session, &[("a", "1"), ("c", "3")]);
+        assert!(resp.error.is_empty());
+        assert!(resp.results.iter().all(|r| r.success));
+        let version = resp.results[0].version;
+
+        let resp = mget(&server, &mut session, &["a", "b", "c"]);
+        assert!(resp.error.is_empty());
+        let values: Vec<Option<Vec<u8>>> = resp.results.iter()
+            .map(|r| r.pair.as_ref().map(|p| p.value.clone()))
+            .collect();
+        assert_eq!(values, vec![Some(b"1".to_vec()), None, Some(b"3".to_vec())]);
+        assert_eq!(resp.results[0].version, version);
+
+        let resp = mdel(&server, &mut session, &["a", "b"]);
+        assert!(resp.error.is_empty());
+        assert_eq!(resp.results.iter().map(|r| r.success).collect::<Vec<_>>(), vec![true, false]);
+        assert!(mget(&server, &mut session, &["a"]).results[0].pair.is_none());
+    }
+
+    #[test]
+    fn test_batches_over_limit_are_refused() {
+        let (server, mut session) = server();
+        let keys: Vec<String> = (0..=MAX_BATCH_KEYS).map(|i| format!("key{}", i)).collect();
+        let keys: Vec<&str> = keys.iter().map(|k| k.as_str()).collect();
+        let pairs: Vec<(&str, &str)> = keys.iter().map(|k| (*k, "v")).collect();
+
+        let resp = mset(&server, &mut session, &pairs);
+        assert!(!resp.error.is_empty());
+        assert!(resp.results.is_empty());
+        assert!(mget(&server, &mut session, &["key0"]).results[0].pair.is_none());
+        let resp = mget(&server, &mut session, &keys);
+        assert!(!resp.error.is_empty());
+        assert!(resp.results.is_empty());
+        let resp = mdel(&server, &mut session, &keys);
+        assert!(!resp.error.is_empty());
+        assert!(resp.results.is_empty());
+
+        // Right at the limit is fine
+        let resp = mset(&server, &mut session, &pairs[..MAX_BATCH_KEYS]);
+        assert!(resp.error.is_empty());
+        assert_eq!(resp.results.len(), MAX_BATCH_KEYS);
+    }
+
+    #[test]
+    fn test_empty_batches() {
+        let (server, mut session) = server();
+        let resp = mget(&server, &mut session, &[]);
+        assert!(resp.error.is_empty());
+        assert!(resp.results.is_empty());
+        let resp = mset(&server, &mut session, &[]);
+        assert!(resp.error.is_empty());
+        assert!(resp.results.is_empty());
+        let resp = mdel(&server, &mut session, &[]);
+        assert!(resp.error.is_empty());
+        assert!(resp.results.is_empty());
+    }
+
+    #[test]
+    fn test_mset_with_duplicate_keys() {
+        let (server, mut session) = server();
+        // Written in order, so the last pair for a key wins
+        let resp = mset(&server, &mut session, &[("a", "1"), ("b", "2"), ("a", "3")]);
+        assert!(resp.results.iter().all(|r| r.success));
+        let version = resp.results[2].version;
+        assert!(version > resp.results[0].version);
+        let resp = mget(&server, &mut session, &["a"]);
+        assert_eq!(resp.results[0].pair.as_ref().unwrap().value, b"3".to_vec());
+        assert_eq!(resp.results[0].version, version);
+    }
+
+    fn store_request(server: &ConstructCacheServer, session: &mut Session, req_type: ReqType,
+            name: &str) -> (bool, String) {
+        let payload = match req_type {
+            ReqType::CreateStore => CreateStoreReq { name: name.to_string() }.encode_to_vec(),
+            ReqType::DropStore => DropStoreReq { name: name.to_string() }.encode_to_vec(),
+            _ => SelectStoreReq { name: name.to_string() }.encode_to_vec()
+        };
+        let resp = server.handle_request(req_type, &payload, session);
+        // The three responses are encoded alike
+        let resp = SelectStoreResp::decode(resp.as_slice()).unwrap();
+        (resp.success, resp.error)
+    }
+
+    #[test]
+    fn test_create_store_twice() {
+        let (server, mut session) = server();
+        assert!(store_request(&server, &mut session, ReqType::CreateStore, "other").0);
+        let (success, error) = store_request(&server, &mut session, ReqType::CreateStore, "other");
+        assert!(!success);
+        assert!(!error.is_empty());
+        let (success, _) = store_request(&server, &mut session, ReqType::CreateStore, "default");
+        assert!(!success);
+    }
+
+    #[test]
+    fn test_drop_selected_store() {
+        let (server, mut session) = server();
+        store_request(&server, &mut session, ReqType::CreateStore, "other");
+        assert!(store_request(&server, &mut session, ReqType::SelectStore, "other").0);
+        create(&server, &mut session, "a", "1");
+        let mut other = Session::new("default");
+        assert!(store_request(&server, &mut other, ReqType::DropStore, "other").0);
+
+        // The connection that selected it gets errors rather than another store
+        let req = ReadKvPairReq { key: b"a".to_vec(), ..Default::default() };
+        let resp = server.handle_request(ReqType::Read, &req.encode_to_vec(), &mut session);
+        let resp = ReadKvPairResp::decode(resp.as_slice()).unwrap();
+        assert!(!resp.success);
+        assert!(resp.error.starts_with(NO_SUCH_STORE), "{:?}", resp.error);
+
+        // Made again, the store starts out empty
+        store_request(&server, &mut other, ReqType::CreateStore, "other");
+        let resp = server.handle_request(ReqType::Read, &req.encode_to_vec(), &mut session);
+        let resp = ReadKvPairResp::decode(resp.as_slice()).unwrap();
+        assert!(!resp.success);
+        assert!(resp.error.is_empty());
+
+        let (success, _) = store_request(&server, &mut other, ReqType::DropStore, "default");
+        assert!(!success);
+    }
+
+    #[test]
+    fn test_select_missing_store() {
+        let (server, mut session) = server();
+        let (success, error) = store_request(&server, &mut session, ReqType::SelectStore, "missing");
+        assert!(!success);
+        assert!(error.starts_with(NO_SUCH_STORE), "{:?}", error);
+        // The connection keeps using the store it had
+        assert_eq!(session.store_, "default");
+        create(&server, &mut session, "a", "1");
+    }
+
+    async fn backup(server: &ConstructCacheServer, session: &mut Session, backup_id: &str,
+            base_backup_id: Option<&str>) {
+        let req = BackupReq {
+            backup_id: backup_id.to_string(),
+            store: None,
+            base_backup_id: base_backup_id.map(String::from)
+        };
+        let resp = server.handle_request(ReqType::Backup, &req.encode_to_vec(), session);
+        let resp = BackupResp::decode(resp.as_slice()).unwrap();
+        assert!(resp.success, "{:?}", resp.error);
+        let status = BackupStatusReq { job_id: resp.job_id }.encode_to_vec();
+        loop {
+            let resp = server.handle_request(ReqType::BackupStatus, &status, session);
+            match BackupStatusResp::decode(resp.as_slice()).unwrap().state() {
+                BackupState::Running => tokio::time::sleep(Duration::from_millis(1)).await,
+                state => {
+                    assert_eq!(state, BackupState::Succeeded);
+                    return;
+                }
+            }
+        }
+    }
+
+    fn backup_info(server: &ConstructCacheServer, session: &mut Session, backup_id: &str)
+            -> BackupInfoResp {
+        let req = BackupInfoReq { backup_id: backup_id.to_string() };
+        let resp = server.handle_request(ReqType::BackupInfo, &req.encode_to_vec(), session);
+        BackupInfoResp::decode(resp.as_slice()).unwrap()
+    }
+
+    fn delete_backup(server: &ConstructCacheServer, session: &mut Session, backup_id: &str)
+            -> DeleteBackupResp {
+        let req = DeleteBackupReq { backup_id: backup_id.to_string() };
+        let resp = server.handle_request(ReqType::DeleteBackup, &req.encode_to_vec(), session);
+        DeleteBackupResp::decode(resp.as_slice()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_list_inspect_and_delete_backups() {
+        let dir = "/tmp/test_server_backups";
+        let _ = std::fs::remove_dir_all(dir);
+        let server = ConstructCacheServer::with_options("127.0.0.1:0", "default", ServerOptions {
+            backup_dir: Some(dir.to_string()),
+            ..Default::default()
+        }).unwrap();
+        let mut session = Session::new("default");
+        create(&server, &mut session, "a", "1");
+        backup(&server, &mut session, "full", None).await;
+        create(&server, &mut session, "b", "2");
+        backup(&server, &mut session, "incr", Some("full")).await;
+
+        let resp = server.handle_request(ReqType::ListBackups,
+            &ListBackupsReq::default().encode_to_vec(), &mut session);
+        let listed: Vec<(String, String)> = ListBackupsResp::decode(resp.as_slice()).unwrap()
+            .backups.into_iter()
+            .map(|b| (b.backup_id, b.base_backup_id))
+            .collect();
+        assert_eq!(listed, vec![(String::from("full"), String::new()),
+            (String::from("incr"), String::from("full"))]);
+
+        let resp = backup_info(&server, &mut session, "incr");
+        assert!(resp.success, "{:?}", resp.error);
+        let info = resp.info.unwrap();
+        assert_eq!((info.store.as_str(), info.base_backup_id.as_str()), ("default", "full"));
+        assert_eq!(info.record_count, 1);
+        assert_eq!(backup_info(&server, &mut session, "full").info.unwrap().record_count, 1);
+        let resp = backup_info(&server, &mut session, "missing");
+        assert!(!resp.success);
+        assert_eq!(resp.error, NO_SUCH_BACKUP);
+
+        // A backup others are based on stays until they are gone
+        let resp = delete_backup(&server, &mut session, "full");
+        assert!(!resp.success);
+        assert!(resp.error.contains("incr"), "{:?}", resp.error);
+        assert!(Path::new(&format!("{}/full", dir)).exists());
+        let resp = delete_backup(&server, &mut session, "missing");
+        assert!(!resp.success);
+        assert_eq!(resp.error, NO_SUCH_BACKUP);
+        assert!(delete_backup(&server, &mut session, "incr").success);
+        assert!(!Path::new(&format!("{}/incr", dir)).exists());
+        assert!(delete_backup(&server, &mut session, "full").success);
+        assert!(backup_info(&server, &mut session, "full").info.is_none());
+    }
+
+    fn incr(server: &ConstructCacheServer, session: &mut Session, key: &str, delta: i64,
+            ttl_ms: Option<u64>) -> IncrResp {
+        let req = IncrReq { key: key.as_bytes().to_vec(), delta, ttl_ms, store: None };
+        let resp = server.handle_request(ReqType::Incr, &req.encode_to_vec(), session);
+        IncrResp::decode(resp.as_slice()).unwrap()
+    }
+
+    fn get_ttl(server: &ConstructCacheServer, session: &mut Session, key: &str) -> GetTtlResp {
+        let req = GetTtlReq { key: key.as_bytes().to_vec(), store: None };
+        let resp = server.handle_request(ReqType::GetTtl, &req.encode_to_vec(), session);
+        GetTtlResp::decode(resp.as_slice()).unwrap()
+    }
+
+    #[test]
+    fn test_incr_overflow_and_underflow() {
+        let (server, mut session) = server();
+        let resp = incr(&server, &mut session, "n", i64::MAX - 1, None);
+        assert!(resp.success, "{:?}", resp.error);
+        assert_eq!(resp.value, i64::MAX - 1);
+        let version = resp.version;
+        let resp = incr(&server, &mut session, "n", 2, None);
+        assert!(!resp.success);
+        assert_eq!(resp.error_kind(), IncrError::Overflow);
+        assert_eq!(resp.value, i64::MAX - 1);
+        assert_eq!(resp.error, format!("Adding 2 to {} overflows a 64-bit signed integer",
+            i64::MAX - 1));
+        let resp = incr(&server, &mut session, "n", 1, None);
+        assert_eq!((resp.success, resp.value), (true, i64::MAX));
+        assert!(resp.version > version);
+
+        assert!(incr(&server, &mut session, "m", i64::MIN, None).success);
+        let resp = incr(&server, &mut session, "m", -1, None);
+        assert!(!resp.success);
+        assert_eq!(resp.error_kind(), IncrError::Overflow);
+        assert_eq!(resp.value, i64::MIN);
+        // Nothing was written
+        let read = ReadKvPairReq { key: b"m".to_vec(), ..Default::default() };
+        let resp = server.handle_request(ReqType::Read, &read.encode_to_vec(), &mut session);
+        assert_eq!(ReadKvPairResp::decode(resp.as_slice()).unwrap().pair.unwrap().value,
+            i64::MIN.to_string());
+    }
+
+    #[test]
+    fn test_incr_non_integer() {
+        let (server, mut session) = server();
+        create(&server, &mut session, "s", "twelve");
+        let resp = incr(&server, &mut session, "s", 1, None);
+        assert!(!resp.success);
+        assert_eq!(resp.error_kind(), IncrError::NotAnInteger);
+        assert_eq!(resp.error, "Key holds a STRING value that is not a 64-bit signed integer");
+        // Other integer types could not keep their type
+        let req = CreateKvPairReq {
+            typed_pair: Some(GenericKeyValuePair {
+                key: b"u".to_vec(),
+                data_type: DataType::Uint32 as i32,
+                value: 7u32.to_le_bytes().to_vec()
+            }),
+            ..Default::default()
+        };
+        let resp = server.handle_request(ReqType::Create, &req.encode_to_vec(), &mut session);
+        assert!(CreateKvPairResp::decode(resp.as_slice()).unwrap().success);
+        let resp = incr(&server, &mut session, "u", 1, None);
+        assert_eq!(resp.error_kind(), IncrError::NotAnInteger);
+        assert_eq!(resp.error, "Key holds a UINT32 value that is not a 64-bit signed integer");
+        // A STRING holding an integer stays a STRING
+        create(&server, &mut session, "t", "12");
+        assert_eq!(incr(&server, &mut session, "t", 1, None).value, 13);
+        let read = ReadKvPairReq { key: b"t".to_vec(), ..Default::default() };
+        let resp = server.handle_request(ReqType::Read, &read.encode_to_vec(), &mut session);
+        let typed = ReadKvPairResp::decode(resp.as_slice()).unwrap().typed_pair.unwrap();
+        assert_eq!((typed.data_type(), typed.value.as_slice()), (DataType::String, &b"13"[..]));
+    }
+
+    #[test]
+    fn test_incr_creates_missing_key_with_ttl() {
+        let (server, mut session) = server();
+        let resp = incr(&server, &mut session, "n", -5, Some(60_000));
+        assert!(resp.success, "{:?}", resp.error);
+        assert_eq!(resp.value, -5);
+        let ttl = get_ttl(&server, &mut session, "n").ttl_ms.unwrap();
+        assert!(ttl > 0 && ttl <= 60_000, "{}", ttl);
+        let read = ReadKvPairReq { key: b"n".to_vec(), ..Default::default() };
+        let resp = server.handle_request(ReqType::Read, &read.encode_to_vec(), &mut session);
+        let typed = ReadKvPairResp::decode(resp.as_slice()).unwrap().typed_pair.unwrap();
+        assert_eq!(typed.data_type(), DataType::Sint64);
+        // The TTL only applies to a key being created
+        let resp = incr(&server, &mut session, "n", 2, Some(1));
+        assert_eq!((resp.success, resp.value), (true, -3));
+        assert!(get_ttl(&server, &mut session, "n").ttl_ms.unwrap() > 1);
+        assert!(incr(&server, &mut session, "forever", 1, None).success);
+        let resp = get_ttl(&server, &mut session, "forever");
+        assert!(resp.success);
+        assert_eq!(resp.ttl_ms, None);
+    }
+
+    #[test]
+    fn test_invalid_backup_ids() {
+        let too_long = "a".repeat(MAX_FILE_NAME_LEN + 1);
+        for id in ["", ".", "..", "../backup", "a/b", "/etc/passwd", "backup.tmp", ".hidden",
+                "nul\0byte", "space d", too_long.as_str()] {
+            assert!(validate_file_name(id).is_err(), "{:?} was accepted", id);
+            let e = check_requested_backup_id(id).unwrap_err();
+            assert!(e.starts_with(INVALID_BACKUP_ID), "{:?}", e);
+        }
+    }
+
+    #[test]
+    fn test_valid_backup_id_stays_in_backup_dir() {
+        let longest = "a".repeat(MAX_FILE_NAME_LEN);
+        for id in ["backup-1", "nightly_2024.01.02", "a..b", longest.as_str()] {
+            assert!(check_requested_backup_id(id).is_ok(), "{:?} was refused", id);
+        }
+        let (server, _) = server();
+        let path = server.backup_catalog_.path("nightly_2024.01.02");
+        let path = Path::new(&path);
+        assert_eq!(path.parent(), Some(Path::new(DEFAULT_BACKUP_DIR)));
+        assert_eq!(path.file_name().unwrap(), "nightly_2024.01.02");
+    }
+
+    #[test]
+    fn test_exec_without_changes_commits() {
+        let (server, mut session) = server();
+        create(&server, &mut session, "a", "1");
+        let resp = watch(&server, &mut session, &["a", "b"]);
+        assert!(resp.success);
+        assert_eq!(resp.watched, 2);
+        let resp = exec(&server, &mut session, vec![create_op("b", "2")]);
+        assert!(resp.success);
+        assert!(!resp.conflict);
+        assert!(session.watched_.is_none());
+    }
+
+    #[test]
+    fn test_exec_without_watch() {
+        let (server, mut session) = server();
+        let resp = exec(&server, &mut session, vec![create_op("a", "1")]);
+        assert!(resp.success);
+        assert!(!resp.conflict);
+        let resp = exec(&server, &mut session, vec![create_op("a", "1")]);
+        assert!(!resp.success);
+        assert!(!resp.conflict);
+    }
+
+    #[test]
+    fn test_write_to_watched_key_aborts_exec() {
+        let (server, mut session) = server();
+        create(&server, &mut session, "a", "1");
+        watch(&server, &mut session, &["a"]);
+        let update = UpdateKvPairReq {
+            pair: Some(KeyValuePair { key: String::from("a"), value: String::from("2") }),
+            ..Default::default()
+        };
+        server.handle_request(ReqType::Update, &update.encode_to_vec(), &mut Session::new("default"));
+        let resp = exec(&server, &mut session, vec![create_op("b", "2")]);
+        assert!(!resp.success);
+        assert!(resp.conflict);
+        assert_eq!(resp.conflicting_key, Some(b"a".to_vec()));
+        assert_eq!(resp.results[0].status(), TxnOpStatus::NotRun);
+        // Nothing was applied, and the watch is over
+        let resp = exec(&server, &mut session, vec![create_op("b", "2")]);
+        assert!(resp.success);
+    }
+
+    #[test]
+    fn test_created_watched_key_aborts_exec() {
+        let (server, mut session) = server();
+        watch(&server, &mut session, &["a"]);
+        create(&server, &mut Session::new("default"), "a", "1");
+        assert!(exec(&server, &mut session, vec![create_op("b", "2")]).conflict);
+    }
+
+    #[test]
+    fn test_created_and_deleted_watched_key_aborts_exec() {
+        let (server, mut session) = server();
+        watch(&server, &mut session, &["a"]);
+        let mut other = Session::new("default");
+        create(&server, &mut other, "a", "1");
+        delete(&server, &mut other, "a");
+        assert!(exec(&server, &mut session, vec![create_op("b", "2")]).conflict);
+    }
+
+    #[test]
+    fn test_ttl_change_aborts_exec() {
+        let (server, mut session) = server();
+        create(&server, &mut session, "a", "1");
+        watch(&server, &mut session, &["a"]);
+        let req = SetTtlReq { key: b"a".to_vec(), ttl_ms: 60_000, store: None };
+        server.handle_request(ReqType::SetTtl, &req.encode_to_vec(), &mut Session::new("default"));
+        assert!(exec(&server, &mut session, vec![create_op("b", "2")]).conflict);
+    }
+
+    #[test]
+    fn test_unwatch_clears_watch() {
+        let (server, mut session) = server();
+        watch(&server, &mut session, &["a", "b"]);
+        let resp = server.handle_request(ReqType::Unwatch, &UnwatchReq {}.encode_to_vec(),
+            &mut session);
+        assert_eq!(UnwatchResp::decode(resp.as_slice()).unwrap().unwatched, 2);
+        assert!(session.watched_.is_none());
+        create(&server, &mut session, "a", "1");
+        let resp = exec(&server, &mut session, vec![create_op("b", "2")]);
+        assert!(resp.success);
+        assert!(!resp.conflict);
+    }
+
+    #[test]
+    fn test_watch_is_per_connection() {
+        let (server, mut first) = server();
+        let mut second = Session::new("default");
+        watch(&server, &mut first, &["a"]);
+        assert!(second.watched_.is_none());
+        // Only the connection that watched the key is affected by a change
+        let resp = exec(&server, &mut second, vec![create_op("a", "1")]);
+        assert!(resp.success);
+        assert!(exec(&server, &mut first, vec![create_op("b", "2")]).conflict);
+        // A watch ended by one connection leaves another's in place
+        watch(&server, &mut first, &["c"]);
+        watch(&server, &mut second, &["c"]);
+        drop(second);
+        create(&server, &mut Session::new("default"), "c", "3");
+        assert!(exec(&server, &mut first, vec![create_op("d", "4")]).conflict);
+    }
+}
@@ -4,7 +4,8 @@ use std::fmt::{Debug, Display, Formatter, Result};
 pub enum ErrorKind {
     ErrorNone,
     ParseError,
-    ConnectError
+    ConnectError,
+    RequestFailedError
 }
 
 pub struct SocketError {
@@ -49,13 +50,11 @@ impl Debug for SocketError {
 }
 
 fn error_kind_to_str(ek: ErrorKind) -> String {
-    let ret: &str;
-    match ek {
-        ErrorKind::ErrorNone => {
-            ret = "";
-        }
-        ErrorKind::ParseError => ret = "Cannot parse payload",
-        ErrorKind::ConnectError => ret = "Cannot connect to server"
-    }
-    return String::from(ret);
+    let ret = match ek {
+        ErrorKind::ErrorNone => "",
+        ErrorKind::ParseError => "Cannot parse payload",
+        ErrorKind::ConnectError => "Cannot connect to server",
+        ErrorKind::RequestFailedError => "Server could not handle request"
+    };
+    String::from(ret)
 }